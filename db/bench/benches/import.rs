@@ -0,0 +1,72 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use nostr_db::{BatchOpts, Db, Event};
+use std::time::Duration;
+use tempfile::Builder;
+
+const BATCH: u64 = 1000;
+
+fn events(start: u64) -> Vec<Event> {
+    (start..start + BATCH)
+        .map(|i| {
+            let mut id = [0u8; 32];
+            id[24..].copy_from_slice(&i.to_be_bytes());
+            let mut pubkey = [0u8; 32];
+            pubkey[31] = (i % 100) as u8;
+            Event::new(
+                id,
+                pubkey,
+                i,
+                1,
+                vec![vec!["t".to_owned(), (i % 10).to_string()]],
+                "import benchmark".to_owned(),
+                [0u8; 64],
+            )
+            .unwrap()
+        })
+        .collect()
+}
+
+fn bench_import(c: &mut Criterion) {
+    let mut group = c.benchmark_group("import");
+    group.measurement_time(Duration::from_secs(3));
+    group.sample_size(20);
+    group.warm_up_time(Duration::from_millis(100));
+    group.throughput(Throughput::Elements(BATCH));
+
+    for append in [false, true] {
+        let dir = Builder::new()
+            .prefix("nostr-db-bench-import")
+            .tempdir()
+            .unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let mut start = 0;
+        let name = if append {
+            "batch_put append"
+        } else {
+            "batch_put"
+        };
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    start += BATCH;
+                    events(start)
+                },
+                |events| {
+                    db.batch_put_opts(
+                        events,
+                        BatchOpts {
+                            append,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap()
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_import);
+criterion_main!(benches);
@@ -0,0 +1,126 @@
+use crate::error::Error;
+use std::borrow::Cow;
+
+#[cfg(feature = "encrypt_at_rest")]
+use {
+    ring::{
+        aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+        rand::{SecureRandom, SystemRandom},
+    },
+    std::sync::Arc,
+};
+
+/// Encrypt the event data with ChaCha20-Poly1305.
+/// A random 96-bit nonce is stored before the ciphertext, the uid is bound as the associated data.
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    #[cfg(feature = "encrypt_at_rest")]
+    key: Option<Arc<LessSafeKey>>,
+    #[cfg(feature = "encrypt_at_rest")]
+    rng: SystemRandom,
+}
+
+impl Cipher {
+    #[cfg(feature = "encrypt_at_rest")]
+    pub fn new(key: Option<&[u8; 32]>) -> Result<Self, Error> {
+        let key = key
+            .map(|key| {
+                UnboundKey::new(&CHACHA20_POLY1305, key)
+                    .map(|key| Arc::new(LessSafeKey::new(key)))
+                    .map_err(|_| Error::Encryption("invalid key".to_owned()))
+            })
+            .transpose()?;
+        Ok(Self {
+            key,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    #[cfg(not(feature = "encrypt_at_rest"))]
+    pub fn new(key: Option<&[u8; 32]>) -> Result<Self, Error> {
+        if key.is_some() {
+            Err(Error::Encryption("Need encrypt_at_rest feature".to_owned()))
+        } else {
+            Ok(Self {})
+        }
+    }
+
+    /// encrypt the data stored by uid, return the data as is without key
+    #[cfg(feature = "encrypt_at_rest")]
+    pub fn seal(&self, uid: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if let Some(key) = &self.key {
+            let mut nonce = [0u8; NONCE_LEN];
+            self.rng
+                .fill(&mut nonce)
+                .map_err(|_| Error::Encryption("generate nonce failed".to_owned()))?;
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(uid),
+                &mut data,
+            )
+            .map_err(|_| Error::Encryption("encrypt failed".to_owned()))?;
+            let mut sealed = Vec::with_capacity(NONCE_LEN + data.len());
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&data);
+            Ok(sealed)
+        } else {
+            Ok(data)
+        }
+    }
+
+    #[cfg(not(feature = "encrypt_at_rest"))]
+    pub fn seal(&self, _uid: &[u8], data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        Ok(data)
+    }
+
+    /// decrypt the data stored by uid, return the data as is without key
+    #[cfg(feature = "encrypt_at_rest")]
+    pub fn open<'a>(&self, uid: &[u8], data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        if let Some(key) = &self.key {
+            if data.len() < NONCE_LEN {
+                return Err(Error::InvalidLength);
+            }
+            let (nonce, data) = data.split_at(NONCE_LEN);
+            let nonce =
+                Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::InvalidLength)?;
+            let mut data = data.to_vec();
+            let len = key
+                .open_in_place(nonce, Aad::from(uid), &mut data)
+                .map_err(|_| Error::Encryption("decrypt failed".to_owned()))?
+                .len();
+            data.truncate(len);
+            Ok(Cow::Owned(data))
+        } else {
+            Ok(Cow::Borrowed(data))
+        }
+    }
+
+    #[cfg(not(feature = "encrypt_at_rest"))]
+    pub fn open<'a>(&self, _uid: &[u8], data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+#[cfg(all(test, feature = "encrypt_at_rest"))]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn seal_open() -> Result<()> {
+        let cipher = Cipher::new(Some(&[1; 32]))?;
+        let uid = 10u64.to_be_bytes();
+        let data = cipher.seal(&uid, b"event".to_vec())?;
+        assert!(!data.windows(5).any(|w| w == b"event"));
+        assert_eq!(cipher.open(&uid, &data)?.as_ref(), b"event");
+        // a fresh nonce for each seal
+        assert_ne!(cipher.seal(&uid, b"event".to_vec())?, data);
+        // bound to the uid and key
+        assert!(cipher.open(&11u64.to_be_bytes(), &data).is_err());
+        assert!(Cipher::new(Some(&[2; 32]))?.open(&uid, &data).is_err());
+
+        let plain = Cipher::new(None)?;
+        assert_eq!(plain.seal(&uid, b"event".to_vec())?, b"event");
+        Ok(())
+    }
+}
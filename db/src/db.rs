@@ -1,20 +1,26 @@
 use crate::{
+    cipher::Cipher,
     error::Error,
-    key::{concat, concat_sep, encode_replace_key, u16_to_ver, u64_to_ver, IndexKey},
-    ArchivedEventIndex, Event, EventIndex, Filter, FromEventData, Stats,
+    key::{
+        concat, concat_sep, decode_coordinate, encode_replace_key, hex_tags_meta, u32_to_ver,
+        u64_to_ver, IndexKey, DEFAULT_HEX_TAGS_META,
+    },
+    ArchivedEventIndex, Event, EventIndex, EventMeta, Filter, FromEventData, HexPrefix, Stats,
 };
 use nostr_kv::{
     lmdb::{Db as Lmdb, Iter as LmdbIter, *},
-    scanner::{Group, GroupItem, MatchResult, Scanner},
+    scanner::{Group, GroupItem, MatchResult, Scanner, TimeKey},
 };
 
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
     marker::PhantomData,
-    ops::Bound,
-    path::Path,
+    ops::{Bound, Deref, DerefMut},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, TryLockError,
     },
     time::{Duration, Instant},
 };
@@ -30,7 +36,167 @@ pub fn upper(mut key: Vec<u8>) -> Option<Vec<u8>> {
 }
 
 const MAX_TAG_VALUE_SIZE: usize = 255;
-const DB_VERSION: &str = "3";
+// lmdb max_key_size
+const MAX_KEY_SIZE: usize = 511;
+// 5: the multi-letter tag keys are indexed in t_tag_multi
+const DB_VERSION: &str = "5";
+const DEFAULT_MAP_SIZE: usize = 1_000_000_000_000;
+
+/// Ephemeral events older than this are removed by the expiration sweeper
+pub const EPHEMERAL_EXPIRED_SECONDS: u64 = 60 * 5;
+
+/// Options for opening the database
+#[derive(Debug, Clone, Default)]
+pub struct DbOptions {
+    /// Advise the kernel to back the memory map with transparent huge pages,
+    /// reduces TLB misses for large working sets. Linux only, no-op elsewhere. A failed advice is logged.
+    pub huge_pages: bool,
+    /// Return [`Error::WriterBusy`] from [`Db::writer`] when another writer is alive,
+    /// instead of blocking until it is committed or dropped.
+    pub writer_busy_error: bool,
+    /// Encrypt the stored event data with the key, the index stays plaintext for queries.
+    /// Needs the `encrypt_at_rest` feature, the key must be the same since the database is created.
+    pub encryption_key: Option<[u8; 32]>,
+    /// Size of the memory map in bytes, the max size of the database. default 1TB
+    pub map_size: Option<usize>,
+    /// Grow the memory map by doubling up to this size when it is full, see [`Db::batch_put_opts`].
+    /// default no growth
+    pub max_map_size: Option<usize>,
+    /// Don't fsync on commit (`MDB_NOSYNC`), call [`Db::flush`] periodically instead.
+    /// Faster writes, a system crash may lose the transactions since the last flush.
+    pub no_sync: bool,
+    /// Verify the id and signature of the events read from the data, the events failed
+    /// are skipped and logged. Detects the corrupted or tampered storage, expensive.
+    /// The id only reads from the index are not verified.
+    pub verify_on_read: bool,
+    /// Open an existing database for reading only (`MDB_RDONLY`), the directory is not created
+    /// and [`Db::writer`] fails with the read-only error.
+    pub read_only: bool,
+}
+
+/// Options for [`Db::batch_put_opts`]
+#[derive(Debug, Clone, Default)]
+pub struct BatchOpts {
+    /// Verify the event id and signature before writing
+    pub verify: bool,
+    /// Abort the whole batch on the first invalid event, nothing is committed.
+    /// Otherwise skip the invalid events and write the others.
+    pub abort_on_error: bool,
+    /// Append to the uid keyed data trees with `MDB_APPEND`, faster for bulk load.
+    /// See [`Db::put_append`].
+    pub append: bool,
+}
+
+/// The number of the queries of [`Db::iter`] by each index path, needs the `index_stats` feature
+#[cfg(feature = "index_stats")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexUsage {
+    pub id: u64,
+    pub pubkey: u64,
+    pub pubkey_kind: u64,
+    pub kind: u64,
+    pub tag: u64,
+    pub word: u64,
+    pub time: u64,
+}
+
+#[cfg(feature = "index_stats")]
+impl IndexUsage {
+    /// (path name, count) pairs
+    pub fn paths(&self) -> [(&'static str, u64); 7] {
+        [
+            ("id", self.id),
+            ("pubkey", self.pubkey),
+            ("pubkey_kind", self.pubkey_kind),
+            ("kind", self.kind),
+            ("tag", self.tag),
+            ("word", self.word),
+            ("time", self.time),
+        ]
+    }
+}
+
+// the index path of a query, the position of the counter
+#[derive(Debug, Clone, Copy)]
+enum IndexPath {
+    Id,
+    Pubkey,
+    PubkeyKind,
+    Kind,
+    Tag,
+    Word,
+    Time,
+}
+
+/// The limit policy of [`Db::iter_opts`]
+#[derive(Debug, Clone, Default)]
+pub struct QueryOpts {
+    /// The limit of the filter without a limit
+    pub default_limit: Option<u64>,
+    /// Clamp the limit of the filter to this
+    pub max_limit: Option<u64>,
+    /// Ignore the limits, iter all the matched events
+    pub no_limit: bool,
+}
+
+impl QueryOpts {
+    /// Fill the default limit then clamp it to the max limit
+    pub fn apply(&self, filter: &mut Filter) {
+        if self.no_limit {
+            filter.limit = None;
+            return;
+        }
+        if let Some(limit) = self.default_limit {
+            filter.default_limit(limit);
+        }
+        if let (Some(limit), Some(max)) = (filter.limit, self.max_limit) {
+            filter.limit = Some(limit.min(max));
+        }
+    }
+}
+
+/// Information about a checkpoint made by [`Db::checkpoint`]
+#[derive(Debug, Clone)]
+pub struct CheckpointInfo {
+    /// the checkpoint directory
+    pub path: PathBuf,
+    /// the schema version of the checkpoint
+    pub version: String,
+    /// the latest event sequence in the checkpoint
+    pub seq: u64,
+    /// size of the data file in bytes
+    pub size: u64,
+}
+
+/// Result of [`Db::prewarm`]
+#[derive(Debug, Clone, Default)]
+pub struct PrewarmInfo {
+    /// number of the index entries read
+    pub entries: u64,
+    /// bytes of the keys and values read
+    pub bytes: u64,
+    /// whether all the trees are read within the budget
+    pub completed: bool,
+}
+
+/// Extract the custom index keys of an event, see [`Db::register_index`]
+pub type IndexExtractor = Arc<dyn Fn(&Event) -> Vec<Vec<u8>> + Send + Sync>;
+
+#[derive(Clone)]
+struct CustomIndex {
+    name: String,
+    tree: Tree,
+    extractor: IndexExtractor,
+}
+
+impl CustomIndex {
+    // the empty and too long keys are ignored
+    fn keys(&self, event: &Event) -> Vec<Vec<u8>> {
+        let mut keys = (self.extractor)(event);
+        keys.retain(|k| !k.is_empty() && k.len() <= MAX_KEY_SIZE);
+        keys
+    }
+}
 
 #[derive(Clone)]
 pub struct Db {
@@ -55,47 +221,135 @@ pub struct Db {
     t_pubkey_kind: Tree,
     t_created_at: Tree,
     t_tag: Tree,
+    // the tags with a multi-letter key, key value time
+    t_tag_multi: Tree,
     t_deletion: Tree,
     t_replacement: Tree,
     t_expiration: Tree,
+    // unsigned event metadata by uid
+    t_event_meta: Tree,
+    // shadowbanned pubkeys
+    t_shadowban: Tree,
+    // the shadowbanned pubkeys cached from t_shadowban, updated by this handle
+    shadowbanned: Arc<RwLock<Arc<HashSet<[u8; 32]>>>>,
     // word time
     t_word: Tree,
     seq: Arc<AtomicU64>,
+    // only one writer at a time
+    writer_lock: Arc<Mutex<()>>,
+    writer_busy_error: bool,
+    cipher: Cipher,
+    verify_on_read: bool,
+    max_map_size: Option<usize>,
+    // application-defined indexes
+    custom_indexes: Arc<RwLock<Vec<CustomIndex>>>,
+    // query count of each index path
+    #[cfg(feature = "index_stats")]
+    index_usage: Arc<[AtomicU64; 7]>,
+}
+
+/// The write transaction of [`Db`], holds the single writer slot until committed or dropped.
+pub struct DbWriter<'env> {
+    txn: Writer<'env>,
+    _guard: MutexGuard<'env, ()>,
+}
+
+impl<'env> Deref for DbWriter<'env> {
+    type Target = Writer<'env>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.txn
+    }
+}
+
+impl<'env> DerefMut for DbWriter<'env> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.txn
+    }
+}
+
+impl<'env> Transaction for DbWriter<'env> {
+    fn txn(&self) -> *mut ffi::MDB_txn {
+        self.txn.txn()
+    }
+
+    // release the writer slot after commit
+    fn commit(self) -> Result<(), nostr_kv::Error> {
+        self.txn.commit()
+    }
+}
+
+/// The transaction of [`Db::transaction`], writes through the single writer of the closure.
+pub struct DbTransaction<'env> {
+    db: &'env Db,
+    writer: DbWriter<'env>,
+}
+
+impl<'env> DbTransaction<'env> {
+    /// put an event, see [`Db::put`]
+    pub fn put<E: AsRef<Event>>(&mut self, event: E) -> Result<CheckEventResult> {
+        self.db.put(&mut self.writer, event)
+    }
+
+    /// delete an event by id, see [`Db::del`]
+    pub fn del<K: AsRef<[u8]>>(&mut self, event_id: K) -> Result<bool> {
+        self.db.del(&mut self.writer, event_id)
+    }
+
+    /// get an event by id, the uncommitted writes of the transaction are visible
+    pub fn get<R: FromEventData, K: AsRef<[u8]>>(&self, event_id: K) -> Result<Option<R>> {
+        self.db.get(&self.writer, event_id)
+    }
+
+    /// check whether an event id is stored, see [`Db::exists`]
+    pub fn exists<K: AsRef<[u8]>>(&self, event_id: K) -> Result<bool> {
+        self.db.exists(&self.writer, event_id)
+    }
 }
 
 fn u64_from_bytes(bytes: &[u8]) -> Result<u64, Error> {
     Ok(u64::from_be_bytes(bytes.try_into()?))
 }
 
-fn u16_from_bytes(bytes: &[u8]) -> Result<u16, Error> {
-    Ok(u16::from_be_bytes(bytes.try_into()?))
+fn u32_from_bytes(bytes: &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_be_bytes(bytes.try_into()?))
 }
 
 // Get the latest seq from db
-fn latest_seq(db: &Lmdb, tree: &Tree) -> Result<u64, Error> {
+fn latest_seq(db: &Lmdb, tree: &Tree) -> Result<Option<u64>, Error> {
     let txn = db.reader()?;
     let mut iter = txn.iter_from(tree, Bound::Unbounded::<Vec<u8>>, true);
     if let Some(item) = iter.next() {
         let (k, _) = item?;
-        u64_from_bytes(k)
+        Ok(Some(u64_from_bytes(k)?))
     } else {
-        Ok(0)
+        Ok(None)
     }
 }
 
+// store the original json when available
 #[cfg(feature = "zstd")]
 fn encode_event(event: &Event) -> Result<Vec<u8>> {
-    let json = event.to_json()?;
+    let json = event.to_raw_json()?;
     let mut json = zstd::encode_all(json.as_bytes(), 5).map_err(Error::Io)?;
     json.push(1);
     Ok(json)
 }
 #[cfg(not(feature = "zstd"))]
-fn encode_event(event: &Event) -> Result<String> {
-    event.to_json()
+fn encode_event(event: &Event) -> Result<Vec<u8>> {
+    Ok(event.to_raw_json()?.into_owned().into_bytes())
 }
 
 impl Db {
+    /// single letter tags are stored in t_tag, the others in t_tag_multi
+    fn tag_tree(&self, key: &[u8]) -> &Tree {
+        if key.len() > 1 {
+            &self.t_tag_multi
+        } else {
+            &self.t_tag
+        }
+    }
+
     fn del_event(&self, writer: &mut Writer, event: &Event, uid: &[u8]) -> Result<(), Error> {
         let index_event = event.index();
         let time = index_event.created_at();
@@ -115,6 +369,7 @@ impl Db {
 
         writer.del(&self.t_data, uid, None)?;
         writer.del(&self.t_index, uid, None)?;
+        writer.del(&self.t_event_meta, uid, None)?;
         writer.del(&self.t_id_uid, index_event.id(), None)?;
 
         writer.del(
@@ -154,7 +409,7 @@ impl Db {
         let tagval = concat(uid, kind.to_be_bytes());
         for tag in index_event.tags() {
             writer.del(
-                &self.t_tag,
+                self.tag_tree(&tag.0),
                 IndexKey::encode_tag(&tag.0, &tag.1, time),
                 Some(&tagval),
             )?;
@@ -171,6 +426,12 @@ impl Db {
             writer.del(&self.t_expiration, IndexKey::encode_time(*t), Some(uid))?;
         }
 
+        for index in self.custom_indexes().iter() {
+            for key in index.keys(event) {
+                writer.del(&index.tree, key, Some(uid))?;
+            }
+        }
+
         Ok(())
     }
 
@@ -180,18 +441,31 @@ impl Db {
         event: &Event,
         uid: &Vec<u8>,
         replace_key: &Option<Vec<u8>>,
+        append: bool,
     ) -> Result<(), Error> {
         let index_event = event.index();
+        // the uid is allocated in ascending order under the writer
+        let flags = if append { ffi::MDB_APPEND } else { 0 };
 
         // put event
         let time = index_event.created_at();
-        let json = encode_event(event)?;
+        let json = self.cipher.seal(uid, encode_event(event)?)?;
 
-        writer.put(&self.t_data, uid, json)?;
+        writer.put_flags(&self.t_data, uid, json, flags)?;
 
         // put index
         let bytes = index_event.to_bytes()?;
-        writer.put(&self.t_index, uid, bytes)?;
+        writer.put_flags(&self.t_index, uid, bytes, flags)?;
+
+        // the metadata isn't encrypted
+        if !event.meta().is_empty() {
+            writer.put_flags(
+                &self.t_event_meta,
+                uid,
+                serde_json::to_vec(event.meta())?,
+                flags,
+            )?;
+        }
 
         // put view
         let kind = index_event.kind();
@@ -229,12 +503,15 @@ impl Db {
         for tag in index_event.tags() {
             let key = &tag.0;
             let v = &tag.1;
-            // tag[0] == 'e'
-            if kind == 5 && key[0] == 101 {
+            if kind == 5 && key == b"e" {
                 writer.put(&self.t_deletion, concat(index_event.id(), v), uid)?;
             }
             // Provide pubkey kind for filter
-            writer.put(&self.t_tag, IndexKey::encode_tag(key, v, time), &tagval)?;
+            writer.put(
+                self.tag_tree(key),
+                IndexKey::encode_tag(key, v, time),
+                &tagval,
+            )?;
         }
 
         // replacement index
@@ -258,6 +535,12 @@ impl Db {
                 writer.put(&self.t_word, IndexKey::encode_word(item, time), uid)?;
             }
         }
+
+        for index in self.custom_indexes().iter() {
+            for key in index.keys(event) {
+                writer.put(&index.tree, key, uid)?;
+            }
+        }
         Ok(())
     }
 }
@@ -267,11 +550,13 @@ fn get_event<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     id_tree: &Tree,
     data_tree: &Tree,
     index_tree: &Tree,
+    cipher: &Cipher,
+    verify: bool,
     event_id: K,
 ) -> Result<Option<(Vec<u8>, R)>, Error> {
     let uid = get_uid(reader, id_tree, event_id)?;
     if let Some(uid) = uid {
-        let event = get_event_by_uid(reader, data_tree, index_tree, &uid)?;
+        let event = get_event_by_uid(reader, data_tree, index_tree, cipher, verify, &uid)?;
         if let Some(event) = event {
             return Ok(Some((uid, event)));
         }
@@ -283,6 +568,8 @@ fn get_event_by_uid<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     reader: &T,
     data_tree: &Tree,
     index_tree: &Tree,
+    cipher: &Cipher,
+    verify: bool,
     uid: K,
 ) -> Result<Option<R>, Error> {
     if R::only_id() {
@@ -295,16 +582,46 @@ fn get_event_by_uid<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
             ));
         }
     } else {
-        let v = reader.get(data_tree, uid)?;
+        let v = reader.get(data_tree, &uid)?;
         if let Some(v) = v {
-            return Ok(Some(
-                R::from_data(v).map_err(|e| Error::Message(e.to_string()))?,
-            ));
+            return decode_event_data(cipher, verify, uid.as_ref(), v);
         }
     }
     Ok(None)
 }
 
+/// decode the stored event data of the uid, none if it fails to verify
+fn decode_event_data<R: FromEventData>(
+    cipher: &Cipher,
+    verify: bool,
+    uid: &[u8],
+    v: &[u8],
+) -> Result<Option<R>, Error> {
+    let v = cipher.open(uid, v)?;
+    if verify && !verified(uid, &v) {
+        return Ok(None);
+    }
+    Ok(Some(
+        R::from_data(v).map_err(|e| Error::Message(e.to_string()))?,
+    ))
+}
+
+/// verify the id and signature of the stored event data, log the failed
+fn verified(uid: &[u8], data: &[u8]) -> bool {
+    let res = Event::from_data(data).and_then(|event| {
+        event.verify_id()?;
+        event.verify_sign()
+    });
+    if let Err(err) = &res {
+        tracing::warn!(
+            uid = hex::encode(uid),
+            error = err.to_string(),
+            "skip the stored event failed to verify"
+        );
+    }
+    res.is_ok()
+}
+
 fn decode_event_index(v: Option<&[u8]>) -> Result<Option<&ArchivedEventIndex>, Error> {
     if let Some(v) = v {
         return Ok(Some(EventIndex::from_zeroes(v)?));
@@ -335,23 +652,177 @@ impl Db {
         Ok(())
     }
 
-    /// check db version, return [`Error::VersionMismatch`] when db schema changed
+    /// check db version, return [`Error::VersionMismatch`] when db schema changed,
+    /// [`Error::HexTagsMismatch`] when the database is indexed with the other hex tags,
+    /// see [`crate::set_hex_tags`]
     pub fn check_schema(&self) -> Result<()> {
-        let mut writer = self.inner.writer()?;
-        let old = writer.get(&self.t_meta, "version")?;
-        if let Some(old) = old {
-            if old != DB_VERSION.as_bytes() {
-                return Err(Error::VersionMismatch);
-            }
-        } else {
+        self.check_schema_with(&hex_tags_meta())
+    }
+
+    fn check_schema_with(&self, hex_tags: &[u8]) -> Result<()> {
+        let mut writer = self.writer()?;
+        let (version, recorded_hex_tags) = self.compare_schema(&writer, hex_tags)?;
+        if version {
             writer.put(&self.t_meta, "version", DB_VERSION)?;
         }
+        if recorded_hex_tags {
+            writer.put(&self.t_meta, "hex_tags", hex_tags)?;
+        }
         writer.commit()?;
         Ok(())
     }
 
+    /// check the schema like [`Db::check_schema`] without writing, works on the read-only database
+    pub fn verify_schema(&self) -> Result<()> {
+        let reader = self.reader()?;
+        self.compare_schema(&reader, &hex_tags_meta())?;
+        Ok(())
+    }
+
+    /// compare the schema records, return whether the version and the hex tags need to be recorded
+    fn compare_schema<T: Transaction>(&self, txn: &T, hex_tags: &[u8]) -> Result<(bool, bool)> {
+        let created = match txn.get(&self.t_meta, "version")? {
+            Some(old) if old != DB_VERSION.as_bytes() => return Err(Error::VersionMismatch),
+            Some(_) => false,
+            None => true,
+        };
+        match txn.get(&self.t_meta, "hex_tags")? {
+            Some(old) if old != hex_tags => Err(Error::HexTagsMismatch),
+            Some(_) => Ok((created, false)),
+            // the databases before recording the hex tags are indexed with the default
+            None if !created && hex_tags != DEFAULT_HEX_TAGS_META => Err(Error::HexTagsMismatch),
+            None => Ok((created, true)),
+        }
+    }
+
+    /// A cheap readiness probe, open a reader and check the db version without writing.
+    /// Returns [`Error::VersionMismatch`] if the schema is not checked or changed.
+    pub fn probe(&self) -> Result<()> {
+        match self.version()? {
+            Some(version) if version == DB_VERSION => Ok(()),
+            _ => Err(Error::VersionMismatch),
+        }
+    }
+
+    fn version(&self) -> Result<Option<String>> {
+        let reader = self.inner.reader()?;
+        let version = reader.get(&self.t_meta, "version")?;
+        Ok(version.map(|v| String::from_utf8_lossy(v).into_owned()))
+    }
+
+    /// Sequentially read the hot index trees to pull the pages into the OS page cache.
+    /// Stops when the time or bytes budget is exhausted, 0 bytes is unlimited.
+    pub fn prewarm(&self, timeout: Option<Duration>, max_bytes: u64) -> Result<PrewarmInfo> {
+        let start = Instant::now();
+        let reader = self.inner.reader()?;
+        let mut info = PrewarmInfo::default();
+        for tree in [&self.t_created_at, &self.t_kind] {
+            for item in reader.iter(tree) {
+                let (k, v) = item?;
+                info.entries += 1;
+                info.bytes += (k.len() + v.len()) as u64;
+                // check the time budget every 1024 entries
+                if (max_bytes != 0 && info.bytes >= max_bytes)
+                    || (info.entries % 1024 == 0
+                        && timeout.map(|t| start.elapsed() >= t).unwrap_or_default())
+                {
+                    return Ok(info);
+                }
+            }
+        }
+        info.completed = true;
+        Ok(info)
+    }
+
+    /// Write a consistent compacted copy of the database to the directory, the directory
+    /// must not contain a database. Writing is not blocked during the copy.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<CheckpointInfo> {
+        self.backup(dir, true)
+    }
+
+    /// Online backup of the database to a directory, created if missing. The directory must
+    /// not contain a database, free pages are omitted when compact. Writing is not blocked.
+    pub fn backup<P: AsRef<Path>>(&self, dest: P, compact: bool) -> Result<CheckpointInfo> {
+        let dir = dest.as_ref();
+        self.inner.backup(dir, compact)?;
+
+        let db = Self::open(dir)?;
+        let version = db.version()?.unwrap_or_else(|| DB_VERSION.to_owned());
+        let seq = latest_seq(&db.inner, &db.t_data)?.unwrap_or_default();
+        let size = fs::metadata(db.inner.data_path()?)?.len();
+        Ok(CheckpointInfo {
+            path: dir.to_path_buf(),
+            version,
+            seq,
+            size,
+        })
+    }
+
+    /// Replace the database at path with a checkpoint made by [`Db::checkpoint`] and open it.
+    /// Returns [`Error::VersionMismatch`] if the checkpoint schema differs, the database is
+    /// left untouched then. Every handle to the old database must be dropped before restoring.
+    pub fn restore_from<C: AsRef<Path>, P: AsRef<Path>>(checkpoint: C, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::Message("invalid database path".to_owned()))?
+            .to_string_lossy();
+        let restore = path.with_file_name(format!("{}.restore", name));
+        let old = path.with_file_name(format!("{}.old", name));
+        {
+            let db = Self::open(checkpoint)?;
+            if db.version()?.filter(|v| v != DB_VERSION).is_some() {
+                return Err(Error::VersionMismatch);
+            }
+            if restore.exists() {
+                fs::remove_dir_all(&restore)?;
+            }
+            fs::create_dir_all(&restore)?;
+            db.inner.copy_to(&restore, false)?;
+        }
+
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        if path.exists() {
+            fs::rename(path, &old)?;
+        }
+        fs::rename(&restore, path)?;
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        Self::open(path)
+    }
+
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let inner = Lmdb::open_with(path, Some(20), Some(100), Some(1_000_000_000_000), 0)?;
+        Self::open_with(path, DbOptions::default())
+    }
+
+    /// Open an existing database for reading only, see [`DbOptions::read_only`]
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(
+            path,
+            DbOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, options: DbOptions) -> Result<Self> {
+        // leave room for the custom indexes
+        let map_size = options.map_size.unwrap_or(DEFAULT_MAP_SIZE);
+        let mut flags = if options.no_sync { ffi::MDB_NOSYNC } else { 0 };
+        if options.read_only {
+            flags |= ffi::MDB_RDONLY;
+        }
+        let inner = Lmdb::open_with(path, Some(64), Some(100), Some(map_size), flags)?;
+        if options.huge_pages {
+            // only a hint, the database works without it
+            if let Err(err) = inner.advise_huge_pages() {
+                tracing::warn!(error = err.to_string(), "advise huge pages error");
+            }
+        }
 
         let default_opts = 0;
         // let integer_default_opts = ffi::MDB_INTEGERKEY;
@@ -367,9 +838,21 @@ impl Db {
 
         let t_data = inner.open_tree(Some("t_data"), integer_default_opts)?;
         let t_meta = inner.open_tree(Some("t_meta"), default_opts)?;
+        let t_shadowban = inner.open_tree(Some("t_shadowban"), default_opts)?;
+        let shadowbanned = {
+            let reader = inner.reader()?;
+            let mut set = HashSet::new();
+            for item in reader.iter(&t_shadowban) {
+                set.insert(item?.0.try_into()?);
+            }
+            set
+        };
 
         Ok(Self {
-            seq: Arc::new(AtomicU64::new(latest_seq(&inner, &t_data)?)),
+            // continue after the latest seq, don't reuse it
+            seq: Arc::new(AtomicU64::new(
+                latest_seq(&inner, &t_data)?.map_or(0, |seq| seq + 1),
+            )),
             t_data,
             t_meta,
             t_index: inner.open_tree(Some("t_index"), integer_default_opts)?,
@@ -383,27 +866,115 @@ impl Db {
             t_pubkey_kind: inner.open_tree(Some("t_pubkey_kind"), index_opts)?,
             t_created_at: inner.open_tree(Some("t_created_at"), integer_index_opts)?,
             t_tag: inner.open_tree(Some("t_tag"), ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED)?,
+            t_tag_multi: inner
+                .open_tree(Some("t_tag_multi"), ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED)?,
             t_expiration: inner.open_tree(Some("t_expiration"), integer_index_opts)?,
+            t_event_meta: inner.open_tree(Some("t_event_meta"), integer_default_opts)?,
+            t_shadowban,
+            shadowbanned: Arc::new(RwLock::new(Arc::new(shadowbanned))),
             t_word: inner.open_tree(Some("t_word"), index_opts)?,
+            writer_lock: Arc::new(Mutex::new(())),
+            writer_busy_error: options.writer_busy_error,
+            verify_on_read: options.verify_on_read,
+            max_map_size: options.max_map_size,
+            cipher: Cipher::new(options.encryption_key.as_ref())?,
+            custom_indexes: Default::default(),
+            #[cfg(feature = "index_stats")]
+            index_usage: Default::default(),
 
             inner,
         })
     }
 
-    pub fn writer(&self) -> Result<Writer> {
-        Ok(self.inner.writer()?)
+    /// Begin a write transaction. LMDB allows only one writer at a time, a second call
+    /// blocks until the alive writer is committed or dropped, or returns [`Error::WriterBusy`]
+    /// when [`DbOptions::writer_busy_error`] is set. Never hold two writers in one thread
+    /// in blocking mode, it deadlocks.
+    pub fn writer(&self) -> Result<DbWriter<'_>> {
+        let guard = if self.writer_busy_error {
+            match self.writer_lock.try_lock() {
+                Ok(guard) => guard,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                Err(TryLockError::WouldBlock) => return Err(Error::WriterBusy),
+            }
+        } else {
+            self.writer_lock
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+        };
+        Ok(DbWriter {
+            txn: self.inner.writer()?,
+            _guard: guard,
+        })
+    }
+
+    /// Run the closure in a write transaction, commit when it returns Ok,
+    /// otherwise nothing is written. Unlike [`Db::batch_put`], the events are put
+    /// as given, without sorting or removing the duplicates.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut DbTransaction<'_>) -> Result<T>,
+    {
+        let mut tx = DbTransaction {
+            db: self,
+            writer: self.writer()?,
+        };
+        // the writer is dropped without commit on error
+        let res = f(&mut tx)?;
+        tx.writer.commit()?;
+        Ok(res)
+    }
+
+    /// The database is opened by [`Db::open_read_only`]
+    pub fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    /// The LMDB environment flags the database is opened with
+    pub fn env_flags(&self) -> Result<u32> {
+        Ok(self.inner.flags()?)
+    }
+
+    /// Information about the environment, such as the map size and the used pages
+    pub fn env_info(&self) -> Result<EnvInfo> {
+        Ok(self.inner.env_info()?)
     }
 
     pub fn reader(&self) -> Result<Reader> {
         Ok(self.inner.reader()?)
     }
 
+    /// A reset read transaction for a reader thread, renew it for each query,
+    /// see [`ResetReader`]
+    pub fn reset_reader(&self) -> Result<ResetReader> {
+        Ok(self.inner.reset_reader()?)
+    }
+
     pub fn commit<T: Transaction>(&self, txn: T) -> Result<()> {
         Ok(txn.commit()?)
     }
 
     pub fn put<E: AsRef<Event>>(&self, writer: &mut Writer, event: E) -> Result<CheckEventResult> {
-        let event = event.as_ref();
+        self.put_with(writer, event.as_ref(), false)
+    }
+
+    /// Same as [`Db::put`], but append the event to the uid keyed data trees with `MDB_APPEND`.
+    /// It skips the btree search, faster for importing a large amount of events.
+    /// The uid is always ascending, a misuse such as a corrupted sequence fails with `MDB_KEYEXIST`.
+    pub fn put_append<E: AsRef<Event>>(
+        &self,
+        writer: &mut Writer,
+        event: E,
+    ) -> Result<CheckEventResult> {
+        self.put_with(writer, event.as_ref(), true)
+    }
+
+    fn put_with(
+        &self,
+        writer: &mut Writer,
+        event: &Event,
+        append: bool,
+    ) -> Result<CheckEventResult> {
         let mut count = 0;
 
         if event.id().len() != 32 || event.pubkey().len() != 32 {
@@ -418,7 +989,7 @@ impl Db {
         // Check duplicate event.
         {
             // dup in the db.
-            if get_uid(writer, &self.t_id_uid, event_id)?.is_some() {
+            if self.exists(writer, event_id)? {
                 return Ok(CheckEventResult::Duplicate);
             }
         }
@@ -443,6 +1014,8 @@ impl Db {
                         &self.t_id_uid,
                         &self.t_data,
                         &self.t_index,
+                        &self.cipher,
+                        self.verify_on_read,
                         key,
                     )?;
                     if let Some((uid, e)) = r {
@@ -456,6 +1029,33 @@ impl Db {
                             self.del_event(writer, &e, &uid)?;
                         }
                     }
+                } else if tag.0 == b"a" {
+                    // delete the replaceable event by coordinate from the author,
+                    // only the versions until the deletion
+                    if let Some((replace_key, author)) = decode_coordinate(&tag.1) {
+                        if &author != event.pubkey() {
+                            continue;
+                        }
+                        let uid = writer
+                            .get(&self.t_replacement, &replace_key)?
+                            .map(|v| v.to_vec());
+                        if let Some(uid) = uid {
+                            let e: Option<Event> = get_event_by_uid(
+                                writer,
+                                &self.t_data,
+                                &self.t_index,
+                                &self.cipher,
+                                self.verify_on_read,
+                                &uid,
+                            )?;
+                            if let Some(e) = e {
+                                if e.created_at() <= event.created_at() {
+                                    count += 1;
+                                    self.del_event(writer, &e, &uid)?;
+                                }
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -479,9 +1079,17 @@ impl Db {
                 // if event.created_at() < t {
                 //     continue;
                 // }
-                let e: Option<Event> = get_event_by_uid(writer, &self.t_data, &self.t_index, &uid)?;
+                let e: Option<Event> = get_event_by_uid(
+                    writer,
+                    &self.t_data,
+                    &self.t_index,
+                    &self.cipher,
+                    false,
+                    &uid,
+                )?;
                 if let Some(e) = e {
                     // If two events have the same timestamp, the event with the lowest id (first in lexical order) SHOULD be retained, and the other discarded.
+                    // The raw id bytes have the same order as the lowercase hex string.
                     if event.created_at() < e.created_at()
                         || (event.created_at() == e.created_at() && event.id() > e.id())
                     {
@@ -498,7 +1106,7 @@ impl Db {
 
         let seq = self.seq.fetch_add(1, Ordering::SeqCst);
         let seq = u64_to_ver(seq);
-        self.put_event(writer, event, &seq, &replace_key)?;
+        self.put_event(writer, event, &seq, &replace_key, append)?;
         Ok(CheckEventResult::Ok(count))
     }
 
@@ -507,16 +1115,45 @@ impl Db {
         txn: &T,
         event_id: K,
     ) -> Result<Option<R>> {
-        let event = get_event(txn, &self.t_id_uid, &self.t_data, &self.t_index, event_id)?;
+        let event = get_event(
+            txn,
+            &self.t_id_uid,
+            &self.t_data,
+            &self.t_index,
+            &self.cipher,
+            self.verify_on_read,
+            event_id,
+        )?;
         Ok(event.map(|e| e.1))
     }
 
+    /// Check whether an event id is stored, only probes the id index without reading the event
+    pub fn exists<K: AsRef<[u8]>, T: Transaction>(&self, txn: &T, event_id: K) -> Result<bool> {
+        Ok(txn.get(&self.t_id_uid, event_id)?.is_some())
+    }
+
+    /// Get the unsigned metadata recorded with the event
+    pub fn event_meta<K: AsRef<[u8]>, T: Transaction>(
+        &self,
+        txn: &T,
+        event_id: K,
+    ) -> Result<Option<EventMeta>> {
+        if let Some(uid) = get_uid(txn, &self.t_id_uid, event_id)? {
+            if let Some(bytes) = txn.get(&self.t_event_meta, uid)? {
+                return Ok(Some(serde_json::from_slice(bytes)?));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn del<K: AsRef<[u8]>>(&self, writer: &mut Writer, event_id: K) -> Result<bool> {
         if let Some((uid, event)) = get_event::<Event, _, _>(
             writer,
             &self.t_id_uid,
             &self.t_data,
             &self.t_index,
+            &self.cipher,
+            self.verify_on_read,
             event_id,
         )? {
             self.del_event(writer, &event, &uid)?;
@@ -531,11 +1168,39 @@ impl Db {
         II: IntoIterator<Item = N>,
         N: AsRef<Event>,
     {
-        let mut writer = self.inner.writer()?;
+        self.batch_put_opts(events, BatchOpts::default())
+    }
+
+    /// Put the events in one transaction. When the map is full, grow the map size
+    /// up to [`DbOptions::max_map_size`] and retry.
+    pub fn batch_put_opts<II, N>(&self, events: II, opts: BatchOpts) -> Result<usize>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<Event>,
+    {
         let mut events = events.into_iter().collect::<Vec<N>>();
 
         // sort for check dup
         events.sort_by(|a, b| a.as_ref().id().cmp(b.as_ref().id()));
+        loop {
+            match self.batch_put_sorted(&events, &opts) {
+                Err(Error::Kv(nostr_kv::Error::MapFull)) if self.grow_map_size()? => continue,
+                res => return res,
+            }
+        }
+    }
+
+    /// Double the map size up to [`DbOptions::max_map_size`], return false if it can't grow.
+    /// Waits for the alive transactions for a while, never call it while holding one.
+    pub fn grow_map_size(&self) -> Result<bool> {
+        match self.max_map_size {
+            Some(max) => Ok(self.inner.grow_map_size(max)?),
+            None => Ok(false),
+        }
+    }
+
+    fn batch_put_sorted<N: AsRef<Event>>(&self, events: &[N], opts: &BatchOpts) -> Result<usize> {
+        let mut writer = self.writer()?;
         let mut count = 0;
 
         for (i, event) in events.iter().enumerate() {
@@ -544,8 +1209,21 @@ impl Db {
             if i != 0 && event.id() == events[i - 1].as_ref().id() {
                 continue;
             }
-            if let CheckEventResult::Ok(c) = self.put(&mut writer, event)? {
-                count += c;
+            if opts.verify {
+                if let Err(err) = event.verify_id().and_then(|_| event.verify_sign()) {
+                    if opts.abort_on_error {
+                        // the writer is dropped without commit
+                        return Err(err);
+                    }
+                    continue;
+                }
+            }
+            match self.put_with(&mut writer, event, opts.append)? {
+                CheckEventResult::Ok(c) => count += c,
+                CheckEventResult::Invald(msg) if opts.abort_on_error => {
+                    return Err(Error::Invalid(msg));
+                }
+                _ => {}
             }
         }
 
@@ -574,7 +1252,7 @@ impl Db {
         II: IntoIterator<Item = N>,
         N: AsRef<[u8]>,
     {
-        let mut writer = self.inner.writer()?;
+        let mut writer = self.writer()?;
         for id in event_ids.into_iter() {
             self.del(&mut writer, &id)?;
         }
@@ -588,6 +1266,14 @@ impl Db {
         txn: &'txn T,
         filter: &Filter,
     ) -> Result<Iter<'txn, T, J>> {
+        // the excluded kinds can't be indexed, check them from the event index
+        let exclude = |index| {
+            if filter.not_kinds.is_empty() {
+                index
+            } else {
+                MatchIndex::All
+            }
+        };
         if filter.search.as_ref().is_some() {
             let match_index = if !filter.ids.is_empty()
                 || !filter.tags.is_empty()
@@ -598,7 +1284,8 @@ impl Db {
             } else {
                 MatchIndex::None
             };
-            Iter::new_word(self, txn, filter, &self.t_word, match_index)
+            self.count_index(IndexPath::Word);
+            Iter::new_word(self, txn, filter, &self.t_word, exclude(match_index))
         } else if !filter.ids.is_empty() {
             let match_index = if !filter.tags.is_empty()
                 || !filter.authors.is_empty()
@@ -608,32 +1295,90 @@ impl Db {
             } else {
                 MatchIndex::None
             };
-            Iter::new_prefix(self, txn, filter, &filter.ids, &self.t_id, match_index)
+            self.count_index(IndexPath::Id);
+            Iter::new_prefix(
+                self,
+                txn,
+                filter,
+                &filter.ids,
+                &self.t_id,
+                exclude(match_index),
+            )
         } else if !filter.tags.is_empty() {
             let match_index = if !filter.authors.is_empty() {
                 MatchIndex::Pubkey
             } else {
                 MatchIndex::None
             };
-            Iter::new_tag(self, txn, filter, &self.t_tag, match_index)
+            self.count_index(IndexPath::Tag);
+            Iter::new_tag(self, txn, filter, exclude(match_index))
         } else if !filter.authors.is_empty() && !filter.kinds.is_empty() {
-            Iter::new_author_kind(self, txn, filter, &self.t_pubkey_kind, MatchIndex::None)
+            self.count_index(IndexPath::PubkeyKind);
+            Iter::new_author_kind(
+                self,
+                txn,
+                filter,
+                &self.t_pubkey_kind,
+                exclude(MatchIndex::None),
+            )
         } else if !filter.authors.is_empty() {
+            self.count_index(IndexPath::Pubkey);
             Iter::new_prefix(
                 self,
                 txn,
                 filter,
                 &filter.authors,
                 &self.t_pubkey,
-                MatchIndex::None,
+                exclude(MatchIndex::None),
             )
         } else if !filter.kinds.is_empty() {
-            Iter::new_kind(self, txn, filter, &self.t_kind, MatchIndex::None)
+            self.count_index(IndexPath::Kind);
+            Iter::new_kind(self, txn, filter, &self.t_kind, exclude(MatchIndex::None))
         } else {
-            Iter::new_time(self, txn, filter, &self.t_created_at, MatchIndex::None)
+            self.count_index(IndexPath::Time);
+            Iter::new_time(
+                self,
+                txn,
+                filter,
+                &self.t_created_at,
+                exclude(MatchIndex::None),
+            )
         }
     }
 
+    #[allow(unused_variables)]
+    fn count_index(&self, path: IndexPath) {
+        #[cfg(feature = "index_stats")]
+        self.index_usage[path as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of the queries by each index path since the database is opened
+    #[cfg(feature = "index_stats")]
+    pub fn index_usage(&self) -> IndexUsage {
+        let get = |path: IndexPath| self.index_usage[path as usize].load(Ordering::Relaxed);
+        IndexUsage {
+            id: get(IndexPath::Id),
+            pubkey: get(IndexPath::Pubkey),
+            pubkey_kind: get(IndexPath::PubkeyKind),
+            kind: get(IndexPath::Kind),
+            tag: get(IndexPath::Tag),
+            word: get(IndexPath::Word),
+            time: get(IndexPath::Time),
+        }
+    }
+
+    /// iter events by filter, the limit of the filter is bounded by the options
+    pub fn iter_opts<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        filter: &Filter,
+        opts: &QueryOpts,
+    ) -> Result<Iter<'txn, T, J>> {
+        let mut filter = filter.clone();
+        opts.apply(&mut filter);
+        self.iter(txn, &filter)
+    }
+
     /// iter expired events
     pub fn iter_expiration<'txn, J: FromEventData, T: Transaction>(
         &self,
@@ -648,6 +1393,24 @@ impl Db {
         Iter::new_time(self, txn, &filter, &self.t_expiration, MatchIndex::None)
     }
 
+    /// iter the reposts (kind 6 and the generic kind 16) of the events of a kind by the `k` tag index,
+    /// [NIP-18](https://nips.be/18), newest first
+    pub fn iter_reposts<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        kind: u32,
+    ) -> Result<Iter<'txn, T, J>> {
+        let mut filter = Filter {
+            kinds: vec![6, 16].into(),
+            desc: true,
+            ..Default::default()
+        };
+        filter
+            .tags
+            .insert(b"k".to_vec(), vec![kind.to_string().into_bytes()].into());
+        self.iter(txn, &filter)
+    }
+
     /// iter ephemeral events
     pub fn iter_ephemeral<'txn, J: FromEventData, T: Transaction>(
         &self,
@@ -660,8 +1423,8 @@ impl Db {
             ..Default::default()
         };
         let mut group = Group::new(filter.desc, false, false);
-        let prefix = u16_to_ver(20000);
-        let end = u16_to_ver(30000);
+        let prefix = u32_to_ver(20000);
+        let end = u32_to_ver(30000);
 
         let iter = create_iter(txn, &self.t_kind, &prefix, filter.desc);
         let scanner = Scanner::new(
@@ -684,6 +1447,347 @@ impl Db {
         group.add(Box::new(scanner))?;
         Iter::new(self, txn, &filter, group, MatchIndex::None)
     }
+
+    /// delete events expired until the time, return the number of deleted events
+    pub fn del_expired(&self, until: u64) -> Result<usize> {
+        let ids = {
+            let reader = self.reader()?;
+            let iter = self.iter_expiration::<Vec<u8>, _>(&reader, Some(until))?;
+            iter.collect::<Result<Vec<_>>>()?
+        };
+        self.batch_del(&ids)?;
+        Ok(ids.len())
+    }
+
+    /// delete ephemeral events created until the time, return the number of deleted events
+    pub fn del_ephemeral(&self, until: u64) -> Result<usize> {
+        let ids = {
+            let reader = self.reader()?;
+            let iter = self.iter_ephemeral::<Vec<u8>, _>(&reader, Some(until))?;
+            iter.collect::<Result<Vec<_>>>()?
+        };
+        self.batch_del(&ids)?;
+        Ok(ids.len())
+    }
+
+    /// Count the events of each author matched by the filter, return the top n authors by count.
+    /// Walk the author index with the duplicate counts if the filter has only authors, since and until.
+    pub fn top_authors<T: Transaction>(
+        &self,
+        txn: &T,
+        n: usize,
+        filter: &Filter,
+    ) -> Result<Vec<([u8; 32], u64)>> {
+        let mut counts: HashMap<[u8; 32], u64> = HashMap::new();
+        if filter.ids.is_empty()
+            && filter.kinds.is_empty()
+            && filter.not_kinds.is_empty()
+            && filter.tags.is_empty()
+            && filter.search.is_none()
+            && filter.limit.is_none()
+        {
+            let mut count = |prefix: Option<&HexPrefix>| -> Result<()> {
+                let from = match prefix {
+                    Some(prefix) => Bound::Included(prefix.bytes()),
+                    None => Bound::Unbounded,
+                };
+                let mut iter = txn.iter_from(&self.t_pubkey, from, false);
+                while let Some(item) = iter.next() {
+                    let (key, _) = item?;
+                    if !prefix.is_none_or(|p| p.matches(key)) {
+                        break;
+                    }
+                    let pubkey: [u8; 32] = key[0..32].try_into()?;
+                    let time = u64::from_be_bytes(key[32..].try_into()?);
+                    if filter.since.is_none_or(|since| time >= since)
+                        && filter.until.is_none_or(|until| time <= until)
+                    {
+                        *counts.entry(pubkey).or_default() += iter.count_dup()?;
+                    }
+                    iter.skip_dup();
+                }
+                Ok(())
+            };
+            if filter.authors.is_empty() {
+                count(None)?;
+            } else {
+                for author in filter.authors.iter() {
+                    count(Some(author))?;
+                }
+            }
+        } else {
+            for event in self.iter::<Event, _>(txn, filter)? {
+                *counts.entry(*event?.pubkey()).or_default() += 1;
+            }
+        }
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        Ok(counts)
+    }
+
+    /// iter the events matched by the filter grouped by author, the authors are in the pubkey order.
+    /// Each author is scanned from the author index separately, the authors without events are skipped.
+    /// The author prefixes are expanded to the full pubkeys found in the author index.
+    /// The filter limit is the total number of events, `per_author` limits the events of each group.
+    /// A limited group has the latest events by time descending, like a REQ with a limit.
+    pub fn iter_grouped_by_author<'txn, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        filter: &Filter,
+        per_author: Option<u64>,
+    ) -> Result<impl Iterator<Item = Result<([u8; 32], Vec<Event>)>> + 'txn> {
+        if filter.authors.is_empty() {
+            return Err(Error::Invalid(
+                "group by author requires authors".to_owned(),
+            ));
+        }
+        let db = self.clone();
+        let filter = filter.clone();
+        let mut authors = expand_prefixes(txn, &self.t_pubkey, &filter.authors)?.into_iter();
+        let mut remaining = filter.limit;
+        Ok(std::iter::from_fn(move || loop {
+            if remaining == Some(0) {
+                return None;
+            }
+            let author = authors.next()?;
+            let mut sub = filter.clone();
+            sub.authors = vec![author].into();
+            sub.limit = match (remaining, per_author) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            if sub.limit.is_some() {
+                sub.desc = true;
+            }
+            let events = match db
+                .iter::<Event, _>(txn, &sub)
+                .and_then(|iter| iter.collect::<Result<Vec<_>>>())
+            {
+                Ok(events) => events,
+                Err(err) => return Some(Err(err)),
+            };
+            if events.is_empty() {
+                continue;
+            }
+            if let Some(remaining) = remaining.as_mut() {
+                *remaining = remaining.saturating_sub(events.len() as u64);
+            }
+            return Some(Ok((author, events)));
+        }))
+    }
+
+    /// Shadowban the pubkey, the events of the author are still stored but hidden
+    /// from the iterators with a viewer, see [`Iter::viewer`].
+    /// The list is cached on open, the other processes see the change after reopening.
+    pub fn shadowban(&self, pubkey: &[u8; 32]) -> Result<()> {
+        let mut writer = self.writer()?;
+        writer.put(&self.t_shadowban, pubkey, b"")?;
+        writer.commit()?;
+        self.update_shadowbanned(|set| set.insert(*pubkey));
+        Ok(())
+    }
+
+    /// Remove the shadowban of the pubkey
+    pub fn unshadowban(&self, pubkey: &[u8; 32]) -> Result<()> {
+        let mut writer = self.writer()?;
+        writer.del(&self.t_shadowban, pubkey, None)?;
+        writer.commit()?;
+        self.update_shadowbanned(|set| set.remove(pubkey));
+        Ok(())
+    }
+
+    fn update_shadowbanned<F: FnOnce(&mut HashSet<[u8; 32]>) -> bool>(&self, f: F) {
+        let mut w = self
+            .shadowbanned
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut set = HashSet::clone(&w);
+        if f(&mut set) {
+            *w = Arc::new(set);
+        }
+    }
+
+    fn shadowbanned(&self) -> Arc<HashSet<[u8; 32]>> {
+        self.shadowbanned
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn is_shadowbanned<T: Transaction>(&self, txn: &T, pubkey: &[u8; 32]) -> Result<bool> {
+        Ok(txn.get(&self.t_shadowban, pubkey)?.is_some())
+    }
+
+    /// Same as [`Db::is_shadowbanned`] from the list cached on open, without a transaction.
+    /// The cache only follows the changes made through this handle,
+    /// the shadowbans of the other processes are seen after reopening.
+    pub fn is_shadowbanned_cached(&self, pubkey: &[u8; 32]) -> bool {
+        self.shadowbanned
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(pubkey)
+    }
+
+    fn custom_indexes(&self) -> std::sync::RwLockReadGuard<'_, Vec<CustomIndex>> {
+        self.custom_indexes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register an application-defined index stored in the tree `t_custom_<name>`,
+    /// the keys extracted from each event are written on put and removed on delete.
+    /// Empty keys and keys longer than 511 bytes are ignored. The events stored before
+    /// the registration are not indexed, register right after open and before writing.
+    /// Register the same name again replaces the extractor.
+    pub fn register_index<F>(&self, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&Event) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        let tree = self.inner.open_tree(
+            Some(&format!("t_custom_{}", name)),
+            ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED,
+        )?;
+        let index = CustomIndex {
+            name: name.to_owned(),
+            tree,
+            extractor: Arc::new(extractor),
+        };
+        let mut indexes = self
+            .custom_indexes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        indexes.retain(|i| i.name != name);
+        indexes.push(index);
+        Ok(())
+    }
+
+    /// iter the events of a custom index by the key prefix, in key order
+    pub fn iter_custom<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        name: &str,
+        prefix: &[u8],
+    ) -> Result<CustomIter<'txn, T, J>> {
+        let end = upper(prefix.to_vec())
+            .map(Bound::Excluded)
+            .unwrap_or(Bound::Unbounded);
+        self.iter_custom_range(
+            txn,
+            name,
+            Bound::Included(prefix),
+            end.as_ref().map(|k| k.as_slice()),
+        )
+    }
+
+    /// iter the events of a custom index with the keys in the range, in key order
+    pub fn iter_custom_range<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        name: &str,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<CustomIter<'txn, T, J>> {
+        let tree = self
+            .custom_indexes()
+            .iter()
+            .find(|i| i.name == name)
+            .map(|i| i.tree.clone())
+            .ok_or_else(|| Error::Invalid(format!("unknown index {}", name)))?;
+        // the empty key is invalid in lmdb
+        let start = match start {
+            Bound::Included(k) | Bound::Excluded(k) if k.is_empty() => Bound::Unbounded,
+            start => start,
+        };
+        Ok(CustomIter {
+            reader: txn,
+            iter: txn.iter_from(&tree, start, false),
+            end: end.map(|k| k.to_vec()),
+            view_data: self.t_data.clone(),
+            view_index: self.t_index.clone(),
+            cipher: self.cipher.clone(),
+            verify: self.verify_on_read,
+            done: false,
+            _r: PhantomData,
+        })
+    }
+
+    /// iter the events in insertion order from the seq, yields the seq and the event.
+    /// A follower can persist the last seq and resume from the next one.
+    pub fn iter_from_seq<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        start_seq: u64,
+    ) -> Result<SeqIter<'txn, T, J>> {
+        Ok(SeqIter {
+            reader: txn,
+            iter: txn.iter_from(
+                &self.t_data,
+                Bound::Included(start_seq.to_be_bytes()),
+                false,
+            ),
+            view_data: self.t_data.clone(),
+            view_index: self.t_index.clone(),
+            cipher: self.cipher.clone(),
+            verify: self.verify_on_read,
+            done: false,
+            _r: PhantomData,
+        })
+    }
+
+    /// Pass the json of all the events to the sink in the insertion order, walk the data
+    /// without the filter overhead. A consistent dump under the transaction, return the count.
+    pub fn export_all<T, F>(&self, txn: &T, mut sink: F) -> Result<usize>
+    where
+        T: Transaction,
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        let mut count = 0;
+        for item in txn.iter(&self.t_data) {
+            let (uid, v) = item?;
+            if let Some(json) =
+                decode_event_data::<String>(&self.cipher, self.verify_on_read, uid, v)?
+            {
+                sink(json.as_bytes())?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Spawn a tokio task deleting expired and ephemeral events every interval,
+    /// the first sweep runs immediately. Abort the handle to stop it.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_expiration_sweeper(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let db = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let db = db.clone();
+                let res = tokio::task::spawn_blocking(move || {
+                    let now = crate::now();
+                    let expired = db.del_expired(now)?;
+                    let ephemeral =
+                        db.del_ephemeral(now.saturating_sub(EPHEMERAL_EXPIRED_SECONDS))?;
+                    Ok::<_, Error>((expired, ephemeral))
+                })
+                .await;
+                match res {
+                    Ok(Ok((expired, ephemeral))) => {
+                        tracing::info!(expired, ephemeral, "sweep events");
+                    }
+                    Ok(Err(err)) => {
+                        tracing::error!(error = err.to_string(), "sweep events error");
+                    }
+                    Err(err) => {
+                        tracing::error!(error = err.to_string(), "sweep events task error");
+                    }
+                }
+            }
+        })
+    }
 }
 
 // type IterChecker<I, E> =
@@ -719,6 +1823,9 @@ where
     reader: &'txn R,
     view_data: Tree,
     view_index: Tree,
+    cipher: Cipher,
+    // verify the events read from the data
+    verify: bool,
     group: Group<'txn, IndexKey, Error>,
     get_data: u64,
     get_index: u64,
@@ -727,6 +1834,192 @@ where
     _r: PhantomData<J>,
     // need get index data for filter
     match_index: MatchIndex,
+    // number of keys to read ahead
+    prefetch: usize,
+    buffer: VecDeque<Result<IndexKey, Error>>,
+    // scan deadline and check step
+    deadline: Option<(Instant, u64)>,
+    // max number of scanned index entries
+    max_scan: Option<u64>,
+    shadowbanned: Arc<HashSet<[u8; 32]>>,
+    // the shadowbanned authors hidden from the viewer, set by `viewer`
+    hidden: Option<Arc<HashSet<[u8; 32]>>>,
+    viewer: Option<[u8; 32]>,
+    // `limit: 0`, iter nothing and count all the matched events by the index
+    count_only: bool,
+    // the number of events of each author for the per author limit
+    per_author: HashMap<[u8; 32], usize>,
+}
+
+/// The iterator of a custom index, see [`Db::iter_custom`], yields the key and the event
+pub struct CustomIter<'txn, R, J>
+where
+    R: Transaction,
+{
+    reader: &'txn R,
+    iter: LmdbIter<'txn>,
+    end: Bound<Vec<u8>>,
+    view_data: Tree,
+    view_index: Tree,
+    cipher: Cipher,
+    // verify the events read from the data
+    verify: bool,
+    done: bool,
+    _r: PhantomData<J>,
+}
+
+impl<'txn, R, J> CustomIter<'txn, R, J>
+where
+    R: Transaction,
+    J: FromEventData,
+{
+    fn next_inner(&mut self) -> Result<Option<(Vec<u8>, J)>, Error> {
+        for item in self.iter.by_ref() {
+            let (k, uid) = item?;
+            let in_range = match &self.end {
+                Bound::Included(end) => k <= end.as_slice(),
+                Bound::Excluded(end) => k < end.as_slice(),
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                return Ok(None);
+            }
+            let event = get_event_by_uid(
+                self.reader,
+                &self.view_data,
+                &self.view_index,
+                &self.cipher,
+                self.verify,
+                uid,
+            )?;
+            if let Some(event) = event {
+                return Ok(Some((k.to_vec(), event)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'txn, R, J> Iterator for CustomIter<'txn, R, J>
+where
+    R: Transaction,
+    J: FromEventData,
+{
+    type Item = Result<(Vec<u8>, J), Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.next_inner().transpose();
+        if !matches!(item, Some(Ok(_))) {
+            self.done = true;
+        }
+        item
+    }
+}
+
+/// The iterator of the events in insertion order, see [`Db::iter_from_seq`], yields the seq and the event
+pub struct SeqIter<'txn, R, J>
+where
+    R: Transaction,
+{
+    reader: &'txn R,
+    iter: LmdbIter<'txn>,
+    view_data: Tree,
+    view_index: Tree,
+    cipher: Cipher,
+    // verify the events read from the data
+    verify: bool,
+    done: bool,
+    _r: PhantomData<J>,
+}
+
+impl<'txn, R, J> SeqIter<'txn, R, J>
+where
+    R: Transaction,
+    J: FromEventData,
+{
+    fn next_inner(&mut self) -> Result<Option<(u64, J)>, Error> {
+        for item in self.iter.by_ref() {
+            let (uid, _) = item?;
+            let event = get_event_by_uid(
+                self.reader,
+                &self.view_data,
+                &self.view_index,
+                &self.cipher,
+                self.verify,
+                uid,
+            )?;
+            if let Some(event) = event {
+                return Ok(Some((u64_from_bytes(uid)?, event)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'txn, R, J> Iterator for SeqIter<'txn, R, J>
+where
+    R: Transaction,
+    J: FromEventData,
+{
+    type Item = Result<(u64, J), Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.next_inner().transpose();
+        if !matches!(item, Some(Ok(_))) {
+            self.done = true;
+        }
+        item
+    }
+}
+
+const PAGE_SIZE: usize = 4096;
+
+/// Read one byte per page so the mmap pages are faulted in
+fn touch(data: &[u8]) {
+    let mut sum = 0u8;
+    for b in data.iter().step_by(PAGE_SIZE) {
+        sum = sum.wrapping_add(*b);
+    }
+    std::hint::black_box(sum);
+}
+
+/// The full values of the hex prefixes found in the view keyed by the value and time,
+/// the full values are kept. Each value is a separate time ordered range, seek to the
+/// next value by `upper()` and skip the other times.
+fn expand_prefixes<R: Transaction>(
+    reader: &R,
+    view: &Tree,
+    prefixes: &[HexPrefix],
+) -> Result<Vec<[u8; 32]>> {
+    let mut list = vec![];
+    for prefix in prefixes {
+        if let Some(full) = prefix.full() {
+            list.push(*full);
+            continue;
+        }
+        let mut from = prefix.bytes().to_vec();
+        loop {
+            let mut iter = reader.iter_from(view, Bound::Included(&from), false);
+            match iter.next().transpose()? {
+                Some((key, _)) if key.len() >= 32 && prefix.matches(key) => {
+                    let value: [u8; 32] = key[0..32].try_into()?;
+                    list.push(value);
+                    match upper(value.to_vec()) {
+                        Some(next) => from = next,
+                        None => break,
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+    list.sort();
+    list.dedup();
+    Ok(list)
 }
 
 fn create_iter<'a, R: Transaction>(
@@ -760,6 +2053,8 @@ where
         Ok(Self {
             view_data: kv_db.t_data.clone(),
             view_index: kv_db.t_index.clone(),
+            cipher: kv_db.cipher.clone(),
+            verify: kv_db.verify_on_read,
             reader,
             group,
             get_data: 0,
@@ -768,6 +2063,15 @@ where
             // checker: None,
             _r: PhantomData,
             match_index,
+            prefetch: 0,
+            buffer: VecDeque::new(),
+            deadline: None,
+            max_scan: None,
+            shadowbanned: kv_db.shadowbanned(),
+            hidden: None,
+            viewer: None,
+            count_only: filter.limit == Some(0),
+            per_author: HashMap::new(),
         })
     }
 
@@ -809,7 +2113,7 @@ where
     ) -> Result<Self, Error> {
         let mut group = Group::new(filter.desc, false, false);
         for kind in filter.kinds.iter() {
-            let prefix = u16_to_ver(*kind);
+            let prefix = u32_to_ver(*kind);
             let iter = create_iter(reader, view, &prefix, filter.desc);
             let scanner = Scanner::new(
                 iter,
@@ -836,7 +2140,6 @@ where
         kv_db: &Db,
         reader: &'txn R,
         filter: &Filter,
-        view: &Tree,
         match_index: MatchIndex,
     ) -> Result<Self, Error> {
         let mut group = Group::new(filter.desc, true, false);
@@ -849,9 +2152,10 @@ where
                 // need add separator to the end, otherwise other tags will intrude
                 // ["t", "nostr"]
                 // ["t", "nostr1"]
+                // the key has no null byte, so ["alt", "x"] doesn't intrude ["al", "tx"]
                 let prefix = concat_sep(concat_sep(tag.0, key), vec![]);
                 let klen = prefix.len() + 8;
-                let iter = create_iter(reader, view, &prefix, filter.desc);
+                let iter = create_iter(reader, kv_db.tag_tree(tag.0), &prefix, filter.desc);
 
                 let scanner = Scanner::new(
                     iter,
@@ -865,7 +2169,7 @@ where
                         let v = r.1;
                         Ok(if k.len() == klen && k.starts_with(&s.prefix) {
                             // filter
-                            if has_kind && !Filter::match_kind(&kinds, u16_from_bytes(&v[8..10])?) {
+                            if has_kind && !Filter::match_kind(&kinds, u32_from_bytes(&v[8..12])?) {
                                 MatchResult::Continue
                             } else {
                                 MatchResult::Found(IndexKey::from(k, v)?)
@@ -891,9 +2195,9 @@ where
     ) -> Result<Self, Error> {
         let mut group = Group::new(filter.desc, false, false);
 
-        for author in filter.authors.iter() {
+        for author in expand_prefixes(reader, &kv_db.t_pubkey, &filter.authors)?.iter() {
             for kind in filter.kinds.iter() {
-                let prefix: Vec<u8> = concat(author, u16_to_ver(*kind));
+                let prefix: Vec<u8> = concat(author, u32_to_ver(*kind));
                 let iter = create_iter(reader, view, &prefix, filter.desc);
                 let scanner = Scanner::new(
                     iter,
@@ -921,13 +2225,13 @@ where
         kv_db: &Db,
         reader: &'txn R,
         filter: &Filter,
-        ids: &[[u8; 32]],
+        prefixes: &[HexPrefix],
         view: &Tree,
         match_index: MatchIndex,
     ) -> Result<Self, Error> {
         let mut group = Group::new(filter.desc, false, false);
 
-        for id in ids.iter() {
+        for id in expand_prefixes(reader, view, prefixes)?.iter() {
             let prefix = id.to_vec();
             let iter = create_iter(reader, view, &prefix, filter.desc);
             let scanner = Scanner::new(
@@ -988,6 +2292,8 @@ where
             self.reader,
             &self.view_data,
             &self.view_index,
+            &self.cipher,
+            self.verify,
             key.uid().to_be_bytes(),
         )
     }
@@ -997,7 +2303,29 @@ where
         Ok(v)
     }
 
+    // count the event of the author, false if the author reached the per author limit
+    fn author_allowed(&mut self, pubkey: &[u8; 32]) -> bool {
+        if let Some(max) = self.filter.per_author_limit {
+            let count = self.per_author.entry(*pubkey).or_default();
+            if *count >= max {
+                return false;
+            }
+            *count += 1;
+        }
+        true
+    }
+
+    // the event index is needed to check the authors
+    fn need_index(&self) -> bool {
+        !matches!(self.match_index, MatchIndex::None)
+            || self.hidden.is_some()
+            || self.filter.per_author_limit.is_some()
+    }
+
     fn limit(&self, num: u64) -> bool {
+        if self.count_only {
+            return false;
+        }
         if let Some(limit) = self.filter.limit {
             num >= limit
         } else {
@@ -1005,10 +2333,56 @@ where
         }
     }
 
+    fn next_key(&mut self) -> Option<Result<IndexKey, Error>> {
+        if self.prefetch == 0 {
+            return self.group.next();
+        }
+        if self.buffer.is_empty() {
+            self.fill_buffer();
+        }
+        self.buffer.pop_front()
+    }
+
+    // read ahead the next keys and touch their data in uid order,
+    // the data is stored by uid, so the sorted reads are close to sequential.
+    fn fill_buffer(&mut self) {
+        let mut uids = Vec::with_capacity(self.prefetch);
+        while self.buffer.len() < self.prefetch {
+            match self.group.next() {
+                Some(Ok(key)) => {
+                    uids.push(key.uid());
+                    self.buffer.push_back(Ok(key));
+                }
+                Some(Err(err)) => {
+                    self.buffer.push_back(Err(err));
+                    break;
+                }
+                None => break,
+            }
+        }
+        uids.sort_unstable();
+        let index = J::only_id() || self.need_index();
+        for uid in uids {
+            let uid = uid.to_be_bytes();
+            // errors will be reported again when reading
+            if index {
+                if let Ok(Some(v)) = self.reader.get(&self.view_index, uid) {
+                    touch(v);
+                }
+            }
+            if !J::only_id() {
+                if let Ok(Some(v)) = self.reader.get(&self.view_data, uid) {
+                    touch(v);
+                }
+            }
+        }
+    }
+
     fn next_inner(&mut self) -> Result<Option<J>, Error> {
-        while let Some(item) = self.group.next() {
+        while let Some(item) = self.next_key() {
             let key = item?;
-            if matches!(self.match_index, MatchIndex::None) {
+            // the hidden authors and the per author limit are checked from the event index
+            if !self.need_index() {
                 self.get_data += 1;
                 if let Some(event) = self.document(&key)? {
                     return Ok(Some(event));
@@ -1018,7 +2392,10 @@ where
                 let event = decode_event_index(data)?;
                 self.get_index += 1;
                 if let Some(event) = event {
-                    if self.match_index.r#match(&self.filter, event) {
+                    if self.match_index.r#match(&self.filter, event)
+                        && !self.is_hidden(event.pubkey())
+                        && self.author_allowed(event.pubkey())
+                    {
                         self.get_data += 1;
                         if let Some(event) = self.document(&key)? {
                             return Ok(Some(event));
@@ -1038,20 +2415,63 @@ where
 {
     /// Limit the total scan time and report [`Error::ScanTimeout`] if it is exceeded
     pub fn scan_time(&mut self, timeout: Duration, check_step: u64) {
-        let start = Instant::now();
-        let mut last = check_step;
+        self.scan_deadline(Instant::now() + timeout, check_step);
+    }
+
+    /// Report [`Error::ScanTimeout`] if the scan is still running after the deadline,
+    /// the deadline can be shared by several iterators.
+    pub fn scan_deadline(&mut self, deadline: Instant, check_step: u64) {
+        self.deadline = Some((deadline, check_step));
+        self.set_watcher();
+    }
+
+    /// Report [`Error::ScanLimit`] once more than `max` index entries are scanned,
+    /// works together with the scan time.
+    pub fn scan_limit(&mut self, max: u64) {
+        self.max_scan = Some(max);
+        self.set_watcher();
+    }
+
+    fn set_watcher(&mut self) {
+        let deadline = self.deadline;
+        let max_scan = self.max_scan;
+        let mut last = deadline.map(|d| d.1).unwrap_or_default();
         self.group.watcher(Box::new(move |count| {
-            if count > last {
-                // check
-                if start.elapsed() > timeout {
-                    return Err(Error::ScanTimeout);
+            if let Some(max) = max_scan {
+                if count > max {
+                    return Err(Error::ScanLimit);
+                }
+            }
+            if let Some((deadline, check_step)) = deadline {
+                if count > last {
+                    // check
+                    if Instant::now() > deadline {
+                        return Err(Error::ScanTimeout);
+                    }
+                    last = count + check_step;
                 }
-                last = count + check_step;
             }
             Ok(())
         }));
     }
 
+    /// Hide the events of the shadowbanned authors except the viewer's own, see [`Db::shadowban`]
+    pub fn viewer(&mut self, viewer: Option<&[u8; 32]>) {
+        self.viewer = viewer.copied();
+        self.hidden = Some(self.shadowbanned.clone()).filter(|set| !set.is_empty());
+    }
+
+    fn is_hidden(&self, pubkey: &[u8; 32]) -> bool {
+        self.hidden.as_ref().is_some_and(|set| set.contains(pubkey))
+            && self.viewer.as_ref() != Some(pubkey)
+    }
+
+    /// Read ahead `num` keys and touch their data before reading,
+    /// reduces the random page faults when scanning on a cold cache. 0 to disable.
+    pub fn prefetch(&mut self, num: usize) {
+        self.prefetch = num;
+    }
+
     /// The stats after scan
     pub fn stats(&self) -> Stats {
         Stats {
@@ -1061,13 +2481,31 @@ where
         }
     }
 
-    /// only count iter size
-    pub fn size(mut self) -> Result<(u64, Stats)> {
+    /// only count iter size, a filter with `limit: 0` counts all the matched events
+    pub fn size(self) -> Result<(u64, Stats)> {
+        let (len, _, stats) = self.size_until(u64::MAX, |_, _| {})?;
+        Ok((len, stats))
+    }
+
+    /// Same as [`Iter::size`], pass the uid and the time of each matched event to `f`.
+    /// Stop once more than `max_scan` index entries are scanned and return the time
+    /// of the key stopped at, `None` if the scan is complete.
+    pub fn size_until<F: FnMut(u64, u64)>(
+        mut self,
+        max_scan: u64,
+        mut f: F,
+    ) -> Result<(u64, Option<u64>, Stats)> {
         let mut len = 0;
+        let mut stopped = None;
         while let Some(item) = self.group.next() {
             let key = item?;
-            if matches!(self.match_index, MatchIndex::None) {
+            if self.group.scan_times > max_scan {
+                stopped = Some(key.time());
+                break;
+            }
+            if !self.need_index() {
                 len += 1;
+                f(key.uid(), key.time());
                 if self.limit(len) {
                     break;
                 }
@@ -1076,8 +2514,12 @@ where
                 let event = decode_event_index(data)?;
                 self.get_index += 1;
                 if let Some(event) = event {
-                    if self.match_index.r#match(&self.filter, event) {
+                    if self.match_index.r#match(&self.filter, event)
+                        && !self.is_hidden(event.pubkey())
+                        && self.author_allowed(event.pubkey())
+                    {
                         len += 1;
+                        f(key.uid(), key.time());
                         if self.limit(len) {
                             break;
                         }
@@ -1087,6 +2529,7 @@ where
         }
         Ok((
             len,
+            stopped,
             Stats {
                 get_data: 0,
                 get_index: self.get_index,
@@ -1103,7 +2546,8 @@ where
 {
     type Item = Result<J, Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.limit(self.get_data) {
+        // never touch the data in the count only mode
+        if self.count_only || self.limit(self.get_data) {
             None
         } else {
             self.next_inner().transpose()
@@ -1113,7 +2557,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::upper;
+    use super::{upper, Db, Error, Event, Transaction, DEFAULT_HEX_TAGS_META};
 
     #[test]
     pub fn test_upper_fn() {
@@ -1126,4 +2570,89 @@ mod tests {
         assert_eq!(upper(vec![1, 2, 3, 255, 5]), Some(vec![1, 2, 3, 255, 6]));
         assert_eq!(upper(vec![255, 2, 3, 4, 5]), Some(vec![255, 2, 3, 4, 6]));
     }
+
+    #[test]
+    pub fn test_check_hex_tags() -> Result<(), Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("nostr-db-test-check-hex-tags")
+            .tempdir()
+            .unwrap();
+        let db = Db::open(dir.path())?;
+        db.check_schema_with(b"e\0p\0q")?;
+        db.check_schema_with(b"e\0p\0q")?;
+        assert!(matches!(
+            db.check_schema_with(DEFAULT_HEX_TAGS_META),
+            Err(Error::HexTagsMismatch)
+        ));
+
+        // the database without the record is indexed with the default
+        let dir = tempfile::Builder::new()
+            .prefix("nostr-db-test-check-hex-tags-old")
+            .tempdir()
+            .unwrap();
+        let db = Db::open(dir.path())?;
+        let mut writer = db.inner.writer()?;
+        writer.put(&db.t_meta, "version", super::DB_VERSION)?;
+        writer.commit()?;
+        assert!(matches!(
+            db.check_schema_with(b"e\0p\0q"),
+            Err(Error::HexTagsMismatch)
+        ));
+        db.check_schema_with(DEFAULT_HEX_TAGS_META)?;
+        db.check_schema()?;
+        db.verify_schema()?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_verify_schema() -> Result<(), Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("nostr-db-test-verify-schema")
+            .tempdir()
+            .unwrap();
+        let db = Db::open(dir.path())?;
+        // nothing is recorded
+        db.verify_schema()?;
+        assert!(db.version()?.is_none());
+        db.check_schema_with(b"e\0p\0q")?;
+        assert!(matches!(db.verify_schema(), Err(Error::HexTagsMismatch)));
+
+        let mut writer = db.inner.writer()?;
+        writer.put(&db.t_meta, "version", "0")?;
+        writer.commit()?;
+        assert!(matches!(db.verify_schema(), Err(Error::VersionMismatch)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_reopen_seq() -> Result<(), Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("nostr-db-test-reopen-seq")
+            .tempdir()
+            .unwrap();
+        let event = |i: u8| {
+            Event::new(
+                [i; 32],
+                [1; 32],
+                i as u64,
+                1,
+                vec![],
+                "".to_owned(),
+                [0; 64],
+            )
+        };
+        {
+            let db = Db::open(dir.path())?;
+            db.batch_put(vec![event(1)?])?;
+        }
+        // the latest event is not overwritten by the next one
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![event(2)?])?;
+        let reader = db.reader()?;
+        for i in 1..=2 {
+            let event = db.get::<Event, _, _>(&reader, [i; 32])?.unwrap();
+            assert_eq!(event.id(), &[i; 32]);
+        }
+        Ok(())
+    }
 }
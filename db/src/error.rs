@@ -20,12 +20,18 @@ pub enum Error {
     Serialization(String),
     #[error("invalid: {0}")]
     Invalid(String),
+    #[error("encryption: {0}")]
+    Encryption(String),
     #[error("invalid length")]
     InvalidLength,
     #[error("message: {0}")]
     Message(String),
+    #[error("another writer is alive")]
+    WriterBusy,
     #[error("Scan timeout")]
     ScanTimeout,
+    #[error("Scan limit exceeded")]
+    ScanLimit,
     #[error("The database schema has been modified. Please run export first, move the old database file, then import and start the program.
       Find the rnostr command at https://github.com/rnostr/rnostr#commands
       rnostr export data/events > events.json
@@ -33,4 +39,6 @@ pub enum Error {
       rnostr import data/events events.json
     ")]
     VersionMismatch,
+    #[error("The hex tags differ from the ones the database is indexed with")]
+    HexTagsMismatch,
 }
@@ -1,4 +1,5 @@
-use crate::error::Error;
+use crate::{error::Error, key::is_hex_tag};
+use rayon::prelude::*;
 use rkyv::{
     vec::ArchivedVec, AlignedVec, Archive, Archived, Deserialize as RkyvDeserialize,
     Serialize as RkyvSerialize,
@@ -8,13 +9,14 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use std::{
+    borrow::Cow,
     fmt::Display,
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 type Tags = Vec<(Vec<u8>, Vec<u8>)>;
-type BuildTags = (Tags, Option<u64>, Option<[u8; 32]>);
+type BuildTags = (Tags, Option<u64>, Option<[u8; 32]>, usize);
 #[derive(
     Serialize,
     Deserialize,
@@ -36,7 +38,7 @@ pub struct EventIndex {
 
     created_at: u64,
 
-    kind: u16,
+    kind: u32,
 
     #[serde(skip)]
     tags: Tags,
@@ -74,25 +76,41 @@ impl EventIndex {
         id: [u8; 32],
         pubkey: [u8; 32],
         created_at: u64,
-        kind: u16,
+        kind: u32,
         tags: &Vec<Vec<String>>,
     ) -> Result<Self, Error> {
-        let (tags, expiration, delegator) = Self::build_index_tags(tags)?;
-        Ok(Self {
-            id,
-            pubkey,
-            created_at,
-            kind,
-            tags,
-            expiration,
-            delegator,
-        })
+        Ok(Self::with_dropped_tags(id, pubkey, created_at, kind, tags)?.0)
+    }
+
+    /// Build the index and count the dropped tags that are not indexed
+    fn with_dropped_tags(
+        id: [u8; 32],
+        pubkey: [u8; 32],
+        created_at: u64,
+        kind: u32,
+        tags: &Vec<Vec<String>>,
+    ) -> Result<(Self, usize), Error> {
+        let (tags, expiration, delegator, dropped) = Self::build_index_tags(tags)?;
+        Ok((
+            Self {
+                id,
+                pubkey,
+                created_at,
+                kind,
+                tags,
+                expiration,
+                delegator,
+            },
+            dropped,
+        ))
     }
 
     pub fn build_index_tags(tags: &Vec<Vec<String>>) -> Result<BuildTags, Error> {
         let mut t = vec![];
         let mut expiration = None;
         let mut delegator = None;
+        // the tags with a value not queryable by the tag filter
+        let mut dropped = 0;
 
         for tag in tags {
             if tag.len() > 1 {
@@ -101,22 +119,24 @@ impl EventIndex {
                         u64::from_str(&tag[1])
                             .map_err(|_| Error::Invalid("invalid expiration".to_string()))?,
                     );
+                    continue;
                 } else if tag[0] == "delegation" {
                     let mut h = [0u8; 32];
                     hex::decode_to_slice(&tag[1], &mut h)?;
                     delegator = Some(h);
+                    continue;
                 }
 
                 let key = tag[0].as_bytes().to_vec();
-                // only index key length 1
+                // single letter keys go to the tag index, longer keys to the multi tag index
                 // 0 will break the index separator, ignore
-                if key.len() == 1 && key[0] != 0 {
+                if !key.is_empty() && key.len() <= 255 && !key.contains(&0) {
                     let v;
-                    // fixed length 32 e and p
-                    if tag[0] == "e" || tag[0] == "p" {
+                    // fixed length 32, e and p by default
+                    if is_hex_tag(&key) {
                         let h = hex::decode(&tag[1])?;
                         if h.len() != 32 {
-                            return Err(Error::Invalid("invalid e or p tag value".to_string()));
+                            return Err(Error::Invalid(format!("invalid {} tag value", tag[0])));
                         }
                         v = h;
                     } else {
@@ -125,14 +145,24 @@ impl EventIndex {
                         // lmdb max_key_size 511 bytes
                         // we only index tag value length < 255
                         if v.contains(&0) || v.len() > 255 {
+                            dropped += 1;
                             continue;
                         }
                     };
                     t.push((key, v));
+                } else {
+                    dropped += 1;
                 }
             }
         }
-        Ok((t, expiration, delegator))
+        Ok((t, expiration, delegator, dropped))
+    }
+
+    /// Build the (key, value) tag index pairs the db stores from a raw json event,
+    /// only the tags field is parsed, the same as [`EventIndex::tags`] of the parsed event.
+    pub fn index_tags_from_json(json: &[u8]) -> Result<Tags, Error> {
+        let tags: _Tags = serde_json::from_slice(json)?;
+        Ok(Self::build_index_tags(&tags.tags)?.0)
     }
 
     pub fn id(&self) -> &[u8; 32] {
@@ -147,7 +177,7 @@ impl EventIndex {
         self.created_at
     }
 
-    pub fn kind(&self) -> u16 {
+    pub fn kind(&self) -> u32 {
         self.kind
     }
 
@@ -163,9 +193,24 @@ impl EventIndex {
         self.delegator.as_ref()
     }
 
+    /// kind 0, 3 or 10000-19999, only the latest event of the pubkey and kind is stored
+    pub fn is_replaceable(&self) -> bool {
+        is_replaceable_kind(self.kind)
+    }
+
+    /// kind 20000-29999, not stored
     pub fn is_ephemeral(&self) -> bool {
-        let kind = self.kind;
-        (20_000..30_000).contains(&kind)
+        is_ephemeral_kind(self.kind)
+    }
+
+    /// kind 30000-39999, only the latest event of the pubkey, kind and `d` tag is stored
+    pub fn is_addressable(&self) -> bool {
+        is_addressable_kind(self.kind)
+    }
+
+    /// the former name of [`Self::is_addressable`] in NIP-33
+    pub fn is_parameterized(&self) -> bool {
+        self.is_addressable()
     }
 
     pub fn is_expired(&self, now: u64) -> bool {
@@ -189,7 +234,7 @@ impl ArchivedEventIndex {
         self.created_at
     }
 
-    pub fn kind(&self) -> u16 {
+    pub fn kind(&self) -> u32 {
         self.kind
     }
 
@@ -205,9 +250,24 @@ impl ArchivedEventIndex {
         self.delegator.as_ref()
     }
 
+    /// kind 0, 3 or 10000-19999, only the latest event of the pubkey and kind is stored
+    pub fn is_replaceable(&self) -> bool {
+        is_replaceable_kind(self.kind)
+    }
+
+    /// kind 20000-29999, not stored
     pub fn is_ephemeral(&self) -> bool {
-        let kind = self.kind;
-        (20_000..30_000).contains(&kind)
+        is_ephemeral_kind(self.kind)
+    }
+
+    /// kind 30000-39999, only the latest event of the pubkey, kind and `d` tag is stored
+    pub fn is_addressable(&self) -> bool {
+        is_addressable_kind(self.kind)
+    }
+
+    /// the former name of [`Self::is_addressable`] in NIP-33
+    pub fn is_parameterized(&self) -> bool {
+        self.is_addressable()
     }
 
     pub fn is_expired(&self, now: u64) -> bool {
@@ -218,6 +278,13 @@ impl ArchivedEventIndex {
         }
     }
 }
+// only the tags of an event
+#[derive(Deserialize)]
+struct _Tags {
+    #[serde(default)]
+    tags: Vec<Vec<String>>,
+}
+
 // the shadow event for deserialize
 #[derive(Deserialize)]
 struct _Event {
@@ -226,17 +293,93 @@ struct _Event {
     #[serde(with = "hex::serde")]
     pubkey: [u8; 32],
     created_at: u64,
-    kind: u16,
+    kind: u32,
     #[serde(default)]
     tags: Vec<Vec<String>>,
     #[serde(default)]
     content: String,
     #[serde(with = "hex::serde")]
     sig: [u8; 64],
+    // the unsigned source ip seen in imports
+    #[serde(default)]
+    ip: Option<String>,
     // #[serde(flatten)]
     // index: IndexEvent,
 }
 
+/// The unsigned metadata of a received event, it's not part of the event id and is stored
+/// separately from the event data, see [`crate::Db::event_meta`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventMeta {
+    /// the source ip of the event, the relay records the ip of the connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+impl EventMeta {
+    pub fn is_empty(&self) -> bool {
+        self.ip.is_none()
+    }
+}
+
+/// The media metadata of an `imeta` tag, [NIP-92](https://nips.be/92)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MediaMeta {
+    pub url: String,
+    /// the `m` field, mime type
+    pub mime: Option<String>,
+    /// the `dim` field, such as 3024x4032
+    pub dim: Option<String>,
+    pub blurhash: Option<String>,
+    pub alt: Option<String>,
+    /// the `x` field, hex sha256 of the file
+    pub sha256: Option<String>,
+    pub fallback: Vec<String>,
+    /// the other fields in order
+    pub extra: Vec<(String, String)>,
+}
+
+impl MediaMeta {
+    /// Parse the `imeta` tag, each item is a space delimited key value pair.
+    /// The items without a value are ignored, return None if the url is missing.
+    pub fn parse<S: AsRef<str>>(tag: &[S]) -> Option<Self> {
+        if tag.first()?.as_ref() != "imeta" {
+            return None;
+        }
+        let mut url = None;
+        let mut meta = Self::default();
+        for item in &tag[1..] {
+            let Some((key, value)) = item.as_ref().split_once(' ') else {
+                continue;
+            };
+            let value = value.to_owned();
+            match key {
+                "url" => url = Some(value),
+                "m" => meta.mime = Some(value),
+                "dim" => meta.dim = Some(value),
+                "blurhash" => meta.blurhash = Some(value),
+                "alt" => meta.alt = Some(value),
+                "x" => meta.sha256 = Some(value),
+                "fallback" => meta.fallback.push(value),
+                _ => meta.extra.push((key.to_owned(), value)),
+            }
+        }
+        meta.url = url?;
+        Some(meta)
+    }
+}
+
+/// The `delegation` tag, [NIP-26](https://nips.be/26)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delegation {
+    /// hex pubkey of the delegator
+    pub delegator: String,
+    /// the query string conditions, such as `kind=1&created_at<1677426236`
+    pub conditions: String,
+    /// hex signature of the delegation token
+    pub sig: String,
+}
+
 /// The default event document.
 // TODO: validate index tag value length 255
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -256,24 +399,38 @@ pub struct Event {
 
     #[serde(skip)]
     pub words: Vec<Vec<u8>>,
+
+    /// the original received json, stored instead of the re-serialized form
+    #[serde(skip)]
+    raw: Option<String>,
+
+    #[serde(skip)]
+    meta: EventMeta,
+
+    #[serde(skip)]
+    dropped_tags: usize,
 }
 
 impl TryFrom<_Event> for Event {
     type Error = Error;
 
     fn try_from(value: _Event) -> Result<Self, Self::Error> {
+        let (index, dropped_tags) = EventIndex::with_dropped_tags(
+            value.id,
+            value.pubkey,
+            value.created_at,
+            value.kind,
+            &value.tags,
+        )?;
         let event = Event {
             content: value.content,
             sig: value.sig,
-            index: EventIndex::new(
-                value.id,
-                value.pubkey,
-                value.created_at,
-                value.kind,
-                &value.tags,
-            )?,
+            index,
             tags: value.tags,
             words: Default::default(),
+            raw: None,
+            meta: EventMeta { ip: value.ip },
+            dropped_tags,
         };
         Ok(event)
     }
@@ -284,18 +441,22 @@ impl Event {
         id: [u8; 32],
         pubkey: [u8; 32],
         created_at: u64,
-        kind: u16,
+        kind: u32,
         tags: Vec<Vec<String>>,
         content: String,
         sig: [u8; 64],
     ) -> Result<Self, Error> {
-        let index = EventIndex::new(id, pubkey, created_at, kind, &tags)?;
+        let (index, dropped_tags) =
+            EventIndex::with_dropped_tags(id, pubkey, created_at, kind, &tags)?;
         let event = Self {
             tags,
             content,
             sig,
             index,
             words: Default::default(),
+            raw: None,
+            meta: Default::default(),
+            dropped_tags,
         };
         Ok(event)
     }
@@ -303,7 +464,7 @@ impl Event {
     pub fn create(
         key_pair: &Keypair,
         created_at: u64,
-        kind: u16,
+        kind: u32,
         tags: Vec<Vec<String>>,
         content: String,
     ) -> Result<Self, Error> {
@@ -314,6 +475,58 @@ impl Event {
             .as_ref();
         Self::new(id, pubkey, created_at, kind, tags, content, sig)
     }
+
+    /// Mine a [NIP-13](https://nips.be/13) proof of work event, increase the value of the `nonce` tag
+    /// until the id has `difficulty` leading zero bits, then sign it.
+    /// Gives up after `max_attempts` hashes.
+    pub fn mine(
+        key_pair: &Keypair,
+        kind: u32,
+        mut tags: Vec<Vec<String>>,
+        content: String,
+        difficulty: u32,
+        max_attempts: u64,
+    ) -> Result<Self, Error> {
+        let pubkey = XOnlyPublicKey::from_keypair(key_pair).0.serialize();
+        let created_at = now();
+        tags.retain(|tag| tag.first().map(String::as_str) != Some("nonce"));
+        tags.push(vec![
+            "nonce".to_owned(),
+            "0".to_owned(),
+            difficulty.to_string(),
+        ]);
+        let last = tags.len() - 1;
+        for nonce in 0..max_attempts {
+            tags[last][1] = nonce.to_string();
+            let id = hash(&pubkey, created_at, kind, &tags, &content);
+            if leading_zero_bits(&id) >= difficulty {
+                let sig = *SECP256K1
+                    .sign_schnorr(&Message::from_digest_slice(&id)?, key_pair)
+                    .as_ref();
+                return Self::new(id, pubkey, created_at, kind, tags, content, sig);
+            }
+        }
+        Err(Error::Invalid(format!(
+            "no proof of work of difficulty {} in {} attempts",
+            difficulty, max_attempts
+        )))
+    }
+
+    /// The [NIP-13](https://nips.be/13) difficulty, the number of leading zero bits of the id
+    pub fn pow_difficulty(&self) -> u32 {
+        leading_zero_bits(self.id())
+    }
+}
+
+fn leading_zero_bits(id: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in id {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
 }
 
 impl AsRef<Event> for Event {
@@ -422,8 +635,13 @@ impl FromEventData for Event {
 impl Event {
     /// build keywords for search ability
     pub fn build_note_words(&mut self) {
+        self.build_note_words_with(&crate::SegmentOptions::default())
+    }
+
+    /// build keywords for search ability with the segmentation options
+    pub fn build_note_words_with(&mut self, opts: &crate::SegmentOptions) {
         if self.kind() == 1 {
-            let mut words = crate::segment(&self.content);
+            let mut words = crate::segment_with(&self.content, opts);
             self.words.append(&mut words);
         }
     }
@@ -435,6 +653,46 @@ impl Event {
         Ok(serde_json::to_string(&self)?)
     }
 
+    /// Parse the event and keep the original json for exact re-emission
+    pub fn from_raw(json: &str) -> Result<Self, Error> {
+        let mut event: Self = serde_json::from_str(json)?;
+        event.raw = Some(json.to_owned());
+        Ok(event)
+    }
+
+    /// The original json if the event is parsed by [`Event::from_raw`]
+    pub fn raw(&self) -> Option<&str> {
+        self.raw.as_deref()
+    }
+
+    /// Drop the original json, the event is re-serialized after
+    pub fn clear_raw(&mut self) {
+        self.raw = None;
+    }
+
+    /// The unsigned metadata, such as the `ip` field of the json
+    pub fn meta(&self) -> &EventMeta {
+        &self.meta
+    }
+
+    pub fn meta_mut(&mut self) -> &mut EventMeta {
+        &mut self.meta
+    }
+
+    /// The number of tags not indexed, such as a key or a value with null bytes
+    /// or longer than 255 bytes, they can't be queried by the tag filter
+    pub fn dropped_tags(&self) -> usize {
+        self.dropped_tags
+    }
+
+    /// The original json if kept, otherwise the re-serialized json
+    pub fn to_raw_json(&self) -> Result<Cow<'_, str>, Error> {
+        match &self.raw {
+            Some(raw) => Ok(Cow::Borrowed(raw)),
+            None => Ok(Cow::Owned(self.to_json()?)),
+        }
+    }
+
     pub fn index(&self) -> &EventIndex {
         &self.index
     }
@@ -459,7 +717,12 @@ impl Event {
         self.index.created_at
     }
 
-    pub fn kind(&self) -> u16 {
+    /// The expiration time from the [NIP-40](https://nips.be/40) tag
+    pub fn expires_at(&self) -> Option<u64> {
+        self.index.expiration
+    }
+
+    pub fn kind(&self) -> u32 {
         self.index.kind
     }
 
@@ -467,13 +730,61 @@ impl Event {
         &self.tags
     }
 
+    /// The kind in the first `k` tag, such as the reposted kind of a generic repost [NIP-18](https://nips.be/18)
+    pub fn k_tag_kind(&self) -> Option<u32> {
+        self.tags
+            .iter()
+            .find(|tag| tag.len() > 1 && tag[0] == "k")
+            .and_then(|tag| u32::from_str(&tag[1]).ok())
+    }
+
     pub fn content(&self) -> &String {
         &self.content
     }
 
+    /// The media attachments of the `imeta` tags, see [`MediaMeta::parse`]
+    pub fn media_attachments(&self) -> Vec<MediaMeta> {
+        self.tags
+            .iter()
+            .filter_map(|tag| MediaMeta::parse(tag))
+            .collect()
+    }
+
     pub fn sig(&self) -> &[u8; 64] {
         &self.sig
     }
+
+    /// Marked as protected by the `["-"]` tag, [NIP-70](https://nips.be/70)
+    pub fn is_protected(&self) -> bool {
+        self.tags()
+            .iter()
+            .any(|tag| tag.len() == 1 && tag[0] == "-")
+    }
+
+    /// The first `delegation` tag, the signature and conditions are not verified,
+    /// see [`Event::verify_delegation`]
+    pub fn delegation(&self) -> Option<Delegation> {
+        self.tags
+            .iter()
+            .find(|tag| tag.len() == 4 && tag[0] == "delegation")
+            .map(|tag| Delegation {
+                delegator: tag[1].clone(),
+                conditions: tag[2].clone(),
+                sig: tag[3].clone(),
+            })
+    }
+}
+
+pub(crate) fn is_replaceable_kind(kind: u32) -> bool {
+    kind == 0 || kind == 3 || (10_000..20_000).contains(&kind)
+}
+
+pub(crate) fn is_ephemeral_kind(kind: u32) -> bool {
+    (20_000..30_000).contains(&kind)
+}
+
+pub(crate) fn is_addressable_kind(kind: u32) -> bool {
+    (30_000..40_000).contains(&kind)
 }
 
 pub fn now() -> u64 {
@@ -486,7 +797,7 @@ pub fn now() -> u64 {
 fn hash(
     pubkey: &[u8],
     created_at: u64,
-    kind: u16,
+    kind: u32,
     tags: &Vec<Vec<String>>,
     content: &String,
 ) -> [u8; 32] {
@@ -523,34 +834,52 @@ impl Event {
         }
     }
 
+    /// Verify the id and signature of the events in parallel,
+    /// the results are in the same order as the events.
+    pub fn verify_batch(events: &[Event]) -> Vec<Result<(), Error>> {
+        events
+            .par_iter()
+            .map(|event| event.verify_id().and_then(|_| event.verify_sign()))
+            .collect()
+    }
+
     /// check event created time newer than (now - older), older than (now + newer)
-    /// ignore when 0
+    /// ignore when 0, the error message follows [NIP-22](https://nips.be/22)
     pub fn verify_time(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
         let time = self.created_at();
-        if 0 != older && time < now - older {
+        if 0 != older && time < now.saturating_sub(older) {
             return Err(Error::Invalid(format!(
-                "event creation date must be newer than {}",
-                now - older
+                "event creation date is too far off from the current time, must be newer than {}",
+                now.saturating_sub(older)
             )));
         }
 
-        if 0 != newer && time > now + newer {
+        if 0 != newer && time > now.saturating_add(newer) {
             return Err(Error::Invalid(format!(
-                "event creation date must be older than {}",
-                now + newer
+                "event creation date is too far off from the current time, must be older than {}",
+                now.saturating_add(newer)
             )));
         }
         Ok(())
     }
 
+    /// check event expiration earlier than (now + max), ignore when 0 or no expiration
+    pub fn verify_expiration(&self, now: u64, max: u64) -> Result<(), Error> {
+        match self.expires_at() {
+            Some(exp) if 0 != max && exp > now.saturating_add(max) => Err(Error::Invalid(format!(
+                "event expiration is too far off from the current time, must be earlier than {}",
+                now.saturating_add(max)
+            ))),
+            _ => Ok(()),
+        }
+    }
+
     pub fn verify_delegation(&self) -> Result<(), Error> {
         if self.index.delegator.is_some() {
-            for tag in self.tags() {
-                if tag.len() == 4 && tag[0] == "delegation" {
-                    return verify_delegation(self, &tag[1], &tag[2], &tag[3]);
-                }
+            match self.delegation() {
+                Some(d) => verify_delegation(self, &d.delegator, &d.conditions, &d.sig),
+                None => Err(Error::Invalid("error delegation arguments".to_owned())),
             }
-            Err(Error::Invalid("error delegation arguments".to_owned()))
         } else {
             Ok(())
         }
@@ -566,6 +895,40 @@ impl Event {
         self.verify_delegation()?;
         Ok(())
     }
+
+    /// Check the tags are consistent with the kind, opt-in for the tooling creating the events:
+    /// a deletion (kind 5) references an `e` or `a` tag, a contact list (kind 3) has `p` tags,
+    /// an addressable event (kind 30000-39999) has a `d` tag.
+    pub fn validate_semantics(&self) -> Result<(), Error> {
+        let has_tag = |name: &str| {
+            self.tags()
+                .iter()
+                .any(|tag| tag.len() > 1 && tag[0] == name)
+        };
+        let kind = self.kind();
+        if kind == 5 && !has_tag("e") && !has_tag("a") {
+            return Err(Error::Invalid(
+                "deletion event must reference an e or a tag".to_owned(),
+            ));
+        }
+        if kind == 3 && !has_tag("p") {
+            return Err(Error::Invalid(
+                "contact list event must have p tags".to_owned(),
+            ));
+        }
+        // an empty d tag is allowed
+        if is_addressable_kind(kind)
+            && !self
+                .tags()
+                .iter()
+                .any(|tag| tag.first().is_some_and(|t| t == "d"))
+        {
+            return Err(Error::Invalid(
+                "addressable event must have a d tag".to_owned(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 fn verify_delegation(
@@ -587,7 +950,7 @@ fn verify_delegation(
     // check conditions
     for cond in conditions.split('&') {
         if let Some(kind) = cond.strip_prefix("kind=") {
-            let n = u16::from_str(kind)?;
+            let n = u32::from_str(kind)?;
             if n != event.kind() {
                 return Err(Error::Invalid(format!(
                     "event kind must be {}",
@@ -633,6 +996,22 @@ mod tests {
     use serde_json::Value;
     use std::str::FromStr;
 
+    #[test]
+    fn raw_json() -> Result<()> {
+        let note = r#"{"tags":[["t","nostr"]], "kind":1,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","created_at":1680690006,"content":"Good morning everyone 😃","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f"}"#;
+        let mut event = Event::from_raw(note)?;
+        assert_eq!(event.raw(), Some(note));
+        assert_eq!(event.to_raw_json()?, note);
+        assert_ne!(event.to_json()?, note);
+        assert_eq!(event.kind(), 1);
+
+        event.clear_raw();
+        assert!(event.raw().is_none());
+        assert_eq!(event.to_raw_json()?, event.to_json()?);
+        assert!(Event::from_str(note)?.raw().is_none());
+        Ok(())
+    }
+
     #[test]
     fn index_event() -> Result<()> {
         let note = r#"
@@ -648,10 +1027,12 @@ mod tests {
         "#;
         let event: Event = Event::from_str(note)?;
         assert_eq!(event.index().tags().len(), 2);
+        assert_eq!(event.dropped_tags(), 0);
         let e2 = EventIndex::from_bytes(&event.index().to_bytes()?)?;
         assert_eq!(&e2, event.index());
         assert!(&e2.expiration().is_some());
         assert!(&e2.delegator().is_some());
+        assert_eq!(event.expires_at(), Some(1));
 
         let note = r#"
         {
@@ -666,11 +1047,141 @@ mod tests {
         "#;
         let event: Event = Event::from_str(note)?;
         assert_eq!(event.index().tags().len(), 0);
+        assert_eq!(event.expires_at(), None);
         let e2 = EventIndex::from_bytes(&event.index().to_bytes()?)?;
         assert_eq!(&e2, event.index());
         Ok(())
     }
 
+    #[test]
+    fn kind_ranges() -> Result<()> {
+        // kind, replaceable, ephemeral, addressable
+        let cases = [
+            (0, true, false, false),
+            (1, false, false, false),
+            (3, true, false, false),
+            (9999, false, false, false),
+            (10000, true, false, false),
+            (19999, true, false, false),
+            (20000, false, true, false),
+            (29999, false, true, false),
+            (30000, false, false, true),
+            (39999, false, false, true),
+            (40000, false, false, false),
+        ];
+        for (kind, replaceable, ephemeral, addressable) in cases {
+            let event = Event::new([0; 32], [0; 32], 0, kind, vec![], "".to_owned(), [0; 64])?;
+            let index = event.index();
+            assert_eq!(index.is_replaceable(), replaceable, "kind {}", kind);
+            assert_eq!(index.is_ephemeral(), ephemeral, "kind {}", kind);
+            assert_eq!(index.is_addressable(), addressable, "kind {}", kind);
+            assert_eq!(index.is_parameterized(), addressable, "kind {}", kind);
+
+            let bytes = index.to_bytes()?;
+            let archived = EventIndex::from_zeroes(&bytes)?;
+            assert_eq!(archived.is_replaceable(), replaceable, "kind {}", kind);
+            assert_eq!(archived.is_ephemeral(), ephemeral, "kind {}", kind);
+            assert_eq!(archived.is_addressable(), addressable, "kind {}", kind);
+            assert_eq!(archived.is_parameterized(), addressable, "kind {}", kind);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn media_attachments() -> Result<()> {
+        let tag = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let tags = vec![
+            tag(&[
+                "imeta",
+                "url https://nostr.build/i/my-image.jpg",
+                "m image/jpeg",
+                "blurhash eVF$^OI:${M{o#*0-nNFxakD-?xVM}WEWB%iNKxvR-oetmo#R-aen$",
+                "dim 3024x4032",
+                "alt A scenic photo overlooking the coast",
+                "x 3093509d1e0bc604ff60cb9286f4cd7c781553957e7b2a9a9ea6f7f3ed7c1c0f",
+                "fallback https://nostrcheck.me/alt1.jpg",
+                "fallback https://void.cat/alt1.jpg",
+            ]),
+            // missing and extra fields
+            tag(&[
+                "imeta",
+                "url https://example.com/a.mp4",
+                "size 1024",
+                "novalue",
+            ]),
+            // no url
+            tag(&["imeta", "m image/png"]),
+            tag(&["t", "url https://example.com"]),
+            tag(&["imeta"]),
+        ];
+        let event = Event::new([1; 32], [1; 32], 10, 1, tags, "".to_owned(), [0; 64])?;
+        let media = event.media_attachments();
+        assert_eq!(media.len(), 2);
+        assert_eq!(media[0].url, "https://nostr.build/i/my-image.jpg");
+        assert_eq!(media[0].mime.as_deref(), Some("image/jpeg"));
+        assert_eq!(media[0].dim.as_deref(), Some("3024x4032"));
+        assert_eq!(
+            media[0].alt.as_deref(),
+            Some("A scenic photo overlooking the coast")
+        );
+        assert!(media[0].blurhash.is_some());
+        assert!(media[0].sha256.is_some());
+        assert_eq!(media[0].fallback.len(), 2);
+        assert!(media[0].extra.is_empty());
+        assert_eq!(
+            media[1],
+            MediaMeta {
+                url: "https://example.com/a.mp4".to_owned(),
+                extra: vec![("size".to_owned(), "1024".to_owned())],
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_tags() -> Result<()> {
+        let tags = vec![
+            vec!["t".to_owned(), "nostr".to_owned()],
+            vec!["t".to_owned()],
+            vec!["alt".to_owned(), "a note".to_owned()],
+            vec!["expiration".to_owned(), "1".to_owned()],
+            vec!["t".to_owned(), "a".repeat(256)],
+            vec!["t".to_owned(), "a\0b".to_owned()],
+            vec!["\0".to_owned(), "a".to_owned()],
+        ];
+        let event = Event::new([1; 32], [1; 32], 10, 1, tags, "".to_owned(), [0; 64])?;
+        assert_eq!(event.index().tags().len(), 2);
+        assert_eq!(event.dropped_tags(), 3);
+        let event = Event::from_str(&event.to_json()?)?;
+        assert_eq!(event.dropped_tags(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn index_tags_from_json() -> Result<()> {
+        let note = r#"
+        {
+            "content": "Good morning everyone 😃",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": [["t", "nostr"], ["t"], ["subject", "db"], ["p", "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef"], ["r", "a\u0000b"]]
+          }
+        "#;
+        let event: Event = Event::from_str(note)?;
+        let tags = EventIndex::index_tags_from_json(note.as_bytes())?;
+        assert_eq!(&tags, event.index().tags());
+        assert_eq!(tags.len(), 3);
+
+        assert!(EventIndex::index_tags_from_json(b"{}")?.is_empty());
+        assert!(EventIndex::index_tags_from_json(br#"{"tags":[["p","ab"]]}"#).is_err());
+        assert!(EventIndex::index_tags_from_json(b"[").is_err());
+        Ok(())
+    }
+
     #[test]
     fn string() -> Result<()> {
         let note = r#"
@@ -743,7 +1254,7 @@ mod tests {
             "content": "Good morning everyone 😃",
             "created_at": 1680690006,
             "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
-            "kind": 65536,
+            "kind": 4294967296,
             "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
             "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
             "tags": [["t", "nostr"]]
@@ -772,6 +1283,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn verify_batch() -> Result<()> {
+        let key_pair = Keypair::new(SECP256K1, &mut thread_rng());
+        let valid = Event::create(&key_pair, 10, 1, vec![], "hello".to_owned())?;
+        // content changed after signing
+        let bad_id = Event::new(
+            *valid.id(),
+            *valid.pubkey(),
+            10,
+            1,
+            vec![],
+            "hi".to_owned(),
+            *valid.sig(),
+        )?;
+        // signed by another key
+        let other = Event::create(
+            &Keypair::new(SECP256K1, &mut thread_rng()),
+            10,
+            1,
+            vec![],
+            "hello".to_owned(),
+        )?;
+        let bad_sig = Event::new(
+            *valid.id(),
+            *valid.pubkey(),
+            10,
+            1,
+            vec![],
+            "hello".to_owned(),
+            *other.sig(),
+        )?;
+
+        let results = Event::verify_batch(&[valid.clone(), bad_id, bad_sig, valid]);
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        assert!(matches!(&results[1], Err(Error::Invalid(msg)) if msg == "bad event id"));
+        assert!(matches!(&results[2], Err(Error::Invalid(msg)) if msg == "signature is wrong"));
+        assert!(results[3].is_ok());
+        assert!(Event::verify_batch(&[]).is_empty());
+        Ok(())
+    }
+
     #[test]
     fn verify() -> Result<()> {
         let note = r#"
@@ -792,9 +1345,17 @@ mod tests {
         assert!(event.index().is_expired(now()));
 
         let event = Event::new([0; 32], [0; 32], 10, 1, vec![], "".to_string(), [0; 64])?;
+        assert_eq!(event.delegation(), None);
         assert!(event.verify_time(10, 1, 1).is_ok());
         assert!(event.verify_time(20, 1, 1).is_err());
         assert!(event.verify_time(5, 1, 1).is_err());
+        assert!(event.verify_expiration(10, 1).is_ok());
+
+        let tags = vec![vec!["expiration".to_owned(), "100".to_owned()]];
+        let event = Event::new([0; 32], [0; 32], 10, 1, tags, "".to_string(), [0; 64])?;
+        assert!(event.verify_expiration(10, 0).is_ok());
+        assert!(event.verify_expiration(10, 90).is_ok());
+        assert!(event.verify_expiration(10, 89).is_err());
 
         let note = r#"
         {
@@ -815,6 +1376,14 @@ mod tests {
           }
         "#;
         let event: Event = serde_json::from_str(note)?;
+        assert_eq!(
+            event.delegation(),
+            Some(Delegation {
+                delegator: "8e0d3d3eb2881ec137a11debe736a9086715a8c8beeeda615780064d68bc25dd".to_owned(),
+                conditions: "kind=1&created_at>1674834236&created_at<1677426236".to_owned(),
+                sig: "6f44d7fe4f1c09f3954640fb58bd12bae8bb8ff4120853c4693106c82e920e2b898f1f9ba9bd65449a987c39c0423426ab7b53910c0c6abfb41b30bc16e5f524".to_owned(),
+            })
+        );
         assert!(event.verify_delegation().is_err());
         assert!(event
             .verify_delegation()
@@ -825,6 +1394,65 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn mine() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let tags = vec![vec!["t".to_owned(), "pow".to_owned()]];
+        let event = Event::mine(&key_pair, 1, tags.clone(), "hello".to_owned(), 8, 1_000_000)?;
+        assert!(event.pow_difficulty() >= 8);
+        event.verify_id()?;
+        event.verify_sign()?;
+        assert_eq!(event.tags()[0], tags[0]);
+        assert_eq!(event.tags()[1][0], "nonce");
+        assert_eq!(event.tags()[1][2], "8");
+
+        assert!(Event::mine(&key_pair, 1, vec![], "".to_owned(), 200, 10).is_err());
+
+        let mut id = [0u8; 32];
+        id[2] = 0b0001_0000;
+        assert_eq!(leading_zero_bits(&id), 19);
+        assert_eq!(leading_zero_bits(&[0; 32]), 256);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_semantics() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let tag = |k: &str, v: &str| vec![k.to_owned(), v.to_owned()];
+        let create = |kind, tags| Event::create(&key_pair, 0, kind, tags, "".to_owned());
+        let hex = "1".repeat(64);
+
+        // a deletion without the references
+        let err = create(5, vec![tag("p", &hex)])?.validate_semantics();
+        assert!(err.unwrap_err().to_string().contains("deletion"));
+        assert!(create(5, vec![tag("e", &hex)])?
+            .validate_semantics()
+            .is_ok());
+        assert!(create(5, vec![tag("a", "1:2:3")])?
+            .validate_semantics()
+            .is_ok());
+
+        // an addressable without d
+        let err = create(30023, vec![tag("t", "nostr")])?.validate_semantics();
+        assert!(err.unwrap_err().to_string().contains("d tag"));
+        assert!(create(30023, vec![tag("d", "")])?
+            .validate_semantics()
+            .is_ok());
+        assert!(create(30023, vec![vec!["d".to_owned()]])?
+            .validate_semantics()
+            .is_ok());
+
+        assert!(create(3, vec![])?.validate_semantics().is_err());
+        assert!(create(3, vec![tag("p", &hex)])?
+            .validate_semantics()
+            .is_ok());
+        // not checked
+        assert!(create(1, vec![])?.validate_semantics().is_ok());
+        // the creation is not validated
+        assert!(create(5, vec![]).is_ok());
+        Ok(())
+    }
+
     #[test]
     fn create() -> Result<()> {
         let mut rng = thread_rng();
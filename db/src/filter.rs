@@ -1,8 +1,18 @@
-use crate::{error::Error, ArchivedEventIndex, EventIndex};
-use serde::Deserialize;
+use crate::{error::Error, key::is_hex_tag, ArchivedEventIndex, EventIndex};
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use serde_json::Value;
 use std::cmp::Ord;
-use std::{collections::HashMap, ops::Deref, str::FromStr};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    str::FromStr,
+};
 
 /// The sort list contains unduplicated and sorted items
 #[derive(PartialEq, Eq, Debug, Clone, Default)]
@@ -36,22 +46,119 @@ impl<T> Deref for SortList<T> {
     }
 }
 
+/// A hex value of the ids and authors filter, the full 64 hex chars or a shorter prefix
+///
+/// The odd length prefix keeps the last half byte in the high 4 bits, the bit length tells it.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HexPrefix {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl HexPrefix {
+    /// the prefix bytes, the low 4 bits of the last byte are 0 if the hex length is odd
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// the number of hex chars
+    pub fn hex_len(&self) -> usize {
+        self.bits / 4
+    }
+
+    /// the full 32 bytes value, none if it's a prefix
+    pub fn full(&self) -> Option<&[u8; 32]> {
+        self.bytes.as_slice().try_into().ok()
+    }
+
+    /// the value starts with the prefix
+    pub fn matches(&self, value: &[u8]) -> bool {
+        let len = self.bits / 8;
+        if value.len() * 8 < self.bits || value[..len] != self.bytes[..len] {
+            return false;
+        }
+        self.bits.is_multiple_of(8) || value[len] & 0xf0 == self.bytes[len]
+    }
+}
+
+impl FromStr for HexPrefix {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > 64 {
+            return Err(Error::Invalid(format!(
+                "invalid hex prefix length {}, expected 1 to 64",
+                s.len()
+            )));
+        }
+        let bytes = if s.len() % 2 == 1 {
+            hex::decode(format!("{}0", s))?
+        } else {
+            hex::decode(s)?
+        };
+        Ok(Self {
+            bytes,
+            bits: s.len() * 4,
+        })
+    }
+}
+
+impl TryFrom<String> for HexPrefix {
+    type Error = Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
+impl From<[u8; 32]> for HexPrefix {
+    fn from(value: [u8; 32]) -> Self {
+        Self {
+            bytes: value.to_vec(),
+            bits: 256,
+        }
+    }
+}
+
+impl From<Vec<[u8; 32]>> for SortList<HexPrefix> {
+    fn from(value: Vec<[u8; 32]>) -> Self {
+        value
+            .into_iter()
+            .map(HexPrefix::from)
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl fmt::Display for HexPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = hex::encode(&self.bytes);
+        s.truncate(self.hex_len());
+        f.write_str(&s)
+    }
+}
+
 /// Events filter
 ///
 /// [NIP-01](https://nips.be/1)
 
 // TODO: hashset uniq, (default limit), limit length, limit item length, empty string, invald hex prefix, validate length
-#[derive(PartialEq, Eq, Debug, Clone, Default, Deserialize)]
-#[serde(try_from = "_Filter")]
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
 pub struct Filter {
-    /// a list of event ids
-    pub ids: SortList<[u8; 32]>,
+    /// a list of event ids or their hex prefixes
+    pub ids: SortList<HexPrefix>,
 
-    /// a list of pubkeys, the pubkey of an event must be one of these
-    pub authors: SortList<[u8; 32]>,
+    /// a list of pubkeys or their hex prefixes, the pubkey of an event must match one of these
+    pub authors: SortList<HexPrefix>,
 
     /// a list of a kind numbers
-    pub kinds: SortList<u16>,
+    pub kinds: SortList<u32>,
+
+    /// a list of kind numbers, the kind of an event must not be one of these. Non-standard
+    pub not_kinds: SortList<u32>,
 
     pub since: Option<u64>,
     pub until: Option<u64>,
@@ -64,11 +171,25 @@ pub struct Filter {
     ///
     pub tags: HashMap<Vec<u8>, SortList<Vec<u8>>>,
 
+    /// names of the tags their values match as prefixes, such as the hierarchical "d" ids. Non-standard,
+    /// it applies to the event matching such as the live dispatch, the db tag index matches the exact values
+    pub prefix_tags: SortList<Vec<u8>>,
+
     /// Query by time descending order
     pub desc: bool,
 
-    #[serde(skip)]
+    /// at most this number of events per author in the query result, the events of the authors
+    /// reaching it are skipped. Non-standard
+    pub per_author_limit: Option<usize>,
+
     pub words: Vec<Vec<u8>>,
+
+    /// top-level keys that are neither known fields nor "#" tags, ignored by the query
+    pub unknown_fields: Vec<String>,
+
+    /// the list fields present but empty, such as `{"ids": []}`, they match all events as absent
+    /// fields, a relay may treat them as matching nothing
+    pub empty_fields: Vec<String>,
 }
 
 impl FromStr for Filter {
@@ -78,26 +199,118 @@ impl FromStr for Filter {
     }
 }
 
+/// Deserialize in the map visitor, so the json error of the invalid value has the position
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FilterVisitor;
+
+        impl<'de> Visitor<'de> for FilterVisitor {
+            type Value = Filter;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a filter object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let filter = _Filter::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Filter::try_from(filter).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_map(FilterVisitor)
+    }
+}
+
+/// Serialize to the NIP-01 filter object, empty fields are omitted
+impl Serialize for Filter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let empty = |name: &str| self.empty_fields.iter().any(|f| f == name);
+        if !self.ids.is_empty() || empty("ids") {
+            map.serialize_entry(
+                "ids",
+                &self.ids.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            )?;
+        }
+        if !self.authors.is_empty() || empty("authors") {
+            map.serialize_entry(
+                "authors",
+                &self
+                    .authors
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>(),
+            )?;
+        }
+        if !self.kinds.is_empty() || empty("kinds") {
+            map.serialize_entry("kinds", &self.kinds.0)?;
+        }
+        if !self.not_kinds.is_empty() {
+            map.serialize_entry("not_kinds", &self.not_kinds.0)?;
+        }
+        if let Some(since) = self.since {
+            map.serialize_entry("since", &since)?;
+        }
+        if let Some(until) = self.until {
+            map.serialize_entry("until", &until)?;
+        }
+        if let Some(limit) = self.limit {
+            map.serialize_entry("limit", &limit)?;
+        }
+        if let Some(search) = &self.search {
+            map.serialize_entry("search", search)?;
+        }
+        if let Some(per_author_limit) = self.per_author_limit {
+            map.serialize_entry("per_author_limit", &per_author_limit)?;
+        }
+        let mut tags = self.tags.iter().collect::<Vec<_>>();
+        tags.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, list) in tags {
+            let values = list
+                .iter()
+                .map(|v| {
+                    if is_hex_tag(key) {
+                        hex::encode(v)
+                    } else {
+                        String::from_utf8_lossy(v).into_owned()
+                    }
+                })
+                .collect::<Vec<_>>();
+            map.serialize_entry(&format!("#{}", String::from_utf8_lossy(key)), &values)?;
+        }
+        for name in self.empty_fields.iter().filter(|f| f.starts_with('#')) {
+            map.serialize_entry(name, &[] as &[String])?;
+        }
+        map.end()
+    }
+}
+
 #[derive(Deserialize, Default)]
 #[serde(default)]
 struct _Filter {
-    pub ids: Vec<_HexString>,
-    pub authors: Vec<_HexString>,
-    pub kinds: Vec<u16>,
+    // none if absent, distinct from the empty list
+    pub ids: Option<Vec<HexPrefix>>,
+    pub authors: Option<Vec<HexPrefix>>,
+    pub kinds: Option<Vec<u32>>,
+    pub not_kinds: Vec<u32>,
     pub since: Option<u64>,
     pub until: Option<u64>,
     pub limit: Option<u64>,
     pub keywords: Vec<String>,
     pub search: Option<String>,
+    pub per_author_limit: Option<usize>,
     #[serde(flatten)]
     pub tags: HashMap<String, Value>,
 }
 
-#[derive(Deserialize)]
-#[serde(transparent)]
-struct _HexString {
-    #[serde(with = "hex::serde")]
-    hex: [u8; 32],
+/// the tag key can be queried by the index, 0 breaks the index separator
+fn is_index_tag_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= 255 && !key.contains(&0)
+}
+
+/// The JSON pointer of a top-level key, such as `/#e`
+fn pointer(key: &str) -> String {
+    format!("/{}", key.replace('~', "~0").replace('/', "~1"))
 }
 
 impl TryFrom<_Filter> for Filter {
@@ -111,22 +324,39 @@ impl TryFrom<_Filter> for Filter {
 
         // only use valid tag, has prefix "#", string item, not empty
         let mut tags = HashMap::new();
+        let mut unknown_fields = vec![];
+        let mut empty_fields = vec![];
+        for (name, list) in [
+            ("ids", filter.ids.as_ref().map(Vec::len)),
+            ("authors", filter.authors.as_ref().map(Vec::len)),
+            ("kinds", filter.kinds.as_ref().map(Vec::len)),
+        ] {
+            if list == Some(0) {
+                empty_fields.push(name.to_owned());
+            }
+        }
         for item in filter.tags {
-            let key = item.0;
-            if let Some(key) = key.strip_prefix('#') {
+            let name = item.0;
+            if let Some(key) = name.strip_prefix('#') {
                 let key = key.as_bytes();
-                // only index for key len 1
-                if key.len() == 1 {
-                    let val = Vec::<String>::deserialize(&item.1)?;
+                // the same keys as the event tag index
+                if is_index_tag_key(key) {
+                    let val = Vec::<String>::deserialize(&item.1)
+                        .map_err(|e| Error::Invalid(format!("{} at {}", e, pointer(&name))))?;
                     let mut list = vec![];
-                    for s in val {
-                        if key == b"e" || key == b"p" {
-                            let h = hex::decode(&s)?;
-                            if h.len() != 32 {
-                                // ignore
-                                return Err(Error::Invalid("invalid e or p tag value".to_string()));
-                            } else {
-                                list.push(h);
+                    for (index, s) in val.into_iter().enumerate() {
+                        if is_hex_tag(key) {
+                            match hex::decode(&s) {
+                                Ok(h) if h.len() == 32 => list.push(h),
+                                _ => {
+                                    return Err(Error::Invalid(format!(
+                                        "invalid {} tag value {:?} at {}/{}",
+                                        String::from_utf8_lossy(key),
+                                        s,
+                                        pointer(&name),
+                                        index
+                                    )));
+                                }
                             }
                         } else {
                             list.push(s.into_bytes());
@@ -136,34 +366,35 @@ impl TryFrom<_Filter> for Filter {
                             // }
                         }
                     }
-                    if !list.is_empty() {
+                    if list.is_empty() {
+                        empty_fields.push(name);
+                    } else {
                         tags.insert(key.to_vec(), list.into());
                     }
                 }
+            } else {
+                unknown_fields.push(name);
             }
         }
+        unknown_fields.sort();
+        empty_fields.sort();
 
         let f = Filter {
-            ids: filter
-                .ids
-                .into_iter()
-                .map(|s| s.hex)
-                .collect::<Vec<_>>()
-                .into(),
-            authors: filter
-                .authors
-                .into_iter()
-                .map(|s| s.hex)
-                .collect::<Vec<_>>()
-                .into(),
-            kinds: filter.kinds.into(),
+            ids: filter.ids.unwrap_or_default().into(),
+            authors: filter.authors.unwrap_or_default().into(),
+            kinds: filter.kinds.unwrap_or_default().into(),
+            not_kinds: filter.not_kinds.into(),
             since: filter.since,
             until: filter.until,
             limit: filter.limit,
             search,
             tags,
+            prefix_tags: Default::default(),
             desc: filter.limit.is_some(),
+            per_author_limit: filter.per_author_limit,
             words: vec![],
+            unknown_fields,
+            empty_fields,
         };
 
         Ok(f)
@@ -171,29 +402,139 @@ impl TryFrom<_Filter> for Filter {
 }
 
 impl Filter {
+    /// to the compact NIP-01 json object, the same as the [`Serialize`] impl.
+    /// The unknown fields, the prefix tags and the search words are not included.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
     #[cfg(feature = "search")]
     /// build keywords for search ability
     pub fn build_words(&mut self) {
+        self.build_words_with(&crate::SegmentOptions::default())
+    }
+
+    #[cfg(feature = "search")]
+    /// build keywords for search ability with the segmentation options
+    pub fn build_words_with(&mut self, opts: &crate::SegmentOptions) {
         if let Some(search) = &self.search {
-            let words = crate::segment(search);
+            let words = crate::segment_with(search, opts);
             if !words.is_empty() {
                 self.words = words;
             }
         }
     }
 
+    /// No ids, authors, tags, kinds, since, until, limit or search, the query scans everything
+    pub fn is_unbounded(&self) -> bool {
+        self.ids.is_empty()
+            && self.authors.is_empty()
+            && self.tags.is_empty()
+            && self.kinds.is_empty()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.limit.is_none()
+            && self.search.is_none()
+    }
+
+    /// Hash of the query conditions, equal filters have the same hash in a process
+    pub fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.ids.hash(&mut hasher);
+        self.authors.hash(&mut hasher);
+        self.kinds.hash(&mut hasher);
+        self.not_kinds.hash(&mut hasher);
+        self.since.hash(&mut hasher);
+        self.until.hash(&mut hasher);
+        self.limit.hash(&mut hasher);
+        self.search.hash(&mut hasher);
+        self.desc.hash(&mut hasher);
+        self.per_author_limit.hash(&mut hasher);
+        let mut tags = self.tags.iter().collect::<Vec<_>>();
+        tags.sort_by(|a, b| a.0.cmp(b.0));
+        for (key, values) in tags {
+            key.hash(&mut hasher);
+            values.hash(&mut hasher);
+        }
+        self.prefix_tags.hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn default_limit(&mut self, limit: u64) {
         if self.limit.is_none() {
             self.limit = Some(limit);
         }
     }
 
+    /// Combine two filters into one matching the events of both, when they differ in exactly one
+    /// of the ids, authors, kinds or the values of one tag, all the other fields must be equal.
+    /// An absent list matches all, so the union with it is absent too.
+    /// Returns none if they can't be merged safely.
+    pub fn merge(&self, other: &Filter) -> Option<Filter> {
+        if self.not_kinds != other.not_kinds
+            || self.since != other.since
+            || self.until != other.until
+            || self.limit != other.limit
+            || self.search != other.search
+            || self.words != other.words
+            || self.prefix_tags != other.prefix_tags
+            || self.desc != other.desc
+            || self.per_author_limit != other.per_author_limit
+            || self.empty_fields != other.empty_fields
+        {
+            return None;
+        }
+
+        fn union<T: Ord + Clone>(a: &SortList<T>, b: &SortList<T>) -> SortList<T> {
+            if a.is_empty() || b.is_empty() {
+                SortList(vec![])
+            } else {
+                a.iter().chain(b.iter()).cloned().collect::<Vec<_>>().into()
+            }
+        }
+
+        let mut merged = self.clone();
+        let mut diff = 0;
+        if self.ids != other.ids {
+            diff += 1;
+            merged.ids = union(&self.ids, &other.ids);
+        }
+        if self.authors != other.authors {
+            diff += 1;
+            merged.authors = union(&self.authors, &other.authors);
+        }
+        if self.kinds != other.kinds {
+            diff += 1;
+            merged.kinds = union(&self.kinds, &other.kinds);
+        }
+        for (key, a) in self.tags.iter() {
+            match other.tags.get(key) {
+                Some(b) if a == b => {}
+                Some(b) => {
+                    diff += 1;
+                    merged.tags.insert(key.clone(), union(a, b));
+                }
+                None => {
+                    diff += 1;
+                    merged.tags.remove(key);
+                }
+            }
+        }
+        // the tags only in other, the merged filter is already absent of them
+        diff += other
+            .tags
+            .keys()
+            .filter(|key| !self.tags.contains_key(*key))
+            .count();
+        (diff <= 1).then_some(merged)
+    }
+
     pub fn set_tags(&mut self, tags: HashMap<String, Vec<String>>) {
         let mut t = HashMap::new();
         for item in tags {
             let key = item.0.into_bytes();
-            // only index for key len 1
-            if key.len() == 1 {
+            // the same keys as the event tag index
+            if is_index_tag_key(&key) {
                 let val = item
                     .1
                     .into_iter()
@@ -209,12 +550,12 @@ impl Filter {
         self.tags = t;
     }
 
-    pub fn match_id(ids: &SortList<[u8; 32]>, id: &[u8; 32]) -> bool {
-        ids.is_empty() || ids.contains(id)
+    pub fn match_id(ids: &SortList<HexPrefix>, id: &[u8; 32]) -> bool {
+        ids.is_empty() || ids.iter().any(|p| p.matches(id))
     }
 
     pub fn match_author(
-        authors: &SortList<[u8; 32]>,
+        authors: &SortList<HexPrefix>,
         pubkey: &[u8; 32],
         delegator: Option<&[u8; 32]>,
     ) -> bool {
@@ -225,10 +566,14 @@ impl Filter {
                 .unwrap_or_default()
     }
 
-    pub fn match_kind(kinds: &SortList<u16>, kind: u16) -> bool {
+    pub fn match_kind(kinds: &SortList<u32>, kind: u32) -> bool {
         kinds.is_empty() || kinds.contains(&kind)
     }
 
+    pub fn match_not_kind(not_kinds: &SortList<u32>, kind: u32) -> bool {
+        not_kinds.is_empty() || !not_kinds.contains(&kind)
+    }
+
     pub fn match_tag<V: AsRef<[u8]>, I: AsRef<[(V, V)]>>(
         tags: &HashMap<Vec<u8>, SortList<Vec<u8>>>,
         event_tags: I,
@@ -269,8 +614,26 @@ impl Filter {
         false
     }
 
+    /// match the tags, the values of the prefix tags match as prefixes
+    fn match_tags<V: AsRef<[u8]>, I: AsRef<[(V, V)]>>(&self, event_tags: I) -> bool {
+        if self.prefix_tags.is_empty() {
+            return Self::match_tag(&self.tags, event_tags);
+        }
+        let event_tags = event_tags.as_ref();
+        self.tags.iter().all(|(name, list)| {
+            if self.prefix_tags.contains(name) {
+                event_tags.iter().any(|tag| {
+                    tag.0.as_ref() == name.as_slice()
+                        && list.iter().any(|v| tag.1.as_ref().starts_with(v))
+                })
+            } else {
+                Self::tag_contains(event_tags, name, list)
+            }
+        })
+    }
+
     pub fn r#match(&self, event: &EventIndex) -> bool {
-        self.match_except_tag(event) && Self::match_tag(&self.tags, event.tags())
+        self.match_except_tag(event) && self.match_tags(event.tags())
     }
 
     pub fn match_except_tag(&self, event: &EventIndex) -> bool {
@@ -278,11 +641,12 @@ impl Filter {
             && self.since.map_or(true, |t| event.created_at() >= t)
             && self.until.map_or(true, |t| event.created_at() <= t)
             && Self::match_kind(&self.kinds, event.kind())
+            && Self::match_not_kind(&self.not_kinds, event.kind())
             && Self::match_author(&self.authors, event.pubkey(), event.delegator())
     }
 
     pub fn match_archived(&self, event: &ArchivedEventIndex) -> bool {
-        self.match_archived_except_tag(event) && Self::match_tag(&self.tags, event.tags())
+        self.match_archived_except_tag(event) && self.match_tags(event.tags())
     }
 
     pub fn match_archived_except_tag(&self, event: &ArchivedEventIndex) -> bool {
@@ -290,6 +654,7 @@ impl Filter {
             && self.since.map_or(true, |t| event.created_at() >= t)
             && self.until.map_or(true, |t| event.created_at() <= t)
             && Self::match_kind(&self.kinds, event.kind())
+            && Self::match_not_kind(&self.not_kinds, event.kind())
             && Self::match_author(&self.authors, event.pubkey(), event.delegator())
     }
 }
@@ -298,7 +663,7 @@ impl Filter {
 mod tests {
     use std::{collections::HashMap, str::FromStr};
 
-    use super::Filter;
+    use super::{Filter, HexPrefix};
     use crate::{filter::SortList, ArchivedEventIndex, Event, EventIndex};
     use anyhow::Result;
 
@@ -328,7 +693,7 @@ mod tests {
           }
         "###;
         let mut filter: Filter = serde_json::from_str(note)?;
-        let li = SortList::from(vec![[0x12; 32], [0xab; 32], [0xcd; 32]]);
+        let li: SortList<HexPrefix> = vec![[0x12; 32], [0xab; 32], [0xcd; 32]].into();
         let tags: SortList<Vec<u8>> = ["ab", "cd", "12"]
             .iter()
             .map(|s| s.as_bytes().to_vec())
@@ -337,6 +702,7 @@ mod tests {
         assert_eq!(&filter.ids, &li);
         assert_eq!(&filter.authors, &li);
         assert_eq!(&filter.kinds, &SortList::from(vec![1, 2]));
+        assert!(filter.not_kinds.is_empty());
         assert_eq!(filter.until, Some(5));
         assert_eq!(filter.since, Some(3));
         assert_eq!(filter.limit, Some(6));
@@ -361,6 +727,39 @@ mod tests {
             .get(&"_invalid".to_string().into_bytes())
             .is_none());
         assert!(filter.tags.get(&"b".to_string().into_bytes()).is_none());
+        // unknown fields are collected, known fields and tags are not
+        assert_eq!(filter.unknown_fields, vec!["_invalid", "invalid"]);
+        let filter1: Filter = serde_json::from_str(r###"{"#x": [], "keywords": []}"###)?;
+        assert!(filter1.unknown_fields.is_empty());
+        // present but empty lists
+        assert_eq!(filter1.empty_fields, vec!["#x"]);
+        assert_eq!(filter.empty_fields, vec!["#b"]);
+        assert!(Filter::default().empty_fields.is_empty());
+        let filter2 = Filter::from_str(r###"{"ids": [], "authors": [], "kinds": [1]}"###)?;
+        assert!(filter2.ids.is_empty());
+        assert_eq!(filter2.empty_fields, vec!["authors", "ids"]);
+        assert_eq!(
+            serde_json::to_string(&filter2)?,
+            r#"{"ids":[],"authors":[],"kinds":[1]}"#
+        );
+        assert_eq!(
+            Filter::from_str(&serde_json::to_string(&filter2)?)?,
+            filter2
+        );
+
+        // the error has the json pointer and the position of the invalid tag
+        let err = Filter::from_str(r###"{"kinds": [1], "#e": ["ab", "xyz"]}"###).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid: invalid e tag value "ab" at /#e/0 at line 1 column 35"#
+        );
+        let p = hex::encode([1; 32]);
+        let err = Filter::from_str(&format!(r###"{{"#p": ["{}", "{}0"]}}"###, p, p)).unwrap_err();
+        assert!(err.to_string().contains(&format!(r#""{}0" at /#p/1"#, p)));
+        let err = Filter::from_str(r###"{"#t": "nostr"}"###).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(r#"invalid: invalid type: string "nostr", expected a sequence at /#t"#));
         // set tag
         filter.set_tags(HashMap::from([
             (
@@ -444,6 +843,176 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn hex_prefix() -> Result<()> {
+        let p = HexPrefix::from_str("abc")?;
+        assert_eq!(p.bytes(), &[0xab, 0xc0]);
+        assert_eq!(p.bits(), 12);
+        assert_eq!(p.to_string(), "abc");
+        assert!(p.full().is_none());
+        assert!(p.matches(&[0xab, 0xcd, 0x01]));
+        assert!(!p.matches(&[0xab, 0xd0]));
+        assert!(!p.matches(&[0xab]));
+
+        let full = HexPrefix::from_str(&hex::encode([0xab; 32]))?;
+        assert_eq!(full, HexPrefix::from([0xab; 32]));
+        assert_eq!(full.full(), Some(&[0xab; 32]));
+        assert!(full.matches(&[0xab; 32]));
+
+        assert!(HexPrefix::from_str("").is_err());
+        assert!(HexPrefix::from_str("xy").is_err());
+        assert!(HexPrefix::from_str(&"a".repeat(65)).is_err());
+
+        let filter = Filter::from_str(r#"{"ids":["abc"],"authors":["ab","abababab"]}"#)?;
+        assert_eq!(filter.ids.len(), 1);
+        assert_eq!(filter.authors.len(), 2);
+        assert_eq!(
+            serde_json::to_string(&filter)?,
+            r#"{"ids":["abc"],"authors":["ab","abababab"]}"#
+        );
+        let mut id = [0xab; 32];
+        assert!(!Filter::match_id(&filter.ids, &id));
+        id[1] = 0xcd;
+        assert!(Filter::match_id(&filter.ids, &id));
+        assert!(Filter::match_author(&filter.authors, &[0xab; 32], None));
+        assert!(Filter::match_author(
+            &filter.authors,
+            &[0; 32],
+            Some(&[0xab; 32])
+        ));
+        assert!(!Filter::match_author(&filter.authors, &[0; 32], None));
+        Ok(())
+    }
+
+    #[test]
+    fn unbounded() -> Result<()> {
+        assert!(Filter::from_str("{}")?.is_unbounded());
+        assert!(Filter::from_str(r##"{"not_kinds":[1],"foo":1}"##)?.is_unbounded());
+        for s in [
+            r##"{"kinds":[1]}"##,
+            r##"{"authors":["abababababababababababababababababababababababababababababababab"]}"##,
+            r##"{"ids":["abababababababababababababababababababababababababababababababab"]}"##,
+            r##"{"#t":["a"]}"##,
+            r##"{"#tt":["a"]}"##,
+            r##"{"since":1}"##,
+            r##"{"until":1}"##,
+            r##"{"limit":0}"##,
+            r##"{"search":"a"}"##,
+        ] {
+            assert!(!Filter::from_str(s)?.is_unbounded(), "{}", s);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn to_json() -> Result<()> {
+        let p = hex::encode([1; 32]);
+        for s in [
+            "{}".to_owned(),
+            format!(
+                r##"{{"ids":["abcdef0123"],"authors":["{}"],"kinds":[1,2],"since":1,"until":2,"limit":3,"search":"nostr db","#alt":["x"],"#p":["{}"],"#t":["a","b"]}}"##,
+                p, p
+            ),
+            r##"{"kinds":[1],"not_kinds":[2],"#e":[]}"##.to_owned(),
+            r##"{"kinds":[1],"per_author_limit":2}"##.to_owned(),
+        ] {
+            let filter = Filter::from_str(&s)?;
+            let json = filter.to_json()?;
+            assert_eq!(json, s);
+            assert_eq!(Filter::from_str(&json)?, filter);
+        }
+
+        // normalized, the words are skipped
+        let mut filter = Filter::from_str(r##"{"kinds":[2,1,1],"#t":["b","a"],"foo":1}"##)?;
+        filter.words = vec![b"a".to_vec()];
+        assert_eq!(filter.to_json()?, r##"{"kinds":[1,2],"#t":["a","b"]}"##);
+        Ok(())
+    }
+
+    #[test]
+    fn merge() -> Result<()> {
+        let a = hex::encode([1; 32]);
+        let b = hex::encode([2; 32]);
+        let merge = |f1: &str, f2: &str| -> Result<Option<String>> {
+            let f1 = Filter::from_str(f1)?;
+            let f2 = Filter::from_str(f2)?;
+            let merged = f1.merge(&f2);
+            // symmetric
+            assert_eq!(merged, f2.merge(&f1));
+            Ok(merged.map(|f| f.to_json()).transpose()?)
+        };
+
+        // kinds union
+        assert_eq!(
+            merge(
+                r#"{"kinds":[1],"since":10}"#,
+                r#"{"kinds":[2,1],"since":10}"#
+            )?,
+            Some(r#"{"kinds":[1,2],"since":10}"#.to_owned())
+        );
+        // the absent kinds match all
+        assert_eq!(
+            merge(
+                &format!(r#"{{"authors":["{}"]}}"#, a),
+                &format!(r#"{{"authors":["{}"],"kinds":[1]}}"#, a)
+            )?,
+            Some(format!(r#"{{"authors":["{}"]}}"#, a))
+        );
+        // authors union
+        assert_eq!(
+            merge(
+                &format!(r##"{{"authors":["{}"],"kinds":[1],"#t":["x"]}}"##, a),
+                &format!(r##"{{"authors":["{}"],"kinds":[1],"#t":["x"]}}"##, b)
+            )?,
+            Some(format!(
+                r##"{{"authors":["{}","{}"],"kinds":[1],"#t":["x"]}}"##,
+                a, b
+            ))
+        );
+        // tag values union
+        assert_eq!(
+            merge(
+                r##"{"kinds":[1],"#t":["x"]}"##,
+                r##"{"kinds":[1],"#t":["y"]}"##
+            )?,
+            Some(r##"{"kinds":[1],"#t":["x","y"]}"##.to_owned())
+        );
+        // equal
+        assert_eq!(
+            merge(r#"{"kinds":[1]}"#, r#"{"kinds":[1]}"#)?,
+            Some(r#"{"kinds":[1]}"#.to_owned())
+        );
+
+        // since and kinds differ
+        assert!(merge(r#"{"kinds":[1],"since":10}"#, r#"{"kinds":[2],"since":20}"#)?.is_none());
+        // kinds and authors differ
+        assert!(merge(
+            &format!(r#"{{"authors":["{}"],"kinds":[1]}}"#, a),
+            &format!(r#"{{"authors":["{}"],"kinds":[2]}}"#, b)
+        )?
+        .is_none());
+        // two tags differ
+        assert!(merge(r##"{"#t":["x"],"#d":["a"]}"##, r##"{"#t":["y"]}"##)?.is_none());
+        assert!(merge(r#"{"kinds":[1],"limit":1}"#, r#"{"kinds":[2],"limit":2}"#)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn hash() -> Result<()> {
+        let filter = Filter::from_str(r##"{"kinds":[1,2],"#t":["a","b"],"#d":["c"],"limit":10}"##)?;
+        let filter1 =
+            Filter::from_str(r##"{"#d":["c"],"limit":10,"#t":["b","a"],"kinds":[2,1,1]}"##)?;
+        assert_eq!(filter.hash(), filter1.hash());
+        for s in [
+            r##"{"kinds":[1,2],"#t":["a","b"],"#d":["c"]}"##,
+            r##"{"kinds":[1],"#t":["a","b"],"#d":["c"],"limit":10}"##,
+            r##"{"kinds":[1,2],"#t":["a","b"],"#r":["c"],"limit":10}"##,
+        ] {
+            assert_ne!(filter.hash(), Filter::from_str(s)?.hash(), "{}", s);
+        }
+        Ok(())
+    }
+
     fn check_match(
         s: &str,
         matched: bool,
@@ -550,6 +1119,80 @@ mod tests {
             archived,
         )?;
 
+        check_match(
+            r###"
+        {
+            "not_kinds": [2, 4]
+        }
+        "###,
+            true,
+            &event,
+            archived,
+        )?;
+
+        check_match(
+            r###"
+        {
+            "kinds": [1],
+            "not_kinds": [1]
+        }
+        "###,
+            false,
+            &event,
+            archived,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn match_hex_tag_case() -> Result<()> {
+        // e and p tag values are decoded to bytes, the hex case doesn't matter
+        let note = r#"
+        {
+            "content": "Good morning everyone 😃",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": [["e", "332747C0FAB8A1A92DEF4B0937E177BE6DF4382CE6DD7724F86DC4710B7D4D7D"], ["p", "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef"]]
+          }
+        "#;
+        let event: Event = serde_json::from_str(note)?;
+        let bytes = event.index().to_bytes()?;
+        let archived = EventIndex::from_zeroes(&bytes)?;
+
+        check_match(
+            r###"
+        {
+            "#e": ["332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d"]
+        }
+        "###,
+            true,
+            &event,
+            archived,
+        )?;
+        check_match(
+            r###"
+        {
+            "#p": ["7ABF57D516B1FF7308CA3BD5650EA6A4674D469C7C5057B1D005FB13D218BFEF"]
+        }
+        "###,
+            true,
+            &event,
+            archived,
+        )?;
+        check_match(
+            r###"
+        {
+            "#e": ["7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef"]
+        }
+        "###,
+            false,
+            &event,
+            archived,
+        )?;
         Ok(())
     }
 
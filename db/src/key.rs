@@ -1,5 +1,53 @@
-use crate::error::Error;
+use crate::{
+    error::Error,
+    event::{is_addressable_kind, is_replaceable_kind},
+};
 use nostr_kv::scanner::TimeKey;
+use std::{str::FromStr, sync::OnceLock};
+
+// the tag keys with a 32 bytes hex value, unset is the default e and p
+static HEX_TAGS: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+
+/// Set the tag keys with a 32 bytes hex value, `e` and `p` by default.
+/// The values are decoded to bytes in both the index and the filter, so it must be set once
+/// before opening the database, setting a different list later fails.
+/// [`crate::Db::check_schema`] refuses the database indexed with a different list.
+pub fn set_hex_tags<I, S>(keys: I) -> Result<(), Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut keys = keys
+        .into_iter()
+        .map(|k| k.as_ref().as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    if HEX_TAGS.get_or_init(|| keys.clone()) != &keys {
+        return Err(Error::Invalid("the hex tags are already set".to_owned()));
+    }
+    Ok(())
+}
+
+/// Whether the tag value is a 32 bytes hex decoded to bytes, see [`set_hex_tags`]
+pub fn is_hex_tag<K: AsRef<[u8]>>(key: K) -> bool {
+    let key = key.as_ref();
+    match HEX_TAGS.get() {
+        Some(keys) => keys.iter().any(|k| k == key),
+        None => key == b"e" || key == b"p",
+    }
+}
+
+/// The hex tag keys in effect separated by [`VIEW_KEY_SEP`], recorded in the database
+pub(crate) fn hex_tags_meta() -> Vec<u8> {
+    match HEX_TAGS.get() {
+        Some(keys) => keys.join(&VIEW_KEY_SEP[..]),
+        None => DEFAULT_HEX_TAGS_META.to_vec(),
+    }
+}
+
+/// The default e and p in the form of [`hex_tags_meta`]
+pub(crate) const DEFAULT_HEX_TAGS_META: &[u8] = b"e\0p";
 
 // a separator for compare
 pub const VIEW_KEY_SEP: [u8; 1] = [0];
@@ -34,7 +82,7 @@ impl IndexKey {
         [id.as_ref(), &time.to_be_bytes()[..]].concat()
     }
 
-    pub fn encode_kind(kind: u16, time: u64) -> Vec<u8> {
+    pub fn encode_kind(kind: u32, time: u64) -> Vec<u8> {
         [&kind.to_be_bytes()[..], &time.to_be_bytes()[..]].concat()
     }
 
@@ -42,7 +90,7 @@ impl IndexKey {
         [pubkey.as_ref(), &time.to_be_bytes()[..]].concat()
     }
 
-    pub fn encode_pubkey_kind<P: AsRef<[u8]>>(pubkey: P, kind: u16, time: u64) -> Vec<u8> {
+    pub fn encode_pubkey_kind<P: AsRef<[u8]>>(pubkey: P, kind: u32, time: u64) -> Vec<u8> {
         [
             pubkey.as_ref(),
             &kind.to_be_bytes()[..],
@@ -99,19 +147,20 @@ pub fn u64_to_ver(num: u64) -> Vec<u8> {
     num.to_be_bytes().to_vec()
 }
 
-pub fn u16_to_ver(num: u16) -> Vec<u8> {
+pub fn u32_to_ver(num: u32) -> Vec<u8> {
     num.to_be_bytes().to_vec()
 }
 
 // Replaceable Events [NIP-16](https://nips.be/16)
 // Parameterized Replaceable Events [NIP-33](https://nips.be/33)
-pub fn encode_replace_key(kind: u16, pubkey: &[u8; 32], tags: &[Vec<String>]) -> Option<Vec<u8>> {
-    if kind == 0 || kind == 3 || kind == 41 || (10_000..20_000).contains(&kind) {
-        let k = u16_to_ver(kind);
+pub fn encode_replace_key(kind: u32, pubkey: &[u8; 32], tags: &[Vec<String>]) -> Option<Vec<u8>> {
+    // kind 41 NIP-28 channel metadata is replaced too
+    if is_replaceable_kind(kind) || kind == 41 {
+        let k = u32_to_ver(kind);
         let p: &[u8] = pubkey.as_ref();
         Some([p, &k[..]].concat())
-    } else if (30_000..40_000).contains(&kind) {
-        let k = u16_to_ver(kind);
+    } else if is_addressable_kind(kind) {
+        let k = u32_to_ver(kind);
         let p: &[u8] = pubkey.as_ref();
         let tag = tags
             .get(0)
@@ -128,16 +177,28 @@ pub fn encode_replace_key(kind: u16, pubkey: &[u8; 32], tags: &[Vec<String>]) ->
         None
     }
 }
-type ReplaceKey<'a> = (&'a [u8], u16, &'a [u8], u64);
+// Decode the `a` tag coordinate `<kind>:<pubkey>:<d tag>` to the replace key
+pub fn decode_coordinate(value: &[u8]) -> Option<(Vec<u8>, [u8; 32])> {
+    let value = std::str::from_utf8(value).ok()?;
+    let mut parts = value.splitn(3, ':');
+    let kind = u32::from_str(parts.next()?).ok()?;
+    let mut pubkey = [0u8; 32];
+    hex::decode_to_slice(parts.next()?, &mut pubkey).ok()?;
+    let tag = parts.next().unwrap_or_default();
+    let tags = vec![vec!["d".to_owned(), tag.to_owned()]];
+    encode_replace_key(kind, &pubkey, &tags).map(|key| (key, pubkey))
+}
+
+type ReplaceKey<'a> = (&'a [u8], u32, &'a [u8], u64);
 #[allow(unused)]
 pub fn decode_replace_key<'a>(val: &'a [u8], time: &'a [u8]) -> Result<ReplaceKey<'a>, Error> {
     let len = val.len();
-    if len < 32 + 2 {
+    if len < 32 + 4 {
         Err(Error::InvalidLength)
     } else {
         let pubkey = &val[0..32];
-        let kind = u16::from_be_bytes(val[32..34].try_into()?);
-        let tag = &val[34..];
+        let kind = u32::from_be_bytes(val[32..36].try_into()?);
+        let tag = &val[36..];
         let time = u64::from_be_bytes(time.try_into()?);
         Ok((pubkey, kind, tag, time))
     }
@@ -178,7 +239,7 @@ mod tests {
     fn index_key() -> Result<()> {
         let time = 20u64;
         let id = pad_start(&vec![1, 2, 3], 32);
-        let kind = 10u16;
+        let kind = 10u32;
         let pubkey = vec![1; 32];
         let tag_key = "d";
         let tag_val = "m";
@@ -241,4 +302,24 @@ mod tests {
         assert_eq!(r.2, "m".as_bytes());
         assert_eq!(r.3, 10);
     }
+
+    #[test]
+    fn coordinate() {
+        let pubkey = [1u8; 32];
+        let hex = hex::encode(pubkey);
+        let tags = vec![vec!["d".to_owned(), "m:n".to_owned()]];
+
+        let (key, p) = decode_coordinate(format!("30001:{}:m:n", hex).as_bytes()).unwrap();
+        assert_eq!(key, encode_replace_key(30001, &pubkey, &tags).unwrap());
+        assert_eq!(p, pubkey);
+        let (key, _) = decode_coordinate(format!("10001:{}:", hex).as_bytes()).unwrap();
+        assert_eq!(key, encode_replace_key(10001, &pubkey, &tags).unwrap());
+        let (key, _) = decode_coordinate(format!("30001:{}", hex).as_bytes()).unwrap();
+        assert_eq!(key, encode_replace_key(30001, &pubkey, &[]).unwrap());
+
+        // not replaceable
+        assert!(decode_coordinate(format!("1:{}:", hex).as_bytes()).is_none());
+        assert!(decode_coordinate(b"30001:xx:m").is_none());
+        assert!(decode_coordinate(b"kind").is_none());
+    }
 }
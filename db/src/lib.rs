@@ -1,5 +1,6 @@
 //! Nostr event database
 
+mod cipher;
 mod db;
 mod error;
 mod event;
@@ -8,10 +9,17 @@ mod key;
 pub use secp256k1;
 
 pub use {
-    db::CheckEventResult, db::Db, db::Iter, error::Error, event::now, event::ArchivedEventIndex,
-    event::Event, event::EventIndex, event::FromEventData, filter::Filter, filter::SortList,
+    db::BatchOpts, db::CheckEventResult, db::CheckpointInfo, db::CustomIter, db::Db, db::DbOptions,
+    db::DbTransaction, db::DbWriter, db::IndexExtractor, db::Iter, db::PrewarmInfo, db::QueryOpts,
+    db::SeqIter, db::EPHEMERAL_EXPIRED_SECONDS, error::Error, event::now,
+    event::ArchivedEventIndex, event::Delegation, event::Event, event::EventIndex,
+    event::EventMeta, event::FromEventData, event::MediaMeta, filter::Filter, filter::HexPrefix,
+    filter::SortList, key::is_hex_tag, key::set_hex_tags,
 };
 
+#[cfg(feature = "index_stats")]
+pub use db::IndexUsage;
+
 pub use nostr_kv as kv;
 
 /// Stats of query
@@ -25,13 +33,32 @@ pub struct Stats {
 #[cfg(feature = "search")]
 use charabia::Segment;
 
+#[cfg(feature = "search")]
+/// Options of the keywords segmentation, use the same options for the events and the filters
+#[derive(Debug, Clone, Default)]
+pub struct SegmentOptions {
+    /// skip the words shorter than this in chars
+    pub min_len: usize,
+    /// skip the lowercased words in this set
+    pub stopwords: std::collections::HashSet<String>,
+}
+
 #[cfg(feature = "search")]
 /// segment keywords by charabia
 pub fn segment(content: &str) -> Vec<Vec<u8>> {
+    segment_with(content, &SegmentOptions::default())
+}
+
+#[cfg(feature = "search")]
+/// segment keywords by charabia, skip the short words and the stopwords
+pub fn segment_with(content: &str, opts: &SegmentOptions) -> Vec<Vec<u8>> {
     let iter = content.segment_str();
     let mut words = iter
         .filter_map(|s| {
             let s = s.to_lowercase();
+            if s.chars().count() < opts.min_len || opts.stopwords.contains(&s) {
+                return None;
+            }
             let bytes = s.as_bytes();
             // limit size
             if bytes.len() < 255 {
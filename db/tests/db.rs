@@ -1,5 +1,13 @@
-use nostr_db::{Db, Error, Event, Filter, Stats};
-use std::collections::HashMap;
+use nostr_db::{
+    secp256k1::{
+        rand::{distributions::Alphanumeric, thread_rng, Rng},
+        Keypair,
+    },
+    segment, segment_with, BatchOpts, Db, DbOptions, Error, Event, Filter, QueryOpts,
+    SegmentOptions, Stats,
+};
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
 use std::str::FromStr;
 use std::thread::sleep;
 use std::time::Duration;
@@ -11,7 +19,7 @@ pub struct MyEvent {
     id: [u8; 32],
     pubkey: [u8; 32],
     created_at: u64,
-    kind: u16,
+    kind: u32,
     tags: Vec<Vec<String>>,
     content: String,
     sig: [u8; 64],
@@ -62,27 +70,941 @@ pub fn create_db(t: &str) -> Result<Db> {
     Db::open(dir.path())
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+pub fn test_open_huge_pages() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-huge-pages")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(
+        dir.path(),
+        DbOptions {
+            huge_pages: true,
+            ..Default::default()
+        },
+    )?;
+    let event: Event = MyEvent {
+        id: [1; 32],
+        pubkey: [1; 32],
+        kind: 1,
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![event.clone()])?;
+    let reader = db.reader()?;
+    let e1: Option<Event> = db.get(&reader, event.id())?;
+    assert_eq!(e1.unwrap().id(), event.id());
+    Ok(())
+}
+
+#[test]
+pub fn test_open_no_sync() -> Result<()> {
+    use nostr_db::kv::lmdb::ffi;
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-no-sync")
+        .tempdir()
+        .unwrap();
+    let event: Event = MyEvent {
+        id: [1; 32],
+        pubkey: [1; 32],
+        kind: 1,
+        ..Default::default()
+    }
+    .into();
+    {
+        let db = Db::open_with(
+            dir.path(),
+            DbOptions {
+                no_sync: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(db.env_flags()? & ffi::MDB_NOSYNC, ffi::MDB_NOSYNC);
+        db.batch_put(vec![event.clone()])?;
+        db.flush()?;
+    }
+    let db = Db::open(dir.path())?;
+    assert_eq!(db.env_flags()? & ffi::MDB_NOSYNC, 0);
+    let reader = db.reader()?;
+    let e1: Option<Event> = db.get(&reader, event.id())?;
+    assert_eq!(e1.unwrap().id(), event.id());
+    Ok(())
+}
+
+#[test]
+pub fn test_checkpoint_restore() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-checkpoint")
+        .tempdir()
+        .unwrap();
+    let path = dir.path().join("events");
+    let checkpoint = dir.path().join("checkpoint");
+    let event = |i: u8| -> Event {
+        MyEvent {
+            id: [i; 32],
+            pubkey: [1; 32],
+            kind: 1,
+            ..Default::default()
+        }
+        .into()
+    };
+    let ids = |db: &Db| -> Result<Vec<u8>> {
+        let events = db.batch_get::<Event, _, _>((1..4).map(|i| [i; 32]))?;
+        Ok(events.iter().map(|e| e.id()[0]).collect())
+    };
+
+    let db = Db::open(&path)?;
+    db.check_schema()?;
+    db.batch_put(vec![event(1), event(2)])?;
+    let info = db.checkpoint(&checkpoint)?;
+    assert_eq!(info.path, checkpoint);
+    assert_eq!(info.seq, 1);
+    assert!(info.size > 0);
+    // the checkpoint directory must be empty
+    assert!(db.checkpoint(&checkpoint).is_err());
+
+    db.batch_del([[1u8; 32]])?;
+    db.batch_put(vec![event(3)])?;
+    assert_eq!(ids(&db)?, vec![2, 3]);
+    drop(db);
+
+    let db = Db::restore_from(&checkpoint, &path)?;
+    assert_eq!(ids(&db)?, vec![1, 2]);
+    db.check_schema()?;
+    // the seq continues from the checkpoint
+    db.batch_put(vec![event(3)])?;
+    assert_eq!(ids(&db)?, vec![1, 2, 3]);
+    drop(db);
+
+    // reject a checkpoint with other schema version
+    let other = dir.path().join("other");
+    {
+        use nostr_db::kv::lmdb::{Db as Lmdb, Transaction};
+        let kv = Lmdb::open(&checkpoint)?;
+        let t_meta = kv.open_tree(Some("t_meta"), 0)?;
+        let mut writer = kv.writer()?;
+        writer.put(&t_meta, "version", "0")?;
+        writer.commit()?;
+        std::fs::create_dir(&other)?;
+        kv.copy_to(&other, false)?;
+    }
+    assert!(matches!(
+        Db::restore_from(&other, &path),
+        Err(Error::VersionMismatch)
+    ));
+    let db = Db::open(&path)?;
+    assert_eq!(ids(&db)?, vec![1, 2, 3]);
+    Ok(())
+}
+
+#[test]
+pub fn test_backup() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-backup")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path().join("events"))?;
+    let events = (0..50u8)
+        .map(|i| {
+            MyEvent {
+                id: id(1, i),
+                pubkey: author(i % 5),
+                kind: 1,
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+
+    for (name, compact) in [("backup", false), ("compact", true)] {
+        let dest = dir.path().join("backups").join(name);
+        db.backup(&dest, compact)?;
+        let copy = Db::open(&dest)?;
+        copy.check_schema()?;
+        let (found, _) = all(&copy, &Filter::default())?;
+        assert_eq!(found.len(), events.len());
+        let reader = copy.reader()?;
+        for event in &events {
+            assert!(copy.exists(&reader, event.id())?);
+        }
+        // the backup directory must not contain a database
+        assert!(db.backup(&dest, compact).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_verify_on_read() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-verify-on-read")
+        .tempdir()
+        .unwrap();
+    let key_pair = Keypair::new_global(&mut thread_rng());
+    let valid = Event::create(&key_pair, 1, 1, vec![], "hello".to_owned())?;
+    // the id and content don't match the signature
+    let tampered = Event::new(
+        [2; 32],
+        *valid.pubkey(),
+        2,
+        1,
+        vec![],
+        "hi".to_owned(),
+        *valid.sig(),
+    )?;
+    {
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![valid.clone(), tampered.clone()])?;
+        {
+            let reader = db.reader()?;
+            assert!(db.get::<Event, _, _>(&reader, tampered.id())?.is_some());
+        }
+        assert_eq!(all(&db, &Filter::default())?.0.len(), 2);
+    }
+
+    let db = Db::open_with(
+        dir.path(),
+        DbOptions {
+            verify_on_read: true,
+            ..Default::default()
+        },
+    )?;
+    let (events, _) = all(&db, &Filter::default())?;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].id(), valid.id());
+    let reader = db.reader()?;
+    assert!(db.get::<Event, _, _>(&reader, tampered.id())?.is_none());
+    assert!(db.get::<Event, _, _>(&reader, valid.id())?.is_some());
+    let seq = db
+        .iter_from_seq::<String, _>(&reader, 0)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(seq.len(), 1);
+    // the id only reads are served by the index
+    assert!(db.exists(&reader, tampered.id())?);
+    Ok(())
+}
+
+#[test]
+pub fn test_grow_map_size() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-grow-map-size")
+        .tempdir()
+        .unwrap();
+    let map_size = 1024 * 1024;
+    let db = Db::open_with(
+        dir.path(),
+        DbOptions {
+            map_size: Some(map_size),
+            max_map_size: Some(64 * 1024 * 1024),
+            ..Default::default()
+        },
+    )?;
+    let events = (0..256u32)
+        .map(|i| {
+            let mut id = [0; 32];
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            MyEvent {
+                id,
+                pubkey: [1; 32],
+                kind: 1000,
+                // random content keeps the size with zstd
+                content: thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(16 * 1024)
+                    .map(char::from)
+                    .collect(),
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    // 4MB past the 1MB map
+    assert_eq!(db.batch_put(&events)?, 256);
+    assert!(db.env_info()?.map_size > map_size as u64);
+    assert_eq!(all(&db, &Filter::default())?.0.len(), 256);
+
+    // no growth without the cap
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-grow-map-size-none")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(
+        dir.path(),
+        DbOptions {
+            map_size: Some(map_size),
+            ..Default::default()
+        },
+    )?;
+    let err = db.batch_put(&events).unwrap_err();
+    assert!(
+        matches!(err, Error::Kv(nostr_db::kv::Error::MapFull)),
+        "{}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_open_read_only() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-read-only")
+        .tempdir()
+        .unwrap();
+    let event: Event = MyEvent {
+        id: [1; 32],
+        pubkey: [1; 32],
+        kind: 1,
+        ..Default::default()
+    }
+    .into();
+    {
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![event.clone()])?;
+    }
+    let db = Db::open_read_only(dir.path())?;
+    assert!(db.is_read_only());
+    let reader = db.reader()?;
+    assert!(db.get::<Event, _, _>(&reader, event.id())?.is_some());
+    drop(reader);
+    assert_eq!(all(&db, &Filter::default())?.0.len(), 1);
+    let err = db.writer().err().unwrap();
+    assert!(
+        matches!(err, Error::Kv(nostr_db::kv::Error::ReadOnly)),
+        "{}",
+        err
+    );
+    assert_eq!(err.to_string(), "error: the database is opened read-only");
+    Ok(())
+}
+
+#[test]
+pub fn test_raw_json() -> Result<()> {
+    let db = create_db("test_raw_json")?;
+    let raw = r#"{ "kind": 1, "tags": [["t", "nostr"]], "content": "raw",
+        "created_at": 10, "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+        "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef", "id": "0000000000000000000000000000000000000000000000000000000000000001" }"#;
+    let event = Event::from_raw(raw)?;
+    let other: Event = MyEvent {
+        id: [2; 32],
+        pubkey: [1; 32],
+        kind: 1,
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![event.clone(), other.clone()])?;
+
+    let reader = db.reader()?;
+    // byte for byte
+    let json: Option<String> = db.get(&reader, event.id())?;
+    assert_eq!(json.unwrap(), raw);
+    let json: Option<String> = db.get(&reader, other.id())?;
+    assert_eq!(json.unwrap(), other.to_json()?);
+    let e1: Option<Event> = db.get(&reader, event.id())?;
+    assert_eq!(e1.unwrap().content(), "raw");
+    Ok(())
+}
+
+#[test]
+pub fn test_single_writer() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-single-writer")
+        .tempdir()
+        .unwrap();
+
+    // error mode
+    let db = Db::open_with(
+        dir.path().join("busy"),
+        DbOptions {
+            writer_busy_error: true,
+            ..Default::default()
+        },
+    )?;
+    let writer = db.writer()?;
+    assert!(matches!(db.writer(), Err(Error::WriterBusy)));
+    // batch methods share the writer slot
+    assert!(matches!(
+        db.batch_del(vec![[1u8; 32]]),
+        Err(Error::WriterBusy)
+    ));
+    db.commit(writer)?;
+    let writer = db.writer()?;
+    drop(writer);
+    assert!(db.writer().is_ok());
+
+    // block mode
+    let db = Db::open(dir.path().join("block"))?;
+    let writer = db.writer()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let handle = {
+        let db = db.clone();
+        std::thread::spawn(move || {
+            let writer = db.writer().unwrap();
+            tx.send(()).unwrap();
+            db.commit(writer).unwrap();
+        })
+    };
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    db.commit(writer)?;
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    handle.join().unwrap();
+    Ok(())
+}
+
+#[test]
+pub fn test_transaction() -> Result<()> {
+    let db = create_db("test_transaction")?;
+    let event = |i: u8| -> Event {
+        MyEvent {
+            id: id(1, i),
+            pubkey: author(1),
+            kind: 1,
+            created_at: i as u64,
+            ..Default::default()
+        }
+        .into()
+    };
+
+    // rollback on error
+    let res = db.transaction(|tx| {
+        tx.put(event(1))?;
+        assert!(tx.get::<Event, _>(id(1, 1))?.is_some());
+        tx.put(event(2))?;
+        Err::<(), _>(Error::Message("abort".to_owned()))
+    });
+    assert!(matches!(res, Err(Error::Message(_))));
+    assert!(db
+        .batch_get::<Event, _, _>([id(1, 1), id(1, 2)])?
+        .is_empty());
+    // the writer is released
+    assert!(db.writer().is_ok());
+
+    let deleted = db.transaction(|tx| {
+        tx.put(event(1))?;
+        tx.put(event(2))?;
+        tx.put(event(3))?;
+        tx.del(id(1, 3))
+    })?;
+    assert!(deleted);
+    assert_eq!(
+        db.batch_get::<Event, _, _>([id(1, 1), id(1, 2), id(1, 3)])?
+            .len(),
+        2
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_large_kind() -> Result<()> {
+    let db = create_db("test_large_kind")?;
+    let event = Event::from_str(&format!(
+        r#"{{"id":"{}","pubkey":"{}","created_at":1,"kind":70000,"tags":[["t","big"]],"content":"","sig":"{}"}}"#,
+        hex::encode(id(50, 1)),
+        hex::encode(author(1)),
+        hex::encode([0u8; 64])
+    ))?;
+    assert_eq!(event.kind(), 70000);
+    let small: Event = MyEvent {
+        id: id(50, 2),
+        pubkey: author(1),
+        // the low 16 bits of 70000
+        kind: 70000 % 65536,
+        tags: vec![vec!["t".to_owned(), "big".to_owned()]],
+        ..Default::default()
+    }
+    .into();
+    db.batch_put([&event, &small])?;
+
+    let reader = db.reader()?;
+    let stored: Event = db.get(&reader, event.id())?.unwrap();
+    assert_eq!(stored.kind(), 70000);
+    drop(reader);
+    for filter in [
+        r#"{"kinds":[70000]}"#,
+        r##"{"kinds":[70000],"#t":["big"]}"##,
+        &format!(
+            r#"{{"kinds":[70000],"authors":["{}"]}}"#,
+            hex::encode(author(1))
+        ),
+    ] {
+        let (events, _) = all(&db, &Filter::from_str(filter)?)?;
+        assert_eq!(events.len(), 1, "{}", filter);
+        assert_eq!(events[0].id(), event.id());
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_schema_version_mismatch() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-schema-version")
+        .tempdir()
+        .unwrap();
+    {
+        use nostr_db::kv::lmdb::{Db as Lmdb, Transaction};
+        // the u16 kind schema
+        let kv = Lmdb::open(dir.path())?;
+        let t_meta = kv.open_tree(Some("t_meta"), 0)?;
+        let mut writer = kv.writer()?;
+        writer.put(&t_meta, "version", "3")?;
+        writer.commit()?;
+    }
+    let db = Db::open(dir.path())?;
+    assert!(matches!(db.check_schema(), Err(Error::VersionMismatch)));
+    Ok(())
+}
+
+#[test]
+pub fn test_probe() -> Result<()> {
+    let db = create_db("test_probe")?;
+    // the schema is not checked
+    assert!(matches!(db.probe(), Err(Error::VersionMismatch)));
+    db.check_schema()?;
+    db.probe()?;
+    // one read transaction at a time in a thread
+    let reader = db.reader()?;
+    assert!(db.probe().is_err());
+    drop(reader);
+    db.probe()?;
+    Ok(())
+}
+
+#[test]
+pub fn test_prewarm() -> Result<()> {
+    let db = create_db("test_prewarm")?;
+    let info = db.prewarm(None, 0)?;
+    assert!(info.completed);
+    assert_eq!(info.entries, 0);
+
+    let events = (0..100u8)
+        .map(|i| {
+            MyEvent {
+                id: id(1, i),
+                pubkey: author(1),
+                kind: 1,
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    // t_created_at and t_kind
+    let info = db.prewarm(Some(Duration::from_secs(10)), 0)?;
+    assert!(info.completed);
+    assert_eq!(info.entries, 200);
+    assert!(info.bytes > 0);
+
+    // bounded by the bytes budget
+    let info = db.prewarm(None, 1)?;
+    assert!(!info.completed);
+    assert_eq!(info.entries, 1);
+
+    let filter = Filter {
+        kinds: vec![1].into(),
+        ..Default::default()
+    };
+    assert_eq!(all(&db, &filter)?.0.len(), 100);
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "index_stats")]
+pub fn test_index_usage() -> Result<()> {
+    let db = create_db("test_index_usage")?;
+    let events = (0..10u8)
+        .map(|i| {
+            MyEvent {
+                id: id(1, i),
+                pubkey: author(1),
+                kind: 1,
+                created_at: i as u64,
+                content: "index usage".to_owned(),
+                tags: vec![vec!["t".to_owned(), "nostr".to_owned()]],
+                ..Default::default()
+            }
+            .into_and_build_words()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+    assert_eq!(db.index_usage(), Default::default());
+
+    let author = hex::encode(author(1));
+    for (json, path) in [
+        (format!(r#"{{"ids": ["{}"]}}"#, hex::encode(id(1, 1))), "id"),
+        (format!(r#"{{"authors": ["{}"]}}"#, author), "pubkey"),
+        (
+            format!(r#"{{"authors": ["{}"], "kinds": [1]}}"#, author),
+            "pubkey_kind",
+        ),
+        (r#"{"kinds": [1]}"#.to_owned(), "kind"),
+        (r##"{"#t": ["nostr"]}"##.to_owned(), "tag"),
+        (r#"{"search": "usage"}"#.to_owned(), "word"),
+        (r#"{"since": 1}"#.to_owned(), "time"),
+    ] {
+        let before = db.index_usage().paths();
+        let mut filter = Filter::from_str(&json)?;
+        filter.build_words();
+        assert!(!all(&db, &filter)?.0.is_empty());
+        // only the counter of the path increments
+        for ((name, count), (_, old)) in db.index_usage().paths().into_iter().zip(before) {
+            assert_eq!(count, old + (name == path) as u64, "{} {}", json, name);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_env_info() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-env-info")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(
+        dir.path(),
+        DbOptions {
+            map_size: Some(10 * 1024 * 1024),
+            ..Default::default()
+        },
+    )?;
+    let ratio = db.env_info()?.used_ratio();
+    let events = (0..100u8)
+        .map(|i| {
+            MyEvent {
+                id: id(1, i),
+                pubkey: author(1),
+                created_at: i as u64,
+                content: "a".repeat(1000),
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+    let info = db.env_info()?;
+    assert_eq!(info.map_size, 10 * 1024 * 1024);
+    assert!(info.used_ratio() > ratio);
+    assert!(info.used_ratio() > 0.0 && info.used_ratio() < 1.0);
+    Ok(())
+}
+
+#[test]
+pub fn test_shadowban() -> Result<()> {
+    let db = create_db("test_shadowban")?;
+    let events = (1..4u8)
+        .map(|a| {
+            MyEvent {
+                id: id(a, 1),
+                pubkey: author(a),
+                kind: 1,
+                created_at: a as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+    db.shadowban(&author(2))?;
+
+    let query = |filter: &Filter, viewer: Option<[u8; 32]>| -> Result<Vec<[u8; 32]>> {
+        let reader = db.reader()?;
+        let mut iter = db.iter::<Event, _>(&reader, filter)?;
+        iter.viewer(viewer.as_ref());
+        iter.map(|e| e.map(|e| *e.pubkey())).collect()
+    };
+    let reader = db.reader()?;
+    assert!(db.is_shadowbanned(&reader, &author(2))?);
+    assert!(!db.is_shadowbanned(&reader, &author(1))?);
+    drop(reader);
+    assert!(db.is_shadowbanned_cached(&author(2)));
+    assert!(!db.is_shadowbanned_cached(&author(1)));
+
+    for filter in [
+        Filter::default(),
+        Filter {
+            kinds: vec![1].into(),
+            ..Default::default()
+        },
+        Filter {
+            authors: vec![author(1), author(2)].into(),
+            ..Default::default()
+        },
+    ] {
+        // invisible to others
+        let pubkeys = query(&filter, None)?;
+        assert!(!pubkeys.contains(&author(2)), "{:?}", filter);
+        assert!(pubkeys.contains(&author(1)));
+        let pubkeys = query(&filter, Some(author(1)))?;
+        assert!(!pubkeys.contains(&author(2)), "{:?}", filter);
+        // visible to the author
+        assert!(query(&filter, Some(author(2)))?.contains(&author(2)));
+    }
+    // stored and iterated without a viewer
+    let reader = db.reader()?;
+    let iter = db.iter::<Event, _>(&reader, &Filter::default())?;
+    assert_eq!(iter.count(), 3);
+    drop(reader);
+
+    db.unshadowban(&author(2))?;
+    assert!(!db.is_shadowbanned_cached(&author(2)));
+    assert_eq!(query(&Filter::default(), None)?.len(), 3);
+    Ok(())
+}
+
+#[test]
+pub fn test_iter_opts() -> Result<()> {
+    let db = create_db("test_iter_opts")?;
+    let events = (0..20u8)
+        .map(|i| {
+            MyEvent {
+                id: id(1, i),
+                pubkey: author(1),
+                created_at: i as u64,
+                kind: 1,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+    let reader = db.reader()?;
+    let count = |filter: &Filter, opts: &QueryOpts| -> Result<usize> {
+        Ok(db.iter_opts::<Event, _>(&reader, filter, opts)?.count())
+    };
+    let opts = QueryOpts {
+        default_limit: Some(5),
+        max_limit: Some(10),
+        ..Default::default()
+    };
+    let unbounded = Filter::default();
+    assert!(unbounded.is_unbounded());
+    // clamped to the default
+    assert_eq!(count(&unbounded, &opts)?, 5);
+    // without opts
+    assert_eq!(count(&unbounded, &QueryOpts::default())?, 20);
+    let filter = Filter {
+        limit: Some(8),
+        ..Default::default()
+    };
+    assert_eq!(count(&filter, &opts)?, 8);
+    let filter = Filter {
+        limit: Some(100),
+        ..Default::default()
+    };
+    assert_eq!(count(&filter, &opts)?, 10);
+    // escape hatch
+    let no_limit = QueryOpts {
+        no_limit: true,
+        ..opts
+    };
+    assert_eq!(count(&filter, &no_limit)?, 20);
+    Ok(())
+}
+
 const PER_NUM: u8 = 30;
 
-fn author(index: u8) -> [u8; 32] {
-    let mut pubkey = [0; 32];
-    pubkey[29] = 1;
-    pubkey[30] = 0;
-    pubkey[31] = index;
-    pubkey
+fn author(index: u8) -> [u8; 32] {
+    let mut pubkey = [0; 32];
+    pubkey[29] = 1;
+    pubkey[30] = 0;
+    pubkey[31] = index;
+    pubkey
+}
+
+fn id(p: u8, index: u8) -> [u8; 32] {
+    let mut id = [0; 32];
+    id[29] = 1;
+    id[30] = p;
+    id[31] = index;
+    id
+}
+
+#[test]
+pub fn test_events() -> Result<()> {
+    let db = create_db("test_events")?;
+    let json = r#"
+    [{
+        "content": "Good morning everyone 😃",
+        "created_at": 1680690006,
+        "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+        "kind": 1,
+        "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+        "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+        "tags": [["t", "nostr"]],
+        "ip": "127.0.0.1"
+      }]
+    "#;
+    let events: Vec<Event> = serde_json::from_str(json).unwrap();
+    db.batch_put(&events)?;
+    let event = events.get(0).unwrap();
+    {
+        let reader = db.reader()?;
+        let e1: Option<Event> = db.get(&reader, event.id())?;
+        assert!(e1.is_some());
+        let e1 = e1.unwrap();
+        assert_eq!(e1.id(), event.id());
+        assert_eq!(&e1.tags(), &event.tags());
+    }
+
+    let li = db.batch_get::<Event, _, _>(vec![event.id()])?;
+    assert_eq!(li.len(), 1);
+
+    let li = db.batch_get::<String, _, _>(vec![event.id()])?;
+    assert_eq!(li.len(), 1);
+    assert!(li[0].contains("nostr"));
+
+    // event id
+    let li = db.batch_get::<Vec<u8>, _, _>(vec![event.id()])?;
+    assert_eq!(li.len(), 1);
+    assert_eq!(&li[0], event.id());
+    Ok(())
+}
+
+#[test]
+pub fn test_exists() -> Result<()> {
+    let db = create_db("test_exists")?;
+    let event: Event = MyEvent {
+        id: id(1, 1),
+        pubkey: author(1),
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![event.clone()])?;
+    {
+        let reader = db.reader()?;
+        assert!(db.exists(&reader, event.id())?);
+        assert!(!db.exists(&reader, id(2, 2))?);
+        assert!(!db.exists(&reader, [0u8; 32])?);
+    }
+
+    // the uncommitted writes are visible to the writer
+    let other: Event = MyEvent {
+        id: id(3, 3),
+        pubkey: author(1),
+        kind: 1,
+        ..Default::default()
+    }
+    .into();
+    db.transaction(|tx| {
+        assert!(!tx.exists(other.id())?);
+        tx.put(&other)?;
+        assert!(tx.exists(other.id())?);
+        Ok(())
+    })?;
+
+    db.batch_del(vec![event.id()])?;
+    let reader = db.reader()?;
+    assert!(!db.exists(&reader, event.id())?);
+    assert!(db.exists(&reader, other.id())?);
+    Ok(())
+}
+
+#[test]
+pub fn test_export_all() -> Result<()> {
+    let db = create_db("test_export_all")?;
+    let events = (0..10u8)
+        .map(|i| {
+            MyEvent {
+                id: id(1, i),
+                pubkey: author(1),
+                created_at: 100 - i as u64,
+                kind: 1,
+                tags: vec![vec!["t".to_owned(), i.to_string()]],
+                content: format!("content {}", i),
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    for event in &events {
+        db.batch_put(vec![event])?;
+    }
+    let reader = db.reader()?;
+    let mut dumped = vec![];
+    let count = db.export_all(&reader, |json| {
+        dumped.push(Event::from_str(std::str::from_utf8(json).unwrap())?);
+        Ok(())
+    })?;
+    assert_eq!(count, 10);
+    // the insertion order
+    assert_eq!(
+        dumped.iter().map(|e| *e.id()).collect::<Vec<_>>(),
+        events.iter().map(|e| *e.id()).collect::<Vec<_>>()
+    );
+    assert_eq!(dumped[3].content(), "content 3");
+    assert_eq!(dumped[3].tags(), events[3].tags());
+
+    // stop on the sink error
+    let mut seen = 0;
+    let res = db.export_all(&reader, |_| {
+        seen += 1;
+        Err(Error::Message("full".to_owned()))
+    });
+    assert!(res.is_err());
+    assert_eq!(seen, 1);
+    Ok(())
 }
 
-fn id(p: u8, index: u8) -> [u8; 32] {
-    let mut id = [0; 32];
-    id[29] = 1;
-    id[30] = p;
-    id[31] = index;
-    id
+#[test]
+pub fn test_iter_from_seq() -> Result<()> {
+    let db = create_db("test_iter_from_seq")?;
+    // inserted in the reverse time order
+    for i in 0..10u8 {
+        let event: Event = MyEvent {
+            id: id(1, i),
+            pubkey: author(1),
+            created_at: 100 - i as u64,
+            kind: 1,
+            ..Default::default()
+        }
+        .into();
+        db.batch_put(vec![event])?;
+    }
+    let reader = db.reader()?;
+    let all = db
+        .iter_from_seq::<Event, _>(&reader, 0)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 10);
+    assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(
+        all.iter().map(|(_, e)| *e.id()).collect::<Vec<_>>(),
+        (0..10u8).map(|i| id(1, i)).collect::<Vec<_>>()
+    );
+
+    // resume after the last seen seq
+    let last = all[4].0;
+    let rest = db
+        .iter_from_seq::<Vec<u8>, _>(&reader, last + 1)?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        rest,
+        all[5..]
+            .iter()
+            .map(|(seq, e)| (*seq, e.id().to_vec()))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        db.iter_from_seq::<Event, _>(&reader, all[9].0 + 1)?.count(),
+        0
+    );
+    drop(reader);
+
+    // the deleted events are skipped
+    db.batch_del(vec![id(1, 5)])?;
+    let reader = db.reader()?;
+    let seqs = db
+        .iter_from_seq::<Event, _>(&reader, last + 1)?
+        .map(|item| item.map(|(seq, _)| seq))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(seqs, vec![all[6].0, all[7].0, all[8].0, all[9].0]);
+    Ok(())
 }
 
 #[test]
-pub fn test_events() -> Result<()> {
-    let db = create_db("test_events")?;
+pub fn test_event_meta() -> Result<()> {
+    let db = create_db("test_event_meta")?;
     let json = r#"
     [{
         "content": "Good morning everyone 😃",
@@ -95,29 +1017,129 @@ pub fn test_events() -> Result<()> {
         "ip": "127.0.0.1"
       }]
     "#;
-    let events: Vec<Event> = serde_json::from_str(json).unwrap();
+    let mut events: Vec<Event> = serde_json::from_str(json).unwrap();
+    assert_eq!(events[0].meta().ip.as_deref(), Some("127.0.0.1"));
+    let other: Event = MyEvent {
+        id: id(1, 1),
+        pubkey: author(1),
+        ..Default::default()
+    }
+    .into();
+    events.push(other.clone());
     db.batch_put(&events)?;
-    let event = events.get(0).unwrap();
+    let event = &events[0];
     {
         let reader = db.reader()?;
-        let e1: Option<Event> = db.get(&reader, event.id())?;
-        assert!(e1.is_some());
-        let e1 = e1.unwrap();
-        assert_eq!(e1.id(), event.id());
-        assert_eq!(&e1.tags(), &event.tags());
+        let meta = db.event_meta(&reader, event.id())?;
+        assert_eq!(meta.unwrap().ip.as_deref(), Some("127.0.0.1"));
+        assert!(db.event_meta(&reader, other.id())?.is_none());
+        // not in the event data
+        let e: Option<String> = db.get(&reader, event.id())?;
+        assert!(!e.unwrap().contains("127.0.0.1"));
     }
+    db.batch_del(vec![event.id()])?;
+    let reader = db.reader()?;
+    assert!(db.event_meta(&reader, event.id())?.is_none());
+    Ok(())
+}
 
-    let li = db.batch_get::<Event, _, _>(vec![event.id()])?;
-    assert_eq!(li.len(), 1);
+#[test]
+pub fn test_batch_put_opts() -> Result<()> {
+    let db = create_db("test_batch_put_opts")?;
+    let key_pair = Keypair::new_global(&mut thread_rng());
+    let mut events = (0..5)
+        .map(|i| Event::create(&key_pair, i, 1, vec![], "batch".to_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    // invalid signature
+    events.push(
+        MyEvent {
+            id: id(0, 1),
+            pubkey: author(1),
+            kind: 1,
+            ..Default::default()
+        }
+        .into(),
+    );
+    let filter = Filter::default();
+
+    let res = db.batch_put_opts(
+        &events,
+        BatchOpts {
+            verify: true,
+            abort_on_error: true,
+            ..Default::default()
+        },
+    );
+    assert!(res.is_err());
+    assert_eq!(count(&db, &filter)?.0, 0);
+
+    let res = db.batch_put_opts(
+        &events,
+        BatchOpts {
+            verify: true,
+            abort_on_error: false,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(res, 5);
+    assert_eq!(count(&db, &filter)?.0, 5);
+    Ok(())
+}
 
-    let li = db.batch_get::<String, _, _>(vec![event.id()])?;
-    assert_eq!(li.len(), 1);
-    assert!(li[0].contains("nostr"));
+#[test]
+pub fn test_batch_put_append() -> Result<()> {
+    let db = create_db("test_batch_put_append")?;
+    let events = (1..=10)
+        .map(|i| {
+            MyEvent {
+                id: id(0, i),
+                pubkey: author(1),
+                kind: 1,
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    let opts = BatchOpts {
+        append: true,
+        ..Default::default()
+    };
+    assert_eq!(db.batch_put_opts(&events[0..5], opts.clone())?, 5);
+    // continue after the existing events
+    assert_eq!(db.batch_put_opts(&events[5..], opts.clone())?, 5);
+    // replaced and the duplicate events
+    let mut writer = db.writer()?;
+    db.put_append(&mut writer, &events[0])?;
+    db.put_append(
+        &mut writer,
+        Event::from(MyEvent {
+            id: id(0, 11),
+            pubkey: author(1),
+            kind: 0,
+            ..Default::default()
+        }),
+    )?;
+    db.commit(writer)?;
 
-    // event id
-    let li = db.batch_get::<Vec<u8>, _, _>(vec![event.id()])?;
-    assert_eq!(li.len(), 1);
-    assert_eq!(&li[0], event.id());
+    let filter = Filter::default();
+    assert_eq!(count(&db, &filter)?.0, 11);
+    let reader = db.reader()?;
+    let ids = db
+        .iter::<Event, _>(&reader, &filter)?
+        .map(|e| e.map(|e| e.id().to_vec()))
+        .collect::<Result<Vec<_>>>()?;
+    // ascending by time, the kind 0 event appended last has the oldest time
+    assert_eq!(
+        ids,
+        [11, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]
+            .into_iter()
+            .map(|i| id(0, i).to_vec())
+            .collect::<Vec<_>>()
+    );
+    for i in 1..=11 {
+        assert!(db.get::<Event, _, _>(&reader, id(0, i))?.is_some());
+    }
     Ok(())
 }
 
@@ -324,6 +1346,132 @@ pub fn test_events_replace() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_events_replace_tie() -> Result<()> {
+    let db = create_db("test_events_replace_tie")?;
+    let event = |id: [u8; 32], kind: u32| -> Event {
+        MyEvent {
+            id,
+            pubkey: author(1),
+            kind,
+            tags: vec![vec!["d".to_owned(), "m".to_owned()]],
+            created_at: 10,
+            ..Default::default()
+        }
+        .into()
+    };
+    for (i, kind) in [10001, 30001].into_iter().enumerate() {
+        // differ in the last byte, and across the sign bit of the first byte
+        let mut low = [0x7f; 32];
+        low[1] = i as u8;
+        low[31] = 1;
+        let mut high = low;
+        high[31] = 2;
+        let mut higher = high;
+        higher[0] = 0x80;
+
+        let mut writer = db.writer()?;
+        assert!(matches!(
+            db.put(&mut writer, event(high, kind))?,
+            nostr_db::CheckEventResult::Ok(1)
+        ));
+        // the higher id is ignored
+        assert!(matches!(
+            db.put(&mut writer, event(higher, kind))?,
+            nostr_db::CheckEventResult::ReplaceIgnored
+        ));
+        // the lower id replaces
+        assert!(matches!(
+            db.put(&mut writer, event(low, kind))?,
+            nostr_db::CheckEventResult::Ok(2)
+        ));
+        assert!(matches!(
+            db.put(&mut writer, event(high, kind))?,
+            nostr_db::CheckEventResult::ReplaceIgnored
+        ));
+        db.commit(writer)?;
+
+        let reader = db.reader()?;
+        let filter = Filter::from_str(&format!(r#"{{"kinds":[{}]}}"#, kind))?;
+        let ids = db
+            .iter::<Event, _>(&reader, &filter)?
+            .map(|e| e.map(|e| *e.id()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids, vec![low]);
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_custom_index() -> Result<()> {
+    let db = create_db("test_custom_index")?;
+    db.register_index("price", |event| {
+        event
+            .tags()
+            .iter()
+            .filter(|t| t.len() > 1 && t[0] == "price")
+            .filter_map(|t| u64::from_str(&t[1]).ok())
+            .map(|price| price.to_be_bytes().to_vec())
+            .collect()
+    })?;
+    let prices = [150u64, 50, 300, 120, 250];
+    let events = prices
+        .iter()
+        .enumerate()
+        .map(|(i, price)| {
+            MyEvent {
+                id: id(0, i as u8),
+                pubkey: author(1),
+                kind: 1000,
+                tags: vec![vec!["price".to_owned(), price.to_string()]],
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+
+    let query = |start: u64, end: u64| -> Result<Vec<u64>> {
+        let reader = db.reader()?;
+        let (start, end) = (start.to_be_bytes(), end.to_be_bytes());
+        let prices = db
+            .iter_custom_range::<Event, _>(
+                &reader,
+                "price",
+                Bound::Included(&start[..]),
+                Bound::Excluded(&end[..]),
+            )?
+            .map(|item| {
+                let (key, event) = item?;
+                let price = u64::from_be_bytes(key[..].try_into().unwrap());
+                assert_eq!(event.tags()[0][1], price.to_string());
+                Ok(price)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(prices)
+    };
+    assert_eq!(query(100, 200)?, vec![120, 150]);
+    assert_eq!(query(0, u64::MAX)?, vec![50, 120, 150, 250, 300]);
+    {
+        // less than 256
+        let reader = db.reader()?;
+        let ids = db
+            .iter_custom::<Vec<u8>, _>(&reader, "price", &[0; 7])?
+            .map(|item| item.map(|(_, id)| id))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids, vec![id(0, 1), id(0, 3), id(0, 0), id(0, 4)]);
+        assert!(db
+            .iter_custom::<Vec<u8>, _>(&reader, "unknown", &[])
+            .is_err());
+    }
+
+    // removed with the event
+    db.batch_del(vec![id(0, 3)])?;
+    assert_eq!(query(100, 200)?, vec![150]);
+    Ok(())
+}
+
 #[test]
 pub fn test_events_del() -> Result<()> {
     let db = create_db("test_events_del")?;
@@ -409,6 +1557,138 @@ pub fn test_events_del() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_events_del_coordinate() -> Result<()> {
+    let db = create_db("test_events_del_coordinate")?;
+    let prefix = 70;
+    let d = |v: &str| vec![vec!["d".to_owned(), v.to_owned()]];
+    let events: Vec<Event> = vec![
+        MyEvent {
+            id: id(prefix, 1),
+            pubkey: author(1),
+            kind: 30001,
+            created_at: 10,
+            tags: d("m"),
+            ..Default::default()
+        }
+        .into(),
+        MyEvent {
+            id: id(prefix, 2),
+            pubkey: author(1),
+            kind: 30001,
+            created_at: 10,
+            tags: d("n"),
+            ..Default::default()
+        }
+        .into(),
+        MyEvent {
+            id: id(prefix, 3),
+            pubkey: author(1),
+            kind: 10001,
+            created_at: 10,
+            ..Default::default()
+        }
+        .into(),
+        MyEvent {
+            id: id(prefix, 4),
+            pubkey: author(1),
+            kind: 30001,
+            created_at: 30,
+            tags: d("o"),
+            ..Default::default()
+        }
+        .into(),
+    ];
+    db.batch_put(&events)?;
+
+    let coordinate = |kind: u32, pubkey: [u8; 32], d: &str| {
+        vec![
+            "a".to_owned(),
+            format!("{}:{}:{}", kind, hex::encode(pubkey), d),
+        ]
+    };
+    let deletion: Event = MyEvent {
+        id: id(prefix, 5),
+        pubkey: author(1),
+        kind: 5,
+        created_at: 20,
+        tags: vec![
+            coordinate(30001, author(1), "m"),
+            coordinate(10001, author(1), ""),
+            // newer than the deletion
+            coordinate(30001, author(1), "o"),
+            // not exists
+            coordinate(30001, author(1), "x"),
+        ],
+        ..Default::default()
+    }
+    .into();
+    // invalid author
+    let other: Event = MyEvent {
+        id: id(prefix, 6),
+        pubkey: author(2),
+        kind: 5,
+        created_at: 20,
+        tags: vec![
+            coordinate(30001, author(1), "n"),
+            coordinate(30001, author(2), "n"),
+        ],
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![deletion, other])?;
+
+    {
+        let reader = db.reader()?;
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 1))?.is_none());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 2))?.is_some());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 3))?.is_none());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 4))?.is_some());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 5))?.is_some());
+    }
+    let filter = Filter::from_str(r#"{"kinds": [30001, 10001]}"#)?;
+    assert_eq!(all(&db, &filter)?.0.len(), 2);
+    Ok(())
+}
+
+#[test]
+pub fn test_events_del_long_form() -> Result<()> {
+    let db = create_db("test_events_del_long_form")?;
+    let prefix = 71;
+    let article: Event = MyEvent {
+        id: id(prefix, 1),
+        pubkey: author(1),
+        kind: 30023,
+        created_at: 10,
+        tags: vec![vec!["d".to_owned(), "my-article".to_owned()]],
+        content: "long-form content".to_owned(),
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![article])?;
+
+    let deletion: Event = MyEvent {
+        id: id(prefix, 2),
+        pubkey: author(1),
+        kind: 5,
+        created_at: 20,
+        tags: vec![vec![
+            "a".to_owned(),
+            format!("30023:{}:my-article", hex::encode(author(1))),
+        ]],
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![deletion])?;
+
+    let reader = db.reader()?;
+    assert!(db.get::<Event, _, _>(&reader, id(prefix, 1))?.is_none());
+    drop(reader);
+    let filter = Filter::from_str(r#"{"kinds": [30023]}"#)?;
+    assert!(all(&db, &filter)?.0.is_empty());
+    Ok(())
+}
+
 #[test]
 pub fn test_events_dup() -> Result<()> {
     let db = create_db("test_events_dup")?;
@@ -583,20 +1863,149 @@ pub fn test_query_count() -> Result<()> {
         limit: Some(10),
         ..Default::default()
     };
-    let e1 = count(&db, &filter)?;
-
-    assert!(e1.1.scan_index >= 10);
-    assert!(e1.1.get_data == 0);
-    assert!(e1.1.get_index == 0);
+    let e1 = count(&db, &filter)?;
+
+    assert!(e1.1.scan_index >= 10);
+    assert!(e1.1.get_data == 0);
+    assert!(e1.1.get_index == 0);
+
+    assert_eq!(e1.0, 10);
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 10);
+
+    assert!(e1.1.scan_index >= 10);
+    assert!(e1.1.get_data == 10);
+    assert!(e1.1.get_index == 0);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_query_count_only() -> Result<()> {
+    let db = create_db("test_query_count_only")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(30, i),
+                pubkey: author(250 + i % 2),
+                kind: 1,
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let filter = Filter {
+        authors: vec![author(250)].into(),
+        limit: Some(0),
+        ..Default::default()
+    };
+    let (num, stats) = count(&db, &filter)?;
+    assert_eq!(num, (PER_NUM as u64).div_ceil(2));
+    assert_eq!(stats.get_data, 0);
+
+    // iter nothing
+    let (events, stats) = all(&db, &filter)?;
+    assert!(events.is_empty());
+    assert_eq!(stats.get_data, 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_query_per_author_limit() -> Result<()> {
+    let db = create_db("test_query_per_author_limit")?;
+    // author 1 has 20 events, author 2 and 3 have 3 events
+    let events = (0..26u8)
+        .map(|i| {
+            let pubkey = if i < 20 { 1 } else { 2 + i % 2 };
+            MyEvent {
+                id: id(40, i),
+                pubkey: author(pubkey),
+                kind: 1,
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let mut filter = Filter::from_str(r#"{"kinds":[1],"per_author_limit":5}"#)?;
+    let (events, _) = all(&db, &filter)?;
+    assert_eq!(events.len(), 11);
+    let num = |a: u8| events.iter().filter(|e| e.pubkey() == &author(a)).count();
+    assert_eq!(num(1), 5);
+    assert_eq!(num(2), 3);
+    assert_eq!(num(3), 3);
+    assert_eq!(count(&db, &filter)?.0, 11);
+
+    // the newest events of author 1 in the desc order
+    filter.desc = true;
+    let (events, _) = all(&db, &filter)?;
+    let created_at = events
+        .iter()
+        .filter(|e| e.pubkey() == &author(1))
+        .map(|e| e.created_at())
+        .collect::<Vec<_>>();
+    assert_eq!(created_at, vec![19, 18, 17, 16, 15]);
+
+    filter.per_author_limit = None;
+    assert_eq!(all(&db, &filter)?.0.len(), 26);
+    Ok(())
+}
+
+#[test]
+pub fn test_query_k_tag() -> Result<()> {
+    let db = create_db("test_query_k_tag")?;
+    let event = |i: u8, kind: u32, k: &str| -> Event {
+        MyEvent {
+            id: id(41, i),
+            pubkey: author(1),
+            kind,
+            created_at: i as u64,
+            tags: vec![vec!["k".to_owned(), k.to_owned()]],
+            ..Default::default()
+        }
+        .into()
+    };
+    let mut events = vec![
+        event(0, 16, "1"),
+        event(1, 6, "1"),
+        // not a repost
+        event(2, 1, "1"),
+    ];
+    // the numeric values sharing the prefix "1" and the other kinds
+    for (i, k) in ["10", "11", "16", "100", "0", "30023"].iter().enumerate() {
+        events.push(event(10 + i as u8 * 2, 16, k));
+        events.push(event(11 + i as u8 * 2, 16, k));
+    }
+    db.batch_put(&events)?;
+    assert_eq!(events[0].k_tag_kind(), Some(1));
+    assert_eq!(events[events.len() - 1].k_tag_kind(), Some(30023));
 
-    assert_eq!(e1.0, 10);
-    let e1 = all(&db, &filter)?;
-    assert_eq!(e1.0.len(), 10);
+    let filter = Filter::from_str(r##"{"#k":["1"]}"##)?;
+    let (found, stats) = all(&db, &filter)?;
+    let mut ids = found.iter().map(|e| *e.id()).collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, vec![id(41, 0), id(41, 1), id(41, 2)]);
+    // only the index entries of the value are scanned
+    assert!(stats.scan_index < 10);
 
-    assert!(e1.1.scan_index >= 10);
-    assert!(e1.1.get_data == 10);
-    assert!(e1.1.get_index == 0);
+    let filter = Filter::from_str(r##"{"#k":["10","30023"]}"##)?;
+    assert_eq!(all(&db, &filter)?.0.len(), 4);
 
+    let reader = db.reader()?;
+    let reposts = db
+        .iter_reposts::<Event, _>(&reader, 1)?
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        reposts.iter().map(|e| *e.id()).collect::<Vec<_>>(),
+        vec![id(41, 1), id(41, 0)]
+    );
+    assert_eq!(db.iter_reposts::<Event, _>(&reader, 16)?.count(), 2);
+    assert_eq!(db.iter_reposts::<Event, _>(&reader, 2)?.count(), 0);
     Ok(())
 }
 
@@ -646,7 +2055,7 @@ pub fn test_query_author_kinds() -> Result<()> {
             MyEvent {
                 id: id(15, i),
                 pubkey: author(20),
-                kind: 1000 + (i / 2) as u16,
+                kind: 1000 + (i / 2) as u32,
                 content: "author 2 kind".to_owned(),
                 created_at: i as u64 * 1000,
                 ..Default::default()
@@ -661,7 +2070,7 @@ pub fn test_query_author_kinds() -> Result<()> {
             MyEvent {
                 id: id(16, i),
                 pubkey: author(20),
-                kind: 1000 + (i / 2) as u16,
+                kind: 1000 + (i / 2) as u32,
                 content: "author 2 kind".to_owned(),
                 created_at: 100_000 + i as u64 * 1000,
                 ..Default::default()
@@ -727,7 +2136,7 @@ pub fn test_query_authors() -> Result<()> {
             MyEvent {
                 id: id(25, i),
                 pubkey: author(20),
-                kind: 1 + i as u16,
+                kind: 1 + i as u32,
                 content: "author 2 kind".to_owned(),
                 created_at: i as u64 * 1000,
                 tags: vec![vec!["t".to_owned(), "query tag1".to_owned()]],
@@ -869,7 +2278,7 @@ pub fn test_query_tag() -> Result<()> {
             MyEvent {
                 id: id(10, i),
                 pubkey: author(30),
-                kind: i as u16,
+                kind: i as u32,
                 content: "author 3 tag".to_owned(),
                 created_at: i as u64 * 1000,
                 tags: vec![
@@ -890,7 +2299,7 @@ pub fn test_query_tag() -> Result<()> {
             MyEvent {
                 id: id(25, i),
                 pubkey: author(20),
-                kind: i as u16,
+                kind: i as u32,
                 content: "author 2 kind".to_owned(),
                 created_at: i as u64 * 1000,
                 tags: vec![
@@ -991,6 +2400,205 @@ pub fn test_query_tag() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_tags_intersection() -> Result<()> {
+    let db = create_db("test_query_tags_intersection")?;
+    let pk = hex::encode(author(9));
+    let t = vec!["t".to_owned(), "x".to_owned()];
+    let p = vec!["p".to_owned(), pk.clone()];
+    let mut events = vec![];
+    for i in 0..20u8 {
+        // t only, p only and both
+        let tags = match i % 4 {
+            0 => vec![t.clone(), p.clone()],
+            1 | 2 => vec![t.clone()],
+            _ => vec![p.clone()],
+        };
+        events.push(
+            MyEvent {
+                id: id(60, i),
+                pubkey: author(1),
+                created_at: i as u64,
+                kind: 1,
+                tags,
+                ..Default::default()
+            }
+            .into(),
+        );
+    }
+    db.batch_put::<_, Event>(events)?;
+
+    let filter = Filter::from_str(&format!(r##"{{"#t": ["x"], "#p": ["{}"]}}"##, pk))?;
+    let e1 = all(&db, &filter)?;
+    assert_eq!(
+        e1.0.iter().map(|e| e.id()[31]).collect::<Vec<_>>(),
+        vec![0, 4, 8, 12, 16]
+    );
+    // both tag indexes are scanned and intersected, no event index is decoded for post-filter
+    assert!(e1.1.scan_index >= 10);
+    assert_eq!(e1.1.get_index, 0);
+    assert_eq!(e1.1.get_data, 5);
+
+    let filter = Filter::from_str(&format!(
+        r##"{{"#t": ["x"], "#p": ["{}"], "limit": 2}}"##,
+        pk
+    ))?;
+    let e1 = all(&db, &filter)?;
+    assert_eq!(
+        e1.0.iter().map(|e| e.id()[31]).collect::<Vec<_>>(),
+        vec![16, 12]
+    );
+    assert_eq!(e1.1.get_index, 0);
+    assert_eq!(e1.1.get_data, 2);
+    Ok(())
+}
+
+#[test]
+pub fn test_query_hex_prefix() -> Result<()> {
+    let db = create_db("test_query_hex_prefix")?;
+    let mut b = [0xcd; 32];
+    b[3] = 0xce;
+    let mut c = [0xcd; 32];
+    c[3] = 0xd0;
+    let authors = [[0xcd; 32], b, c];
+    let events = (0..9u8)
+        .map(|i| {
+            let mut id = [0xab; 32];
+            id[3] = 0xab + i;
+            MyEvent {
+                id,
+                pubkey: authors[i as usize % 3],
+                kind: 1 + i as u32 % 2,
+                created_at: i as u64 * 10,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let times = |json: &str| -> Result<Vec<u64>> {
+        let filter = Filter::from_str(json)?;
+        Ok(all(&db, &filter)?
+            .0
+            .iter()
+            .map(|e| e.created_at())
+            .collect())
+    };
+
+    // a 4-byte and a 32-byte prefix hit the same event
+    let full = hex::encode([0xab; 32]);
+    assert_eq!(times(r#"{"ids":["abababab"]}"#)?, vec![0]);
+    assert_eq!(times(&format!(r#"{{"ids":["{}"]}}"#, full))?, vec![0]);
+    assert_eq!(
+        times(r#"{"ids":["ababab"], "limit": 20}"#)?,
+        vec![80, 70, 60, 50, 40, 30, 20, 10, 0]
+    );
+
+    let full = hex::encode([0xcd; 32]);
+    assert_eq!(times(r#"{"authors":["cdcdcdcd"]}"#)?, vec![0, 30, 60]);
+    assert_eq!(
+        times(&format!(r#"{{"authors":["{}"]}}"#, full))?,
+        vec![0, 30, 60]
+    );
+    // the odd length prefix, the authors are merged in time order
+    assert_eq!(
+        times(r#"{"authors":["cdcdcdc"], "limit": 20}"#)?,
+        vec![70, 60, 40, 30, 10, 0]
+    );
+    assert_eq!(
+        times(r#"{"authors":["cdcdcdc"], "kinds": [1], "limit": 20}"#)?,
+        vec![60, 40, 0]
+    );
+    assert!(times(r#"{"authors":["cdcdcdcf"]}"#)?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn test_query_multi_letter_tag() -> Result<()> {
+    let db = create_db("test_query_multi_letter_tag")?;
+    let tags = [
+        vec!["alt".to_owned(), "x".to_owned()],
+        vec!["al".to_owned(), "tx".to_owned()],
+        vec!["a".to_owned(), "ltx".to_owned()],
+    ];
+    let events = tags
+        .iter()
+        .enumerate()
+        .map(|(i, tag)| {
+            MyEvent {
+                id: id(61, i as u8),
+                pubkey: author(1),
+                created_at: i as u64,
+                kind: 1,
+                tags: vec![tag.clone()],
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    for (i, json) in [
+        r##"{"#alt":["x"]}"##,
+        r##"{"#al":["tx"]}"##,
+        r##"{"#a":["ltx"]}"##,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let filter = Filter::from_str(json)?;
+        let e1 = all(&db, &filter)?;
+        assert_eq!(
+            e1.0.iter().map(|e| e.id()[31]).collect::<Vec<_>>(),
+            vec![i as u8],
+            "{}",
+            json
+        );
+    }
+
+    let filter = Filter::from_str(r##"{"#alt":["x"], "#a":["ltx"]}"##)?;
+    assert!(all(&db, &filter)?.0.is_empty());
+
+    // removed from the multi tag index
+    assert!(db.transaction(|tx| tx.del(id(61, 0)))?);
+    let filter = Filter::from_str(r##"{"#alt":["x"]}"##)?;
+    assert!(all(&db, &filter)?.0.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn test_query_tag_hex_case() -> Result<()> {
+    let db = create_db("test_query_tag_hex_case")?;
+    let target = hex::encode(id(1, 1));
+    let events = vec![
+        MyEvent {
+            id: id(50, 1),
+            pubkey: author(1),
+            kind: 1,
+            tags: vec![vec!["e".to_owned(), target.to_uppercase()]],
+            ..Default::default()
+        }
+        .into(),
+        MyEvent {
+            id: id(50, 2),
+            pubkey: author(1),
+            kind: 1,
+            tags: vec![vec!["e".to_owned(), target.clone()]],
+            ..Default::default()
+        }
+        .into(),
+    ];
+    db.batch_put::<_, Event>(events)?;
+
+    for value in [target.clone(), target.to_uppercase()] {
+        let filter = Filter::from_str(&format!(r##"{{"#e": ["{}"]}}"##, value))?;
+        let e1 = all(&db, &filter)?;
+        assert_eq!(e1.0.len(), 2);
+    }
+    Ok(())
+}
+
 #[test]
 pub fn test_query_kinds() -> Result<()> {
     let db = create_db("test_query_kinds")?;
@@ -1001,7 +2609,7 @@ pub fn test_query_kinds() -> Result<()> {
             MyEvent {
                 id: id(15, i),
                 pubkey: author(20),
-                kind: 1000 + (i / 2) as u16,
+                kind: 1000 + (i / 2) as u32,
                 content: "author 2 kind".to_owned(),
                 created_at: i as u64 * 1000,
                 ..Default::default()
@@ -1016,7 +2624,7 @@ pub fn test_query_kinds() -> Result<()> {
             MyEvent {
                 id: id(16, i),
                 pubkey: author(20),
-                kind: 1000 + (i / 2) as u16,
+                kind: 1000 + (i / 2) as u32,
                 content: "author 2 kind".to_owned(),
                 created_at: 100_000 + i as u64 * 1000,
                 ..Default::default()
@@ -1049,6 +2657,65 @@ pub fn test_query_kinds() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_not_kinds() -> Result<()> {
+    let db = create_db("test_query_not_kinds")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(17, i),
+                pubkey: author(21 + i % 2),
+                kind: 1000 + (i % 3) as u32,
+                created_at: i as u64 * 1000,
+                tags: vec![vec!["t".to_owned(), "not".to_owned()]],
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    // drive from the time index
+    let filter = Filter {
+        not_kinds: vec![1000].into(),
+        ..Default::default()
+    };
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 20);
+    assert!(e1.0.iter().all(|e| e.kind() != 1000));
+
+    let filter = Filter {
+        not_kinds: vec![1000, 1002].into(),
+        kinds: vec![1000, 1001].into(),
+        ..Default::default()
+    };
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 10);
+    assert!(e1.0.iter().all(|e| e.kind() == 1001));
+
+    let filter = Filter {
+        not_kinds: vec![1001].into(),
+        authors: vec![author(21)].into(),
+        ..Default::default()
+    };
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 10);
+    assert!(e1.0.iter().all(|e| e.kind() != 1001));
+
+    let filter = Filter {
+        not_kinds: vec![1001, 1002].into(),
+        tags: HashMap::from([(b"t".to_vec(), vec![b"not".to_vec()].into())]),
+        ..Default::default()
+    };
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 10);
+    assert!(e1.0.iter().all(|e| e.kind() == 1000));
+    let e1 = count(&db, &filter)?;
+    assert_eq!(e1.0, 10);
+
+    Ok(())
+}
+
 #[test]
 pub fn test_query_ids() -> Result<()> {
     let db = create_db("test_query_ids")?;
@@ -1075,7 +2742,7 @@ pub fn test_query_ids() -> Result<()> {
             MyEvent {
                 id: id(10, i),
                 pubkey: author(30),
-                kind: i as u16,
+                kind: i as u32,
                 content: "author 3 tag".to_owned(),
                 created_at: i as u64 * 1000,
                 tags: vec![vec!["t".to_owned(), "query tag".to_owned()]],
@@ -1272,6 +2939,209 @@ pub fn test_query_search() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_search_segment_options() -> Result<()> {
+    let opts = SegmentOptions {
+        min_len: 2,
+        stopwords: HashSet::from(["the".to_owned()]),
+    };
+    let words = segment_with("A note from The relay", &opts);
+    assert!(words.contains(&b"note".to_vec()));
+    assert!(words.contains(&b"relay".to_vec()));
+    // min length
+    assert!(!words.contains(&b"a".to_vec()));
+    // stopword
+    assert!(!words.contains(&b"the".to_vec()));
+    assert!(segment("A note from The relay").contains(&b"the".to_vec()));
+
+    let db = create_db("test_search_segment_options")?;
+    let mut event: Event = MyEvent {
+        id: id(10, 1),
+        pubkey: author(1),
+        kind: 1,
+        content: "A note from The relay".to_owned(),
+        ..Default::default()
+    }
+    .into();
+    event.build_note_words_with(&opts);
+    db.batch_put(vec![event])?;
+
+    let search = |search: &str, opts: &SegmentOptions| -> Result<usize> {
+        let mut filter = Filter {
+            search: Some(search.to_owned()),
+            ..Default::default()
+        };
+        filter.build_words_with(opts);
+        Ok(all(&db, &filter)?.0.len())
+    };
+    assert_eq!(search("note", &opts)?, 1);
+    // the stopword is dropped from the query
+    assert_eq!(search("the note", &opts)?, 1);
+    assert_eq!(search("the", &opts)?, 0);
+    // the stopword is not indexed
+    assert_eq!(search("the", &SegmentOptions::default())?, 0);
+    assert_eq!(search("the note", &SegmentOptions::default())?, 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_query_tag_word_desc() -> Result<()> {
+    let db = create_db("test_query_tag_word_desc")?;
+    // two events share each created_at, the neighbor tags and words sort around "x"
+    let mut events = vec![];
+    for (p, tag, content) in [
+        (70, "w", "w note"),
+        (71, "x", "x note"),
+        (72, "xy", "xy note"),
+    ] {
+        for i in 0..20u8 {
+            events.push(
+                MyEvent {
+                    id: id(p, i),
+                    pubkey: author(1),
+                    kind: 1,
+                    content: content.to_owned(),
+                    created_at: (i / 2) as u64 * 10,
+                    tags: vec![vec!["t".to_owned(), tag.to_owned()]],
+                    ..Default::default()
+                }
+                .into_and_build_words(),
+            );
+        }
+    }
+    db.batch_put(events)?;
+
+    let ids = |filter: &Filter| -> Result<Vec<[u8; 32]>> {
+        Ok(all(&db, filter)?.0.iter().map(|e| *e.id()).collect())
+    };
+    let check = |filter: Filter, len: usize| -> Result<()> {
+        let asc = ids(&Filter {
+            desc: false,
+            ..filter.clone()
+        })?;
+        let desc = ids(&Filter {
+            desc: true,
+            ..filter.clone()
+        })?;
+        assert_eq!(asc.len(), len);
+        assert!(asc.iter().all(|id| id[30] == 71));
+        assert_eq!(desc, asc.iter().rev().cloned().collect::<Vec<_>>());
+
+        let events = all(
+            &db,
+            &Filter {
+                desc: true,
+                limit: Some(3),
+                ..filter
+            },
+        )?
+        .0;
+        assert_eq!(
+            events.iter().map(|e| *e.id()).collect::<Vec<_>>(),
+            desc[..desc.len().min(3)].to_vec()
+        );
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].created_at() >= w[1].created_at()));
+        Ok(())
+    };
+
+    let tag = Filter {
+        tags: HashMap::from([(b"t".to_vec(), vec![b"x".to_vec()].into())]),
+        ..Default::default()
+    };
+    let mut word = Filter {
+        search: Some("x".to_owned()),
+        ..Default::default()
+    };
+    word.build_words();
+
+    for filter in [tag, word] {
+        check(filter.clone(), 20)?;
+        // seek by the until and since bounds in both directions
+        check(
+            Filter {
+                since: Some(30),
+                until: Some(70),
+                ..filter.clone()
+            },
+            10,
+        )?;
+        check(
+            Filter {
+                until: Some(25),
+                ..filter.clone()
+            },
+            6,
+        )?;
+        check(
+            Filter {
+                since: Some(85),
+                ..filter
+            },
+            2,
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_query_search_tag() -> Result<()> {
+    let db = create_db("test_query_search_tag")?;
+    let cases = [
+        (1, "foo bar", Some("nostr")),
+        (2, "foo", Some("other")),
+        (3, "baz", Some("nostr")),
+        (4, "foo", None),
+    ];
+    for (p, content, tag) in cases {
+        let events = (0..PER_NUM)
+            .map(|i| {
+                MyEvent {
+                    id: id(p, i),
+                    pubkey: author(p),
+                    kind: 1,
+                    content: content.to_owned(),
+                    created_at: i as u64,
+                    tags: tag
+                        .map(|t| vec![vec!["t".to_owned(), t.to_owned()]])
+                        .unwrap_or_default(),
+                    ..Default::default()
+                }
+                .into_and_build_words()
+            })
+            .collect::<Vec<Event>>();
+        db.batch_put(events)?;
+    }
+
+    let mut filter = Filter::from_str(r##"{"search": "foo", "#t": ["nostr"]}"##)?;
+    filter.build_words();
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), PER_NUM as usize);
+    assert!(e1.0.iter().all(|e| e.pubkey() == &author(1)));
+    // drive from the word index, check the tag from the event index
+    assert!(e1.1.scan_index >= PER_NUM as u64 * 3);
+    assert!(e1.1.get_index >= PER_NUM as u64 * 3);
+    assert_eq!(e1.1.get_data, PER_NUM as u64);
+
+    let mut filter = Filter::from_str(r##"{"search": "foo", "#t": ["nostr", "other"]}"##)?;
+    filter.build_words();
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), PER_NUM as usize * 2);
+
+    let mut filter = Filter::from_str(r##"{"search": "baz", "#t": ["other"]}"##)?;
+    filter.build_words();
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 0);
+
+    let mut filter = Filter::from_str(r##"{"search": "foo", "#t": ["nostr"], "limit": 5}"##)?;
+    filter.build_words();
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 5);
+    assert!(e1.0.iter().all(|e| e.pubkey() == &author(1)));
+    Ok(())
+}
+
 #[test]
 pub fn test_query_scan_limit_time() -> Result<()> {
     let db = create_db("test_query_scan_limit_time")?;
@@ -1310,6 +3180,46 @@ pub fn test_query_scan_limit_time() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_scan_limit() -> Result<()> {
+    let db = create_db("test_query_scan_limit")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(0, i),
+                pubkey: author(10),
+                kind: 1,
+                created_at: i as u64 * 1000,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let filter = Filter::default();
+    let reader = db.reader()?;
+
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.scan_limit(10);
+    iter.scan_time(Duration::from_secs(100), 2);
+    let mut found = 0;
+    let res = iter.try_for_each(|e| e.map(|_| found += 1));
+    assert!(matches!(res, Err(Error::ScanLimit)));
+    assert_eq!(found, 10);
+
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.scan_limit(10);
+    assert!(matches!(iter.size(), Err(Error::ScanLimit)));
+
+    // the cap includes the last scan
+    let (_, stats) = db.iter::<Event, _>(&reader, &filter)?.size()?;
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.scan_limit(stats.scan_index);
+    assert_eq!(iter.size()?.0, PER_NUM as u64);
+    Ok(())
+}
+
 #[test]
 pub fn test_events_ephemeral() -> Result<()> {
     let db = create_db("test_events_ephemeral")?;
@@ -1391,3 +3301,210 @@ pub fn test_events_ephemeral() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+pub fn test_query_prefetch() -> Result<()> {
+    let db = create_db("test_query_prefetch")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(40, i),
+                pubkey: author(i % 3),
+                kind: 1000 + (i % 2) as u32,
+                tags: vec![vec!["t".to_owned(), format!("tag{}", i % 4)]],
+                content: "prefetch".to_owned(),
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    fn ids(db: &Db, filter: &Filter, prefetch: usize) -> Result<Vec<[u8; 32]>> {
+        let reader = db.reader()?;
+        let mut iter = db.iter::<Event, _>(&reader, filter)?;
+        iter.prefetch(prefetch);
+        iter.map(|e| e.map(|e| *e.id())).collect()
+    }
+
+    let filters = [
+        r#"{}"#.to_owned(),
+        r#"{"limit": 7}"#.to_owned(),
+        r#"{"kinds": [1000], "since": 5, "until": 20}"#.to_owned(),
+        format!(
+            r#"{{"authors": ["{}"], "limit": 4}}"#,
+            hex::encode(author(1))
+        ),
+        r##"{"#t": ["tag1", "tag2"], "kinds": [1001]}"##.to_owned(),
+        format!(
+            r##"{{"#t": ["tag3"], "authors": ["{}"]}}"##,
+            hex::encode(author(2))
+        ),
+    ];
+    for filter in filters {
+        let filter = Filter::from_str(&filter)?;
+        let expected = ids(&db, &filter, 0)?;
+        assert!(!expected.is_empty());
+        for num in [1, 3, 100] {
+            assert_eq!(ids(&db, &filter, num)?, expected);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_top_authors() -> Result<()> {
+    let db = create_db("test_top_authors")?;
+    // author 41: 6 events, 42: 4 events, 43: 1 event, 44: 4 events
+    let volumes = [(41u8, 6u8), (42, 4), (43, 1), (44, 4)];
+    let mut events: Vec<Event> = vec![];
+    for (a, num) in volumes {
+        for i in 0..num {
+            events.push(
+                MyEvent {
+                    id: id(a, i),
+                    pubkey: author(a),
+                    kind: 1 + (i % 2) as u32,
+                    // duplicate times of an author
+                    created_at: (i / 2) as u64 * 10,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+    }
+    db.batch_put(events)?;
+    let reader = db.reader()?;
+
+    let top = db.top_authors(&reader, 3, &Filter::default())?;
+    assert_eq!(top, vec![(author(41), 6), (author(42), 4), (author(44), 4)]);
+    let top = db.top_authors(&reader, 10, &Filter::default())?;
+    assert_eq!(top.len(), 4);
+    assert_eq!(top[3], (author(43), 1));
+
+    // by the time index
+    let filter = Filter {
+        since: Some(10),
+        ..Default::default()
+    };
+    let top = db.top_authors(&reader, 10, &filter)?;
+    assert_eq!(top, vec![(author(41), 4), (author(42), 2), (author(44), 2)]);
+
+    let filter = Filter {
+        authors: vec![author(42), author(43)].into(),
+        until: Some(0),
+        ..Default::default()
+    };
+    let top = db.top_authors(&reader, 10, &filter)?;
+    assert_eq!(top, vec![(author(42), 2), (author(43), 1)]);
+
+    // by events
+    let filter = Filter {
+        kinds: vec![2].into(),
+        ..Default::default()
+    };
+    let top = db.top_authors(&reader, 2, &filter)?;
+    assert_eq!(top, vec![(author(41), 3), (author(42), 2)]);
+    Ok(())
+}
+
+#[test]
+pub fn test_iter_grouped_by_author() -> Result<()> {
+    let db = create_db("test_iter_grouped_by_author")?;
+    // author 51: 4 events, 52: 1 event, 53: 3 events, 54: none
+    let volumes = [(51u8, 4u8), (52, 1), (53, 3)];
+    let mut events: Vec<Event> = vec![];
+    for (a, num) in volumes {
+        for i in 0..num {
+            events.push(
+                MyEvent {
+                    id: id(a, i),
+                    pubkey: author(a),
+                    kind: 1,
+                    created_at: i as u64 * 10,
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+    }
+    db.batch_put(events)?;
+    let reader = db.reader()?;
+    let group = |filter: &Filter, per_author| -> Result<Vec<([u8; 32], Vec<u64>)>> {
+        db.iter_grouped_by_author(&reader, filter, per_author)?
+            .map(|r| r.map(|(a, e)| (a, e.iter().map(|e| e.created_at()).collect())))
+            .collect()
+    };
+
+    let filter = Filter {
+        authors: vec![author(53), author(51), author(52), author(54)].into(),
+        desc: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        group(&filter, None)?,
+        vec![
+            (author(51), vec![30, 20, 10, 0]),
+            (author(52), vec![0]),
+            (author(53), vec![20, 10, 0]),
+        ]
+    );
+    // per author limit
+    assert_eq!(
+        group(&filter, Some(2))?,
+        vec![
+            (author(51), vec![30, 20]),
+            (author(52), vec![0]),
+            (author(53), vec![20, 10]),
+        ]
+    );
+    // the latest events of the limited groups
+    let asc = Filter {
+        desc: false,
+        ..filter.clone()
+    };
+    assert_eq!(
+        group(&asc, Some(2))?,
+        vec![
+            (author(51), vec![30, 20]),
+            (author(52), vec![0]),
+            (author(53), vec![20, 10]),
+        ]
+    );
+    assert_eq!(
+        group(&asc, None)?,
+        vec![
+            (author(51), vec![0, 10, 20, 30]),
+            (author(52), vec![0]),
+            (author(53), vec![0, 10, 20]),
+        ]
+    );
+    // total limit
+    let filter = Filter {
+        limit: Some(4),
+        ..filter
+    };
+    assert_eq!(
+        group(&filter, Some(2))?,
+        vec![
+            (author(51), vec![30, 20]),
+            (author(52), vec![0]),
+            (author(53), vec![20]),
+        ]
+    );
+    // other conditions
+    let filter = Filter {
+        since: Some(10),
+        limit: None,
+        ..filter
+    };
+    assert_eq!(
+        group(&filter, None)?,
+        vec![(author(51), vec![30, 20, 10]), (author(53), vec![20, 10])]
+    );
+    assert!(db
+        .iter_grouped_by_author(&reader, &Filter::default(), None)
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use nostr_db::{Db, DbOptions, Error, Event, Filter};
+use std::{fs, path::Path, str::FromStr};
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+const CONTENT: &str = "a secret direct message content";
+
+fn open(path: &Path, key: Option<[u8; 32]>) -> Result<Db> {
+    Db::open_with(
+        path,
+        DbOptions {
+            encryption_key: key,
+            ..Default::default()
+        },
+    )
+}
+
+fn contains(path: &Path, content: &str) -> bool {
+    let data = fs::read(path.join("data.mdb")).unwrap();
+    data.windows(content.len()).any(|w| w == content.as_bytes())
+}
+
+#[test]
+pub fn test_encrypt_at_rest() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-encrypt")
+        .tempdir()
+        .unwrap();
+    let event = Event::new(
+        [1; 32],
+        [1; 32],
+        10,
+        4,
+        vec![vec!["p".to_owned(), hex::encode([2; 32])]],
+        CONTENT.to_owned(),
+        [0; 64],
+    )?;
+
+    // plaintext
+    let path = dir.path().join("plain");
+    {
+        let db = open(&path, None)?;
+        db.batch_put(vec![event.clone()])?;
+        db.flush()?;
+    }
+    // the compressed data has no plaintext json
+    #[cfg(not(feature = "zstd"))]
+    assert!(contains(&path, CONTENT));
+
+    // encrypted
+    let path = dir.path().join("encrypted");
+    {
+        let db = open(&path, Some([7; 32]))?;
+        db.batch_put(vec![event.clone()])?;
+        db.flush()?;
+
+        let reader = db.reader()?;
+        let e: Option<Event> = db.get(&reader, event.id())?;
+        assert_eq!(e.unwrap().content(), CONTENT);
+        let events = db
+            .iter::<String, _>(&reader, &Filter::default())?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(events, vec![event.to_json()?]);
+        // query by the plaintext index
+        let filter =
+            Filter::from_str(&format!(r##"{{"#p":["{}"]}}"##, hex::encode([2; 32]))).unwrap();
+        assert_eq!(db.iter::<Event, _>(&reader, &filter)?.count(), 1);
+    }
+    assert!(!contains(&path, CONTENT));
+
+    // wrong key
+    {
+        let db = open(&path, Some([8; 32]))?;
+        let reader = db.reader()?;
+        assert!(db.get::<Event, _, _>(&reader, event.id()).is_err());
+        // the id is read from the index
+        assert!(db.get::<Vec<u8>, _, _>(&reader, event.id())?.is_some());
+    }
+    Ok(())
+}
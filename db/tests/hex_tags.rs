@@ -0,0 +1,113 @@
+//! The hex tags are set once per process, these tests run in a separate binary
+use nostr_db::{set_hex_tags, Db, Error, Event, EventIndex, Filter};
+use std::str::FromStr;
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+const Q: &str = "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d";
+
+fn setup() -> Result<()> {
+    set_hex_tags(["e", "p", "q"])
+}
+
+fn event(id: u8, tags: Vec<Vec<String>>) -> Result<Event> {
+    Event::new(
+        [id; 32],
+        [1; 32],
+        id as u64,
+        1,
+        tags,
+        "".to_owned(),
+        [0; 64],
+    )
+}
+
+#[test]
+pub fn test_match_hex_tag() -> Result<()> {
+    setup()?;
+    let a = "30023:7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef:slug";
+    let event = event(
+        1,
+        vec![
+            vec!["q".to_owned(), Q.to_uppercase()],
+            vec!["a".to_owned(), a.to_owned()],
+        ],
+    )?;
+    // the same encoding in the index and the filter
+    let filter = Filter::from_str(&format!(r###"{{"#q": ["{}"], "#a": ["{}"]}}"###, Q, a))?;
+    let value = hex::decode(Q)?;
+    assert_eq!(
+        filter.tags.get(b"q".as_slice()).unwrap().first(),
+        Some(&value)
+    );
+    assert!(event
+        .index()
+        .tags()
+        .contains(&(b"q".to_vec(), value.clone())));
+    // the coordinate is not hex
+    assert!(event
+        .index()
+        .tags()
+        .contains(&(b"a".to_vec(), a.as_bytes().to_vec())));
+    assert_eq!(
+        filter.to_json()?,
+        format!(r###"{{"#a":["{}"],"#q":["{}"]}}"###, a, Q)
+    );
+
+    let bytes = event.index().to_bytes()?;
+    let archived = EventIndex::from_zeroes(&bytes)?;
+    for s in [
+        format!(r###"{{"#q": ["{}"]}}"###, Q.to_uppercase()),
+        format!(r###"{{"#a": ["{}"]}}"###, a),
+    ] {
+        let filter = Filter::from_str(&s)?;
+        assert!(filter.r#match(event.index()));
+        assert!(filter.match_archived(archived));
+    }
+
+    let err = Filter::from_str(r###"{"#q": ["ab"]}"###).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains(r#"invalid q tag value "ab" at /#q/0"#));
+    Ok(())
+}
+
+#[test]
+pub fn test_query_hex_tag() -> Result<()> {
+    setup()?;
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-query-hex-tag")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    db.check_schema()?;
+    let events = (1..4u8)
+        .map(|i| event(i, vec![vec!["q".to_owned(), hex::encode([60 + i; 32])]]))
+        .collect::<Result<Vec<_>>>()?;
+    db.batch_put(events)?;
+
+    let filter = Filter::from_str(&format!(
+        r###"{{"#q": ["{}", "{}"]}}"###,
+        hex::encode([61; 32]),
+        hex::encode([63; 32]).to_uppercase()
+    ))?;
+    let reader = db.reader()?;
+    let mut ids = db
+        .iter::<Event, _>(&reader, &filter)?
+        .map(|e| e.map(|e| *e.id()))
+        .collect::<Result<Vec<_>>>()?;
+    ids.sort();
+    assert_eq!(ids, vec![[1; 32], [3; 32]]);
+    // the filter value is decoded the same as the index
+    assert!(Filter::from_str(r###"{"#q": ["nostr"]}"###).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn test_set_hex_tags_once() -> Result<()> {
+    setup()?;
+    // the same list in any order
+    set_hex_tags(["q", "p", "e", "q"])?;
+    assert!(set_hex_tags(["e", "p"]).is_err());
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use nostr_db::{now, Db, Error, Event};
+use std::time::Duration;
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+fn create_db(t: &str) -> Result<Db> {
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("nostr-db-test-{}", t))
+        .tempdir()
+        .unwrap();
+    Db::open(dir.path())
+}
+
+fn event(index: u8, created_at: u64, kind: u32, tags: Vec<Vec<String>>) -> Event {
+    let mut id = [0; 32];
+    id[31] = index;
+    Event::new(id, [1; 32], created_at, kind, tags, "".to_owned(), [0; 64]).unwrap()
+}
+
+#[tokio::test]
+async fn test_expiration_sweeper() -> Result<()> {
+    let db = create_db("test_expiration_sweeper")?;
+    let now = now();
+    let expired = vec![vec!["expiration".to_owned(), (now - 10).to_string()]];
+    let later = vec![vec!["expiration".to_owned(), (now + 1000).to_string()]];
+    let events = vec![
+        event(1, now, 1, expired.clone()),
+        event(2, now, 1000, expired),
+        event(3, now, 1, later),
+        event(4, now - 3600, 20001, vec![]),
+        event(5, now, 20001, vec![]),
+        event(6, now, 1, vec![]),
+    ];
+    db.batch_put(&events)?;
+
+    let handle = db.spawn_expiration_sweeper(Duration::from_secs(3600));
+    // the first tick runs immediately
+    let mut remain = events.len();
+    for _ in 0..100 {
+        remain = db
+            .batch_get::<Event, _, _>(events.iter().map(|e| e.id()))?
+            .len();
+        if remain == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(remain, 3);
+    handle.abort();
+    assert!(handle.await.unwrap_err().is_cancelled());
+
+    let left = db.batch_get::<Event, _, _>(events.iter().map(|e| e.id()))?;
+    let ids = left.iter().map(|e| e.id()[31]).collect::<Vec<_>>();
+    assert_eq!(ids, vec![3, 5, 6]);
+    Ok(())
+}
@@ -1,14 +1,19 @@
 use metrics::{counter, describe_counter};
-use nostr_relay::db::now;
+use nostr_relay::db::{
+    now,
+    secp256k1::rand::{thread_rng, RngCore},
+};
 use nostr_relay::{
-    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    duration::NonZeroDuration,
+    message::{event_rejected, ClientMessage, IncomingMessage, OutgoingMessage},
     setting::SettingWrapper,
     Extension, ExtensionMessageResult, List, Session,
 };
 use serde::Deserialize;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-#[derive(Deserialize, Default, Debug)]
+#[derive(Deserialize, Debug)]
 #[serde(default)]
 pub struct Permission {
     pub ip_whitelist: Option<List>,
@@ -17,6 +22,26 @@ pub struct Permission {
     pub pubkey_blacklist: Option<List>,
     pub event_pubkey_whitelist: Option<List>,
     pub event_pubkey_blacklist: Option<List>,
+    /// Only the event kinds require NIP-42 auth, the pubkey lists are not checked for other kinds
+    pub auth_required_kinds: Option<Vec<u32>>,
+    /// When false, the `ip_whitelist` doesn't deny the other IPs, the unauthenticated connections
+    /// from the whitelisted IPs skip the pubkey lists, the others still need NIP-42 auth. default true
+    pub require_nip42: bool,
+}
+
+impl Default for Permission {
+    fn default() -> Self {
+        Self {
+            ip_whitelist: None,
+            pubkey_whitelist: None,
+            ip_blacklist: None,
+            pubkey_blacklist: None,
+            event_pubkey_whitelist: None,
+            event_pubkey_blacklist: None,
+            auth_required_kinds: None,
+            require_nip42: true,
+        }
+    }
 }
 
 #[derive(Deserialize, Default, Debug)]
@@ -27,16 +52,39 @@ pub struct AuthSetting {
     pub req: Option<Permission>,
     /// write auth: ["EVENT"]
     pub event: Option<Permission>,
+    /// max_limit for authenticated connections, sent back in the AUTH OK message
+    pub authed_max_limit: Option<u64>,
+    /// the challenge expires after the ttl, a new one is sent on the AUTH with the expired challenge.
+    /// default never expires
+    pub challenge_ttl: Option<NonZeroDuration>,
+    pub challenge_format: ChallengeFormat,
+    /// number of the random bytes of the hex challenge. default 16
+    pub challenge_length: Option<usize>,
+    /// Send the NIP-70 protected events to the sessions authenticated as their author only
+    pub protect_reads: bool,
+}
+
+/// The format of the AUTH challenge
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeFormat {
+    /// uuid v4 string
+    #[default]
+    Uuid,
+    /// hex of the random bytes
+    Hex,
 }
 
 #[derive(Default, Debug)]
 pub struct Auth {
     setting: AuthSetting,
+    /// `network.external_url`, checked against the relay tag of the AUTH event
+    external_url: Option<String>,
 }
 
 pub enum AuthState {
-    /// The AUTH challenge
-    Challenge(String),
+    /// The AUTH challenge and the time it's issued
+    Challenge(String, Instant),
     /// Authenticated with pubkey
     Pubkey(String),
 }
@@ -49,7 +97,7 @@ impl AuthState {
     pub fn pubkey(&self) -> Option<&String> {
         match self {
             Self::Pubkey(p) => Some(p),
-            Self::Challenge(_) => None,
+            Self::Challenge(..) => None,
         }
     }
 }
@@ -62,18 +110,44 @@ impl Auth {
         );
         Self {
             setting: AuthSetting::default(),
+            external_url: None,
+        }
+    }
+
+    /// Generate a challenge in the configured format
+    pub fn challenge(&self) -> String {
+        match self.setting.challenge_format {
+            ChallengeFormat::Uuid => Uuid::new_v4().to_string(),
+            ChallengeFormat::Hex => {
+                let mut bytes = vec![0u8; self.setting.challenge_length.unwrap_or(16)];
+                thread_rng().fill_bytes(&mut bytes);
+                hex::encode(bytes)
+            }
         }
     }
 
+    fn send_challenge(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {
+        let challenge = self.challenge();
+        ctx.text(OutgoingMessage::auth(&challenge));
+        session.set(AuthState::Challenge(challenge, Instant::now()));
+    }
+
+    fn expired(&self, issued_at: &Instant) -> bool {
+        self.setting
+            .challenge_ttl
+            .is_some_and(|ttl| issued_at.elapsed() > Duration::from(ttl))
+    }
+
     pub fn verify_permission(
         permission: Option<&Permission>,
         pubkey: Option<&String>,
         event_pubkey: Option<&String>,
+        event_kind: Option<u32>,
         ip: &String,
     ) -> Result<(), &'static str> {
         if let Some(permission) = permission {
             if let Some(list) = &permission.ip_whitelist {
-                if !list.contains(ip) {
+                if permission.require_nip42 && !list.contains(ip) {
                     return Err("ip not in whitelist");
                 }
             }
@@ -96,6 +170,25 @@ impl Auth {
                 }
             }
 
+            if !permission.require_nip42
+                && pubkey.is_none()
+                && permission
+                    .ip_whitelist
+                    .as_ref()
+                    .is_some_and(|list| list.contains(ip))
+            {
+                return Ok(());
+            }
+
+            if let (Some(kinds), Some(kind)) = (&permission.auth_required_kinds, event_kind) {
+                if !kinds.contains(&kind) {
+                    return Ok(());
+                }
+                if pubkey.is_none() {
+                    return Err("NIP-42 auth required");
+                }
+            }
+
             if let Some(list) = &permission.pubkey_whitelist {
                 if let Some(pubkey) = pubkey {
                     if !list.contains(pubkey) {
@@ -119,6 +212,19 @@ impl Auth {
     }
 }
 
+/// Compare the relay urls with the case-insensitive scheme and host, ignore the trailing slash
+fn same_relay(a: &str, b: &str) -> bool {
+    fn normalize(url: &str) -> String {
+        let url = url.trim().trim_end_matches('/');
+        let (origin, path) = match url.find("://") {
+            Some(i) => url.split_at(url[i + 3..].find('/').map_or(url.len(), |j| i + 3 + j)),
+            None => (url, ""),
+        };
+        format!("{}{}", origin.to_lowercase(), path)
+    }
+    normalize(a) == normalize(b)
+}
+
 impl Extension for Auth {
     fn name(&self) -> &'static str {
         "auth"
@@ -127,6 +233,7 @@ impl Extension for Auth {
     fn setting(&mut self, setting: &SettingWrapper) {
         let mut w = setting.write();
         self.setting = w.parse_extension(self.name());
+        self.external_url = w.network.external_url.clone();
         if self.setting.enabled {
             w.add_nip(42);
         }
@@ -134,10 +241,7 @@ impl Extension for Auth {
 
     fn connected(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {
         if self.setting.enabled {
-            let uuid = Uuid::new_v4().to_string();
-            let state = AuthState::Challenge(uuid.clone());
-            session.set(state);
-            ctx.text(format!(r#"["AUTH", "{uuid}"]"#));
+            self.send_challenge(session, ctx);
         }
     }
 
@@ -145,16 +249,21 @@ impl Extension for Auth {
         &self,
         msg: ClientMessage,
         session: &mut Session,
-        _ctx: &mut <Session as actix::Actor>::Context,
+        ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
         let mut msg = msg;
 
+        // follow the reloaded setting
+        if matches!(session.get::<AuthState>(), Some(AuthState::Pubkey(_))) {
+            session.set_max_limit(self.setting.authed_max_limit);
+        }
+
         if self.setting.enabled {
             let state = session.get::<AuthState>();
             msg.nip70_checked = true;
             match &msg.msg {
                 IncomingMessage::Auth(event) => {
-                    if let Some(AuthState::Challenge(challenge)) = state {
+                    if let Some(AuthState::Challenge(challenge, issued_at)) = state {
                         if let Err(err) = event.validate(now(), 0, 0) {
                             return OutgoingMessage::ok(
                                 &event.id_str(),
@@ -163,10 +272,43 @@ impl Extension for Auth {
                             )
                             .into();
                         } else if event.kind() == 22242 {
+                            if let Some(url) = &self.external_url {
+                                if !event.tags().iter().any(|tag| {
+                                    tag.len() > 1 && tag[0] == "relay" && same_relay(&tag[1], url)
+                                }) {
+                                    return OutgoingMessage::ok(
+                                        &event.id_str(),
+                                        false,
+                                        "auth-required: invalid relay tag",
+                                    )
+                                    .into();
+                                }
+                            }
                             for tag in event.tags() {
                                 if tag.len() > 1 && tag[0] == "challenge" && &tag[1] == challenge {
+                                    if self.expired(issued_at) {
+                                        let id = event.id_str();
+                                        self.send_challenge(session, ctx);
+                                        return OutgoingMessage::ok(
+                                            &id,
+                                            false,
+                                            "auth-required: challenge expired",
+                                        )
+                                        .into();
+                                    }
                                     session.set(AuthState::Pubkey(event.pubkey_str()));
-                                    return OutgoingMessage::ok(&event.id_str(), true, "").into();
+                                    session.set_pubkey(Some(*event.pubkey()));
+                                    let mut message = String::new();
+                                    if self.setting.authed_max_limit.is_some() {
+                                        session.set_max_limit(self.setting.authed_max_limit);
+                                        message = format!(
+                                            "limitation: {}",
+                                            serde_json::to_string(&session.limitation())
+                                                .unwrap_or_default()
+                                        );
+                                    }
+                                    return OutgoingMessage::ok(&event.id_str(), true, &message)
+                                        .into();
                                 }
                             }
                         }
@@ -183,9 +325,11 @@ impl Extension for Auth {
                         self.setting.event.as_ref(),
                         state.and_then(|s| s.pubkey()),
                         Some(&event.pubkey_str()),
+                        Some(event.kind()),
                         session.ip(),
                     ) {
                         counter!("nostr_relay_auth_unauthorized", "command" => "EVENT", "reason" => err).increment(1);
+                        event_rejected("unauthorized");
                         return OutgoingMessage::ok(
                             &event.id_str(),
                             false,
@@ -194,26 +338,23 @@ impl Extension for Auth {
                         .into();
                     } else {
                         // check nip70 protected event
-                        for tag in event.tags() {
-                            if tag.len() == 1 && tag[0] == "-" {
-                                if let Some(AuthState::Pubkey(pubkey)) = state {
-                                    if pubkey != &event.pubkey_str() {
-                                        return OutgoingMessage::ok(
-                                            &event.id_str(),
-                                            false,
-                                            "auth-required: this event may only be published by its author",
-                                        )
-                                        .into();
-                                    }
-                                } else {
+                        if event.is_protected() {
+                            if let Some(AuthState::Pubkey(pubkey)) = state {
+                                if pubkey != &event.pubkey_str() {
                                     return OutgoingMessage::ok(
                                         &event.id_str(),
                                         false,
-                                        "auth-required: this event require authorization",
+                                        "auth-required: this event may only be published by its author",
                                     )
                                     .into();
                                 }
-                                break;
+                            } else {
+                                return OutgoingMessage::ok(
+                                    &event.id_str(),
+                                    false,
+                                    "auth-required: this event require authorization",
+                                )
+                                .into();
                             }
                         }
                     }
@@ -223,6 +364,7 @@ impl Extension for Auth {
                         self.setting.req.as_ref(),
                         state.and_then(|s| s.pubkey()),
                         None,
+                        None,
                         session.ip(),
                     ) {
                         counter!("nostr_relay_auth_unauthorized", "command" => "REQ", "reason" => err).increment(1);
@@ -232,6 +374,9 @@ impl Extension for Auth {
                 }
                 _ => {}
             }
+            if let IncomingMessage::Req(sub) | IncomingMessage::Count(sub) = &mut msg.msg {
+                sub.protect_reads = self.setting.protect_reads;
+            }
         }
         ExtensionMessageResult::Continue(msg)
     }
@@ -269,6 +414,7 @@ mod tests {
             }),
             None,
             None,
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_ok());
@@ -279,6 +425,7 @@ mod tests {
             }),
             None,
             None,
+            None,
             &"127.0.0.2".to_owned()
         )
         .is_err());
@@ -290,6 +437,7 @@ mod tests {
             }),
             None,
             None,
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_err());
@@ -300,6 +448,7 @@ mod tests {
             }),
             None,
             None,
+            None,
             &"127.0.0.2".to_owned()
         )
         .is_ok());
@@ -311,6 +460,7 @@ mod tests {
             }),
             Some(&"xx".to_owned()),
             None,
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_ok());
@@ -321,6 +471,7 @@ mod tests {
             }),
             Some(&"xxxx".to_owned()),
             None,
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_err());
@@ -332,6 +483,7 @@ mod tests {
             }),
             Some(&"xx".to_owned()),
             None,
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_err());
@@ -342,6 +494,7 @@ mod tests {
             }),
             Some(&"xxxx".to_owned()),
             None,
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_ok());
@@ -353,6 +506,7 @@ mod tests {
             }),
             None,
             Some(&"xx".to_owned()),
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_ok());
@@ -363,6 +517,7 @@ mod tests {
             }),
             None,
             Some(&"xxxx".to_owned()),
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_err());
@@ -374,6 +529,7 @@ mod tests {
             }),
             None,
             Some(&"xx".to_owned()),
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_err());
@@ -384,9 +540,115 @@ mod tests {
             }),
             None,
             Some(&"xxxx".to_owned()),
+            None,
             &"127.0.0.1".to_owned()
         )
         .is_ok());
+
+        let permission = Permission {
+            auth_required_kinds: Some(vec![4, 1059]),
+            ..Default::default()
+        };
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            None,
+            None,
+            Some(1),
+            &"127.0.0.1".to_owned()
+        )
+        .is_ok());
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            None,
+            None,
+            Some(4),
+            &"127.0.0.1".to_owned()
+        )
+        .is_err());
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            Some(&"xx".to_owned()),
+            None,
+            Some(4),
+            &"127.0.0.1".to_owned()
+        )
+        .is_ok());
+
+        // the pubkey whitelist only applies to the listed kinds
+        let permission = Permission {
+            auth_required_kinds: Some(vec![4]),
+            pubkey_whitelist: Some(vec!["xx".to_string()].into()),
+            ..Default::default()
+        };
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            Some(&"xxxx".to_owned()),
+            None,
+            Some(1),
+            &"127.0.0.1".to_owned()
+        )
+        .is_ok());
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            Some(&"xxxx".to_owned()),
+            None,
+            Some(4),
+            &"127.0.0.1".to_owned()
+        )
+        .is_err());
+
+        // the whitelisted ip skips the pubkey lists without auth
+        let permission = Permission {
+            ip_whitelist: Some(vec!["127.0.0.1".to_string()].into()),
+            pubkey_whitelist: Some(vec!["xx".to_string()].into()),
+            require_nip42: false,
+            ..Default::default()
+        };
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            None,
+            None,
+            None,
+            &"127.0.0.1".to_owned()
+        )
+        .is_ok());
+        // the authenticated pubkey is still checked
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            Some(&"xxxx".to_owned()),
+            None,
+            None,
+            &"127.0.0.1".to_owned()
+        )
+        .is_err());
+        // the other ips are not denied by the whitelist, they need auth
+        assert_eq!(
+            Auth::verify_permission(Some(&permission), None, None, None, &"127.0.0.2".to_owned()),
+            Err("NIP-42 auth required")
+        );
+        assert!(Auth::verify_permission(
+            Some(&permission),
+            Some(&"xx".to_owned()),
+            None,
+            None,
+            &"127.0.0.2".to_owned()
+        )
+        .is_ok());
+        // required by default
+        assert_eq!(
+            Auth::verify_permission(
+                Some(&Permission {
+                    ip_whitelist: Some(vec!["127.0.0.1".to_string()].into()),
+                    pubkey_whitelist: Some(vec!["xx".to_string()].into()),
+                    ..Default::default()
+                }),
+                None,
+                None,
+                None,
+                &"127.0.0.1".to_owned()
+            ),
+            Err("NIP-42 auth required")
+        );
         Ok(())
     }
 
@@ -469,6 +731,128 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn relay_url() {
+        assert!(same_relay(
+            "wss://relay.example.com",
+            "wss://relay.example.com/"
+        ));
+        assert!(same_relay(
+            "WSS://Relay.Example.com/",
+            "wss://relay.example.com"
+        ));
+        assert!(same_relay(
+            "wss://example.com/relay/",
+            "wss://example.com/relay"
+        ));
+        assert!(!same_relay(
+            "wss://example.com/Relay",
+            "wss://example.com/relay"
+        ));
+        assert!(!same_relay(
+            "ws://relay.example.com",
+            "wss://relay.example.com"
+        ));
+        assert!(!same_relay(
+            "wss://other.example.com",
+            "wss://relay.example.com"
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn relay_tag() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let app = create_test_app("auth-relay-tag")?;
+        {
+            let mut w = app.setting.write();
+            w.network.external_url = Some("wss://relay.example.com".to_owned());
+            w.extra = serde_json::from_str(r#"{ "auth": { "enabled": true } }"#)?;
+        }
+        let app = app.add_extension(Auth::new());
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let state: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(state.0, "AUTH");
+
+        let auth = |relay: Option<&str>| -> Result<ws::Message> {
+            let mut tags = vec![vec!["challenge".to_owned(), state.1.clone()]];
+            if let Some(relay) = relay {
+                tags.push(vec!["relay".to_owned(), relay.to_owned()]);
+            }
+            let event = Event::create(&key_pair, now(), 22242, tags, "".to_owned())?;
+            Ok(ws::Message::Text(format!(r#"["AUTH", {}]"#, event).into()))
+        };
+
+        for relay in [None, Some("wss://other.example.com")] {
+            framed.send(auth(relay)?).await?;
+            let notice: (String, String, bool, String) =
+                parse_text(&framed.next().await.unwrap()?)?;
+            assert!(!notice.2);
+            assert_eq!(notice.3, "auth-required: invalid relay tag");
+        }
+
+        framed.send(auth(Some("wss://relay.example.com/"))?).await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn challenge_expired() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let app = create_test_app("auth-challenge-expired")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "auth": {
+                    "enabled": true,
+                    "challenge_ttl": "100ms",
+                    "challenge_format": "hex",
+                    "challenge_length": 8
+                }
+            }"#,
+            )?;
+        }
+        let app = app.add_extension(Auth::new());
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let state: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(state.0, "AUTH");
+        assert_eq!(state.1.len(), 16);
+        assert!(hex::decode(&state.1).is_ok());
+
+        let auth = |challenge: &str| -> Result<ws::Message> {
+            let event = Event::create(
+                &key_pair,
+                now(),
+                22242,
+                vec![vec!["challenge".to_owned(), challenge.to_owned()]],
+                "".to_owned(),
+            )?;
+            Ok(ws::Message::Text(format!(r#"["AUTH", {}]"#, event).into()))
+        };
+
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        framed.send(auth(&state.1)?).await?;
+        // a fresh challenge
+        let fresh: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(fresh.0, "AUTH");
+        assert_ne!(fresh.1, state.1);
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(!notice.2);
+        assert_eq!(notice.3, "auth-required: challenge expired");
+
+        framed.send(auth(&fresh.1)?).await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn pubkey_whitelist() -> Result<()> {
         let mut rng = thread_rng();
@@ -591,6 +975,180 @@ mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn auth_required_kinds() -> Result<()> {
+        let mut rng = thread_rng();
+        let key_pair = Keypair::new_global(&mut rng);
+
+        let app = create_test_app("auth-required-kinds")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "auth": {
+                    "enabled": true,
+                    "event": {
+                        "auth_required_kinds": [4, 1059]
+                    }
+                }
+            }"#,
+            )?;
+        }
+        let app = app.add_extension(Auth::new());
+        let app = web::Data::new(app);
+
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+
+        // client service
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let item = framed.next().await.unwrap()?;
+        let state: (String, String) = parse_text(&item)?;
+        assert_eq!(state.0, "AUTH");
+
+        // public note without auth
+        let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+
+        // dm requires auth
+        let event = Event::create(&key_pair, now(), 4, vec![], "test".to_owned())?;
+        let text = format!(r#"["EVENT", {}]"#, event);
+        framed.send(ws::Message::Text(text.clone().into())).await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(!notice.2);
+        assert!(notice.3.contains("auth-required"));
+
+        let auth = Event::create(
+            &key_pair,
+            now(),
+            22242,
+            vec![vec!["challenge".to_owned(), state.1.clone()]],
+            "".to_owned(),
+        )?;
+        framed
+            .send(ws::Message::Text(format!(r#"["AUTH", {}]"#, auth).into()))
+            .await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+
+        framed.send(ws::Message::Text(text.into())).await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+
+        framed
+            .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(item, ws::Frame::Close(Some(ws::CloseCode::Normal.into())));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn authed_max_limit() -> Result<()> {
+        let mut rng = thread_rng();
+        let key_pair = Keypair::new_global(&mut rng);
+
+        let app = create_test_app("auth-authed-max-limit")?;
+        {
+            let mut w = app.setting.write();
+            w.limitation.max_limit = 5;
+            w.extra = serde_json::from_str(
+                r#"{
+                "auth": {
+                    "enabled": true,
+                    "authed_max_limit": 10
+                }
+            }"#,
+            )?;
+        }
+        let app = app.add_extension(Auth::new());
+        let app = web::Data::new(app);
+        let data = app.clone();
+
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+
+        // client service
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let item = framed.next().await.unwrap()?;
+        let state: (String, String) = parse_text(&item)?;
+        assert_eq!(state.0, "AUTH");
+
+        // anonymous
+        let req = r#"["REQ", "1", {"limit": 8}]"#;
+        framed.send(ws::Message::Text(req.into())).await?;
+        let notice: (String, String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "CLOSED");
+        assert!(notice.2.contains("max_limit 5"));
+
+        let event = Event::create(
+            &key_pair,
+            now(),
+            22242,
+            vec![vec!["challenge".to_owned(), state.1.clone()]],
+            "".to_owned(),
+        )?;
+        framed
+            .send(ws::Message::Text(format!(r#"["AUTH", {}]"#, event).into()))
+            .await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+        let limitation: serde_json::Value =
+            serde_json::from_str(notice.3.strip_prefix("limitation: ").unwrap())?;
+        assert_eq!(limitation["max_limit"], 10);
+
+        // authed
+        framed.send(ws::Message::Text(req.into())).await?;
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "EOSE");
+
+        let req = r#"["REQ", "1", {"limit": 11}]"#;
+        framed.send(ws::Message::Text(req.into())).await?;
+        let notice: (String, String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "CLOSED");
+        assert!(notice.2.contains("max_limit 10"));
+
+        // reload
+        {
+            let mut w = data.setting.write();
+            w.limitation.max_filters = 1;
+            w.extra = serde_json::from_str(
+                r#"{
+                "auth": {
+                    "enabled": true,
+                    "authed_max_limit": 20
+                }
+            }"#,
+            )?;
+        }
+        data.extensions.write().call_setting(&data.setting);
+        let req = r#"["REQ", "1", {"limit": 1}, {"limit": 1}]"#;
+        framed.send(ws::Message::Text(req.into())).await?;
+        let notice: (String, String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "CLOSED");
+        assert!(notice.2.contains("max_filters 1"));
+        // the authed max_limit follows from the next message passed to the extension
+        let req = r#"["REQ", "1", {"limit": 1}]"#;
+        framed.send(ws::Message::Text(req.into())).await?;
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "EOSE");
+        let req = r#"["REQ", "1", {"limit": 15}]"#;
+        framed.send(ws::Message::Text(req.into())).await?;
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "EOSE");
+
+        framed
+            .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(item, ws::Frame::Close(Some(ws::CloseCode::Normal.into())));
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn nip70() -> Result<()> {
         let mut rng = thread_rng();
@@ -732,4 +1290,118 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn protect_reads() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+
+        let app = create_test_app("auth-protect-reads")?;
+        {
+            let mut w = app.setting.write();
+            w.extra =
+                serde_json::from_str(r#"{ "auth": { "enabled": true, "protect_reads": true } }"#)?;
+        }
+        let app = app.add_extension(Auth::new());
+        let app = web::Data::new(app);
+
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+
+        // the author
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let state: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        let auth = Event::create(
+            &key_pair,
+            now(),
+            22242,
+            vec![vec!["challenge".to_owned(), state.1]],
+            "".to_owned(),
+        )?;
+        framed
+            .send(ws::Message::Text(format!(r#"["AUTH", {}]"#, auth).into()))
+            .await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+
+        let protected = Event::create(
+            &key_pair,
+            now(),
+            1,
+            vec![vec!["-".to_owned()]],
+            "protected".to_owned(),
+        )?;
+        let public = Event::create(&key_pair, now(), 1, vec![], "public".to_owned())?;
+        for event in [&protected, &public] {
+            framed
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+                .await?;
+            let notice: (String, String, bool, String) =
+                parse_text(&framed.next().await.unwrap()?)?;
+            assert!(notice.2);
+        }
+
+        // the contents of the stored events until EOSE
+        async fn req<S>(framed: &mut S) -> Result<Vec<String>>
+        where
+            S: futures_util::Sink<ws::Message, Error = ws::ProtocolError>
+                + futures_util::Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+                + Unpin,
+        {
+            framed
+                .send(ws::Message::Text(r#"["REQ", "1", {"kinds": [1]}]"#.into()))
+                .await?;
+            let mut contents = vec![];
+            loop {
+                let frame = framed.next().await.unwrap()?;
+                if let Ok(res) = parse_text::<(String, String, Event)>(&frame) {
+                    contents.push(res.2.content().to_owned());
+                } else {
+                    let res: (String, String) = parse_text(&frame)?;
+                    assert_eq!(res.0, "EOSE");
+                    break;
+                }
+            }
+            contents.sort();
+            Ok(contents)
+        }
+
+        // visible to the authenticated author
+        assert_eq!(req(&mut framed).await?, vec!["protected", "public"]);
+        let mut author = framed;
+
+        // hidden from the unauthenticated session
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let state: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(state.0, "AUTH");
+        assert_eq!(req(&mut framed).await?, vec!["public"]);
+
+        // the live events are dispatched by the same rule
+        let protected = Event::create(
+            &key_pair,
+            now(),
+            1,
+            vec![vec!["-".to_owned()]],
+            "live protected".to_owned(),
+        )?;
+        let public = Event::create(&key_pair, now(), 1, vec![], "live public".to_owned())?;
+        let mut contents = vec![];
+        for event in [&protected, &public] {
+            author
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+                .await?;
+        }
+        // the oks and the events of the open subscription in any order
+        for _ in 0..4 {
+            let frame = author.next().await.unwrap()?;
+            if let Ok(res) = parse_text::<(String, String, Event)>(&frame) {
+                contents.push(res.2.content().to_owned());
+            }
+        }
+        contents.sort();
+        assert_eq!(contents, vec!["live protected", "live public"]);
+
+        // dispatched in order, the protected one is skipped
+        let res: (String, String, Event) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.2.content(), "live public");
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 use metrics::{describe_histogram, histogram};
 use nostr_relay::{
-    db::{Db, Filter},
+    db::{kv::lmdb::Reader, Db, Event, Filter, FromEventData, Iter, QueryOpts},
     duration::NonZeroDuration,
     message::{ClientMessage, IncomingMessage, OutgoingMessage},
     setting::SettingWrapper,
@@ -10,9 +10,38 @@ use serde::Deserialize;
 use std::sync::Arc;
 use std::time::Instant;
 
-#[derive(Deserialize, Default, Debug)]
+pub mod hll;
+use hll::Hll;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
 pub struct CountSetting {
     pub enabled: bool,
+    /// add the `hll` field to the response if the filter has a tag, see [`Hll::offset`]
+    pub hll: bool,
+    /// stop the count after scanning `approx_threshold` index entries and estimate it
+    /// from the scanned part, the response has `"approximate": true`
+    pub approximate: bool,
+    pub approx_threshold: u64,
+}
+
+impl Default for CountSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hll: false,
+            approximate: false,
+            approx_threshold: 100_000,
+        }
+    }
+}
+
+/// The result of [`Count::count`]
+#[derive(Debug, Default)]
+struct Counted {
+    size: u64,
+    hll: Option<Hll>,
+    approximate: bool,
 }
 
 pub struct Count {
@@ -29,16 +58,110 @@ impl Count {
         }
     }
 
-    fn count(&self, filter: &Filter, timeout: Option<NonZeroDuration>) -> Result<u64, Error> {
+    /// Count the filter, the requested limit is clamped to `max_limit`.
+    /// The shadowbanned authors are not counted except for the viewer's own,
+    /// so are the NIP-70 protected events if `protect_reads`.
+    /// Build the hll from the pubkeys of the events if enabled,
+    /// estimate the count over `approx_threshold` scanned index entries if approximate
+    fn count(
+        &self,
+        filter: &Filter,
+        viewer: Option<&[u8; 32]>,
+        protect_reads: bool,
+        timeout: Option<NonZeroDuration>,
+        max_scan: Option<u64>,
+        max_limit: u64,
+    ) -> Result<Counted, Error> {
+        let mut filter = filter.clone();
+        QueryOpts {
+            max_limit: Some(max_limit),
+            ..Default::default()
+        }
+        .apply(&mut filter);
+        fn limit<J: FromEventData>(
+            iter: &mut Iter<'_, Reader, J>,
+            viewer: Option<&[u8; 32]>,
+            timeout: Option<NonZeroDuration>,
+            max_scan: Option<u64>,
+        ) {
+            iter.viewer(viewer);
+            if let Some(time) = timeout {
+                iter.scan_time(time.into(), 2000);
+            }
+            if let Some(max) = max_scan {
+                iter.scan_limit(max);
+            }
+        }
         let reader = self.db.reader()?;
         let start = Instant::now();
-        let mut iter = self.db.iter::<String, _>(&reader, filter)?;
-        if let Some(time) = timeout {
-            iter.scan_time(time.into(), 2000);
-        }
-        let (size, _) = iter.size()?;
+        let offset = Hll::offset(&filter).filter(|_| self.setting.hll);
+        // the protected tag is not indexed, check the events
+        let res = if offset.is_some() || protect_reads {
+            let mut iter = self.db.iter::<Event, _>(&reader, &filter)?;
+            limit(&mut iter, viewer, timeout, max_scan);
+            let mut hll = offset.map(|_| Hll::new());
+            let mut size = 0;
+            for event in iter {
+                let event = event?;
+                if protect_reads && event.is_protected() && viewer != Some(event.pubkey()) {
+                    continue;
+                }
+                if let (Some(hll), Some(offset)) = (hll.as_mut(), offset) {
+                    hll.add(event.pubkey(), offset);
+                }
+                size += 1;
+            }
+            Counted {
+                size,
+                hll,
+                approximate: false,
+            }
+        } else if self.setting.approximate {
+            let mut iter = self.db.iter::<String, _>(&reader, &filter)?;
+            limit(&mut iter, viewer, timeout, max_scan);
+            let mut hll = Hll::new();
+            let mut first = None;
+            let (size, stopped, _) =
+                iter.size_until(self.setting.approx_threshold, |uid, time| {
+                    hll.add_uid(uid);
+                    first.get_or_insert(time);
+                })?;
+            match (first, stopped) {
+                (Some(first), Some(stopped)) => {
+                    // the time of the matched event at the other end
+                    let mut rev = filter.clone();
+                    rev.desc = !rev.desc;
+                    rev.limit = Some(1);
+                    let mut iter = self.db.iter::<Event, _>(&reader, &rev)?;
+                    limit(&mut iter, viewer, timeout, max_scan);
+                    let last = match iter.next().transpose()? {
+                        Some(event) => event.created_at(),
+                        None => stopped,
+                    };
+                    // scale the scanned time range to the whole matched range
+                    let scale =
+                        (last.abs_diff(first) + 1) as f64 / (stopped.abs_diff(first) + 1) as f64;
+                    Counted {
+                        size: (hll.estimate() as f64 * scale) as u64,
+                        hll: None,
+                        approximate: true,
+                    }
+                }
+                _ => Counted {
+                    size,
+                    ..Default::default()
+                },
+            }
+        } else {
+            let mut iter = self.db.iter::<String, _>(&reader, &filter)?;
+            limit(&mut iter, viewer, timeout, max_scan);
+            Counted {
+                size: iter.size()?.0,
+                ..Default::default()
+            }
+        };
         histogram!("nostr_relay_count_size").record(start.elapsed());
-        Ok(size)
+        Ok(res)
     }
 }
 
@@ -64,13 +187,33 @@ impl Extension for Count {
         if self.setting.enabled {
             if let IncomingMessage::Count(sub) = &msg.msg {
                 if !sub.filters.is_empty() {
-                    let timeout = session.app.setting.read().data.db_query_timeout;
-                    match self.count(&sub.filters[0], timeout) {
-                        Ok(size) => {
-                            return ExtensionMessageResult::Stop(OutgoingMessage(format!(
-                                r#"["COUNT","{}",{{"count": {}}}]"#,
-                                sub.id, size
-                            )))
+                    let limitation = session.limitation();
+                    if limitation.reject_empty_lists && !sub.filters[0].empty_fields.is_empty() {
+                        return ExtensionMessageResult::Stop(OutgoingMessage::count(&sub.id, 0));
+                    }
+                    let max_limit = limitation.max_limit;
+                    let r = session.app.setting.read();
+                    let timeout = r.data.db_query_timeout;
+                    let max_scan = r.data.db_query_max_scan;
+                    drop(r);
+                    match self.count(
+                        &sub.filters[0],
+                        session.pubkey(),
+                        sub.protect_reads,
+                        timeout,
+                        max_scan,
+                        max_limit,
+                    ) {
+                        Ok(counted) => {
+                            return ExtensionMessageResult::Stop(match counted.hll {
+                                Some(hll) => {
+                                    OutgoingMessage::count_hll(&sub.id, counted.size, &hll.to_hex())
+                                }
+                                None if counted.approximate => {
+                                    OutgoingMessage::count_approximate(&sub.id, counted.size)
+                                }
+                                None => OutgoingMessage::count(&sub.id, counted.size),
+                            })
                         }
                         Err(err) => {
                             return ExtensionMessageResult::Stop(OutgoingMessage::closed(
@@ -115,6 +258,10 @@ mod tests {
     #[derive(Deserialize, Default, Debug)]
     struct CountResult {
         pub count: u64,
+        #[serde(default)]
+        pub hll: Option<String>,
+        #[serde(default)]
+        pub approximate: bool,
     }
 
     #[actix_rt::test]
@@ -136,8 +283,9 @@ mod tests {
         let db = app.db.clone();
         let app = app.add_extension(Count::new(db));
         let app = web::Data::new(app);
+        let data = app.clone();
 
-        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut srv = actix_test::start(move || create_web_app(data.clone()));
 
         // client service
         let mut framed = srv.ws_at("/").await.unwrap();
@@ -174,6 +322,20 @@ mod tests {
         let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
         assert_eq!(res.2.count, 3);
 
+        // clamp to max_limit
+        app.setting.write().limitation.max_limit = 5;
+        framed
+            .send(ws::Message::Text(r#"["COUNT", "1", {"limit": 8}]"#.into()))
+            .await?;
+        let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.2.count, 5);
+
+        framed
+            .send(ws::Message::Text(r#"["COUNT", "1", {"limit": 2}]"#.into()))
+            .await?;
+        let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.2.count, 2);
+
         // close
         framed
             .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
@@ -183,4 +345,218 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn shadowban() -> Result<()> {
+        let app = create_test_app("count_shadowban")?;
+        let db = app.db.clone();
+        let events = (1..4u8)
+            .map(|i| Event::new([i; 32], [i; 32], 10, 1, vec![], "".to_owned(), [0; 64]))
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        db.shadowban(&[2; 32])?;
+        let count = Count::new(db);
+        let filter = Filter::default();
+        assert_eq!(count.count(&filter, None, false, None, None, 100)?.size, 2);
+        assert_eq!(
+            count
+                .count(&filter, Some(&[1; 32]), false, None, None, 100)?
+                .size,
+            2
+        );
+        // the author sees the own events
+        assert_eq!(
+            count
+                .count(&filter, Some(&[2; 32]), false, None, None, 100)?
+                .size,
+            3
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn protect_reads() -> Result<()> {
+        let app = create_test_app("count_protect_reads")?;
+        let db = app.db.clone();
+        let events = (1..4u8)
+            .map(|i| {
+                let tags = if i == 2 {
+                    vec![vec!["-".to_owned()]]
+                } else {
+                    vec![]
+                };
+                Event::new([i; 32], [i; 32], 10, 1, tags, "".to_owned(), [0; 64])
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let count = Count::new(db);
+        let filter = Filter::default();
+        assert_eq!(count.count(&filter, None, false, None, None, 100)?.size, 3);
+        assert_eq!(count.count(&filter, None, true, None, None, 100)?.size, 2);
+        assert_eq!(
+            count
+                .count(&filter, Some(&[1; 32]), true, None, None, 100)?
+                .size,
+            2
+        );
+        // the author counts the own protected events
+        assert_eq!(
+            count
+                .count(&filter, Some(&[2; 32]), true, None, None, 100)?
+                .size,
+            3
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn approximate() -> Result<()> {
+        let app = create_test_app("count_approximate")?;
+        let db = app.db.clone();
+        // one event per second
+        let events = (0..50u8)
+            .map(|i| {
+                Event::new(
+                    [i; 32],
+                    [1; 32],
+                    i as u64 + 1,
+                    1,
+                    vec![],
+                    "".to_owned(),
+                    [0; 64],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let filter = Filter {
+            kinds: vec![1].into(),
+            ..Default::default()
+        };
+        let count = |approx_threshold: u64| {
+            let mut count = Count::new(db.clone());
+            count.setting = CountSetting {
+                enabled: true,
+                approximate: true,
+                approx_threshold,
+                ..Default::default()
+            };
+            count.count(&filter, None, false, None, None, 100)
+        };
+
+        // exact at the threshold, one index entry per event
+        let counted = count(50)?;
+        assert!(!counted.approximate);
+        assert_eq!(counted.size, 50);
+
+        // estimated over the threshold
+        let counted = count(49)?;
+        assert!(counted.approximate);
+        assert!(counted.size.abs_diff(50) < 5, "{}", counted.size);
+
+        let counted = count(25)?;
+        assert!(counted.approximate);
+        assert!(counted.size.abs_diff(50) < 10, "{}", counted.size);
+
+        // the flag in the response
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "count": {
+                    "enabled": true,
+                    "approximate": true,
+                    "approx_threshold": 49
+                }
+            }"#,
+            )?;
+        }
+        let app = web::Data::new(app.add_extension(Count::new(db)));
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(
+                r#"["COUNT", "1", {"kinds": [1]}]"#.into(),
+            ))
+            .await?;
+        let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(res.2.approximate);
+        framed
+            .send(ws::Message::Text(
+                r#"["COUNT", "1", {"kinds": [1], "until": 40}]"#.into(),
+            ))
+            .await?;
+        let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(!res.2.approximate);
+        assert_eq!(res.2.count, 40);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn hll() -> Result<()> {
+        let mut rng = thread_rng();
+        let app = create_test_app("count_hll")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "count": {
+                    "enabled": true,
+                    "hll": true
+                }
+            }"#,
+            )?;
+        }
+        let db = app.db.clone();
+        let app = app.add_extension(Count::new(db));
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        // reactions of 30 users to a pubkey
+        let target = Keypair::new_global(&mut rng)
+            .x_only_public_key()
+            .0
+            .to_string();
+        for _ in 0..30 {
+            let event = Event::create(
+                &Keypair::new_global(&mut rng),
+                now(),
+                7,
+                vec![vec!["p".to_owned(), target.clone()]],
+                "+".to_owned(),
+            )?;
+            let msg = format!(r#"["EVENT", {}]"#, event);
+            framed.send(ws::Message::Text(msg.into())).await?;
+            let notice: (String, String, bool, String) =
+                parse_text(&framed.next().await.unwrap()?)?;
+            assert!(notice.2);
+        }
+
+        framed
+            .send(ws::Message::Text(
+                format!(
+                    r##"["COUNT", "1", {{"kinds": [7], "#p": ["{}"]}}]"##,
+                    target
+                )
+                .into(),
+            ))
+            .await?;
+        let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.2.count, 30);
+        let hll = res.2.hll.unwrap();
+        assert_eq!(hll.len(), 512);
+        let estimate = Hll::from_hex(&hll)?.estimate();
+        assert!((25..=35).contains(&estimate), "{}", estimate);
+
+        // no tag in the filter
+        framed
+            .send(ws::Message::Text(
+                r#"["COUNT", "1", {"kinds": [7]}]"#.into(),
+            ))
+            .await?;
+        let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.2.count, 30);
+        assert!(res.2.hll.is_none());
+        Ok(())
+    }
 }
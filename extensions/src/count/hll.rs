@@ -0,0 +1,172 @@
+//! HyperLogLog of [NIP-45](https://nips.be/45), 256 registers of one byte,
+//! the COUNT results of several relays can be merged by the registers.
+
+use nostr_relay::db::{is_hex_tag, Filter};
+
+const M: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hll {
+    registers: [u8; M],
+}
+
+impl Default for Hll {
+    fn default() -> Self {
+        Self { registers: [0; M] }
+    }
+}
+
+impl Hll {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The offset of the pubkey bytes, read from the hex char at position 32 of the first
+    /// tag value of the filter plus 8. None if the filter has no such tag value.
+    pub fn offset(filter: &Filter) -> Option<usize> {
+        let mut tags = filter.tags.iter().collect::<Vec<_>>();
+        tags.sort_by(|a, b| a.0.cmp(b.0));
+        let (key, values) = tags.first()?;
+        let value = values.first()?;
+        let nibble = if is_hex_tag(key) {
+            // the hex values are decoded, position 32 is the high half of byte 16
+            value.get(16)? >> 4
+        } else {
+            (*value.get(32)? as char).to_digit(16)? as u8
+        };
+        Some(nibble as usize + 8)
+    }
+
+    /// Add the pubkey of an event, offset is between 8 and 23
+    pub fn add(&mut self, pubkey: &[u8; 32], offset: usize) {
+        let index = pubkey[offset] as usize;
+        let bytes: [u8; 8] = pubkey[offset + 1..offset + 9].try_into().unwrap();
+        let count = u64::from_be_bytes(bytes).leading_zeros() as u8 + 1;
+        if count > self.registers[index] {
+            self.registers[index] = count;
+        }
+    }
+
+    /// Add an event by the uid, the sequential uid is hashed first
+    pub fn add_uid(&mut self, uid: u64) {
+        // splitmix64 finalizer
+        let mut z = uid.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        let index = (z >> 56) as usize;
+        // the low 56 bits
+        let count = ((z << 8) | 0xff).leading_zeros() as u8 + 1;
+        if count > self.registers[index] {
+            self.registers[index] = count;
+        }
+    }
+
+    /// Union with another hll
+    pub fn merge(&mut self, other: &Hll) {
+        for (r, o) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *o > *r {
+                *r = *o;
+            }
+        }
+    }
+
+    /// The estimated count
+    pub fn estimate(&self) -> u64 {
+        let m = M as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // small range correction by linear counting
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    /// The hex of the registers, the `hll` field of the COUNT response
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.registers)
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, hex::FromHexError> {
+        let mut registers = [0; M];
+        hex::decode_to_slice(s, &mut registers)?;
+        Ok(Self { registers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // deterministic pseudo-random pubkeys
+    fn pubkeys(skip: usize, n: usize) -> Vec<[u8; 32]> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        (0..skip + n)
+            .map(|_| {
+                let mut key = [0u8; 32];
+                for chunk in key.chunks_mut(8) {
+                    chunk.copy_from_slice(&next().to_be_bytes());
+                }
+                key
+            })
+            .skip(skip)
+            .collect()
+    }
+
+    #[test]
+    fn offset() {
+        let p = format!("{}{}", "0".repeat(32), "a".repeat(32));
+        let filter = Filter::from_str(&format!(r##"{{"#p": ["{}"]}}"##, p)).unwrap();
+        assert_eq!(Hll::offset(&filter), Some(18));
+        let filter = Filter::from_str(&format!(r##"{{"#t": ["{}"]}}"##, p)).unwrap();
+        assert_eq!(Hll::offset(&filter), Some(18));
+        let filter = Filter::from_str(r##"{"#t": ["nostr"]}"##).unwrap();
+        assert_eq!(Hll::offset(&filter), None);
+        assert_eq!(Hll::offset(&Filter::default()), None);
+    }
+
+    #[test]
+    fn estimate_merge() -> anyhow::Result<()> {
+        let mut a = Hll::new();
+        let mut b = Hll::new();
+        assert_eq!(a.estimate(), 0);
+        for key in pubkeys(0, 600) {
+            a.add(&key, 10);
+        }
+        for key in pubkeys(400, 600) {
+            b.add(&key, 10);
+        }
+        // duplicates are not counted
+        for key in pubkeys(400, 100) {
+            b.add(&key, 10);
+        }
+        let near = |count: u64, expected: u64| count.abs_diff(expected) < expected * 15 / 100;
+        assert!(near(a.estimate(), 600), "{}", a.estimate());
+        assert!(near(b.estimate(), 600), "{}", b.estimate());
+        a.merge(&Hll::from_hex(&b.to_hex())?);
+        assert!(near(a.estimate(), 1000), "{}", a.estimate());
+        assert!(Hll::from_hex("00").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_uid() {
+        let mut hll = Hll::new();
+        for uid in 0..2000 {
+            hll.add_uid(uid);
+        }
+        let estimate = hll.estimate();
+        assert!(estimate.abs_diff(2000) < 300, "{}", estimate);
+    }
+}
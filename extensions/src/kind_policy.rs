@@ -0,0 +1,143 @@
+use nostr_relay::{
+    message::{event_rejected, ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use serde::Deserialize;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct KindPolicySetting {
+    /// only the kinds are allowed to write, empty allows all
+    pub allow: Vec<u32>,
+    /// the kinds are denied to write, takes precedence over allow
+    pub deny: Vec<u32>,
+}
+
+impl KindPolicySetting {
+    pub fn allowed(&self, kind: u32) -> bool {
+        !self.deny.contains(&kind) && (self.allow.is_empty() || self.allow.contains(&kind))
+    }
+}
+
+/// Allow or deny writing the event kinds
+#[derive(Default, Debug)]
+pub struct KindPolicy {
+    setting: KindPolicySetting,
+}
+
+impl KindPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Extension for KindPolicy {
+    fn name(&self) -> &'static str {
+        "kind_policy"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        self.setting = setting.read().parse_extension(self.name());
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if let IncomingMessage::Event(event) = &msg.msg {
+            if !self.setting.allowed(event.kind()) {
+                event_rejected("kind_blocked");
+                return OutgoingMessage::ok(&event.id_str(), false, "blocked: kind not allowed")
+                    .into();
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web::web;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::create_web_app;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, Keypair},
+        Event,
+    };
+
+    fn parse_text<T: serde::de::DeserializeOwned>(frame: &ws::Frame) -> Result<T> {
+        if let ws::Frame::Text(text) = &frame {
+            let data: T = serde_json::from_slice(text)?;
+            Ok(data)
+        } else {
+            Err(nostr_relay::Error::Message("invalid frame type".to_string()).into())
+        }
+    }
+
+    #[test]
+    fn allowed() {
+        let setting = |allow: Vec<u32>, deny: Vec<u32>| KindPolicySetting { allow, deny };
+        // allow list only
+        let allow = setting(vec![1, 7], vec![]);
+        assert!(allow.allowed(1));
+        assert!(allow.allowed(7));
+        assert!(!allow.allowed(4));
+
+        // deny list only
+        let deny = setting(vec![], vec![4]);
+        assert!(!deny.allowed(4));
+        assert!(deny.allowed(1));
+        assert!(deny.allowed(30023));
+
+        // deny takes precedence over allow
+        let both = setting(vec![1, 4], vec![4]);
+        assert!(both.allowed(1));
+        assert!(!both.allowed(4));
+        assert!(!both.allowed(7));
+
+        assert!(KindPolicySetting::default().allowed(4));
+    }
+
+    #[actix_rt::test]
+    async fn message() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let app = create_test_app("kind_policy")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "kind_policy": {
+                    "allow": [1, 7],
+                    "deny": [4]
+                }
+            }"#,
+            )?;
+        }
+        let app = app.add_extension(KindPolicy::new());
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        for (kind, ok) in [(1, true), (7, true), (4, false), (3, false)] {
+            let event = Event::create(&key_pair, now(), kind, vec![], "test".to_owned())?;
+            framed
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+                .await?;
+            let notice: (String, String, bool, String) =
+                parse_text(&framed.next().await.unwrap()?)?;
+            assert_eq!(notice.2, ok, "kind {}", kind);
+            if !ok {
+                assert_eq!(notice.3, "blocked: kind not allowed");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 pub mod auth;
 pub use auth::Auth;
 
+pub mod kind_policy;
+pub use kind_policy::KindPolicy;
+
 #[cfg(feature = "metrics")]
 pub mod metrics;
 #[cfg(feature = "metrics")]
@@ -1,13 +1,18 @@
 use actix_web::{web, HttpResponse};
-use metrics::{describe_counter, describe_gauge, describe_histogram};
+#[cfg(feature = "index_stats")]
+use metrics::counter;
+use metrics::{describe_counter, describe_gauge, describe_histogram, gauge};
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 use nostr_relay::{setting::SettingWrapper, App, Extension};
 use serde::Deserialize;
+use tracing::warn;
 
 #[derive(Deserialize, Default, Debug)]
 pub struct MetricsSetting {
     pub enabled: bool,
     pub auth: Option<String>,
+    /// log a warning when the used ratio of the database map is above this, 0 to 1
+    pub map_used_warning: Option<f64>,
 }
 
 pub struct Metrics {
@@ -62,8 +67,29 @@ pub fn describe_metrics() {
         "The total count of message from client"
     );
     describe_counter!("nostr_relay_new_event", "The total count of new event");
+    describe_counter!(
+        "nostr_relay_event_rejected_total",
+        "The total count of rejected events by reason"
+    );
+    describe_counter!(
+        "nostr_relay_message_dropped_total",
+        "The total count of subscription messages dropped by the full session mailbox"
+    );
     describe_histogram!("nostr_relay_db_get", "The time of per filter get");
+    describe_counter!(
+        "nostr_relay_query_cache_hit",
+        "The total count of filters served from the query cache"
+    );
     describe_histogram!("nostr_relay_db_write", "The time of per write transaction");
+    describe_gauge!(
+        "nostr_relay_db_map_used_ratio",
+        "The ratio of the used pages to the database memory map"
+    );
+    #[cfg(feature = "index_stats")]
+    describe_counter!(
+        "nostr_relay_db_index_query_total",
+        "The total count of queries by index path"
+    );
 }
 
 pub fn create_prometheus_handle() -> PrometheusHandle {
@@ -90,6 +116,23 @@ async fn route_metrics(
     let setting = app.setting.read();
     if let Some(s) = setting.get_extension::<MetricsSetting>() {
         if s.enabled && s.auth == query.auth {
+            // update the map utilization on each scrape
+            match app.db.env_info() {
+                Ok(info) => {
+                    let ratio = info.used_ratio();
+                    gauge!("nostr_relay_db_map_used_ratio").set(ratio);
+                    if s.map_used_warning.is_some_and(|max| ratio > max) {
+                        warn!(ratio, "database map is filling up");
+                    }
+                }
+                Err(err) => {
+                    warn!(error = err.to_string(), "get database info error");
+                }
+            }
+            #[cfg(feature = "index_stats")]
+            for (index, count) in app.db.index_usage().paths() {
+                counter!("nostr_relay_db_index_query_total", "index" => index).absolute(count);
+            }
             return Ok(HttpResponse::Ok()
                 .insert_header(("Content-Type", "text/plain"))
                 .body(handle.render()));
@@ -140,6 +183,7 @@ pub mod tests {
         let result = read_body(res).await;
         let result = String::from_utf8(result.to_vec())?;
         assert!(result.contains("test_metric"));
+        assert!(result.contains("nostr_relay_db_map_used_ratio"));
         Ok(())
     }
 }
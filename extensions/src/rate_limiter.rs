@@ -5,7 +5,7 @@ use metrics::{counter, describe_counter};
 use nostr_relay::db::Event;
 use nostr_relay::{
     duration::NonZeroDuration,
-    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    message::{event_rejected, ClientMessage, IncomingMessage, OutgoingMessage},
     setting::SettingWrapper,
     Extension, ExtensionMessageResult, Session,
 };
@@ -224,6 +224,7 @@ impl Extension for Ratelimiter {
                     let q = &self.setting.event[index];
                     if q.hit(event, ip) && limiter.check_key(ip).is_err() {
                         counter!("nostr_relay_rate_limiter_exceeded", "command" => "EVENT", "name" => q.name.clone()).increment(1);
+                        event_rejected("rate_limited");
                         return OutgoingMessage::ok(
                             &event.id_str(),
                             false,
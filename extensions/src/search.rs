@@ -1,4 +1,5 @@
 use nostr_relay::{
+    db::SegmentOptions,
     message::{ClientMessage, IncomingMessage},
     setting::SettingWrapper,
     Extension, ExtensionMessageResult, Session,
@@ -6,13 +7,28 @@ use nostr_relay::{
 use serde::Deserialize;
 
 #[derive(Deserialize, Default, Debug)]
+#[serde(default)]
 pub struct SearchSetting {
     pub enabled: bool,
+    /// skip the words shorter than this in chars
+    pub min_len: usize,
+    /// skip the words, case-insensitive
+    pub stopwords: Vec<String>,
+}
+
+impl SearchSetting {
+    pub fn segment_options(&self) -> SegmentOptions {
+        SegmentOptions {
+            min_len: self.min_len,
+            stopwords: self.stopwords.iter().map(|s| s.to_lowercase()).collect(),
+        }
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct Search {
     setting: SearchSetting,
+    options: SegmentOptions,
 }
 
 impl Search {
@@ -29,6 +45,7 @@ impl Extension for Search {
     fn setting(&mut self, setting: &SettingWrapper) {
         let mut w = setting.write();
         self.setting = w.parse_extension(self.name());
+        self.options = self.setting.segment_options();
         if self.setting.enabled {
             w.add_nip(50);
         }
@@ -43,11 +60,11 @@ impl Extension for Search {
         if self.setting.enabled {
             match &mut msg.msg {
                 IncomingMessage::Event(event) => {
-                    event.build_note_words();
+                    event.build_note_words_with(&self.options);
                 }
                 IncomingMessage::Req(sub) => {
                     for filter in &mut sub.filters {
-                        filter.build_words();
+                        filter.build_words_with(&self.options);
                     }
                 }
                 _ => {}
@@ -83,6 +100,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn segment_options() -> Result<()> {
+        let setting: SearchSetting = serde_json::from_str(
+            r#"{
+                "enabled": true,
+                "min_len": 2,
+                "stopwords": ["The", "and"]
+            }"#,
+        )?;
+        let options = setting.segment_options();
+        assert_eq!(options.min_len, 2);
+        assert!(options.stopwords.contains("the"));
+        assert!(options.stopwords.contains("and"));
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn message() -> Result<()> {
         let mut rng = thread_rng();
@@ -11,4 +11,10 @@ pub enum Error {
     Message(String),
     #[error("Lmdb error: {0}")]
     Lmdb(String),
+    /// `MDB_MAP_FULL`, the map size is reached
+    #[error("Lmdb error: MDB_MAP_FULL: Environment mapsize limit reached")]
+    MapFull,
+    /// Write to a database opened by `Db::open_read_only`
+    #[error("error: the database is opened read-only")]
+    ReadOnly,
 }
@@ -1,7 +1,10 @@
 use crate::Error;
 use libc::{c_char, c_int, c_uint, c_void, size_t, EINVAL};
 pub use lmdb_master_sys as ffi;
-use parking_lot::RwLock;
+use parking_lot::{
+    lock_api::{RawRwLock as _, RawRwLockRecursive as _, RawRwLockTimed as _},
+    RawRwLock, RwLock,
+};
 use std::{
     cmp::Ordering,
     collections::HashMap,
@@ -10,11 +13,15 @@ use std::{
     marker::PhantomData,
     mem::{self, MaybeUninit},
     ops::Bound,
-    path::Path,
+    path::{Path, PathBuf},
     ptr, slice,
     sync::Arc,
+    time::Duration,
 };
 
+/// How long [`Db::grow_map_size`] waits for the alive transactions to finish
+const RESIZE_TIMEOUT: Duration = Duration::from_secs(5);
+
 macro_rules! lmdb_try {
     ($expr:expr) => {{
         match $expr {
@@ -67,6 +74,54 @@ pub struct Tree {
 unsafe impl Send for Tree {}
 unsafe impl Sync for Tree {}
 
+/// Information about the environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvInfo {
+    /// size of the memory map
+    pub map_size: u64,
+    /// id of the last used page
+    pub last_pgno: u64,
+    /// id of the last committed transaction
+    pub last_txnid: u64,
+    /// max reader slots
+    pub max_readers: u32,
+    /// used reader slots
+    pub num_readers: u32,
+    /// size of a database page
+    pub page_size: u32,
+}
+
+impl EnvInfo {
+    /// bytes of the used pages in the memory map
+    pub fn used_size(&self) -> u64 {
+        (self.last_pgno + 1) * self.page_size as u64
+    }
+
+    /// the ratio of the used pages to the memory map, between 0 and 1
+    pub fn used_ratio(&self) -> f64 {
+        if self.map_size == 0 {
+            0.0
+        } else {
+            self.used_size() as f64 / self.map_size as f64
+        }
+    }
+}
+
+/// Statistics of a tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeStat {
+    /// number of data items
+    pub entries: u64,
+    /// depth of the B-tree
+    pub depth: u32,
+    /// number of internal (non-leaf) pages
+    pub branch_pages: u64,
+    /// number of leaf pages
+    pub leaf_pages: u64,
+    /// number of overflow pages
+    pub overflow_pages: u64,
+}
+
 pub trait Transaction: Sized {
     fn txn(&self) -> *mut ffi::MDB_txn;
 
@@ -109,16 +164,68 @@ pub trait Transaction: Sized {
     fn iter(&self, tree: &Tree) -> Iter {
         self.iter_from(tree, Bound::Unbounded::<Vec<u8>>, false)
     }
+
+    /// Statistics of the tree in this transaction
+    fn tree_stat(&self, tree: &Tree) -> Result<TreeStat> {
+        let mut stat = MaybeUninit::<ffi::MDB_stat>::uninit();
+        let stat = unsafe {
+            lmdb_result(ffi::mdb_stat(self.txn(), tree.inner, stat.as_mut_ptr()))?;
+            stat.assume_init()
+        };
+        Ok(TreeStat {
+            entries: stat.ms_entries as u64,
+            depth: stat.ms_depth,
+            branch_pages: stat.ms_branch_pages as u64,
+            leaf_pages: stat.ms_leaf_pages as u64,
+            overflow_pages: stat.ms_overflow_pages as u64,
+        })
+    }
+}
+
+/// Begin a transaction holding the shared resize lock, the map can't be resized
+/// while a transaction is alive. Recursive, a thread may hold several transactions.
+fn begin_txn(db: &DbInner, flags: c_uint) -> Result<*mut ffi::MDB_txn> {
+    db.resize.lock_shared_recursive();
+    let mut txn: *mut ffi::MDB_txn = ptr::null_mut();
+    let res = unsafe {
+        lmdb_result(ffi::mdb_txn_begin(
+            db.inner,
+            ptr::null_mut(),
+            flags,
+            &mut txn,
+        ))
+    };
+    if let Err(err) = res {
+        unsafe { db.resize.unlock_shared() };
+        return Err(err);
+    }
+    Ok(txn)
+}
+
+/// Commit the transaction and release the resize lock
+fn commit_txn(txn: *mut ffi::MDB_txn, resize: &RawRwLock) -> Result<()> {
+    let result = unsafe { lmdb_result(ffi::mdb_txn_commit(txn)) };
+    unsafe { resize.unlock_shared() };
+    result
 }
 
 pub struct Reader<'env> {
     inner: *mut ffi::MDB_txn,
-    _marker: PhantomData<&'env Db>,
+    resize: &'env RawRwLock,
+    // renewed from a ResetReader, reset instead of freed at the end
+    renewed: bool,
 }
 
 impl<'env> Drop for Reader<'env> {
     fn drop(&mut self) {
-        unsafe { ffi::mdb_txn_abort(self.inner) }
+        unsafe {
+            if self.renewed {
+                ffi::mdb_txn_reset(self.inner);
+            } else {
+                ffi::mdb_txn_abort(self.inner);
+            }
+            self.resize.unlock_shared();
+        }
     }
 }
 
@@ -126,34 +233,84 @@ impl<'env> Transaction for Reader<'env> {
     fn txn(&self) -> *mut ffi::MDB_txn {
         self.inner
     }
+
+    fn commit(self) -> Result<()> {
+        // nothing to commit in a renewed read transaction, reset it for the next renew
+        if self.renewed {
+            return Ok(());
+        }
+        let (txn, resize) = (self.inner, self.resize);
+        mem::forget(self);
+        commit_txn(txn, resize)
+    }
 }
 
 impl<'env> Reader<'env> {
     fn new(db: &'env DbInner) -> Result<Self> {
-        let mut txn: *mut ffi::MDB_txn = ptr::null_mut();
+        Ok(Self {
+            inner: begin_txn(db, ffi::MDB_RDONLY)?,
+            resize: &db.resize,
+            renewed: false,
+        })
+    }
+}
+
+/// A reset read transaction kept by a thread and renewed for each read, see [`Db::reset_reader`].
+///
+/// The environment is opened without `MDB_NOTLS`, LMDB binds the reader slot to the thread,
+/// so it is not `Send`. A thread has one reader slot, only one of its read transactions can be
+/// alive at a time, don't open another reader while the renewed [`Reader`] is alive.
+pub struct ResetReader {
+    inner: *mut ffi::MDB_txn,
+    db: Arc<DbInner>,
+}
+
+impl Drop for ResetReader {
+    fn drop(&mut self) {
+        unsafe { ffi::mdb_txn_abort(self.inner) }
+    }
+}
+
+impl ResetReader {
+    fn new(db: &Arc<DbInner>) -> Result<Self> {
+        let txn = begin_txn(db, ffi::MDB_RDONLY)?;
+        // the reset transaction doesn't hold a snapshot, the map can be resized
         unsafe {
-            lmdb_result(ffi::mdb_txn_begin(
-                db.inner,
-                ptr::null_mut(),
-                ffi::MDB_RDONLY,
-                &mut txn,
-            ))?;
+            ffi::mdb_txn_reset(txn);
+            db.resize.unlock_shared();
         }
         Ok(Self {
             inner: txn,
-            _marker: PhantomData,
+            db: Arc::clone(db),
+        })
+    }
+
+    /// Renew the transaction with the latest snapshot, the returned reader resets it on drop
+    pub fn renew(&mut self) -> Result<Reader<'_>> {
+        self.db.resize.lock_shared_recursive();
+        if let Err(err) = unsafe { lmdb_result(ffi::mdb_txn_renew(self.inner)) } {
+            unsafe { self.db.resize.unlock_shared() };
+            return Err(err);
+        }
+        Ok(Reader {
+            inner: self.inner,
+            resize: &self.db.resize,
+            renewed: true,
         })
     }
 }
 
 pub struct Writer<'env> {
     inner: *mut ffi::MDB_txn,
-    _marker: PhantomData<&'env Db>,
+    resize: &'env RawRwLock,
 }
 
 impl<'env> Drop for Writer<'env> {
     fn drop(&mut self) {
-        unsafe { ffi::mdb_txn_abort(self.inner) }
+        unsafe {
+            ffi::mdb_txn_abort(self.inner);
+            self.resize.unlock_shared();
+        }
     }
 }
 
@@ -161,17 +318,22 @@ impl<'env> Transaction for Writer<'env> {
     fn txn(&self) -> *mut ffi::MDB_txn {
         self.inner
     }
+
+    fn commit(self) -> Result<()> {
+        let (txn, resize) = (self.inner, self.resize);
+        mem::forget(self);
+        commit_txn(txn, resize)
+    }
 }
 
 impl<'env> Writer<'env> {
     fn new(db: &'env DbInner) -> Result<Self> {
-        let mut txn: *mut ffi::MDB_txn = ptr::null_mut();
-        unsafe {
-            lmdb_result(ffi::mdb_txn_begin(db.inner, ptr::null_mut(), 0, &mut txn))?;
+        if db.read_only {
+            return Err(Error::ReadOnly);
         }
         Ok(Self {
-            inner: txn,
-            _marker: PhantomData,
+            inner: begin_txn(db, 0)?,
+            resize: &db.resize,
         })
     }
 
@@ -180,7 +342,17 @@ impl<'env> Writer<'env> {
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
-        let flags = 0;
+        self.put_flags(tree, key, value, 0)
+    }
+
+    /// Put with the `mdb_put` flags, such as `MDB_APPEND` to skip the B-tree search when the keys
+    /// are written in ascending order, or `MDB_NODUPDATA` to keep the existing duplicate.
+    /// An out of order append fails with the `MDB_KEYEXIST` error.
+    pub fn put_flags<K, V>(&mut self, tree: &Tree, key: K, value: V, flags: c_uint) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
         let key = key.as_ref();
         let value = value.as_ref();
 
@@ -239,6 +411,9 @@ fn to_cpath<P: AsRef<Path>>(path: P) -> Result<CString, Error> {
 struct DbInner {
     inner: *mut ffi::MDB_env,
     dbs: RwLock<HashMap<Option<String>, Dbi>>,
+    // shared by the alive transactions, exclusive to resize the map
+    resize: RawRwLock,
+    read_only: bool,
 }
 
 impl Drop for DbInner {
@@ -259,11 +434,14 @@ impl DbInner {
 
         let path = path.as_ref();
         let c_path = to_cpath(path)?;
+        let read_only = flag & ffi::MDB_RDONLY == ffi::MDB_RDONLY;
 
-        if let Err(e) = fs::create_dir_all(path) {
-            return Err(Error::Message(format!(
-                "Failed to create LMDB directory: `{e:?}`."
-            )));
+        if !read_only {
+            if let Err(e) = fs::create_dir_all(path) {
+                return Err(Error::Message(format!(
+                    "Failed to create LMDB directory: `{e:?}`."
+                )));
+            }
         }
 
         let mut env: *mut ffi::MDB_env = ptr::null_mut();
@@ -300,6 +478,8 @@ impl DbInner {
         Ok(Self {
             inner: env,
             dbs: RwLock::new(HashMap::new()),
+            resize: RawRwLock::INIT,
+            read_only,
         })
     }
 
@@ -325,6 +505,16 @@ impl DbInner {
             });
         }
 
+        // open the existing tree in a read-only environment
+        if self.read_only {
+            let reader = Reader::new(self)?;
+            let dbi = Dbi::new(reader.inner, name, flags)?;
+            let inner = dbi.inner;
+            reader.commit()?;
+            dbs.insert(sname, dbi);
+            return Ok(Tree { flags, inner });
+        }
+
         // create
         let writer = Writer::new(self)?;
         let flags = ffi::MDB_CREATE | flags;
@@ -375,6 +565,23 @@ impl Db {
         Self::open_with(path, Some(20), Some(100), Some(1_000_000_000_000), 0)
     }
 
+    /// Open an existing database for reading only (`MDB_RDONLY`), the directory is not created
+    /// and [`Db::writer`] returns [`Error::ReadOnly`].
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(
+            path,
+            Some(20),
+            Some(100),
+            Some(1_000_000_000_000),
+            ffi::MDB_RDONLY,
+        )
+    }
+
+    /// The database is opened by [`Db::open_read_only`] or with the `MDB_RDONLY` flag
+    pub fn is_read_only(&self) -> bool {
+        self.inner.read_only
+    }
+
     pub fn open_with<P: AsRef<Path>>(
         path: P,
         maxdbs: Option<u32>,
@@ -391,12 +598,151 @@ impl Db {
         Reader::new(&self.inner)
     }
 
+    /// A read transaction to keep in a reader thread and renew for each read,
+    /// it saves the allocation and the reader slot lookup of a new transaction.
+    pub fn reset_reader(&self) -> Result<ResetReader> {
+        ResetReader::new(&self.inner)
+    }
+
     pub fn flush(&self) -> Result<()> {
         unsafe {
             lmdb_result(ffi::mdb_env_sync(self.inner.inner, 1))?;
         }
         Ok(())
     }
+
+    /// Copy a consistent snapshot of the environment to an existing empty directory,
+    /// free pages are omitted when compact. Writers are not blocked during the copy.
+    pub fn copy_to<P: AsRef<Path>>(&self, path: P, compact: bool) -> Result<()> {
+        let c_path = to_cpath(path)?;
+        let flags = if compact { ffi::MDB_CP_COMPACT } else { 0 };
+        unsafe {
+            lmdb_result(ffi::mdb_env_copy2(self.inner.inner, c_path.as_ptr(), flags))?;
+        }
+        Ok(())
+    }
+
+    /// Online backup to a directory, created if missing, see [`Db::copy_to`]
+    pub fn backup<P: AsRef<Path>>(&self, dest: P, compact: bool) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest).map_err(|e| Error::Message(e.to_string()))?;
+        self.copy_to(dest, compact)
+    }
+
+    /// Double the map size up to the max, return false if it is already the max or the alive
+    /// transactions don't finish in time. LMDB can't resize the map while a transaction of
+    /// this process is alive. The new transactions don't wait for a pending resize, the resize
+    /// fails under a continuous overlapping read load.
+    pub fn grow_map_size(&self, max: usize) -> Result<bool> {
+        let current = self.env_info()?.map_size as usize;
+        if current >= max {
+            return Ok(false);
+        }
+        let size = current.saturating_mul(2).min(max);
+        if !self.inner.resize.try_lock_exclusive_for(RESIZE_TIMEOUT) {
+            return Ok(false);
+        }
+        let res = unsafe { lmdb_result(ffi::mdb_env_set_mapsize(self.inner.inner, size)) };
+        unsafe { self.inner.resize.unlock_exclusive() };
+        res?;
+        Ok(true)
+    }
+
+    /// Run the closure in a write transaction and commit. When the map is full, grow the map
+    /// size by [`Db::grow_map_size`] up to `max_map_size` and retry, the closure may run again.
+    pub fn write<T, F>(&self, max_map_size: Option<usize>, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Writer) -> Result<T>,
+    {
+        loop {
+            let res = {
+                let mut writer = self.writer()?;
+                f(&mut writer).and_then(|v| writer.commit().map(|_| v))
+            };
+            match res {
+                Err(Error::MapFull) => match max_map_size {
+                    Some(max) if self.grow_map_size(max)? => continue,
+                    _ => return Err(Error::MapFull),
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Information about the environment, such as the map size and the used pages
+    pub fn env_info(&self) -> Result<EnvInfo> {
+        let mut info = MaybeUninit::<ffi::MDB_envinfo>::uninit();
+        let mut stat = MaybeUninit::<ffi::MDB_stat>::uninit();
+        let (info, stat) = unsafe {
+            lmdb_result(ffi::mdb_env_info(self.inner.inner, info.as_mut_ptr()))?;
+            lmdb_result(ffi::mdb_env_stat(self.inner.inner, stat.as_mut_ptr()))?;
+            (info.assume_init(), stat.assume_init())
+        };
+        Ok(EnvInfo {
+            map_size: info.me_mapsize as u64,
+            last_pgno: info.me_last_pgno as u64,
+            last_txnid: info.me_last_txnid as u64,
+            max_readers: info.me_maxreaders,
+            num_readers: info.me_numreaders,
+            page_size: stat.ms_psize,
+        })
+    }
+
+    /// The environment flags the database is opened with
+    pub fn flags(&self) -> Result<c_uint> {
+        let mut flags: c_uint = 0;
+        unsafe {
+            lmdb_result(ffi::mdb_env_get_flags(self.inner.inner, &mut flags))?;
+        }
+        Ok(flags)
+    }
+
+    /// The path of the data file
+    pub fn data_path(&self) -> Result<PathBuf> {
+        let mut path: *const c_char = ptr::null();
+        let path = unsafe {
+            lmdb_result(ffi::mdb_env_get_path(self.inner.inner, &mut path))?;
+            PathBuf::from(CStr::from_ptr(path).to_string_lossy().into_owned())
+        };
+        if self.flags()? & ffi::MDB_NOSUBDIR == ffi::MDB_NOSUBDIR {
+            Ok(path)
+        } else {
+            Ok(path.join("data.mdb"))
+        }
+    }
+
+    /// Advise the kernel to back the memory map with transparent huge pages.
+    /// No-op on unsupported platforms.
+    pub fn advise_huge_pages(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let path =
+                fs::canonicalize(self.data_path()?).map_err(|e| Error::Message(e.to_string()))?;
+            let path = path.to_string_lossy();
+            let maps =
+                fs::read_to_string("/proc/self/maps").map_err(|e| Error::Message(e.to_string()))?;
+            for line in maps.lines() {
+                // 7f3a5c000000-7f3a5c021000 r--s 00000000 fd:01 1234 /path/data.mdb
+                let name = line.find('/').map(|pos| line[pos..].trim_end());
+                if name != Some(path.as_ref()) {
+                    continue;
+                }
+                let range = line.split(' ').next().and_then(|r| r.split_once('-'));
+                if let Some((start, end)) = range {
+                    let parse =
+                        |v| usize::from_str_radix(v, 16).map_err(|e| Error::Message(e.to_string()));
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    let res = unsafe {
+                        libc::madvise(start as *mut c_void, end - start, libc::MADV_HUGEPAGE)
+                    };
+                    if res != 0 {
+                        return Err(Error::Message(std::io::Error::last_os_error().to_string()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Iter<'txn> {
@@ -475,6 +821,8 @@ impl<'txn> Iter<'txn> {
                             Ok(Some((key, _))) => {
                                 if key >= start.as_ref() {
                                     if self.dup {
+                                        // skip the duplicates of the current key,
+                                        // lands on the last duplicate of the previous key
                                         self.op = ffi::MDB_PREV_NODUP;
                                     } else {
                                         self.op = ffi::MDB_PREV;
@@ -533,6 +881,32 @@ impl<'txn> Iter<'txn> {
     }
 }
 
+impl<'txn> Iter<'txn> {
+    /// Count the duplicates of the current key by `mdb_cursor_count`,
+    /// always 1 if the tree isn't dup sorted.
+    pub fn count_dup(&self) -> Result<u64> {
+        match &self.inner {
+            Some(inner) if self.dup => inner.count(),
+            Some(_) => Ok(1),
+            None => Err(self
+                .err
+                .clone()
+                .unwrap_or(Error::Message("cursor is not positioned".to_owned()))),
+        }
+    }
+
+    /// Skip the remaining duplicates of the current key on the next item.
+    pub fn skip_dup(&mut self) {
+        if self.dup {
+            self.op = if self.rev {
+                ffi::MDB_PREV_NODUP
+            } else {
+                ffi::MDB_NEXT_NODUP
+            };
+        }
+    }
+}
+
 impl<'txn> Iterator for Iter<'txn> {
     type Item = Result<(&'txn [u8], &'txn [u8]), Error>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -548,6 +922,9 @@ impl<'txn> Iterator for Iter<'txn> {
 }
 
 fn lmdb_error(err_code: c_int) -> Error {
+    if err_code == ffi::MDB_MAP_FULL {
+        return Error::MapFull;
+    }
     unsafe {
         // This is safe since the error messages returned from mdb_strerror are static.
         let err: *const c_char = ffi::mdb_strerror(err_code) as *const c_char;
@@ -603,6 +980,14 @@ impl<'txn> IterInner<'txn> {
         }
     }
 
+    fn count(&self) -> Result<u64> {
+        let mut count: ffi::mdb_size_t = 0;
+        unsafe {
+            lmdb_result(ffi::mdb_cursor_count(self.cursor, &mut count))?;
+        }
+        Ok(count as u64)
+    }
+
     fn get(&mut self, op: c_uint) -> Item<'txn> {
         let mut key = MaybeUninit::uninit();
         let mut data = MaybeUninit::uninit();
@@ -1,6 +1,9 @@
 use anyhow::Result;
 use nostr_kv::lmdb::{ffi, Db, Transaction};
-use std::ops::{Bound, Deref};
+use std::{
+    ops::{Bound, Deref},
+    sync::{Arc, Barrier},
+};
 
 #[test]
 pub fn test_txn() -> Result<()> {
@@ -376,3 +379,381 @@ pub fn test_dup() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+pub fn test_huge_pages() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-huge-pages")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    assert_eq!(db.data_path()?, dir.path().join("data.mdb"));
+    db.advise_huge_pages()?;
+
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let mut writer = db.writer()?;
+    writer.put(&t1, b"k1", b"v1")?;
+    writer.commit()?;
+    let reader = db.reader()?;
+    assert_eq!(reader.get(&t1, "k1")?.unwrap(), b"v1");
+    Ok(())
+}
+
+#[test]
+pub fn test_copy_to() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-copy-to")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path().join("src"))?;
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let mut writer = db.writer()?;
+    writer.put(&t1, b"k1", b"v1")?;
+    writer.commit()?;
+
+    for (name, compact) in [("copy", false), ("compact", true)] {
+        let path = dir.path().join(name);
+        std::fs::create_dir(&path)?;
+        db.copy_to(&path, compact)?;
+        let copy = Db::open(&path)?;
+        let t1 = copy.open_tree(Some("t1"), 0)?;
+        let reader = copy.reader()?;
+        assert_eq!(reader.get(&t1, "k1")?.unwrap(), b"v1");
+    }
+    // the target must not contain a data file
+    assert!(db.copy_to(dir.path().join("copy"), false).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn test_backup() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-backup")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path().join("src"))?;
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let mut writer = db.writer()?;
+    writer.put(&t1, b"k1", b"v1")?;
+    writer.commit()?;
+
+    // the missing directories are created
+    let path = dir.path().join("backup").join("1");
+    db.backup(&path, true)?;
+    let copy = Db::open(&path)?;
+    let t1 = copy.open_tree(Some("t1"), 0)?;
+    let reader = copy.reader()?;
+    assert_eq!(reader.get(&t1, "k1")?.unwrap(), b"v1");
+    Ok(())
+}
+
+#[test]
+pub fn test_dup_rev_bound() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-dup-rev-bound")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let keys: Vec<&[u8]> = vec![b"b", b"d", b"d1", b"f", b"h"];
+    let mut items = vec![];
+    for (i, key) in keys.iter().enumerate() {
+        // a single value key and multiple duplicates
+        let num = if i == 2 { 1 } else { 3 };
+        for v in 0..num {
+            items.push((key.to_vec(), ((i * 10 + v) as u64).to_be_bytes().to_vec()));
+        }
+    }
+
+    for flags in [ffi::MDB_DUPSORT, ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED] {
+        let tree = db.open_tree(Some(&format!("t{}", flags)), flags)?;
+        let mut writer = db.writer()?;
+        for (k, v) in &items {
+            writer.put(&tree, k, v)?;
+        }
+        writer.commit()?;
+
+        let reader = db.reader()?;
+        let collect = |iter: nostr_kv::lmdb::Iter| -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let mut list = vec![];
+            for item in iter {
+                let (k, v) = item?;
+                list.push((k.to_vec(), v.to_vec()));
+            }
+            Ok(list)
+        };
+        let expect = |bound: Bound<&[u8]>| {
+            items
+                .iter()
+                .rev()
+                .filter(|(k, _)| match bound {
+                    Bound::Included(b) => k.as_slice() <= b,
+                    Bound::Excluded(b) => k.as_slice() < b,
+                    Bound::Unbounded => true,
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // exact keys, between keys, prefix of a key, before all and after all
+        let bounds: Vec<&[u8]> = vec![
+            b"a", b"b", b"c", b"d", b"d0", b"d1", b"d2", b"f", b"h", b"z",
+        ];
+        for b in &bounds {
+            for bound in [Bound::Excluded(*b), Bound::Included(*b)] {
+                let iter = reader.iter_from(&tree, bound, true);
+                assert_eq!(collect(iter)?, expect(bound), "{:?}", bound);
+            }
+        }
+        assert_eq!(
+            collect(reader.iter_from(&tree, Bound::Unbounded::<Vec<u8>>, true))?,
+            expect(Bound::Unbounded)
+        );
+
+        // seek again on a used iterator
+        let mut iter = reader.iter_from(&tree, Bound::Unbounded::<Vec<u8>>, true);
+        for b in &bounds {
+            iter.seek(Bound::Excluded(*b), true);
+            let mut list = vec![];
+            for item in iter.by_ref().take(4) {
+                let (k, v) = item?;
+                list.push((k.to_vec(), v.to_vec()));
+            }
+            let mut exp = expect(Bound::Excluded(*b));
+            exp.truncate(4);
+            assert_eq!(list, exp, "seek {:?}", b);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_count_dup() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-count-dup")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let tree = db.open_tree(Some("t1"), ffi::MDB_DUPSORT)?;
+    let t2 = db.open_tree(Some("t2"), 0)?;
+    let mut writer = db.writer()?;
+    for (key, num) in [(b"a", 3u8), (b"b", 1), (b"c", 5)] {
+        for v in 0..num {
+            writer.put(&tree, key, [v])?;
+        }
+        writer.put(&t2, key, [num])?;
+    }
+    writer.commit()?;
+
+    let reader = db.reader()?;
+    for rev in [false, true] {
+        let mut iter = reader.iter_from(&tree, Bound::Unbounded::<Vec<u8>>, rev);
+        let mut list = vec![];
+        while let Some(item) = iter.next() {
+            let (k, _v) = item?;
+            list.push((k.to_vec(), iter.count_dup()?));
+            iter.skip_dup();
+        }
+        let mut expect = vec![(b"a".to_vec(), 3), (b"b".to_vec(), 1), (b"c".to_vec(), 5)];
+        if rev {
+            expect.reverse();
+        }
+        assert_eq!(list, expect);
+    }
+
+    // not dup sorted
+    let mut iter = reader.iter(&t2);
+    iter.next().unwrap()?;
+    assert_eq!(iter.count_dup()?, 1);
+    iter.skip_dup();
+    assert_eq!(iter.next().unwrap()?.0, b"b");
+    Ok(())
+}
+
+#[test]
+pub fn test_stat() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-stat")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let tree = db.open_tree(Some("t1"), 0)?;
+    let dup = db.open_tree(Some("t2"), ffi::MDB_DUPSORT)?;
+    let info = db.env_info()?;
+    assert_eq!(info.map_size, 1_000_000_000_000);
+    assert_eq!(info.max_readers, 100);
+    assert!(info.page_size > 0);
+    let used = info.used_size();
+
+    let mut writer = db.writer()?;
+    for i in 0..1000u32 {
+        writer.put(&tree, i.to_be_bytes(), [1; 100])?;
+        writer.put(&dup, [(i % 10) as u8], i.to_be_bytes())?;
+    }
+    let stat = writer.tree_stat(&tree)?;
+    assert_eq!(stat.entries, 1000);
+    writer.commit()?;
+
+    let reader = db.reader()?;
+    let stat = reader.tree_stat(&tree)?;
+    assert_eq!(stat.entries, 1000);
+    assert!(stat.depth > 1);
+    assert!(stat.leaf_pages > 1);
+    assert!(stat.branch_pages > 0);
+    assert_eq!(stat.overflow_pages, 0);
+    // the duplicates are counted
+    assert_eq!(reader.tree_stat(&dup)?.entries, 1000);
+
+    let info = db.env_info()?;
+    assert!(info.used_size() > used);
+    assert!(info.last_txnid > 0);
+    assert!(info.num_readers >= 1);
+    Ok(())
+}
+
+#[test]
+pub fn test_put_flags() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-put-flags")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let tree = db.open_tree(Some("t1"), 0)?;
+    let dup = db.open_tree(Some("t2"), ffi::MDB_DUPSORT)?;
+
+    let mut writer = db.writer()?;
+    for i in 0..1000u64 {
+        writer.put_flags(&tree, i.to_be_bytes(), i.to_be_bytes(), ffi::MDB_APPEND)?;
+    }
+    // out of order
+    let err = writer
+        .put_flags(&tree, 10u64.to_be_bytes(), b"v", ffi::MDB_APPEND)
+        .unwrap_err();
+    assert!(err.to_string().contains("MDB_KEYEXIST"), "{}", err);
+    writer.commit()?;
+
+    let reader = db.reader()?;
+    assert_eq!(reader.tree_stat(&tree)?.entries, 1000);
+    assert_eq!(
+        reader.get(&tree, 10u64.to_be_bytes())?.unwrap(),
+        10u64.to_be_bytes()
+    );
+    drop(reader);
+
+    // keep the existing duplicate
+    let mut writer = db.writer()?;
+    writer.put_flags(&dup, b"k", b"v1", ffi::MDB_NODUPDATA)?;
+    let err = writer
+        .put_flags(&dup, b"k", b"v1", ffi::MDB_NODUPDATA)
+        .unwrap_err();
+    assert!(err.to_string().contains("MDB_KEYEXIST"), "{}", err);
+    writer.put_flags(&dup, b"k", b"v2", ffi::MDB_NODUPDATA)?;
+    writer.commit()?;
+    assert_eq!(db.reader()?.tree_stat(&dup)?.entries, 2);
+    Ok(())
+}
+
+#[test]
+pub fn test_grow_map_size() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-grow-map-size")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(dir.path(), Some(20), Some(100), Some(1024 * 1024), 0)?;
+    let tree = db.open_tree(Some("t1"), 0)?;
+    let value = vec![1u8; 1024];
+    let write = |writer: &mut nostr_kv::lmdb::Writer| -> Result<(), nostr_kv::Error> {
+        for i in 0..2048u64 {
+            writer.put(&tree, i.to_be_bytes(), &value)?;
+        }
+        Ok(())
+    };
+
+    // no growth
+    let err = db.write(None, write).unwrap_err();
+    assert!(matches!(err, nostr_kv::Error::MapFull), "{}", err);
+    assert_eq!(db.reader()?.tree_stat(&tree)?.entries, 0);
+
+    db.write(Some(64 * 1024 * 1024), write)?;
+    assert!(db.env_info()?.map_size > 1024 * 1024);
+    assert_eq!(db.reader()?.tree_stat(&tree)?.entries, 2048);
+    Ok(())
+}
+
+#[test]
+pub fn test_open_read_only() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-read-only")
+        .tempdir()
+        .unwrap();
+    // the directory is not created
+    assert!(Db::open_read_only(dir.path().join("none")).is_err());
+    assert!(!dir.path().join("none").exists());
+
+    let db = Db::open(dir.path())?;
+    let tree = db.open_tree(Some("t1"), 0)?;
+    let mut writer = db.writer()?;
+    writer.put(&tree, b"k1", b"v1")?;
+    writer.commit()?;
+
+    let ro = Db::open_read_only(dir.path())?;
+    assert!(ro.is_read_only());
+    assert!(!db.is_read_only());
+    let tree = ro.open_tree(Some("t1"), 0)?;
+    assert_eq!(ro.reader()?.get(&tree, b"k1")?.unwrap(), b"v1");
+    // can't create the tree
+    assert!(ro.open_tree(Some("t2"), 0).is_err());
+    assert!(matches!(ro.writer(), Err(nostr_kv::Error::ReadOnly)));
+    Ok(())
+}
+
+#[test]
+pub fn test_reset_reader() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-reset-reader")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(dir.path(), Some(20), Some(100), Some(1024 * 1024), 0)?;
+    let tree = db.open_tree(Some("t1"), 0)?;
+    let barrier = Arc::new(Barrier::new(3));
+
+    let handles = (0..3u64)
+        .map(|n| {
+            let db = db.clone();
+            let tree = tree.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || -> Result<usize> {
+                let mut txn = db.reset_reader()?;
+                let mut ptrs = vec![];
+                for i in 0..10u64 {
+                    let key = (n * 10 + i).to_be_bytes();
+                    let mut writer = db.writer()?;
+                    writer.put(&tree, key, b"v")?;
+                    writer.commit()?;
+                    // the renewed transaction sees the latest write
+                    let reader = txn.renew()?;
+                    assert_eq!(reader.get(&tree, key)?.unwrap(), b"v");
+                    ptrs.push(reader.txn() as usize);
+                }
+                // the same transaction of the thread in each renew
+                assert!(ptrs.iter().all(|p| *p == ptrs[0]));
+                // keep the transactions of all threads alive to compare
+                barrier.wait();
+                Ok(ptrs[0])
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut ptrs = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    ptrs.sort();
+    ptrs.dedup();
+    assert_eq!(ptrs.len(), 3);
+    assert_eq!(db.reader()?.tree_stat(&tree)?.entries, 30);
+
+    // a reset transaction doesn't block the map growth
+    let mut txn = db.reset_reader()?;
+    assert!(db.grow_map_size(4 * 1024 * 1024)?);
+    assert!(txn.renew()?.get(&tree, 0u64.to_be_bytes())?.is_some());
+    Ok(())
+}
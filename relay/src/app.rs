@@ -1,4 +1,8 @@
-use crate::{setting::SettingWrapper, Extension, Extensions, Result, Server, Setting};
+use crate::{
+    message::SettingChanged,
+    setting::{Durability, SettingWrapper},
+    Extension, Extensions, Result, Server, Setting,
+};
 use actix::Addr;
 use actix_cors::Cors;
 use actix_web::{
@@ -6,9 +10,15 @@ use actix_web::{
     dev::{ServiceFactory, ServiceRequest},
     web, App as WebApp, HttpServer,
 };
-use nostr_db::Db;
-use parking_lot::RwLock;
-use std::{path::Path, sync::Arc};
+use nostr_db::{Db, DbOptions};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 use tracing::info;
 
 pub mod route {
@@ -16,6 +26,7 @@ pub mod route {
     use actix_web::http::header::{ACCEPT, LOCATION, UPGRADE};
     use actix_web::{web, Error, HttpRequest, HttpResponse};
     use actix_web_actors::ws;
+    use tracing::warn;
 
     fn get_ip(req: &HttpRequest, header: Option<&String>) -> Option<String> {
         if let Some(header) = header {
@@ -48,9 +59,19 @@ pub mod route {
         let r = data.setting.read();
         let ip = get_ip(&req, r.network.real_ip_header.as_ref());
         let max_size = r.limitation.max_message_length;
+        let max_conn = r.network.max_conn_per_ip;
         drop(r);
 
-        let session = Session::new(ip.unwrap_or_default(), data);
+        let ip = ip.unwrap_or_default();
+        let connection = match data.connections.acquire(&ip, max_conn) {
+            Some(connection) => connection,
+            None => {
+                warn!(ip = %ip, max_conn, "too many connections from the ip");
+                return Ok(HttpResponse::TooManyRequests().body("too many connections"));
+            }
+        };
+        let mut session = Session::new(ip, data);
+        session.set_connection(connection);
 
         // ws::start(session, &req, stream)
         // The default max frame size is 60k, change from setting.
@@ -70,6 +91,22 @@ pub mod route {
             .body(r.render_information()?))
     }
 
+    /// The process is up
+    pub async fn healthz() -> HttpResponse {
+        HttpResponse::Ok().body("ok")
+    }
+
+    /// The db is open with the current schema and can serve reads
+    pub async fn readyz(data: web::Data<App>) -> HttpResponse {
+        match data.db.probe() {
+            Ok(()) => HttpResponse::Ok().body("ok"),
+            Err(err) => {
+                warn!(error = %err, "readiness probe failed");
+                HttpResponse::ServiceUnavailable().body(err.to_string())
+            }
+        }
+    }
+
     pub async fn index(
         req: HttpRequest,
         stream: web::Payload,
@@ -97,12 +134,59 @@ pub mod route {
     }
 }
 
+/// The websocket connections of each ip
+#[derive(Debug, Default)]
+pub struct Connections(Arc<Mutex<HashMap<String, usize>>>);
+
+impl Connections {
+    /// Count a connection of the ip, None if the ip has max connections already, 0 unlimited.
+    /// The connection is released when the guard is dropped.
+    pub fn acquire(&self, ip: &str, max: usize) -> Option<Connection> {
+        let mut map = self.0.lock();
+        let count = map.entry(ip.to_owned()).or_default();
+        if max != 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Connection {
+            ip: ip.to_owned(),
+            connections: Arc::clone(&self.0),
+        })
+    }
+
+    /// The number of the connections of the ip
+    pub fn count(&self, ip: &str) -> usize {
+        self.0.lock().get(ip).copied().unwrap_or_default()
+    }
+}
+
+/// A connection counted by [`Connections::acquire`]
+#[derive(Debug)]
+pub struct Connection {
+    ip: String,
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut map = self.connections.lock();
+        if let Some(count) = map.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.ip);
+            }
+        }
+    }
+}
+
 /// App with data
 pub struct App {
     pub server: Addr<Server>,
     pub db: Arc<Db>,
     pub setting: SettingWrapper,
     pub extensions: Arc<RwLock<Extensions>>,
+    /// the websocket connections of each ip, limited by `network.max_conn_per_ip`
+    pub connections: Connections,
 }
 
 impl App {
@@ -115,32 +199,25 @@ impl App {
     ) -> Result<Self> {
         let extensions = Arc::new(RwLock::new(Extensions::default()));
         let c_extensions = Arc::clone(&extensions);
-        let env_notice = setting_env_prefix
-            .as_ref()
-            .map(|s| {
-                format!(
-                    ", config will be overrided by ENV seting with prefix `{}_`",
-                    s
-                )
-            })
-            .unwrap_or_default();
-
+        // the server is created after the setting
+        let server: Arc<RwLock<Option<Addr<Server>>>> = Arc::new(RwLock::new(None));
+        let c_server = Arc::clone(&server);
         let setting = if watch && setting_path.is_some() {
             let path = setting_path.as_ref().unwrap().as_ref();
-            info!("Watch config file {:?}{}", path, env_notice);
+            info!(
+                "Watch config file {:?}{}",
+                path,
+                env_notice(setting_env_prefix.as_ref())
+            );
             SettingWrapper::watch(path, setting_env_prefix, move |s| {
                 let mut w = c_extensions.write();
                 w.call_setting(s);
+                if let Some(server) = c_server.read().as_ref() {
+                    server.do_send(SettingChanged);
+                }
             })?
-        } else if let Some(path) = setting_path {
-            info!("Load config {:?}{}", path.as_ref(), env_notice);
-            Setting::read(path.as_ref(), setting_env_prefix)?.into()
-        } else if let Some(prefix) = setting_env_prefix {
-            info!("Load default config{}", env_notice);
-            Setting::from_env(prefix)?.into()
         } else {
-            info!("Load default config");
-            Setting::default().into()
+            read_setting(setting_path, setting_env_prefix)?.into()
         };
 
         {
@@ -148,21 +225,55 @@ impl App {
         }
 
         let r = setting.read();
-        let path = data_path
-            .map(|p| p.as_ref().to_path_buf())
-            .unwrap_or_else(|| r.data.path.clone())
-            .join("events");
+        let path = events_path(&r, data_path);
+        let encryption_key = r.data.encryption_key()?;
+        let no_sync = r.data.durability == Durability::Async;
+        let verify_on_read = r.data.verify_on_read;
+        let map_size = r.data.map_size;
+        let max_map_size = r.data.max_map_size;
+        nostr_db::set_hex_tags(&r.data.hex_tags)?;
+        let prewarm = r
+            .data
+            .prewarm
+            .then_some((r.data.prewarm_timeout, r.data.prewarm_max_bytes));
         drop(r);
-        let db = Arc::new(Db::open(path)?);
+        let db = Arc::new(Db::open_with(
+            path,
+            DbOptions {
+                encryption_key,
+                no_sync,
+                verify_on_read,
+                map_size,
+                max_map_size,
+                ..Default::default()
+            },
+        )?);
         db.check_schema()?;
 
-        let server = Server::create_with(db.clone(), setting.clone());
+        if let Some((timeout, max_bytes)) = prewarm {
+            let start = Instant::now();
+            let info = db.prewarm(Some(timeout), max_bytes)?;
+            info!(
+                "Prewarm {} entries {} bytes in {:?}, completed: {}",
+                info.entries,
+                info.bytes,
+                start.elapsed(),
+                info.completed
+            );
+        }
+
+        let server = {
+            let addr = Server::create_with(db.clone(), setting.clone());
+            *server.write() = Some(addr.clone());
+            addr
+        };
 
         Ok(Self {
             server,
             setting,
             db,
             extensions,
+            connections: Connections::default(),
         })
     }
 
@@ -190,6 +301,35 @@ impl App {
         create_web_app(web::Data::new(self))
     }
 
+    /// Check everything needed to serve without creating the app or writing anything:
+    /// validate the setting, check the database schema read-only and probe the bind address.
+    pub fn dry_run<P: AsRef<Path>>(
+        setting_path: Option<P>,
+        setting_env_prefix: Option<String>,
+        data_path: Option<P>,
+    ) -> Result<()> {
+        let setting = read_setting(setting_path, setting_env_prefix)?;
+        setting.validate()?;
+        nostr_db::set_hex_tags(&setting.data.hex_tags)?;
+        let path = events_path(&setting, data_path);
+        if path.exists() {
+            let db = Db::open_with(
+                &path,
+                DbOptions {
+                    read_only: true,
+                    ..Default::default()
+                },
+            )?;
+            db.verify_schema()?;
+            info!("Database check {:?} ok", path);
+        } else {
+            info!("Database {:?} will be created", path);
+        }
+        let listener = TcpListener::bind((setting.network.host.as_str(), setting.network.port))?;
+        info!("Bind check {} ok", listener.local_addr()?);
+        Ok(())
+    }
+
     pub fn web_server(self) -> Result<actix_web::dev::Server, std::io::Error> {
         let r = self.setting.read();
         let num = if r.thread.http == 0 {
@@ -209,6 +349,42 @@ impl App {
     }
 }
 
+fn env_notice(setting_env_prefix: Option<&String>) -> String {
+    setting_env_prefix
+        .map(|s| {
+            format!(
+                ", config will be overrided by ENV seting with prefix `{}_`",
+                s
+            )
+        })
+        .unwrap_or_default()
+}
+
+fn read_setting<P: AsRef<Path>>(
+    setting_path: Option<P>,
+    setting_env_prefix: Option<String>,
+) -> Result<Setting> {
+    let env_notice = env_notice(setting_env_prefix.as_ref());
+    Ok(if let Some(path) = setting_path {
+        info!("Load config {:?}{}", path.as_ref(), env_notice);
+        Setting::read(path.as_ref(), setting_env_prefix)?
+    } else if let Some(prefix) = setting_env_prefix {
+        info!("Load default config{}", env_notice);
+        Setting::from_env(prefix)?
+    } else {
+        info!("Load default config");
+        Setting::default()
+    })
+}
+
+/// the events database path in the data path
+fn events_path<P: AsRef<Path>>(setting: &Setting, data_path: Option<P>) -> PathBuf {
+    data_path
+        .map(|p| p.as_ref().to_path_buf())
+        .unwrap_or_else(|| setting.data.path.clone())
+        .join("events")
+}
+
 pub fn create_web_app(
     data: web::Data<App>,
 ) -> WebApp<
@@ -226,6 +402,8 @@ pub fn create_web_app(
         .configure(|cfg| {
             extensions.write().call_config_web(cfg);
         })
+        .service(web::resource("/healthz").route(web::get().to(route::healthz)))
+        .service(web::resource("/readyz").route(web::get().to(route::readyz)))
         .service(web::resource("/").route(web::get().to(route::index)))
         .wrap(
             Cors::default()
@@ -241,7 +419,8 @@ pub fn create_web_app(
 pub mod tests {
     use std::time::Duration;
 
-    use crate::create_test_app;
+    use super::Connections;
+    use crate::{create_test_app, temp_data_path, App};
     use actix_rt::time::sleep;
     use actix_test::read_body;
     use actix_web::{
@@ -276,6 +455,41 @@ pub mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn health() -> Result<()> {
+        let data = create_test_app("health")?;
+        let db = data.db.clone();
+        let app = init_service(data.web_app()).await;
+        for uri in ["/healthz", "/readyz"] {
+            let res = app
+                .call(TestRequest::with_uri(uri).to_request())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200, "{}", uri);
+        }
+
+        // a thread can only have one read transaction, the probe fails with the reader held
+        let reader = db.reader()?;
+        let res = app
+            .call(TestRequest::with_uri("/readyz").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 503);
+        let res = app
+            .call(TestRequest::with_uri("/healthz").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        drop(reader);
+
+        let res = app
+            .call(TestRequest::with_uri("/readyz").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn connect_ws() -> Result<()> {
         let mut srv = actix_test::start(|| {
@@ -297,4 +511,106 @@ pub mod tests {
         assert_eq!(item, ws::Frame::Close(Some(ws::CloseCode::Normal.into())));
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn max_conn_per_ip() -> Result<()> {
+        let data = create_test_app("max_conn_per_ip")?;
+        data.setting.write().network.max_conn_per_ip = 2;
+        let data = actix_web::web::Data::new(data);
+        let c_data = data.clone();
+        let mut srv = actix_test::start(move || crate::create_web_app(c_data.clone()));
+
+        let mut first = srv.ws_at("/").await.unwrap();
+        let _second = srv.ws_at("/").await.unwrap();
+        assert_eq!(data.connections.count("127.0.0.1"), 2);
+        // the extra upgrade is refused
+        let err = format!("{:?}", srv.ws_at("/").await.err().unwrap());
+        assert!(err.contains("429"), "{}", err);
+        assert_eq!(data.connections.count("127.0.0.1"), 2);
+
+        // released when the session stops
+        first
+            .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
+            .await?;
+        first.next().await.unwrap()?;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(data.connections.count("127.0.0.1"), 1);
+        let _third = srv.ws_at("/").await.unwrap();
+        assert_eq!(data.connections.count("127.0.0.1"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn connections() {
+        let connections = Connections::default();
+        let a = connections.acquire("a", 1).unwrap();
+        assert!(connections.acquire("a", 1).is_none());
+        // unlimited
+        let b = connections.acquire("a", 0).unwrap();
+        assert_eq!(connections.count("a"), 2);
+        let _c = connections.acquire("c", 1).unwrap();
+        drop(a);
+        drop(b);
+        assert_eq!(connections.count("a"), 0);
+        assert!(connections.0.lock().get("a").is_none());
+        assert!(connections.acquire("a", 1).is_some());
+    }
+
+    #[actix_rt::test]
+    async fn durability() -> Result<()> {
+        use nostr_db::kv::lmdb::ffi;
+        let dir = temp_data_path("durability")?;
+        let path = dir.path().join("rnostr.toml");
+        std::fs::write(&path, "[data]\ndurability = \"async\"\n")?;
+        let app = App::create(Some(path), false, None, Some(dir.path().join("data")))?;
+        assert_eq!(app.db.env_flags()? & ffi::MDB_NOSYNC, ffi::MDB_NOSYNC);
+
+        let app = create_test_app("durability_sync")?;
+        assert_eq!(app.db.env_flags()? & ffi::MDB_NOSYNC, 0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn dry_run() -> Result<()> {
+        let dir = temp_data_path("dry_run")?;
+        let data = dir.path().join("data");
+        let check = |name: &str, content: &str| -> Result<()> {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            Ok(App::dry_run(Some(path), None, Some(data.clone()))?)
+        };
+
+        check("good.toml", "[network]\nport = 0\n")?;
+        // nothing is created
+        assert!(!data.exists());
+
+        assert!(check(
+            "pubkey.toml",
+            "[information]\npubkey = \"xx\"\n[network]\nport = 0\n",
+        )
+        .is_err());
+        assert!(check("limit.toml", "[limitation]\nmax_limit = 0\n").is_err());
+
+        // the address is in use
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        assert!(check("bind.toml", &format!("[network]\nport = {}\n", port)).is_err());
+
+        // unparsable
+        assert!(check("bad.toml", "[network]\nport = \"abc\"\n").is_err());
+
+        // the existing database is checked without writing
+        let path = dir.path().join("good.toml");
+        drop(App::create(
+            Some(path.clone()),
+            false,
+            None,
+            Some(data.clone()),
+        )?);
+        let file = data.join("events").join("data.mdb");
+        let modified = std::fs::metadata(&file)?.modified()?;
+        App::dry_run(Some(path), None, Some(data.clone()))?;
+        assert_eq!(std::fs::metadata(&file)?.modified()?, modified);
+        Ok(())
+    }
 }
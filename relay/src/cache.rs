@@ -0,0 +1,156 @@
+use nostr_db::{Event, Filter};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The least recently used cache of the query results keyed by [`Filter::hash`].
+/// The results which may contain a written event are removed,
+/// the ttl bounds the staleness changed by other ways, such as the replaced or expired events.
+pub struct QueryCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<u64, Entry>,
+    /// increased on every invalidation, the result read before it is not cached
+    version: u64,
+    tick: u64,
+}
+
+struct Entry {
+    filter: Filter,
+    events: Arc<Vec<String>>,
+    time: Instant,
+    used: u64,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The version for inserting the result, get it before opening the db reader
+    pub fn version(&self) -> u64 {
+        self.inner.lock().version
+    }
+
+    pub fn get(&self, filter: &Filter) -> Option<Arc<Vec<String>>> {
+        let key = filter.hash();
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(&key)?;
+        if &entry.filter == filter && entry.time.elapsed() < self.ttl {
+            entry.used = tick;
+            Some(Arc::clone(&entry.events))
+        } else {
+            inner.entries.remove(&key);
+            None
+        }
+    }
+
+    /// Cache the complete result of the filter, ignored if invalidated after the version
+    pub fn insert(&self, version: u64, filter: &Filter, events: Vec<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = filter.hash();
+        let mut inner = self.inner.lock();
+        if inner.version != version {
+            return;
+        }
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.capacity {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        inner.tick += 1;
+        let used = inner.tick;
+        inner.entries.insert(
+            key,
+            Entry {
+                filter: filter.clone(),
+                events: Arc::new(events),
+                time: Instant::now(),
+                used,
+            },
+        );
+    }
+
+    /// Remove the results may contain the written event, call it after the event committed
+    pub fn invalidate(&self, event: &Event) {
+        let mut inner = self.inner.lock();
+        inner.version += 1;
+        // NIP-09 deletion changes the results of the other filters
+        if event.kind() == 5 {
+            inner.entries.clear();
+        } else {
+            inner
+                .entries
+                .retain(|_, entry| !entry.filter.r#match(event.index()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::str::FromStr;
+
+    #[test]
+    fn lru() -> Result<()> {
+        let cache = QueryCache::new(2, Duration::from_secs(10));
+        let filters = (1..4)
+            .map(|i| Filter::from_str(&format!(r#"{{"kinds":[{}]}}"#, i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        cache.insert(cache.version(), &filters[0], vec!["1".to_owned()]);
+        cache.insert(cache.version(), &filters[1], vec!["2".to_owned()]);
+        assert_eq!(cache.get(&filters[0]).unwrap().as_ref(), &vec!["1"]);
+        // the least recently used is removed
+        cache.insert(cache.version(), &filters[2], vec!["3".to_owned()]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&filters[1]).is_none());
+        assert!(cache.get(&filters[0]).is_some());
+
+        // invalidated after the version
+        let version = cache.version();
+        let event = Event::new([1; 32], [1; 32], 1, 1, vec![], "".to_owned(), [0; 64])?;
+        cache.invalidate(&event);
+        assert!(cache.get(&filters[0]).is_none());
+        assert!(cache.get(&filters[2]).is_some());
+        cache.insert(version, &filters[0], vec!["1".to_owned()]);
+        assert!(cache.get(&filters[0]).is_none());
+
+        // expired
+        let cache = QueryCache::new(2, Duration::from_millis(10));
+        cache.insert(cache.version(), &filters[0], vec![]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get(&filters[0]).is_none());
+        assert!(cache.is_empty());
+        Ok(())
+    }
+}
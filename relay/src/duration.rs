@@ -20,6 +20,11 @@ where
     d.deserialize_any(DurationVisitor)
 }
 
+/// Parse a duration str by [`duration_str`], such as: 7d, 12h, 30m
+pub fn parse_str(s: &str) -> Result<Duration, String> {
+    parse(s)
+}
+
 /// Serialize a `Duration`
 pub fn serialize<S>(d: &Duration, s: S) -> Result<S::Ok, S::Error>
 where
@@ -213,6 +218,9 @@ mod tests {
 
         let t = serde_json::from_str::<Test>(r#"{"time": 0}"#)?;
         assert_eq!(t.time, Duration::from_secs(0));
+
+        assert_eq!(parse_str("7d").unwrap(), Duration::from_secs(7 * 86400));
+        assert!(parse_str("7x").is_err());
         Ok(())
     }
 
@@ -12,6 +12,8 @@ pub enum Error {
     Json(#[from] serde_json::Error),
     #[error("invalid: {0}")]
     Invalid(String),
+    #[error("invalid: filter too broad")]
+    FilterTooBroad,
     #[error("{0}")]
     Message(String),
     #[error("{0}")]
@@ -23,6 +25,7 @@ impl actix_web::ResponseError for Error {}
 pub type Result<T, E = Error> = core::result::Result<T, E>;
 
 mod app;
+mod cache;
 pub mod duration;
 mod extension;
 mod hash;
@@ -38,8 +41,8 @@ mod writer;
 pub use metrics;
 pub use nostr_db as db;
 pub use {
-    app::*, extension::*, list::List, reader::Reader, server::Server, session::Session,
-    setting::Setting, subscriber::Subscriber, writer::Writer,
+    app::*, cache::QueryCache, extension::*, list::List, reader::Reader, server::Server,
+    session::Session, setting::Setting, subscriber::Subscriber, writer::Writer,
 };
 
 #[cfg(test)]
@@ -58,3 +61,27 @@ pub fn create_test_app(db_path: &str) -> anyhow::Result<App> {
         Some(temp_data_path(db_path)?),
     )?)
 }
+
+#[cfg(test)]
+pub fn counter_value(
+    snapshotter: &metrics_util::debugging::Snapshotter,
+    name: &str,
+    label: (&str, &str),
+) -> u64 {
+    snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .find_map(|(key, _, _, val)| {
+            let key = key.key();
+            let matched = key.name() == name
+                && key
+                    .labels()
+                    .any(|l| l.key() == label.0 && l.value() == label.1);
+            match val {
+                metrics_util::debugging::DebugValue::Counter(v) if matched => Some(v),
+                _ => None,
+            }
+        })
+        .unwrap_or_default()
+}
@@ -1,16 +1,22 @@
 use actix::{Message, MessageResponse, Recipient};
 use bytestring::ByteString;
+use metrics::counter;
 use nostr_db::{now, CheckEventResult, Event, Filter};
 use serde::{
-    de::{self, SeqAccess, Visitor},
-    Deserialize, Deserializer,
+    de::{self, IgnoredAny, SeqAccess, Visitor},
+    ser::SerializeSeq,
+    Deserialize, Deserializer, Serialize, Serializer,
 };
-use serde_json::{json, Value};
-use std::fmt::Display;
+use serde_json::{json, value::RawValue, Value};
+use std::{collections::HashMap, fmt::Display};
 use std::{fmt, marker::PhantomData};
+use tracing::warn;
 
 use crate::{setting::Limitation, Error};
 
+/// 3000-01-01, the filter timestamps later than this are milliseconds
+const MAX_TIMESTAMP: u64 = 32_503_680_000;
+
 /// New session is created
 #[derive(Message, Clone, Debug)]
 #[rtype(usize)]
@@ -56,6 +62,33 @@ macro_rules! check_max {
             return Err(Error::Invalid(format!("{} {}", stringify!($limit), $limit)));
         }
     };
+    ($source:expr, $limit:expr, $reason:expr) => {
+        if $source > $limit {
+            event_rejected($reason);
+            return Err(Error::Invalid(format!("{} {}", stringify!($limit), $limit)));
+        }
+    };
+}
+
+macro_rules! reject {
+    ($reason:expr, $res:expr) => {
+        if let Err(err) = $res {
+            event_rejected($reason);
+            return Err(err.into());
+        }
+    };
+}
+
+/// Count the rejected event, the reason is a stable metrics label.
+pub fn event_rejected(reason: &'static str) {
+    counter!("nostr_relay_event_rejected_total", "reason" => reason).increment(1);
+}
+
+/// Control characters except tab and newlines, and the invisible characters often used by spam.
+/// The zero-width joiner is allowed for the emoji sequences.
+fn is_disallowed_char(c: char) -> bool {
+    (c.is_control() && !matches!(c, '\t' | '\n' | '\r'))
+        || matches!(c, '\u{200B}' | '\u{2060}' | '\u{FEFF}')
 }
 
 macro_rules! check_min {
@@ -70,45 +103,125 @@ impl ClientMessage {
     pub fn validate_nip70(&self) -> Result<(), Error> {
         if !self.nip70_checked {
             if let IncomingMessage::Event(event) = &self.msg {
-                for tag in event.tags() {
-                    if tag.len() == 1 && tag[0] == "-" {
-                        return Err(Error::Message(
-                            "blocked: event marked as protected".to_owned(),
-                        ));
-                    }
+                if event.is_protected() {
+                    event_rejected("protected");
+                    return Err(Error::Message(
+                        "blocked: event marked as protected".to_owned(),
+                    ));
                 }
             }
         }
         Ok(())
     }
 
+    fn validate_event(len: usize, event: &Event, limitation: &Limitation) -> Result<(), Error> {
+        check_max!(len, limitation.max_message_length, "too_large");
+        check_max!(
+            event.tags().len(),
+            limitation.max_event_tags,
+            "too_many_tags"
+        );
+        if limitation.reject_control_chars && event.content().chars().any(is_disallowed_char) {
+            event_rejected("invalid_content");
+            return Err(Error::Message(
+                "blocked: invalid content characters".to_owned(),
+            ));
+        }
+        let now = now();
+        if event.index().is_expired(now) {
+            event_rejected("expired");
+            return Err(nostr_db::Error::Invalid("event is expired".to_owned()).into());
+        }
+        reject!(
+            "too_old",
+            event.verify_time(now, limitation.max_event_time_older_than_now, 0)
+        );
+        reject!(
+            "too_new",
+            event.verify_time(now, 0, limitation.max_event_time_newer_than_now)
+        );
+        reject!(
+            "expiration_too_far",
+            event.verify_expiration(now, limitation.max_event_expiration_from_now)
+        );
+        reject!("invalid_id", event.verify_id());
+        reject!("invalid_sig", event.verify_sign());
+        reject!("invalid_delegation", event.verify_delegation());
+        Ok(())
+    }
+
     pub fn validate(&mut self, limitation: &Limitation) -> Result<(), Error> {
-        check_max!(self.text.as_bytes().len(), limitation.max_message_length);
+        let len = self.text.len();
 
         match &mut self.msg {
             IncomingMessage::Event(event) => {
-                check_max!(event.tags().len(), limitation.max_event_tags);
-                event.validate(
-                    now(),
-                    limitation.max_event_time_older_than_now,
-                    limitation.max_event_time_newer_than_now,
-                )?;
+                Self::validate_event(len, event, limitation)?;
             }
 
             IncomingMessage::Req(sub) => {
+                check_max!(len, limitation.max_message_length);
                 check_max!(sub.filters.len(), limitation.max_filters);
                 check_max!(sub.id.len(), limitation.max_subid_length);
+                Self::remove_empty_lists(&mut sub.filters, limitation);
+                Self::check_timestamps(&mut sub.filters, limitation)?;
 
+                Self::validate_filters(&sub.filters, limitation)?;
+                // check before filling the default limit
+                if limitation.reject_unbounded_req && sub.filters.iter().any(Filter::is_unbounded) {
+                    return Err(Error::FilterTooBroad);
+                }
                 for f in &mut sub.filters {
                     // fill default limit
                     f.default_limit(limitation.max_limit);
                     check_max!(f.limit.unwrap(), limitation.max_limit);
-                    for id in f.ids.iter() {
-                        check_min!(id.len(), limitation.min_prefix);
+                }
+            }
+            IncomingMessage::Count(sub) => {
+                check_max!(len, limitation.max_message_length);
+                Self::check_timestamps(&mut sub.filters, limitation)?;
+                Self::validate_filters(&sub.filters, limitation)?;
+            }
+            _ => {
+                check_max!(len, limitation.max_message_length);
+            }
+        }
+        Ok(())
+    }
+
+    // the filters matching nothing are removed
+    fn remove_empty_lists(filters: &mut Vec<Filter>, limitation: &Limitation) {
+        if limitation.reject_empty_lists {
+            filters.retain(|f| f.empty_fields.is_empty());
+        }
+    }
+
+    // the millisecond timestamps query an empty window in the future
+    fn check_timestamps(filters: &mut [Filter], limitation: &Limitation) -> Result<(), Error> {
+        for f in filters {
+            for time in [&mut f.since, &mut f.until].into_iter().flatten() {
+                if *time > MAX_TIMESTAMP {
+                    if limitation.lenient_timestamps && *time / 1000 <= MAX_TIMESTAMP {
+                        *time /= 1000;
+                    } else {
+                        return Err(Error::Invalid("timestamp out of range".to_owned()));
                     }
                 }
             }
-            _ => {}
+        }
+        Ok(())
+    }
+
+    fn validate_filters(filters: &[Filter], limitation: &Limitation) -> Result<(), Error> {
+        for f in filters {
+            for prefix in f.ids.iter().chain(f.authors.iter()) {
+                check_min!(prefix.hex_len(), limitation.min_prefix);
+            }
+            if let Some(field) = f.unknown_fields.first() {
+                if !limitation.lenient_filters {
+                    return Err(Error::Invalid(format!("unknown filter field {}", field)));
+                }
+                warn!(fields = ?f.unknown_fields, "ignore unknown filter fields");
+            }
         }
         Ok(())
     }
@@ -138,6 +251,8 @@ pub enum IncomingMessage {
     Auth(Event),
     /// nip-45
     Count(Subscription),
+    /// Non-standard, submit multiple events in one message
+    Events(Vec<Event>),
     Unknown(String, Vec<Value>),
 }
 
@@ -149,6 +264,7 @@ impl IncomingMessage {
             IncomingMessage::Req(_) => "REQ",
             IncomingMessage::Auth(_) => "AUTH",
             IncomingMessage::Count(_) => "COUNT",
+            IncomingMessage::Events(_) => "EVENTS",
             IncomingMessage::Unknown(cmd, _) => cmd,
         }
     }
@@ -160,6 +276,7 @@ impl IncomingMessage {
             IncomingMessage::Req(_) => Some("REQ"),
             IncomingMessage::Auth(_) => Some("AUTH"),
             IncomingMessage::Count(_) => Some("COUNT"),
+            IncomingMessage::Events(_) => Some("EVENTS"),
             IncomingMessage::Unknown(_, _) => None,
         }
     }
@@ -184,10 +301,15 @@ impl<'de> Visitor<'de> for MessageVisitor {
             .next_element()?
             .ok_or_else(|| de::Error::invalid_length(0, &self))?;
         match t {
-            "EVENT" => Ok(IncomingMessage::Event(
-                seq.next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?,
-            )),
+            "EVENT" => {
+                // keep the original json
+                let raw: Box<RawValue> = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                Ok(IncomingMessage::Event(
+                    Event::from_raw(raw.get()).map_err(de::Error::custom)?,
+                ))
+            }
             "CLOSE" => Ok(IncomingMessage::Close(
                 seq.next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?,
@@ -196,8 +318,11 @@ impl<'de> Visitor<'de> for MessageVisitor {
                 let t = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                let r = Vec::<Filter>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
-                Ok(IncomingMessage::Req(Subscription { id: t, filters: r }))
+                let r =
+                    Vec::<Box<RawValue>>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+                Ok(IncomingMessage::Req(
+                    Subscription::from_raw(t, r).map_err(de::Error::custom)?,
+                ))
             }
             "AUTH" => Ok(IncomingMessage::Auth(
                 seq.next_element()?
@@ -207,8 +332,21 @@ impl<'de> Visitor<'de> for MessageVisitor {
                 let t = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                let r = Vec::<Filter>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
-                Ok(IncomingMessage::Count(Subscription { id: t, filters: r }))
+                let r =
+                    Vec::<Box<RawValue>>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+                Ok(IncomingMessage::Count(
+                    Subscription::from_raw(t, r).map_err(de::Error::custom)?,
+                ))
+            }
+            "EVENTS" => {
+                let raws =
+                    Vec::<Box<RawValue>>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+                Ok(IncomingMessage::Events(
+                    raws.iter()
+                        .map(|raw| Event::from_raw(raw.get()))
+                        .collect::<Result<_, _>>()
+                        .map_err(de::Error::custom)?,
+                ))
             }
             _ => Ok(IncomingMessage::Unknown(
                 t.to_string(),
@@ -227,6 +365,44 @@ impl<'de> Deserialize<'de> for IncomingMessage {
     }
 }
 
+impl Serialize for IncomingMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element(self.command())?;
+        match self {
+            IncomingMessage::Event(event) | IncomingMessage::Auth(event) => {
+                seq.serialize_element(event)?;
+            }
+            IncomingMessage::Close(id) => {
+                seq.serialize_element(id)?;
+            }
+            IncomingMessage::Req(sub) | IncomingMessage::Count(sub) => {
+                seq.serialize_element(&sub.id)?;
+                for filter in &sub.filters {
+                    seq.serialize_element(filter)?;
+                }
+                if !sub.options.is_empty() {
+                    seq.serialize_element(&sub.options)?;
+                }
+            }
+            IncomingMessage::Events(events) => {
+                for event in events {
+                    seq.serialize_element(event)?;
+                }
+            }
+            IncomingMessage::Unknown(_, values) => {
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+            }
+        }
+        seq.end()
+    }
+}
+
 // fn ignore_contents<'de, D>(deserializer: D) -> Result<(), D::Error>
 // where
 //     D: Deserializer<'de>,
@@ -238,10 +414,75 @@ impl<'de> Deserialize<'de> for IncomingMessage {
 // }
 
 /// Subscription
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Subscription {
     pub id: String,
     pub filters: Vec<Filter>,
+    pub options: SubscriptionOptions,
+    /// The authenticated pubkey of the requesting session, set by the relay, see [`nostr_db::Iter::viewer`]
+    pub viewer: Option<[u8; 32]>,
+    /// Hide the [NIP-70](https://nips.be/70) protected events not authored by the viewer,
+    /// set by the auth extension
+    pub protect_reads: bool,
+}
+
+impl Subscription {
+    /// Parse the filters, a trailing object after the filters without any filter key is the options
+    fn from_raw(id: String, raws: Vec<Box<RawValue>>) -> Result<Self, serde_json::Error> {
+        let mut filters = raws
+            .iter()
+            .map(|raw| serde_json::from_str::<Filter>(raw.get()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut options = SubscriptionOptions::default();
+        if filters.len() > 1 {
+            let last = filters.last().unwrap();
+            // the known fields and tags are not collected to the unknown fields
+            if !last.unknown_fields.is_empty()
+                && serde_json::from_str::<HashMap<String, IgnoredAny>>(raws[raws.len() - 1].get())?
+                    .len()
+                    == last.unknown_fields.len()
+            {
+                options = serde_json::from_str(raws[raws.len() - 1].get())?;
+                filters.pop();
+            }
+        }
+        Ok(Self {
+            id,
+            filters,
+            options,
+            viewer: None,
+            protect_reads: false,
+        })
+    }
+}
+
+/// Relay-specific hints of a subscription, unknown keys are ignored.
+///
+/// `["REQ", <subscription_id>, <filters1>, <filters2>, ..., {"total_limit": 10}]`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SubscriptionOptions {
+    /// The max number of stored events sent before EOSE for all filters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_limit: Option<u64>,
+    /// Send only the parts of the stored events, such as `{"projection": "ids"}`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub projection: Option<Projection>,
+}
+
+/// The parts of the stored events sent for a subscription
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    /// Only the event ids by an index-only scan, in `["IDS", <subscription_id>, [<id>, ...]]` messages
+    /// of `max_limit` ids at most before EOSE, the live events after EOSE are sent as full events
+    Ids,
+}
+
+impl SubscriptionOptions {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
 }
 
 // https://github.com/serde-rs/serde/issues/1337
@@ -298,9 +539,165 @@ impl OutgoingMessage {
         Self(format!(r#"["EVENT","{}",{}]"#, sub_id, event))
     }
 
+    /// The message is an `EVENT` of a subscription
+    pub fn is_event(&self) -> bool {
+        self.0.starts_with(r#"["EVENT","#)
+    }
+
     pub fn ok(event_id: &str, saved: bool, message: &str) -> Self {
         Self(json!(["OK", event_id, saved, message]).to_string())
     }
+
+    /// nip-42
+    pub fn auth(challenge: &str) -> Self {
+        Self(json!(["AUTH", challenge]).to_string())
+    }
+
+    /// nip-45
+    pub fn count(sub_id: &str, count: u64) -> Self {
+        Self(json!(["COUNT", sub_id, { "count": count }]).to_string())
+    }
+
+    /// The count with the hex registers of the HyperLogLog, [NIP-45](https://nips.be/45)
+    pub fn count_hll(sub_id: &str, count: u64, hll: &str) -> Self {
+        Self(json!(["COUNT", sub_id, { "count": count, "hll": hll }]).to_string())
+    }
+
+    /// The estimated count, [NIP-45](https://nips.be/45)
+    pub fn count_approximate(sub_id: &str, count: u64) -> Self {
+        Self(json!(["COUNT", sub_id, { "count": count, "approximate": true }]).to_string())
+    }
+
+    /// The stored event ids of a subscription with the ids [`Projection`]
+    pub fn ids(sub_id: &str, ids: &[String]) -> Self {
+        Self(json!(["IDS", sub_id, ids]).to_string())
+    }
+}
+
+/// Parsed messages from a relay, the typed form of [`OutgoingMessage`]
+#[derive(Clone, Debug)]
+pub enum RelayMessage {
+    Event {
+        sub_id: String,
+        event: Box<Event>,
+    },
+    Ok {
+        event_id: String,
+        saved: bool,
+        message: String,
+    },
+    Eose(String),
+    Closed {
+        sub_id: String,
+        message: String,
+    },
+    Notice(String),
+    /// nip-42
+    Auth(String),
+    /// nip-45
+    Count {
+        sub_id: String,
+        count: u64,
+    },
+    /// the stored event ids of the ids [`Projection`]
+    Ids {
+        sub_id: String,
+        ids: Vec<String>,
+    },
+}
+
+impl From<&RelayMessage> for OutgoingMessage {
+    fn from(msg: &RelayMessage) -> Self {
+        match msg {
+            RelayMessage::Event { sub_id, event } => {
+                OutgoingMessage::event(sub_id, &event.to_string())
+            }
+            RelayMessage::Ok {
+                event_id,
+                saved,
+                message,
+            } => OutgoingMessage::ok(event_id, *saved, message),
+            RelayMessage::Eose(sub_id) => OutgoingMessage::eose(sub_id),
+            RelayMessage::Closed { sub_id, message } => OutgoingMessage::closed(sub_id, message),
+            RelayMessage::Notice(message) => OutgoingMessage::notice(message),
+            RelayMessage::Auth(challenge) => OutgoingMessage::auth(challenge),
+            RelayMessage::Count { sub_id, count } => OutgoingMessage::count(sub_id, *count),
+            RelayMessage::Ids { sub_id, ids } => OutgoingMessage::ids(sub_id, ids),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CountPayload {
+    count: u64,
+}
+
+struct RelayMessageVisitor(PhantomData<()>);
+
+impl<'de> Visitor<'de> for RelayMessageVisitor {
+    type Value = RelayMessage;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("sequence")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        macro_rules! next {
+            ($index:expr) => {
+                seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length($index, &self))?
+            };
+        }
+        let t: &str = next!(0);
+        match t {
+            "EVENT" => Ok(RelayMessage::Event {
+                sub_id: next!(1),
+                event: next!(2),
+            }),
+            "OK" => Ok(RelayMessage::Ok {
+                event_id: next!(1),
+                saved: next!(2),
+                message: next!(3),
+            }),
+            "EOSE" => Ok(RelayMessage::Eose(next!(1))),
+            "CLOSED" => Ok(RelayMessage::Closed {
+                sub_id: next!(1),
+                message: next!(2),
+            }),
+            "NOTICE" => Ok(RelayMessage::Notice(next!(1))),
+            "AUTH" => Ok(RelayMessage::Auth(next!(1))),
+            "COUNT" => {
+                let sub_id = next!(1);
+                let payload: CountPayload = next!(2);
+                Ok(RelayMessage::Count {
+                    sub_id,
+                    count: payload.count,
+                })
+            }
+            "IDS" => Ok(RelayMessage::Ids {
+                sub_id: next!(1),
+                ids: next!(2),
+            }),
+            _ => Err(de::Error::unknown_variant(
+                t,
+                &[
+                    "EVENT", "OK", "EOSE", "CLOSED", "NOTICE", "AUTH", "COUNT", "IDS",
+                ],
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RelayMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(RelayMessageVisitor(PhantomData))
+    }
 }
 
 impl Display for OutgoingMessage {
@@ -329,6 +726,14 @@ pub struct WriteEvent {
     pub event: Event,
 }
 
+/// The events of an `EVENTS` message, written in one transaction
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct WriteEvents {
+    pub id: usize,
+    pub events: Vec<Event>,
+}
+
 #[derive(Message, Clone, Debug)]
 #[rtype(result = "()")]
 pub enum WriteEventResult {
@@ -367,6 +772,8 @@ pub struct ReadEventResult {
 #[derive(MessageResponse, Clone, Debug, PartialEq, Eq)]
 pub enum Subscribed {
     Ok,
+    /// the subscription is replaced with the same filters
+    Unchanged,
     Overlimit,
     InvalidIdLength,
 }
@@ -392,6 +799,11 @@ pub struct Dispatch {
     pub event: Event,
 }
 
+/// The setting is reloaded, close the subscriptions over the new limits
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct SettingChanged;
+
 #[derive(Message, Clone, Debug)]
 #[rtype(result = "()")]
 pub struct SubscribeResult {
@@ -403,7 +815,10 @@ pub struct SubscribeResult {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::counter_value;
     use anyhow::Result;
+    use metrics_util::debugging::DebuggingRecorder;
+    use nostr_db::secp256k1::{rand::thread_rng, Keypair};
 
     #[test]
     fn de_incoming_message() -> Result<()> {
@@ -440,6 +855,42 @@ mod tests {
         let msg = serde_json::from_str::<IncomingMessage>(r#"["REQ", "sub_id1"]"#);
         assert!(msg.is_ok());
 
+        // trailing options
+        let text = r#"["REQ","sub_id1",{"kinds":[1]},{"total_limit":10,"prefer":"index-only"}]"#;
+        let msg: IncomingMessage = serde_json::from_str(text)?;
+        assert!(matches!(msg, IncomingMessage::Req(ref sub)
+            if sub.filters.len() == 1 && sub.options.total_limit == Some(10)));
+        assert_eq!(
+            serde_json::to_string(&msg)?,
+            r#"["REQ","sub_id1",{"kinds":[1]},{"total_limit":10}]"#
+        );
+        // filter-shaped objects are filters
+        for text in [
+            r#"["REQ","sub_id1",{"kinds":[1]},{"limit":10}]"#,
+            r##"["REQ","sub_id1",{"kinds":[1]},{"#t":["nostr"],"total_limit":10}]"##,
+            r#"["REQ","sub_id1",{"kinds":[1]},{}]"#,
+        ] {
+            let msg: IncomingMessage = serde_json::from_str(text)?;
+            assert!(matches!(msg, IncomingMessage::Req(ref sub)
+                if sub.filters.len() == 2 && sub.options.is_empty()));
+        }
+        // a single object is a filter
+        let msg: IncomingMessage = serde_json::from_str(r#"["REQ","sub_id1",{"foo":1}]"#)?;
+        assert!(matches!(msg, IncomingMessage::Req(ref sub) if sub.filters.len() == 1));
+        let msg = serde_json::from_str::<IncomingMessage>(
+            r#"["REQ","sub_id1",{"kinds":[1]},{"total_limit":"10"}]"#,
+        );
+        assert!(msg.is_err());
+        let text = r#"["REQ","sub_id1",{"kinds":[1]},{"projection":"ids"}]"#;
+        let msg: IncomingMessage = serde_json::from_str(text)?;
+        assert!(matches!(msg, IncomingMessage::Req(ref sub)
+            if sub.filters.len() == 1 && sub.options.projection == Some(Projection::Ids)));
+        assert_eq!(serde_json::to_string(&msg)?, text);
+        let msg = serde_json::from_str::<IncomingMessage>(
+            r#"["REQ","sub_id1",{"kinds":[1]},{"projection":"events"}]"#,
+        );
+        assert!(msg.is_err());
+
         // unknown
         let msg: IncomingMessage = serde_json::from_str(r#"["REQ1", "sub_id1", {}]"#)?;
         assert!(matches!(msg, IncomingMessage::Unknown(ref cmd, ref _val) if cmd == "REQ1"));
@@ -462,6 +913,16 @@ mod tests {
         let msg: IncomingMessage = serde_json::from_str(r#"["COUNT", "sub_id1", {}]"#)?;
         assert!(matches!(msg, IncomingMessage::Count(sub) if sub.id == "sub_id1"));
 
+        // events
+        let event = r#"{"content":"","created_at":1,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[]}"#;
+        let msg: IncomingMessage =
+            serde_json::from_str(&format!(r#"["EVENTS", {}, {}]"#, event, event))?;
+        assert!(matches!(msg, IncomingMessage::Events(ref events) if events.len() == 2));
+        assert_eq!(msg.known_command(), Some("EVENTS"));
+        let msg: IncomingMessage = serde_json::from_str(r#"["EVENTS"]"#)?;
+        assert!(matches!(msg, IncomingMessage::Events(ref events) if events.is_empty()));
+        assert!(serde_json::from_str::<IncomingMessage>(r#"["EVENTS", {}]"#).is_err());
+
         Ok(())
     }
 
@@ -519,4 +980,322 @@ mod tests {
         assert!(msg.validate_nip70().is_err());
         Ok(())
     }
+
+    #[test]
+    fn validate_control_chars() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let validate = |content: &str, limitation: &Limitation| -> Result<Result<(), Error>> {
+            let event = Event::create(&key_pair, now(), 1, vec![], content.to_owned())?;
+            let text = serde_json::to_string(&json!(["EVENT", event]))?;
+            let mut msg = ClientMessage::new(1, text.clone(), serde_json::from_str(&text)?);
+            Ok(msg.validate(limitation))
+        };
+        let reject = Limitation {
+            reject_control_chars: true,
+            ..Default::default()
+        };
+        let spam = "free\u{200B}money";
+        // off by default
+        assert!(validate(spam, &Limitation::default())?.is_ok());
+        let err = validate(spam, &reject)?.unwrap_err();
+        assert_eq!(err.to_string(), "blocked: invalid content characters");
+        assert!(validate("bell\u{7}", &reject)?.is_err());
+
+        assert!(validate(
+            "Good morning\teveryone 😃\r\n👨\u{200D}👩\u{200D}👧",
+            &reject
+        )?
+        .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_unknown_filter_fields() -> Result<()> {
+        let text = r##"["REQ","sub1",{"kinds":[1],"#t":["nostr"]},{"kinds":[1],"foo":1}]"##;
+        let parse = || -> Result<ClientMessage> {
+            Ok(ClientMessage::new(
+                1,
+                text.to_owned(),
+                serde_json::from_str(text)?,
+            ))
+        };
+
+        // lenient by default
+        let mut msg = parse()?;
+        assert!(msg.validate(&Limitation::default()).is_ok());
+
+        let strict = Limitation {
+            lenient_filters: false,
+            ..Default::default()
+        };
+        let err = parse()?.validate(&strict).unwrap_err();
+        assert!(err.to_string().contains("unknown filter field foo"));
+
+        let text = r##"["COUNT","sub1",{"kinds":[1],"bar":true}]"##;
+        let mut msg = ClientMessage::new(1, text.to_owned(), serde_json::from_str(text)?);
+        assert!(msg.validate(&strict).is_err());
+
+        let text = r##"["REQ","sub1",{"kinds":[1],"#t":["nostr"]}]"##;
+        let mut msg = ClientMessage::new(1, text.to_owned(), serde_json::from_str(text)?);
+        assert!(msg.validate(&strict).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_unbounded() -> Result<()> {
+        let validate = |text: &str, limitation: &Limitation| -> Result<Result<(), Error>> {
+            let mut msg = ClientMessage::new(1, text.to_owned(), serde_json::from_str(text)?);
+            Ok(msg.validate(limitation))
+        };
+        let reject = Limitation {
+            reject_unbounded_req: true,
+            ..Default::default()
+        };
+        let unbounded = r##"["REQ","sub1",{"kinds":[1]},{}]"##;
+        assert!(validate(unbounded, &Limitation::default())?.is_ok());
+        let err = validate(unbounded, &reject)?.unwrap_err();
+        assert!(matches!(err, Error::FilterTooBroad));
+        assert_eq!(err.to_string(), "invalid: filter too broad");
+
+        assert!(validate(r##"["REQ","sub1",{"kinds":[1]},{"limit":10}]"##, &reject)?.is_ok());
+        assert!(validate(r##"["REQ","sub1",{"since":10}]"##, &reject)?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_timestamps() -> Result<()> {
+        let filters = |text: &str, limitation: &Limitation| -> Result<Result<Vec<Filter>, Error>> {
+            let mut msg = ClientMessage::new(1, text.to_owned(), serde_json::from_str(text)?);
+            Ok(msg.validate(limitation).map(|_| match msg.msg {
+                IncomingMessage::Req(sub) | IncomingMessage::Count(sub) => sub.filters,
+                _ => unreachable!(),
+            }))
+        };
+        let lenient = Limitation {
+            lenient_timestamps: true,
+            ..Default::default()
+        };
+        let text = r##"["REQ","sub1",{"since":1700000000000,"until":1700000001}]"##;
+        let err = filters(text, &Limitation::default())?.unwrap_err();
+        assert_eq!(err.to_string(), "invalid: timestamp out of range");
+        let f = filters(text, &lenient)?.unwrap();
+        assert_eq!(f[0].since, Some(1700000000));
+        assert_eq!(f[0].until, Some(1700000001));
+
+        let text = r##"["COUNT","sub1",{"until":1700000000000}]"##;
+        assert!(filters(text, &Limitation::default())?.is_err());
+        assert_eq!(filters(text, &lenient)?.unwrap()[0].until, Some(1700000000));
+        // microseconds
+        let text = r##"["REQ","sub1",{"since":1700000000000000}]"##;
+        assert!(filters(text, &lenient)?.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_empty_lists() -> Result<()> {
+        let filters = |text: &str, limitation: &Limitation| -> Result<Vec<Filter>> {
+            let mut msg = ClientMessage::new(1, text.to_owned(), serde_json::from_str(text)?);
+            msg.validate(limitation)?;
+            match msg.msg {
+                IncomingMessage::Req(sub) => Ok(sub.filters),
+                _ => unreachable!(),
+            }
+        };
+        let reject = Limitation {
+            reject_empty_lists: true,
+            ..Default::default()
+        };
+        let text = r##"["REQ","sub1",{"ids":[]}]"##;
+        // match all as absent
+        let f = filters(text, &Limitation::default())?;
+        assert_eq!(f.len(), 1);
+        assert!(f[0].ids.is_empty());
+        assert_eq!(f[0].empty_fields, vec!["ids"]);
+        // match nothing
+        assert!(filters(text, &reject)?.is_empty());
+
+        let text = r##"["REQ","sub1",{"kinds":[1],"#e":[]},{"kinds":[1]}]"##;
+        assert_eq!(filters(text, &Limitation::default())?.len(), 2);
+        assert_eq!(filters(text, &reject)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_min_prefix() -> Result<()> {
+        let validate = |text: &str| -> Result<(), Error> {
+            let mut msg = ClientMessage::new(1, text.to_owned(), serde_json::from_str(text)?);
+            msg.validate(&Limitation::default())
+        };
+        // default min_prefix 10
+        assert!(validate(r##"["REQ","sub1",{"authors":["0123456789"]}]"##).is_ok());
+        assert!(validate(r##"["REQ","sub1",{"authors":["012345678"]}]"##).is_err());
+        assert!(validate(r##"["REQ","sub1",{"ids":["01234567"]}]"##).is_err());
+        assert!(validate(r##"["COUNT","sub1",{"ids":["01234567"]}]"##).is_err());
+        let id = "0".repeat(64);
+        assert!(validate(&format!(r##"["REQ","sub1",{{"ids":["{}"]}}]"##, id)).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let event = r#"{"content":"Good morning everyone 😃","created_at":1680690006,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[["t","nostr"]]}"#;
+        let pubkey = "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef";
+
+        // client to relay
+        let incoming = [
+            ("EVENT", format!(r#"["EVENT",{}]"#, event)),
+            ("AUTH", format!(r#"["AUTH",{}]"#, event)),
+            ("CLOSE", r#"["CLOSE","sub1"]"#.to_owned()),
+            ("REQ", r#"["REQ","sub1"]"#.to_owned()),
+            (
+                "REQ",
+                format!(
+                    r##"["REQ","sub1",{{"authors":["{}"],"kinds":[1,2],"since":10,"until":20,"limit":5,"#p":["{}"],"#t":["nostr"]}},{{"search":"hello"}}]"##,
+                    pubkey, pubkey
+                ),
+            ),
+            (
+                "COUNT",
+                format!(r#"["COUNT","sub1",{{"ids":["{}"]}}]"#, pubkey),
+            ),
+            ("UNKNOWN", r#"["UNKNOWN","sub1",{"a":1}]"#.to_owned()),
+        ];
+        for (cmd, text) in incoming {
+            let msg: IncomingMessage = serde_json::from_str(&text)?;
+            assert_eq!(msg.command(), cmd);
+            let rendered = serde_json::to_string(&msg)?;
+            assert_eq!(
+                serde_json::from_str::<Value>(&rendered)?,
+                serde_json::from_str::<Value>(&text)?,
+                "{}",
+                text
+            );
+            let msg: IncomingMessage = serde_json::from_str(&rendered)?;
+            assert_eq!(serde_json::to_string(&msg)?, rendered);
+        }
+
+        // relay to client
+        let outgoing = [
+            OutgoingMessage::event("sub1", event),
+            OutgoingMessage::ok("id", true, ""),
+            OutgoingMessage::ok("id", false, "invalid: bad"),
+            OutgoingMessage::eose("sub1"),
+            OutgoingMessage::closed("sub1", "error: closed"),
+            OutgoingMessage::notice("hello"),
+            OutgoingMessage::auth("challenge"),
+            OutgoingMessage::count("sub1", 10),
+            OutgoingMessage::ids("sub1", &["aa".to_owned(), "bb".to_owned()]),
+        ];
+        for out in outgoing {
+            let msg: RelayMessage = serde_json::from_str(&out.0)?;
+            let rendered = OutgoingMessage::from(&msg);
+            assert_eq!(
+                serde_json::from_str::<Value>(&rendered.0)?,
+                serde_json::from_str::<Value>(&out.0)?,
+                "{}",
+                out
+            );
+        }
+
+        let msg: RelayMessage = serde_json::from_str(r#"["COUNT","sub1",{"count": 3}]"#)?;
+        assert!(matches!(msg, RelayMessage::Count { ref sub_id, count: 3 } if sub_id == "sub1"));
+        let msg: RelayMessage = serde_json::from_str(r#"["CLOSED","sub1","reason"]"#)?;
+        assert!(
+            matches!(msg, RelayMessage::Closed { ref sub_id, ref message } if sub_id == "sub1" && message == "reason")
+        );
+        assert!(serde_json::from_str::<RelayMessage>(r#"["CLOSED","sub1"]"#).is_err());
+        assert!(serde_json::from_str::<RelayMessage>(r#"["REQ","sub1"]"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_metrics() -> Result<()> {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let limitation = Limitation {
+            max_event_tags: 2,
+            ..Default::default()
+        };
+        let validate = |event: Event| {
+            let text = format!(r#"["EVENT",{}]"#, event);
+            let mut msg = ClientMessage::new(1, text, IncomingMessage::Event(event));
+            msg.validate(&limitation)
+        };
+        let create = |time: u64, tags: Vec<Vec<String>>| {
+            Event::create(&key_pair, time, 1, tags, "".to_owned())
+        };
+
+        metrics::with_local_recorder(&recorder, || -> Result<()> {
+            assert!(validate(create(now(), vec![])?).is_ok());
+            // NIP-22
+            let window = "invalid: event creation date is too far off from the current time";
+            let err = validate(create(10, vec![])?).unwrap_err().to_string();
+            assert!(err.starts_with(window), "{}", err);
+            let err = validate(create(now() + 10000, vec![])?)
+                .unwrap_err()
+                .to_string();
+            assert!(err.starts_with(window), "{}", err);
+            let tags = vec![vec!["t".to_owned(), "nostr".to_owned()]; 3];
+            assert!(validate(create(now(), tags)?).is_err());
+            let tags = vec![vec!["expiration".to_owned(), "1".to_owned()]];
+            assert!(validate(create(now(), tags)?).is_err());
+            // NIP-40
+            let capped = Limitation {
+                max_event_expiration_from_now: 3600,
+                ..Default::default()
+            };
+            let far = now() + 100 * 365 * 86400;
+            let expiring = |expiration: u64| {
+                create(
+                    now(),
+                    vec![vec!["expiration".to_owned(), expiration.to_string()]],
+                )
+            };
+            let validate_capped = |event: Event| {
+                let text = format!(r#"["EVENT",{}]"#, event);
+                ClientMessage::new(1, text, IncomingMessage::Event(event)).validate(&capped)
+            };
+            assert!(validate_capped(expiring(now() + 60)?).is_ok());
+            let err = validate_capped(expiring(far)?).unwrap_err().to_string();
+            assert!(
+                err.starts_with("invalid: event expiration is too far off from the current time"),
+                "{}",
+                err
+            );
+            // no cap by default
+            assert!(validate(expiring(far)?).is_ok());
+
+            let event = create(now(), vec![])?;
+            let other = create(now(), vec![vec!["t".to_owned()]])?;
+            let mut json: Value = serde_json::from_str(&event.to_string())?;
+            json["sig"] = Value::String(hex::encode(other.sig()));
+            assert!(validate(serde_json::from_value(json.clone())?).is_err());
+            json["content"] = Value::String("changed".to_owned());
+            assert!(validate(serde_json::from_value(json)?).is_err());
+            Ok(())
+        })?;
+
+        for reason in [
+            "too_old",
+            "too_new",
+            "too_many_tags",
+            "expired",
+            "expiration_too_far",
+            "invalid_sig",
+            "invalid_id",
+        ] {
+            assert_eq!(
+                counter_value(
+                    &snapshotter,
+                    "nostr_relay_event_rejected_total",
+                    ("reason", reason)
+                ),
+                1,
+                "{}",
+                reason
+            );
+        }
+        Ok(())
+    }
 }
@@ -1,8 +1,25 @@
-use crate::{message::*, setting::SettingWrapper, Result};
+use crate::{message::*, setting::SettingWrapper, QueryCache, Result};
 use actix::prelude::*;
-use metrics::histogram;
-use nostr_db::Db;
-use std::{sync::Arc, time::Instant};
+use metrics::{counter, histogram};
+use nostr_db::{
+    kv::lmdb::{Reader as Txn, ResetReader},
+    Db, Error, FromEventData, Iter,
+};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The fields of the stored event json checked before sending
+#[derive(Deserialize)]
+struct EventFields {
+    id: String,
+    pubkey: String,
+    tags: Vec<Vec<String>>,
+}
 
 /// Requst by filter
 /// Concurrent read events from db
@@ -10,32 +27,203 @@ pub struct Reader {
     pub db: Arc<Db>,
     pub addr: Recipient<ReadEventResult>,
     pub setting: SettingWrapper,
+    pub cache: Option<Arc<QueryCache>>,
+    // the read transaction of the reader thread, renewed for each request
+    txn: RefCell<Option<ResetReader>>,
 }
 
 impl Reader {
     pub fn new(db: Arc<Db>, addr: Recipient<ReadEventResult>, setting: SettingWrapper) -> Self {
-        Self { db, addr, setting }
+        Self {
+            db,
+            addr,
+            setting,
+            cache: None,
+            txn: RefCell::new(None),
+        }
+    }
+
+    /// Serve the repeated identical filters from the cache
+    pub fn with_cache(mut self, cache: Option<Arc<QueryCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Send the event, return false if reach the total limit
+    fn send(
+        &self,
+        msg: &ReadEvent,
+        event: &str,
+        sent: Option<&mut HashSet<String>>,
+        remaining: &mut Option<u64>,
+    ) -> Result<bool> {
+        if msg.subscription.protect_reads || sent.is_some() {
+            let e = serde_json::from_str::<EventFields>(event)?;
+            // NIP-70: the protected events are sent to the author only
+            if msg.subscription.protect_reads
+                && e.tags.iter().any(|tag| tag.len() == 1 && tag[0] == "-")
+                && msg.subscription.viewer.map(hex::encode).as_ref() != Some(&e.pubkey)
+            {
+                return Ok(true);
+            }
+            if let Some(sent) = sent {
+                if !sent.insert(e.id) {
+                    return Ok(true);
+                }
+            }
+        }
+        if let Some(remaining) = remaining.as_mut() {
+            if *remaining == 0 {
+                return Ok(false);
+            }
+            *remaining -= 1;
+        }
+        self.addr.do_send(ReadEventResult {
+            id: msg.id,
+            sub_id: msg.subscription.id.clone(),
+            msg: OutgoingMessage::event(&msg.subscription.id, event),
+        });
+        Ok(true)
+    }
+
+    /// Send the collected ids of the ids projection in one message
+    fn send_ids(&self, msg: &ReadEvent, ids: &mut Vec<String>) {
+        if ids.is_empty() {
+            return;
+        }
+        self.addr.do_send(ReadEventResult {
+            id: msg.id,
+            sub_id: msg.subscription.id.clone(),
+            msg: OutgoingMessage::ids(&msg.subscription.id, ids),
+        });
+        ids.clear();
+    }
+
+    /// Bound the scan of a filter by the subscription deadline and the setting
+    fn limit_scan<J: FromEventData>(
+        iter: &mut Iter<'_, Txn, J>,
+        viewer: Option<&[u8; 32]>,
+        deadline: Option<Instant>,
+        max_scan: Option<u64>,
+    ) -> Result<()> {
+        iter.viewer(viewer);
+        if let Some(deadline) = deadline {
+            iter.scan_deadline(deadline, 2000);
+        }
+        if let Some(max) = max_scan {
+            iter.scan_limit(max);
+        }
+        Ok(())
     }
 
     pub fn read(&self, msg: &ReadEvent) -> Result<()> {
-        let reader = self.db.reader()?;
-        let timeout = self.setting.read().data.db_query_timeout;
-        for filter in &msg.subscription.filters {
+        // before the reader, a result read before the write committed is not cached
+        let version = self.cache.as_ref().map(|cache| cache.version());
+        // the actor runs on its own SyncArbiter thread, LMDB binds the transaction to it
+        let mut txn = self.txn.borrow_mut();
+        if txn.is_none() {
+            *txn = Some(self.db.reset_reader()?);
+        }
+        let reader = txn.as_mut().unwrap().renew().map_err(Error::from)?;
+        let r = self.setting.read();
+        let timeout = r.data.db_query_timeout;
+        let max_scan = r.data.db_query_max_scan;
+        let max_ids = r.limitation.max_limit.max(1) as usize;
+        drop(r);
+        // all filters of the subscription share the timeout
+        let deadline = timeout.map(|time| Instant::now() + Duration::from(time));
+        // an event may match several filters, such as {"authors": [me]} and {"#p": [me]}, send it once
+        let dedup = msg.subscription.filters.len() > 1;
+        let mut sent = HashSet::new();
+        let mut remaining = msg.subscription.options.total_limit;
+        // the cached results don't have the hidden events of the shadowbanned viewer
+        let viewer = msg.subscription.viewer.as_ref();
+        let shadowbanned = match viewer {
+            Some(pubkey) => self.db.is_shadowbanned_cached(pubkey),
+            None => false,
+        };
+        let cache = self.cache.as_ref().filter(|_| !shadowbanned);
+        let ids_only = msg.subscription.options.projection == Some(Projection::Ids);
+        let mut ids = vec![];
+        for (index, filter) in msg.subscription.filters.iter().enumerate() {
+            if remaining == Some(0) {
+                break;
+            }
+            // `limit: 0` only asks for the EOSE
+            if filter.limit == Some(0) {
+                continue;
+            }
+            if let Some(deadline) = deadline {
+                if index > 0 && Instant::now() > deadline {
+                    // the ids found before the timeout are still sent
+                    self.send_ids(msg, &mut ids);
+                    return Err(Error::ScanTimeout.into());
+                }
+            }
             let start = Instant::now();
-            let mut iter = self.db.iter::<String, _>(&reader, filter)?;
-            if let Some(time) = timeout {
-                iter.scan_time(time.into(), 2000);
+            if ids_only {
+                // index-only scan, the event data is not read
+                let mut iter = self.db.iter::<Vec<u8>, _>(&reader, filter)?;
+                Self::limit_scan(&mut iter, viewer, deadline, max_scan)?;
+                for id in iter {
+                    if remaining == Some(0) {
+                        break;
+                    }
+                    let id = match id {
+                        Ok(id) => hex::encode(id),
+                        Err(err) => {
+                            self.send_ids(msg, &mut ids);
+                            return Err(err.into());
+                        }
+                    };
+                    if dedup && !sent.insert(id.clone()) {
+                        continue;
+                    }
+                    if let Some(remaining) = remaining.as_mut() {
+                        *remaining -= 1;
+                    }
+                    ids.push(id);
+                    // a message has max_limit ids at most
+                    if ids.len() >= max_ids {
+                        self.send_ids(msg, &mut ids);
+                    }
+                }
+                histogram!("nostr_relay_db_get").record(start.elapsed());
+                continue;
+            }
+            if let Some(events) = cache.and_then(|cache| cache.get(filter)) {
+                counter!("nostr_relay_query_cache_hit").increment(1);
+                for event in events.iter() {
+                    if !self.send(msg, event, dedup.then_some(&mut sent), &mut remaining)? {
+                        break;
+                    }
+                }
+                histogram!("nostr_relay_db_get").record(start.elapsed());
+                continue;
             }
+            // cache the complete result only
+            let mut cached = cache.map(|_| Vec::new());
+            let mut iter = self.db.iter::<String, _>(&reader, filter)?;
+            Self::limit_scan(&mut iter, viewer, deadline, max_scan)?;
             for event in iter {
                 let event = event?;
-                self.addr.do_send(ReadEventResult {
-                    id: msg.id,
-                    sub_id: msg.subscription.id.clone(),
-                    msg: OutgoingMessage::event(&msg.subscription.id, &event),
-                });
+                if !self.send(msg, &event, dedup.then_some(&mut sent), &mut remaining)? {
+                    cached = None;
+                    break;
+                }
+                if let Some(cached) = cached.as_mut() {
+                    cached.push(event);
+                }
+            }
+            if let (Some(cache), Some(version), Some(cached)) = (cache, version, cached) {
+                cache.insert(version, filter, cached);
             }
             histogram!("nostr_relay_db_get").record(start.elapsed());
         }
+        if ids_only {
+            self.send_ids(msg, &mut ids);
+        }
+        // NIP-15: the results of all filters are queued to the same mailbox before EOSE
         self.addr.do_send(ReadEventResult {
             id: msg.id,
             sub_id: msg.subscription.id.clone(),
@@ -71,12 +259,12 @@ impl Handler<ReadEvent> for Reader {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{temp_data_path, Setting};
+    use crate::{duration::NonZeroDuration, temp_data_path, Setting, Writer};
     use actix_rt::time::sleep;
     use anyhow::Result;
     use nostr_db::{Event, Filter};
     use parking_lot::RwLock;
-    use std::{str::FromStr, time::Duration};
+    use std::str::FromStr;
 
     #[derive(Default)]
     struct Receiver(Arc<RwLock<Vec<ReadEventResult>>>);
@@ -91,6 +279,11 @@ mod tests {
         }
     }
 
+    impl Handler<WriteEventResult> for Receiver {
+        type Result = ();
+        fn handle(&mut self, _msg: WriteEventResult, _ctx: &mut Self::Context) {}
+    }
+
     #[actix_rt::test]
     async fn read() -> Result<()> {
         let db = Arc::new(Db::open(temp_data_path("reader")?)?);
@@ -126,6 +319,7 @@ mod tests {
                         filters: vec![Filter {
                             ..Default::default()
                         }],
+                        ..Default::default()
                     },
                 })
                 .await?;
@@ -136,4 +330,510 @@ mod tests {
         assert_eq!(r.len(), 8);
         Ok(())
     }
+
+    #[cfg(feature = "search")]
+    #[actix_rt::test]
+    async fn search() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_search")?)?);
+        for (id, content) in [(1, "Good morning everyone"), (2, "Good night")] {
+            let mut event = Event::new(
+                [id; 32],
+                [1; 32],
+                10,
+                1,
+                vec![],
+                content.to_owned(),
+                [0; 64],
+            )?;
+            event.build_note_words();
+            db.batch_put(vec![event])?;
+        }
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+        });
+        // NIP-50: the words are built by the search extension
+        let mut filter = Filter::from_str(r#"{"search": "morning"}"#)?;
+        filter.build_words();
+        reader
+            .send(ReadEvent {
+                id: 0,
+                subscription: Subscription {
+                    id: "1".to_owned(),
+                    filters: vec![filter],
+                    ..Default::default()
+                },
+            })
+            .await?;
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 2);
+        assert!(r[0].msg.0.contains("Good morning everyone"));
+        assert_eq!(r[1].msg.0, OutgoingMessage::eose("1").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn dedup() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_dedup")?)?);
+        let me = [1u8; 32];
+        let mention = vec![vec!["p".to_owned(), hex::encode(me)]];
+        let events = vec![
+            // from me and mentioning me
+            Event::new([1; 32], me, 1, 1, mention.clone(), "".to_owned(), [0; 64])?,
+            // from me
+            Event::new([2; 32], me, 2, 1, vec![], "".to_owned(), [0; 64])?,
+            // mentioning me
+            Event::new([3; 32], [2; 32], 3, 1, mention, "".to_owned(), [0; 64])?,
+            Event::new([4; 32], [2; 32], 4, 1, vec![], "".to_owned(), [0; 64])?,
+        ];
+        db.batch_put(events)?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+        });
+
+        let filters = format!(
+            r##"[{{"authors": ["{0}"]}}, {{"#p": ["{0}"]}}]"##,
+            hex::encode(me)
+        );
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription: Subscription {
+                    id: "1".to_owned(),
+                    filters: serde_json::from_str(&filters)?,
+                    ..Default::default()
+                },
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 4);
+        let mut ids = r[0..3]
+            .iter()
+            .map(|m| {
+                let v: serde_json::Value = serde_json::from_str(&m.msg.0).unwrap();
+                v[2]["id"].as_str().unwrap().to_owned()
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec![
+                hex::encode([1; 32]),
+                hex::encode([2; 32]),
+                hex::encode([3; 32])
+            ]
+        );
+        assert_eq!(r[3].msg.0, OutgoingMessage::eose("1").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn total_limit() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_total_limit")?)?);
+        let events = (0..10u8)
+            .map(|i| {
+                Event::new(
+                    [i; 32],
+                    [1; 32],
+                    i as u64,
+                    (i % 2 + 1) as u32,
+                    vec![],
+                    "".to_owned(),
+                    [0; 64],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+        });
+
+        let msg: IncomingMessage = serde_json::from_str(
+            r#"["REQ","1",{"kinds":[1]},{"kinds":[2]},{"total_limit":7,"prefer":"index-only"}]"#,
+        )?;
+        let IncomingMessage::Req(subscription) = msg else {
+            panic!("not a REQ");
+        };
+        assert_eq!(subscription.filters.len(), 2);
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription,
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        // 5 events of the first filter, 2 of the second
+        assert_eq!(r.len(), 8);
+        assert_eq!(r[7].msg.0, OutgoingMessage::eose("1").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn projection_ids() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_projection_ids")?)?);
+        let events = (1..=4u8)
+            .map(|i| {
+                Event::new(
+                    [i; 32],
+                    [1; 32],
+                    i as u64,
+                    1,
+                    vec![],
+                    "".to_owned(),
+                    [0; 64],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+        });
+
+        let msg: IncomingMessage = serde_json::from_str(&format!(
+            r#"["REQ","1",{{"kinds":[1],"limit":3}},{{"ids":["{}"]}},{{"projection":"ids"}}]"#,
+            hex::encode([4u8; 32])
+        ))?;
+        let IncomingMessage::Req(subscription) = msg else {
+            panic!("not a REQ");
+        };
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription,
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 2);
+        let msg: RelayMessage = serde_json::from_str(&r[0].msg.0)?;
+        let RelayMessage::Ids { sub_id, ids } = msg else {
+            panic!("not IDS");
+        };
+        assert_eq!(sub_id, "1");
+        // the id of the second filter is sent by the first
+        assert_eq!(
+            ids,
+            vec![
+                hex::encode([4u8; 32]),
+                hex::encode([3u8; 32]),
+                hex::encode([2u8; 32])
+            ]
+        );
+        assert_eq!(r[1].msg.0, OutgoingMessage::eose("1").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn projection_ids_chunk() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_projection_ids_chunk")?)?);
+        let events = (1..=3u8)
+            .map(|i| {
+                Event::new(
+                    [i; 32],
+                    [1; 32],
+                    i as u64,
+                    1,
+                    vec![],
+                    "".to_owned(),
+                    [0; 64],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().limitation.max_limit = 2;
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), setting.clone())
+        });
+
+        let msg: IncomingMessage =
+            serde_json::from_str(r#"["REQ","1",{"kinds":[1]},{"projection":"ids"}]"#)?;
+        let IncomingMessage::Req(mut subscription) = msg else {
+            panic!("not a REQ");
+        };
+        subscription.filters[0].limit = Some(10);
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription,
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        // 2 ids in the first message, 1 in the second
+        assert_eq!(r.len(), 3);
+        let lens = r[0..2]
+            .iter()
+            .map(|r| match serde_json::from_str(&r.msg.0) {
+                Ok(RelayMessage::Ids { ids, .. }) => ids.len(),
+                _ => panic!("not IDS"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lens, vec![2, 1]);
+        assert_eq!(r[2].msg.0, OutgoingMessage::eose("1").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn limit_zero() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_limit_zero")?)?);
+        db.batch_put(vec![Event::new(
+            [1; 32],
+            [1; 32],
+            1,
+            1,
+            vec![],
+            "".to_owned(),
+            [0; 64],
+        )?])?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+        });
+
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription: Subscription {
+                    id: "1".to_owned(),
+                    filters: serde_json::from_str(r#"[{"kinds":[1],"limit":0}]"#)?,
+                    ..Default::default()
+                },
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].msg.0, OutgoingMessage::eose("1").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn timeout() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_timeout")?)?);
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().data.db_query_timeout = NonZeroDuration::new(Duration::from_nanos(1));
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), setting.clone())
+        });
+
+        // each filter is too small to reach the scan check step,
+        // but the subscription as a whole is over the timeout.
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription: Subscription {
+                    id: "1".to_owned(),
+                    filters: vec![Filter::default(), Filter::default()],
+                    ..Default::default()
+                },
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 1);
+        assert_eq!(
+            r[0].msg.0,
+            OutgoingMessage::closed("1", "get event error: Scan timeout").0
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn cache() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_cache")?)?);
+        let event = |i: u8| {
+            Event::new(
+                [i; 32],
+                [1; 32],
+                i as u64,
+                1,
+                vec![],
+                "".to_owned(),
+                [0; 64],
+            )
+        };
+        db.batch_put(vec![event(1)?, event(2)?])?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        let cache = Some(Arc::new(QueryCache::new(10, Duration::from_secs(10))));
+        let mut writer =
+            Writer::new(Arc::clone(&db), receiver.clone().recipient()).with_cache(cache.clone());
+        let addr = receiver.recipient();
+        let reader_db = Arc::clone(&db);
+        let reader_cache = cache.clone();
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(
+                Arc::clone(&reader_db),
+                addr.clone(),
+                Setting::default().into(),
+            )
+            .with_cache(reader_cache.clone())
+        });
+        let read = |id: usize| ReadEvent {
+            id,
+            subscription: Subscription {
+                id: id.to_string(),
+                filters: vec![Filter::from_str(r#"{"kinds":[1]}"#).unwrap()],
+                ..Default::default()
+            },
+        };
+
+        reader.send(read(1)).await?;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(messages.read().len(), 3);
+        assert_eq!(cache.as_ref().unwrap().len(), 1);
+
+        // served from the cache, the event stored without the writer is not seen
+        db.batch_put(vec![event(3)?])?;
+        reader.send(read(2)).await?;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(messages.read().len(), 6);
+
+        // invalidated by the writer
+        writer.events.push(WriteEvent {
+            id: 1,
+            event: event(4)?,
+        });
+        writer.write()?;
+        assert!(cache.as_ref().unwrap().is_empty());
+        reader.send(read(3)).await?;
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 11);
+        assert_eq!(r[10].msg.0, OutgoingMessage::eose("3").0);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn scan_limit() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_scan_limit")?)?);
+        let events = (0..10u8)
+            .map(|i| {
+                Event::new(
+                    [i; 32],
+                    [1; 32],
+                    i as u64,
+                    1,
+                    vec![],
+                    "".to_owned(),
+                    [0; 64],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().data.db_query_max_scan = Some(5);
+        let reader = SyncArbiter::start(1, move || {
+            Reader::new(Arc::clone(&db), addr.clone(), setting.clone())
+        });
+
+        reader
+            .send(ReadEvent {
+                id: 1,
+                subscription: Subscription {
+                    id: "1".to_owned(),
+                    filters: vec![Filter::default()],
+                    ..Default::default()
+                },
+            })
+            .await?;
+
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 6);
+        assert_eq!(
+            r[5].msg.0,
+            OutgoingMessage::closed("1", "get event error: Scan limit exceeded").0
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_renew() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader_concurrent_renew")?)?);
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let db1 = db.clone();
+        let reader = SyncArbiter::start(3, move || {
+            Reader::new(Arc::clone(&db1), addr.clone(), Setting::default().into())
+        });
+
+        for round in 0..3u8 {
+            let event = Event::new(
+                [round; 32],
+                [1; 32],
+                round as u64,
+                1,
+                vec![],
+                "".to_owned(),
+                [0; 64],
+            )?;
+            db.batch_put(vec![event])?;
+            // the reader threads renew their transactions and see the new event
+            let reads = (0..9)
+                .map(|i| {
+                    reader.send(ReadEvent {
+                        id: i,
+                        subscription: Subscription {
+                            id: i.to_string(),
+                            filters: vec![Filter::default()],
+                            ..Default::default()
+                        },
+                    })
+                })
+                .collect::<Vec<_>>();
+            for read in reads {
+                read.await?;
+            }
+            sleep(Duration::from_millis(100)).await;
+            let mut r = messages.write();
+            assert_eq!(r.len(), 9 * (round as usize + 2));
+            for i in 0..9 {
+                let events = r
+                    .iter()
+                    .filter(|m| m.id == i && m.msg.0.starts_with(r#"["EVENT""#))
+                    .count();
+                assert_eq!(events, round as usize + 1);
+            }
+            r.clear();
+        }
+        Ok(())
+    }
 }
@@ -1,8 +1,58 @@
-use crate::{message::*, setting::SettingWrapper, Reader, Subscriber, Writer};
+use crate::{
+    message::*,
+    setting::{Durability, SettingWrapper},
+    QueryCache, Reader, Subscriber, Writer,
+};
 use actix::prelude::*;
+use metrics::counter;
 use nostr_db::{CheckEventResult, Db};
-use std::{collections::HashMap, sync::Arc};
-use tracing::info;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// log the dropped messages of a session at most once per interval
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The subscription messages dropped by the full session mailboxes
+#[derive(Debug)]
+struct Drops {
+    interval: Duration,
+    /// map session_id -> (count since the last log, the last log time)
+    sessions: HashMap<usize, (u64, Option<Instant>)>,
+}
+
+impl Drops {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Count a message dropped by the full mailbox, log the number of the drops of the session
+    /// since the last log at most once per interval.
+    /// Return the number logged.
+    fn dropped(&mut self, session_id: usize) -> Option<u64> {
+        counter!("nostr_relay_message_dropped_total", "source" => "subscriber").increment(1);
+        let (count, logged_at) = self.sessions.entry(session_id).or_default();
+        *count += 1;
+        if logged_at.is_some_and(|time| time.elapsed() < self.interval) {
+            return None;
+        }
+        let logged = *count;
+        warn!(
+            session_id,
+            count = logged,
+            "dropped messages of the session, the mailbox is full"
+        );
+        *count = 0;
+        *logged_at = Some(Instant::now());
+        Some(logged)
+    }
+}
 
 /// Server
 #[derive(Debug)]
@@ -12,6 +62,8 @@ pub struct Server {
     reader: Addr<Reader>,
     subscriber: Addr<Subscriber>,
     sessions: HashMap<usize, Recipient<OutgoingMessage>>,
+    setting: SettingWrapper,
+    drops: Drops,
 }
 
 impl Server {
@@ -22,15 +74,32 @@ impl Server {
         } else {
             r.thread.reader
         };
+        let cache = (r.data.query_cache > 0).then(|| {
+            Arc::new(QueryCache::new(
+                r.data.query_cache,
+                r.data.query_cache_ttl.into(),
+            ))
+        });
+        let expiration_sweep_interval = r.data.expiration_sweep_interval;
+        let flush_interval =
+            (r.data.durability == Durability::Async).then(|| r.data.flush_interval.into());
         drop(r);
 
         Server::create(|ctx| {
-            let writer = Writer::new(Arc::clone(&db), ctx.address().recipient()).start();
-            let subscriber = Subscriber::new(ctx.address().recipient(), setting.clone()).start();
+            let writer = Writer::new(Arc::clone(&db), ctx.address().recipient())
+                .with_cache(cache.clone())
+                .with_expiration_sweep_interval(expiration_sweep_interval)
+                .with_flush_interval(flush_interval)
+                .start();
+            let subscriber = Subscriber::new(ctx.address().recipient(), setting.clone())
+                .with_db(Some(Arc::clone(&db)))
+                .start();
             let addr = ctx.address().recipient();
             info!("starting {} reader workers", num);
+            let reader_setting = setting.clone();
             let reader = SyncArbiter::start(num, move || {
-                Reader::new(Arc::clone(&db), addr.clone(), setting.clone())
+                Reader::new(Arc::clone(&db), addr.clone(), reader_setting.clone())
+                    .with_cache(cache.clone())
             });
 
             Server {
@@ -39,6 +108,8 @@ impl Server {
                 reader,
                 subscriber,
                 sessions: HashMap::new(),
+                setting,
+                drops: Drops::new(DROP_LOG_INTERVAL),
             }
         })
     }
@@ -84,6 +155,7 @@ impl Handler<Disconnect> for Server {
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
         // remove address
         self.sessions.remove(&msg.id);
+        self.drops.sessions.remove(&msg.id);
 
         // clear subscriptions
         self.subscriber.do_send(Unsubscribe {
@@ -103,6 +175,9 @@ impl Handler<ClientMessage> for Server {
                 // save ephemeral for check duplicate, disconnection recovery, will be deleted
                 self.writer.do_send(WriteEvent { id: msg.id, event })
             }
+            IncomingMessage::Events(events) => {
+                self.writer.do_send(WriteEvents { id: msg.id, events })
+            }
             IncomingMessage::Close(id) => self.subscriber.do_send(Unsubscribe {
                 id: msg.id,
                 sub_id: Some(id),
@@ -126,6 +201,10 @@ impl Handler<ClientMessage> for Server {
                                 Subscribed::Ok => {
                                     act.reader.do_send(read_event);
                                 }
+                                Subscribed::Unchanged => {
+                                    // the client has the stored events of the same filters
+                                    act.send_to_client(session_id, OutgoingMessage::eose(&sub_id));
+                                }
                                 Subscribed::Overlimit => {
                                     act.send_to_client(
                                         session_id,
@@ -167,7 +246,18 @@ impl Handler<WriteEventResult> for Server {
             WriteEventResult::Write { id, event, result } => {
                 let event_id = event.id_str();
                 let out_msg = match &result {
-                    CheckEventResult::Ok(_num) => OutgoingMessage::ok(&event_id, true, ""),
+                    CheckEventResult::Ok(_num) => {
+                        let dropped = event.dropped_tags();
+                        if dropped > 0 && self.setting.read().limitation.notice_on_unindexed_tags {
+                            OutgoingMessage::ok(
+                                &event_id,
+                                true,
+                                &format!("indexed: {} tags not indexed", dropped),
+                            )
+                        } else {
+                            OutgoingMessage::ok(&event_id, true, "")
+                        }
+                    }
                     CheckEventResult::Duplicate => {
                         OutgoingMessage::ok(&event_id, true, "duplicate: event exists")
                     }
@@ -201,21 +291,36 @@ impl Handler<ReadEventResult> for Server {
     }
 }
 
+impl Handler<SettingChanged> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: SettingChanged, _: &mut Self::Context) {
+        self.subscriber.do_send(msg);
+    }
+}
+
 impl Handler<SubscribeResult> for Server {
     type Result = ();
     fn handle(&mut self, msg: SubscribeResult, _: &mut Self::Context) {
-        self.send_to_client(msg.id, msg.msg);
+        // the slow session drops the live events of the subscriptions by the own mailbox,
+        // the stored events and the other messages such as CLOSED are always sent
+        if let Some(addr) = self.sessions.get(&msg.id) {
+            if !msg.msg.is_event() {
+                addr.do_send(msg.msg);
+            } else if let Err(SendError::Full(_)) = addr.try_send(msg.msg) {
+                self.drops.dropped(msg.id);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{temp_data_path, Setting};
+    use crate::{counter_value, temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
+    use metrics_util::debugging::DebuggingRecorder;
     use parking_lot::RwLock;
-    use std::time::Duration;
 
     #[derive(Default)]
     struct Receiver(Arc<RwLock<Vec<OutgoingMessage>>>);
@@ -381,4 +486,241 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn raw_json() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_raw_json")?)?);
+        let note = r#"{"tags": [["t", "nostr"]], "kind": 1, "content": "Good morning everyone 😃", "created_at": 1680690006, "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d", "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef", "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f"}"#;
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        let addr = receiver.recipient();
+        let server = Server::create_with(db, Setting::default().into());
+        let id = server.send(Connect { addr }).await?;
+
+        let send = |text: String| {
+            let msg = serde_json::from_str::<IncomingMessage>(&text).unwrap();
+            server.send(ClientMessage::new(id, text, msg))
+        };
+
+        send(r#"["REQ", "1", {}]"#.to_owned()).await?;
+        sleep(Duration::from_millis(50)).await;
+        messages.write().clear();
+
+        send(format!(r#"["EVENT", {}]"#, note)).await?;
+        sleep(Duration::from_millis(200)).await;
+        {
+            let mut w = messages.write();
+            assert_eq!(w.len(), 2);
+            assert!(w[0].0.contains("OK"));
+            assert_eq!(w[1].0, OutgoingMessage::event("1", note).0);
+            w.clear();
+        }
+
+        send(r#"["REQ", "2", {}]"#.to_owned()).await?;
+        sleep(Duration::from_millis(50)).await;
+        {
+            let w = messages.read();
+            assert_eq!(w.len(), 2);
+            assert_eq!(w[0].0, OutgoingMessage::event("2", note).0);
+            assert!(w[1].0.contains("EOSE"));
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn unindexed_tags() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_unindexed_tags")?)?);
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        let addr = receiver.recipient();
+        let setting: SettingWrapper = Setting::default().into();
+        let server = Server::create_with(db, setting.clone());
+        let id = server.send(Connect { addr }).await?;
+
+        let tags = vec![
+            vec!["t".to_owned(), "nostr".to_owned()],
+            vec!["\0".to_owned(), "a note".to_owned()],
+            vec!["t".to_owned(), "a".repeat(256)],
+            vec!["t".to_owned(), "a\0b".to_owned()],
+        ];
+        for (i, notice) in [false, true].into_iter().enumerate() {
+            setting.write().limitation.notice_on_unindexed_tags = notice;
+            let event = nostr_db::Event::new(
+                [i as u8; 32],
+                [1; 32],
+                10,
+                1,
+                tags.clone(),
+                "".to_owned(),
+                [0; 64],
+            )?;
+            let text = format!(r#"["EVENT", {}]"#, event);
+            let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+            server.send(ClientMessage::new(id, text, msg)).await?;
+            sleep(Duration::from_millis(200)).await;
+            let mut w = messages.write();
+            assert_eq!(w.len(), 1);
+            let message = if notice {
+                "indexed: 3 tags not indexed"
+            } else {
+                ""
+            };
+            assert_eq!(
+                w[0].0,
+                OutgoingMessage::ok(&event.id_str(), true, message).0
+            );
+            w.clear();
+        }
+        Ok(())
+    }
+
+    /// A session busy for a while, the mailbox is full after the capacity
+    struct SlowReceiver(Arc<RwLock<Vec<OutgoingMessage>>>);
+    impl Actor for SlowReceiver {
+        type Context = Context<Self>;
+        fn started(&mut self, ctx: &mut Self::Context) {
+            ctx.set_mailbox_capacity(2);
+            ctx.wait(sleep(Duration::from_millis(500)).into_actor(self));
+        }
+    }
+
+    impl Handler<OutgoingMessage> for SlowReceiver {
+        type Result = ();
+        fn handle(&mut self, msg: OutgoingMessage, _ctx: &mut Self::Context) {
+            self.0.write().push(msg);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn drop_on_full_mailbox() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_drop_on_full_mailbox")?)?);
+        let server = Server::create_with(db, Setting::default().into());
+        let fast = Receiver::default();
+        let fast_messages = fast.0.clone();
+        let fast = server
+            .send(Connect {
+                addr: fast.start().recipient(),
+            })
+            .await?;
+        let slow_messages = Arc::new(RwLock::new(vec![]));
+        let slow = server
+            .send(Connect {
+                addr: SlowReceiver(slow_messages.clone()).start().recipient(),
+            })
+            .await?;
+
+        let send = |id: usize, text: String| {
+            let msg = serde_json::from_str::<IncomingMessage>(&text).unwrap();
+            server.send(ClientMessage::new(id, text, msg))
+        };
+        send(fast, r#"["REQ", "1", {}]"#.to_owned()).await?;
+        send(slow, r#"["REQ", "1", {}]"#.to_owned()).await?;
+        sleep(Duration::from_millis(50)).await;
+        for i in 0..5u8 {
+            let event =
+                nostr_db::Event::new([i + 1; 32], [1; 32], 10, 1, vec![], "".to_owned(), [0; 64])?;
+            send(fast, format!(r#"["EVENT", {}]"#, event)).await?;
+        }
+        // a reply to the full mailbox
+        sleep(Duration::from_millis(100)).await;
+        server
+            .send(SubscribeResult {
+                id: slow,
+                sub_id: "1".to_owned(),
+                msg: OutgoingMessage::closed("1", "error: closed"),
+            })
+            .await?;
+        sleep(Duration::from_millis(800)).await;
+
+        let events = |messages: &Arc<RwLock<Vec<OutgoingMessage>>>| {
+            messages
+                .read()
+                .iter()
+                .filter(|m| m.0.starts_with(r#"["EVENT""#))
+                .count()
+        };
+        // the other sessions are not blocked by the slow session
+        assert_eq!(events(&fast_messages), 5);
+        // the stored events reply is kept, the dispatched events over the capacity are dropped
+        assert!(slow_messages.read()[0].0.contains("EOSE"));
+        assert!(events(&slow_messages) < 5);
+        // the replies are never dropped
+        assert!(slow_messages
+            .read()
+            .iter()
+            .any(|m| m.0 == r#"["CLOSED","1","error: closed"]"#));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn closed_on_full_mailbox() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_closed_on_full_mailbox")?)?);
+        let setting: SettingWrapper = Setting::default().into();
+        let server = Server::create_with(db, setting.clone());
+        let messages = Arc::new(RwLock::new(vec![]));
+        let slow = server
+            .send(Connect {
+                addr: SlowReceiver(messages.clone()).start().recipient(),
+            })
+            .await?;
+        let send = |text: String| {
+            let msg = serde_json::from_str::<IncomingMessage>(&text).unwrap();
+            server.send(ClientMessage::new(slow, text, msg))
+        };
+        send(r#"["REQ", "1", {}]"#.to_owned()).await?;
+        send(r#"["REQ", "2", {}]"#.to_owned()).await?;
+        sleep(Duration::from_millis(50)).await;
+        for i in 0..5u8 {
+            let event =
+                nostr_db::Event::new([i + 1; 32], [1; 32], 10, 1, vec![], "".to_owned(), [0; 64])?;
+            send(format!(r#"["EVENT", {}]"#, event)).await?;
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        // reload while the mailbox is full
+        setting.write().limitation.max_subscriptions = 1;
+        server.send(SettingChanged).await?;
+        sleep(Duration::from_millis(800)).await;
+        assert!(messages
+            .read()
+            .iter()
+            .any(|m| m.0.starts_with(r#"["CLOSED""#)
+                && m.0.contains("error: subscription limit reduced")));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn drop_log() -> Result<()> {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let mut drops = Drops::new(Duration::from_millis(200));
+
+        let logged = metrics::with_local_recorder(&recorder, || {
+            (0..100)
+                .filter_map(|_| drops.dropped(1))
+                .collect::<Vec<_>>()
+        });
+        // only the first drop is logged in the interval
+        assert_eq!(logged, vec![1]);
+        // the other session has its own interval
+        assert_eq!(drops.dropped(2), Some(1));
+
+        sleep(Duration::from_millis(250)).await;
+        let logged = metrics::with_local_recorder(&recorder, || drops.dropped(1));
+        // the drops since the last log
+        assert_eq!(logged, Some(100));
+        assert_eq!(drops.dropped(1), None);
+        assert_eq!(
+            counter_value(
+                &snapshotter,
+                "nostr_relay_message_dropped_total",
+                ("source", "subscriber")
+            ),
+            101
+        );
+        Ok(())
+    }
 }
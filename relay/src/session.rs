@@ -1,4 +1,4 @@
-use crate::{hash::NoOpHasherDefault, message::*, App, Server};
+use crate::{hash::NoOpHasherDefault, message::*, setting::Limitation, App, Connection, Server};
 use actix::prelude::*;
 use actix_http::ws::Item;
 use actix_web::web;
@@ -7,6 +7,7 @@ use bytes::BytesMut;
 use metrics::{counter, gauge};
 use std::{
     any::{Any, TypeId},
+    borrow::Cow,
     collections::HashMap,
     time::{Duration, Instant},
 };
@@ -41,6 +42,15 @@ pub struct Session {
 
     /// Buffer for constructing continuation messages
     cont: Option<BytesMut>,
+
+    /// max_limit of this connection, overrides the global setting
+    max_limit: Option<u64>,
+
+    /// The authenticated pubkey of this connection
+    pubkey: Option<[u8; 32]>,
+
+    /// Counted in the connections of the ip, released when the session stops
+    connection: Option<Connection>,
 }
 
 impl Session {
@@ -66,6 +76,40 @@ impl Session {
         &self.ip
     }
 
+    /// Set the max_limit of this connection, None to use the global setting
+    pub fn set_max_limit(&mut self, max_limit: Option<u64>) {
+        self.max_limit = max_limit;
+    }
+
+    /// The effective limits of this connection, the global setting with the overrides
+    pub fn limitation(&self) -> Limitation {
+        self.effective_limitation(&self.app.setting.read().limitation)
+            .into_owned()
+    }
+
+    fn effective_limitation<'a>(&self, limitation: &'a Limitation) -> Cow<'a, Limitation> {
+        let mut limitation = Cow::Borrowed(limitation);
+        if let Some(max_limit) = self.max_limit {
+            limitation.to_mut().max_limit = max_limit;
+        }
+        limitation
+    }
+
+    /// Set the authenticated pubkey, the shadowbanned author can see the own events
+    pub fn set_pubkey(&mut self, pubkey: Option<[u8; 32]>) {
+        self.pubkey = pubkey;
+    }
+
+    /// The authenticated pubkey of this connection
+    pub fn pubkey(&self) -> Option<&[u8; 32]> {
+        self.pubkey.as_ref()
+    }
+
+    /// Hold the counted connection of the ip until the session stops
+    pub fn set_connection(&mut self, connection: Connection) {
+        self.connection = Some(connection);
+    }
+
     pub fn new(ip: String, app: web::Data<App>) -> Session {
         let setting = app.setting.read();
         let heartbeat_timeout = setting.network.heartbeat_timeout.into();
@@ -81,6 +125,9 @@ impl Session {
             app,
             data: HashMap::default(),
             cont: None,
+            max_limit: None,
+            pubkey: None,
+            connection: None,
         }
     }
 
@@ -130,43 +177,120 @@ impl Session {
                     // only insert known command metrics
                     counter!("nostr_relay_message_total", "command" => cmd).increment(1);
                 }
-
-                let mut msg = ClientMessage::new(self.id, text, msg);
-                {
-                    let r = self.app.setting.read();
-                    if let Err(err) = msg.validate(&r.limitation) {
-                        self.send_error(err, &msg, ctx);
-                        return;
-                    }
-                }
-
-                match self
-                    .app
-                    .clone()
-                    .extensions
-                    .read()
-                    .call_message(msg, self, ctx)
-                {
-                    crate::ExtensionMessageResult::Continue(msg) => {
-                        if let Err(err) = msg.validate_nip70() {
-                            self.send_error(err, &msg, ctx);
-                            return;
+                if let IncomingMessage::Events(events) = msg {
+                    let max = {
+                        let r = self.app.setting.read();
+                        r.limitation
+                            .accept_event_batches
+                            .then_some(r.limitation.max_event_batch)
+                    };
+                    match max {
+                        Some(max) if events.len() > max => {
+                            let err = crate::Error::Invalid(format!("max_event_batch {}", max));
+                            ctx.text(OutgoingMessage::notice(&err.to_string()));
+                        }
+                        Some(_) => {
+                            // check each event as an EVENT message, write the accepted together
+                            let events = events
+                                .into_iter()
+                                .filter_map(|event| {
+                                    let msg = IncomingMessage::Event(event);
+                                    let text = serde_json::to_string(&msg).unwrap_or_default();
+                                    let msg = ClientMessage::new(self.id, text, msg);
+                                    match self.check_client_message(msg, ctx)?.msg {
+                                        IncomingMessage::Event(event) => Some(event),
+                                        _ => None,
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+                            if !events.is_empty() {
+                                let msg = IncomingMessage::Events(events);
+                                self.server.do_send(ClientMessage::new(self.id, text, msg));
+                            }
+                        }
+                        None => {
+                            ctx.text(OutgoingMessage::notice("Unsupported message"));
                         }
-                        self.server.do_send(msg);
-                    }
-                    crate::ExtensionMessageResult::Stop(out) => {
-                        ctx.text(out);
-                    }
-                    crate::ExtensionMessageResult::Ignore => {
-                        // ignore
                     }
-                };
+                    return;
+                }
+                self.handle_client_message(ClientMessage::new(self.id, text, msg), ctx);
             }
             Err(err) => {
                 ctx.text(OutgoingMessage::notice(&format!("json error: {}", err)));
             }
         };
     }
+
+    fn handle_client_message(&mut self, msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(msg) = self.check_client_message(msg, ctx) {
+            self.server.do_send(msg);
+        }
+    }
+
+    /// Validate the message and pass it to the extensions, None if it is answered or ignored
+    fn check_client_message(
+        &mut self,
+        mut msg: ClientMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Option<ClientMessage> {
+        {
+            let r = self.app.setting.read();
+            if let Some(cmd) = msg.msg.known_command() {
+                if r.network
+                    .disabled_commands
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(cmd))
+                {
+                    self.send_error(
+                        crate::Error::Str("unsupported: command disabled"),
+                        &msg,
+                        ctx,
+                    );
+                    return None;
+                }
+            }
+            let limitation = self.effective_limitation(&r.limitation);
+            if let Err(err) = msg.validate(&limitation) {
+                self.send_error(err, &msg, ctx);
+                return None;
+            }
+            if let IncomingMessage::Event(event) = &mut msg.msg {
+                // the ip of the json is set by the client, record the ip of the connection
+                event.meta_mut().ip = Some(self.ip.clone());
+                if !r.data.preserve_raw_json {
+                    event.clear_raw();
+                }
+            }
+        }
+
+        match self
+            .app
+            .clone()
+            .extensions
+            .read()
+            .call_message(msg, self, ctx)
+        {
+            crate::ExtensionMessageResult::Continue(mut msg) => {
+                if let Err(err) = msg.validate_nip70() {
+                    self.send_error(err, &msg, ctx);
+                    return None;
+                }
+                if let IncomingMessage::Req(sub) = &mut msg.msg {
+                    sub.viewer = self.pubkey;
+                }
+                Some(msg)
+            }
+            crate::ExtensionMessageResult::Stop(out) => {
+                ctx.text(out);
+                None
+            }
+            crate::ExtensionMessageResult::Ignore => {
+                // ignore
+                None
+            }
+        }
+    }
 }
 
 /// Handle messages from server, we simply send it to peer websocket
@@ -185,6 +309,8 @@ impl Actor for Session {
     fn started(&mut self, ctx: &mut Self::Context) {
         counter!("nostr_relay_session_total").increment(1);
         gauge!("nostr_relay_session").increment(1.0);
+        // the subscription events over the capacity are dropped, see `Server`
+        ctx.set_mailbox_capacity(self.limitation().max_pending_messages.max(1));
 
         // we'll start heartbeat process on session start.
         self.hb(ctx);
@@ -227,6 +353,7 @@ impl Actor for Session {
             .extensions
             .read()
             .call_disconnected(self, ctx);
+        self.connection.take();
         debug!("Session stopped {} {}", self.id, self.ip);
     }
 }
@@ -315,6 +442,8 @@ mod tests {
     use anyhow::Result;
     use bytes::Bytes;
     use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_db::secp256k1::{rand::thread_rng, Keypair};
+    use serde_json::Value;
 
     #[actix_rt::test]
     async fn pingpong() -> Result<()> {
@@ -454,6 +583,394 @@ mod tests {
         Ok(())
     }
 
+    async fn next_text<S, E>(framed: &mut S) -> Option<String>
+    where
+        S: futures_util::Stream<Item = Result<ws::Frame, E>> + Unpin,
+    {
+        let item = actix_rt::time::timeout(Duration::from_millis(500), framed.next())
+            .await
+            .ok()??
+            .ok()?;
+        match item {
+            ws::Frame::Text(text) => Some(String::from_utf8(text.to_vec()).unwrap()),
+            _ => None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn eose_order() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let now = nostr_db::now();
+        let note = nostr_db::Event::create(&key_pair, now, 1, vec![], "live".to_owned())?;
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_eose_order").unwrap();
+            // the stored events of the two filters interleave by time
+            let events = (0..20u8)
+                .map(|i| {
+                    nostr_db::Event::new(
+                        [i + 1; 32],
+                        [1; 32],
+                        i as u64,
+                        (i % 2 + 1) as u32,
+                        vec![],
+                        "".to_owned(),
+                        [0; 64],
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+            data.db.batch_put(events).unwrap();
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(
+                r#"["REQ", "1", {"kinds": [1]}, {"kinds": [2]}, {"kinds": [1, 2], "limit": 5}]"#
+                    .into(),
+            ))
+            .await?;
+
+        let mut events = 0;
+        loop {
+            let text = next_text(&mut framed).await.expect("EOSE");
+            if text == r#"["EOSE","1"]"# {
+                break;
+            }
+            assert!(text.starts_with(r#"["EVENT","1","#), "{}", text);
+            events += 1;
+        }
+        // the events of the limited filter are sent by the former filters
+        assert_eq!(events, 20);
+        // no stored event after EOSE
+        assert!(next_text(&mut framed).await.is_none());
+
+        // live dispatch
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, note).into()))
+            .await?;
+        let mut texts = [
+            next_text(&mut framed).await.unwrap(),
+            next_text(&mut framed).await.unwrap(),
+        ];
+        texts.sort();
+        assert!(texts[0].starts_with(r#"["EVENT","1","#));
+        assert!(texts[1].starts_with(r#"["OK","#));
+        assert!(next_text(&mut framed).await.is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn event_ip() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let event = nostr_db::Event::create(&key_pair, nostr_db::now(), 1, vec![], "".to_owned())?;
+        let mut json: Value = serde_json::from_str(&event.to_string())?;
+        json["ip"] = "1.1.1.1".into();
+
+        let data = web::Data::new(create_test_app("session_event_ip")?);
+        let db = data.db.clone();
+        let mut srv = actix_test::start(move || crate::create_web_app(data.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, json).into()))
+            .await?;
+        let text = next_text(&mut framed).await.expect("OK");
+        assert!(text.starts_with(r#"["OK","#), "{}", text);
+
+        let reader = db.reader()?;
+        let meta = db.event_meta(&reader, event.id())?.unwrap();
+        // the ip of the connection, not the one in the json
+        assert_eq!(meta.ip.as_deref(), Some("127.0.0.1"));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn preserve_raw_json() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let event =
+            nostr_db::Event::create(&key_pair, nostr_db::now(), 1, vec![], "raw".to_owned())?;
+        // the field order and the spaces differ from the serialized form
+        let raw = format!(
+            r#"{{ "sig": "{}", "content": "raw", "tags": [], "kind": 1, "created_at": {}, "pubkey": "{}", "id": "{}" }}"#,
+            hex::encode(event.sig()),
+            event.created_at(),
+            event.pubkey_str(),
+            event.id_str()
+        );
+        assert_ne!(raw, event.to_string());
+
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_preserve_raw_json").unwrap();
+            data.setting.write().data.preserve_raw_json = true;
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, raw).into()))
+            .await?;
+        assert_eq!(
+            next_text(&mut framed).await.unwrap(),
+            OutgoingMessage::ok(&event.id_str(), true, "").0
+        );
+        framed
+            .send(ws::Message::Text(
+                format!(r#"["REQ", "1", {{"ids": ["{}"]}}]"#, event.id_str()).into(),
+            ))
+            .await?;
+        assert_eq!(
+            next_text(&mut framed).await.unwrap(),
+            format!(r#"["EVENT","1",{}]"#, raw)
+        );
+        assert_eq!(next_text(&mut framed).await.unwrap(), r#"["EOSE","1"]"#);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn limit_over_matches() -> Result<()> {
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_limit_over_matches").unwrap();
+            // 5 events of kind 1 between the others
+            let events = (0..15u8)
+                .map(|i| {
+                    nostr_db::Event::new(
+                        [i + 1; 32],
+                        [i % 3 + 1; 32],
+                        i as u64,
+                        (i % 3 + 1) as u32,
+                        vec![],
+                        "".to_owned(),
+                        [0; 64],
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+            data.db.batch_put(events).unwrap();
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        for (id, filter) in [
+            ("1", r#"{"kinds": [1], "limit": 100}"#.to_owned()),
+            (
+                "2",
+                format!(
+                    r#"{{"authors": ["{}"], "limit": 100}}"#,
+                    hex::encode([1u8; 32])
+                ),
+            ),
+        ] {
+            framed
+                .send(ws::Message::Text(
+                    format!(r#"["REQ", "{}", {}]"#, id, filter).into(),
+                ))
+                .await?;
+            let mut events = 0;
+            loop {
+                // each message in 500ms
+                let text = next_text(&mut framed).await.expect("EOSE");
+                if text == format!(r#"["EOSE","{}"]"#, id) {
+                    break;
+                }
+                assert!(
+                    text.starts_with(&format!(r#"["EVENT","{}","#, id)),
+                    "{}",
+                    text
+                );
+                events += 1;
+            }
+            assert_eq!(events, 5, "{}", filter);
+            assert!(next_text(&mut framed).await.is_none());
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn resubscribe() -> Result<()> {
+        let start = |resend: bool| {
+            actix_test::start(move || {
+                let data = create_test_app("session_resubscribe").unwrap();
+                data.setting.write().limitation.resend_on_resubscribe = resend;
+                let events = (0..3u8)
+                    .map(|i| {
+                        nostr_db::Event::new(
+                            [i + 1; 32],
+                            [1; 32],
+                            i as u64,
+                            1,
+                            vec![],
+                            "".to_owned(),
+                            [0; 64],
+                        )
+                        .unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                data.db.batch_put(events).unwrap();
+                data.web_app()
+            })
+        };
+        // count the stored events until EOSE
+        async fn req<S>(framed: &mut S, text: &'static str) -> Result<usize>
+        where
+            S: futures_util::Sink<ws::Message, Error = ws::ProtocolError>
+                + futures_util::Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+                + Unpin,
+        {
+            framed.send(ws::Message::Text(text.into())).await?;
+            let mut events = 0;
+            loop {
+                let text = next_text(framed).await.expect("EOSE");
+                if text == r#"["EOSE","1"]"# {
+                    return Ok(events);
+                }
+                assert!(text.starts_with(r#"["EVENT","1","#), "{}", text);
+                events += 1;
+            }
+        }
+
+        let mut srv = start(false);
+        let mut framed = srv.ws_at("/").await.unwrap();
+        assert_eq!(
+            req(&mut framed, r#"["REQ", "1", {"kinds": [1]}]"#).await?,
+            3
+        );
+        // the same filters
+        assert_eq!(
+            req(&mut framed, r#"["REQ", "1", {"kinds": [1, 1]}]"#).await?,
+            0
+        );
+        assert_eq!(
+            req(&mut framed, r#"["REQ", "1", {"kinds": [1], "limit": 2}]"#).await?,
+            2
+        );
+
+        let mut srv = start(true);
+        let mut framed = srv.ws_at("/").await.unwrap();
+        assert_eq!(
+            req(&mut framed, r#"["REQ", "1", {"kinds": [1]}]"#).await?,
+            3
+        );
+        assert_eq!(
+            req(&mut framed, r#"["REQ", "1", {"kinds": [1]}]"#).await?,
+            3
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn event_batches() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let now = nostr_db::now();
+        let events = (0..3)
+            .map(|i| nostr_db::Event::create(&key_pair, now, 1, vec![], format!("batch {}", i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut invalid = serde_json::to_value(&events[2])?;
+        invalid["content"] = "changed".into();
+        let text = format!(r#"["EVENTS", {}, {}, {}]"#, events[0], events[1], invalid);
+
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_event_batches").unwrap();
+            data.setting.write().limitation.accept_event_batches = true;
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed.send(ws::Message::Text(text.clone().into())).await?;
+        let mut oks = vec![];
+        while let Some(text) = next_text(&mut framed).await {
+            let v: Value = serde_json::from_str(&text)?;
+            assert_eq!(v[0], "OK");
+            oks.push((v[1].as_str().unwrap().to_owned(), v[2].as_bool().unwrap()));
+        }
+        oks.sort();
+        let mut expect = vec![
+            (events[0].id_str(), true),
+            (events[1].id_str(), true),
+            (events[2].id_str(), false),
+        ];
+        expect.sort();
+        assert_eq!(oks, expect);
+
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_event_batches_max").unwrap();
+            {
+                let mut w = data.setting.write();
+                w.limitation.accept_event_batches = true;
+                w.limitation.max_event_batch = 2;
+            }
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed.send(ws::Message::Text(text.clone().into())).await?;
+        assert_eq!(
+            next_text(&mut framed).await.unwrap(),
+            r#"["NOTICE","invalid: max_event_batch 2"]"#
+        );
+        assert!(next_text(&mut framed).await.is_none());
+
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_event_batches_disabled").unwrap();
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed.send(ws::Message::Text(text.into())).await?;
+        assert_eq!(
+            next_text(&mut framed).await.unwrap(),
+            r#"["NOTICE","Unsupported message"]"#
+        );
+        assert!(next_text(&mut framed).await.is_none());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn disabled_commands() -> Result<()> {
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let event = nostr_db::Event::create(&key_pair, nostr_db::now(), 1, vec![], "".to_owned())?;
+
+        // read-only relay
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_disabled_event").unwrap();
+            data.setting.write().network.disabled_commands = vec!["EVENT".to_owned()];
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        assert_eq!(
+            next_text(&mut framed).await.unwrap(),
+            format!(
+                r#"["OK","{}",false,"unsupported: command disabled"]"#,
+                event.id_str()
+            )
+        );
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        assert_eq!(next_text(&mut framed).await.unwrap(), r#"["EOSE","1"]"#);
+
+        // write-only relay
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("session_disabled_req").unwrap();
+            data.setting.write().network.disabled_commands = vec!["req".to_owned()];
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        assert_eq!(
+            next_text(&mut framed).await.unwrap(),
+            r#"["CLOSED","1","unsupported: command disabled"]"#
+        );
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let text = next_text(&mut framed).await.unwrap();
+        assert!(
+            text.starts_with(&format!(r#"["OK","{}",true"#, event.id_str())),
+            "{}",
+            text
+        );
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn continuation() -> Result<()> {
         let text = br#"["REQ", "1", {}]"#;
@@ -1,6 +1,6 @@
 use crate::Error;
 use crate::{duration::NonZeroDuration, hash::NoOpHasherDefault, Result};
-use config::{Config, Environment, File, FileFormat};
+use config::{Config, ConfigError, Environment, File, FileFormat};
 use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use parking_lot::RwLock;
 use serde::de::DeserializeOwned;
@@ -9,7 +9,7 @@ use serde_json::{json, Value};
 use std::{
     any::{Any, TypeId},
     collections::HashMap,
-    fs,
+    fmt, fs,
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
@@ -55,13 +55,118 @@ impl Default for Information {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(default)]
 pub struct Data {
     pub path: PathBuf,
 
     /// Query filter timeout time
     pub db_query_timeout: Option<NonZeroDuration>,
+
+    /// Max number of index entries scanned by a query filter
+    pub db_query_max_scan: Option<u64>,
+
+    /// Store and send events as the original received json instead of re-serializing them
+    pub preserve_raw_json: bool,
+
+    /// Hex encoded 32 bytes key for encrypting the stored events, needs the `encrypt_at_rest` feature
+    pub encryption_key: Option<String>,
+
+    /// Max number of the cached query filter results, 0 disables the cache
+    pub query_cache: usize,
+
+    /// How long a cached query filter result is served
+    pub query_cache_ttl: NonZeroDuration,
+
+    /// How often the expired [NIP-40](https://nips.be/40) events are deleted, 0 disables the sweep
+    #[serde(with = "crate::duration")]
+    pub expiration_sweep_interval: Duration,
+
+    /// Whether a commit waits for the fsync, needs restart
+    pub durability: Durability,
+
+    /// How often the database is flushed to disk in the `async` durability
+    pub flush_interval: NonZeroDuration,
+
+    /// Verify the signature of the events read from the database, skip the failed, needs restart
+    pub verify_on_read: bool,
+
+    /// Read the hot index trees into the OS page cache on startup before accepting connections
+    pub prewarm: bool,
+
+    /// Max time spent on the prewarm
+    #[serde(with = "crate::duration")]
+    pub prewarm_timeout: Duration,
+
+    /// Max bytes read by the prewarm, 0 is unlimited
+    pub prewarm_max_bytes: u64,
+
+    /// The tag keys with a 32 bytes hex value, stored and queried as bytes, needs restart.
+    /// The list is recorded in the database, it can't be opened with a changed list
+    pub hex_tags: Vec<String>,
+
+    /// Size of the memory map in bytes, the max size of the database, needs restart. default 1TB
+    pub map_size: Option<usize>,
+
+    /// Grow the memory map by doubling up to this size in bytes when it is full, needs restart.
+    /// default no growth
+    pub max_map_size: Option<usize>,
+}
+
+/// How the written events are persisted to disk
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// fsync on every commit
+    #[default]
+    Sync,
+    /// no fsync on commit, flush periodically by `data.flush_interval`.
+    /// A system crash may lose the events written since the last flush.
+    Async,
+}
+
+// hide the encryption key
+impl fmt::Debug for Data {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Data")
+            .field("path", &self.path)
+            .field("db_query_timeout", &self.db_query_timeout)
+            .field("db_query_max_scan", &self.db_query_max_scan)
+            .field("preserve_raw_json", &self.preserve_raw_json)
+            .field("query_cache", &self.query_cache)
+            .field("query_cache_ttl", &self.query_cache_ttl)
+            .field("expiration_sweep_interval", &self.expiration_sweep_interval)
+            .field("durability", &self.durability)
+            .field("flush_interval", &self.flush_interval)
+            .field("verify_on_read", &self.verify_on_read)
+            .field("prewarm", &self.prewarm)
+            .field("prewarm_timeout", &self.prewarm_timeout)
+            .field("prewarm_max_bytes", &self.prewarm_max_bytes)
+            .field("hex_tags", &self.hex_tags)
+            .field("map_size", &self.map_size)
+            .field("max_map_size", &self.max_map_size)
+            .field(
+                "encryption_key",
+                &self.encryption_key.as_ref().map(|_| "***"),
+            )
+            .finish()
+    }
+}
+
+impl Data {
+    /// decode the encryption key
+    pub fn encryption_key(&self) -> Result<Option<[u8; 32]>> {
+        self.encryption_key
+            .as_ref()
+            .map(|key| {
+                let mut bytes = [0u8; 32];
+                hex::decode_to_slice(key, &mut bytes).map_err(|_| {
+                    Error::Invalid("data.encryption_key must be a 32 bytes hex string".to_owned())
+                })?;
+                Ok(bytes)
+            })
+            .transpose()
+    }
 }
 
 impl Default for Data {
@@ -69,6 +174,21 @@ impl Default for Data {
         Self {
             path: PathBuf::from("./data"),
             db_query_timeout: None,
+            db_query_max_scan: None,
+            preserve_raw_json: false,
+            encryption_key: None,
+            query_cache: 0,
+            query_cache_ttl: Duration::from_secs(5).try_into().unwrap(),
+            expiration_sweep_interval: Duration::from_secs(60),
+            durability: Durability::Sync,
+            flush_interval: Duration::from_secs(1).try_into().unwrap(),
+            verify_on_read: false,
+            prewarm: false,
+            prewarm_timeout: Duration::from_secs(30),
+            prewarm_max_bytes: 0,
+            hex_tags: vec!["e".to_owned(), "p".to_owned()],
+            map_size: None,
+            max_map_size: None,
         }
     }
 }
@@ -103,6 +223,16 @@ pub struct Network {
 
     /// redirect to other site when user access the http index page
     pub index_redirect_to: Option<String>,
+
+    /// the client message types rejected by the relay, such as `["EVENT"]` for a read-only relay
+    pub disabled_commands: Vec<String>,
+
+    /// the public websocket url of the relay, such as `wss://relay.example.com`,
+    /// the NIP-42 AUTH events must have the same `relay` tag
+    pub external_url: Option<String>,
+
+    /// refuse the websocket upgrade with 429 when the client ip has the max connections. default 0 unlimited
+    pub max_conn_per_ip: usize,
 }
 
 impl Default for Network {
@@ -114,6 +244,9 @@ impl Default for Network {
             heartbeat_timeout: Duration::from_secs(120).try_into().unwrap(),
             real_ip_header: None,
             index_redirect_to: None,
+            disabled_commands: vec![],
+            external_url: None,
+            max_conn_per_ip: 0,
         }
     }
 }
@@ -139,6 +272,31 @@ pub struct Limitation {
     pub max_event_time_older_than_now: u64,
     /// Events newer than this will be rejected. default 15 minutes, 0 ignore
     pub max_event_time_newer_than_now: u64,
+    /// Events expiring later than this from now will be rejected, [NIP-40](https://nips.be/40). default 0 ignore
+    pub max_event_expiration_from_now: u64,
+    /// ignore unknown top-level filter fields, reject the subscription when false. default true
+    pub lenient_filters: bool,
+    /// reject the subscription if a filter has no condition and no limit, see [`nostr_db::Filter::is_unbounded`]. default false
+    pub reject_unbounded_req: bool,
+    /// accept the non-standard `["EVENTS", <event>, ...]` message, each event is validated and answered with an OK like EVENT,
+    /// the accepted events are written in one transaction. default false
+    pub accept_event_batches: bool,
+    /// maximum number of events in an `EVENTS` message. default 100
+    pub max_event_batch: usize,
+    /// maximum number of messages queued for a websocket connection, the live events of the subscriptions
+    /// over it are dropped for a slow client, the other messages are always sent. default 1000
+    pub max_pending_messages: usize,
+    /// add a note such as "indexed: 3 tags not indexed" to the OK message if some tags of the event can't be queried, see [`nostr_db::Event::dropped_tags`]. default false
+    pub notice_on_unindexed_tags: bool,
+    /// a filter with an explicitly empty list such as `{"ids": []}` matches nothing, otherwise it matches as the field is absent. default false
+    pub reject_empty_lists: bool,
+    /// run the stored query again when a REQ reuses a subscription id with the same filters and options. default true
+    pub resend_on_resubscribe: bool,
+    /// reject the events with control characters other than tab and newlines, or invisible characters such as the zero-width space in the content. default false
+    pub reject_control_chars: bool,
+    /// treat the filter since and until later than the year 3000 as milliseconds and divide them by 1000,
+    /// reject the subscription with "invalid: timestamp out of range" when false. default false
+    pub lenient_timestamps: bool,
 }
 
 impl Default for Limitation {
@@ -153,6 +311,17 @@ impl Default for Limitation {
             max_event_tags: 5000,
             max_event_time_older_than_now: 94608000,
             max_event_time_newer_than_now: 900,
+            max_event_expiration_from_now: 0,
+            lenient_filters: true,
+            reject_unbounded_req: false,
+            accept_event_batches: false,
+            max_event_batch: 100,
+            max_pending_messages: 1000,
+            notice_on_unindexed_tags: false,
+            reject_empty_lists: false,
+            resend_on_resubscribe: true,
+            reject_control_chars: false,
+            lenient_timestamps: false,
         }
     }
 }
@@ -166,6 +335,10 @@ pub struct Setting {
     pub network: Network,
     pub limitation: Limitation,
 
+    /// additional config files merged after the config file, such as extension settings.
+    /// relative paths are resolved from the dir of the config file
+    pub include: Vec<PathBuf>,
+
     /// flatten extensions setting to json::Value
     #[serde(flatten)]
     pub extra: HashMap<String, Value>,
@@ -190,6 +363,7 @@ impl PartialEq for Setting {
             && self.thread == other.thread
             && self.network == other.network
             && self.limitation == other.limitation
+            && self.include == other.include
             && self.extra == other.extra
     }
 }
@@ -240,14 +414,27 @@ impl SettingWrapper {
         // symbolic links
         let file = fs::canonicalize(file.as_ref())?;
         let c_file = file.clone();
+        // reload when the config file or the included files changed
+        let mut files = Setting::include_files(&file)?
+            .iter()
+            .map(fs::canonicalize)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.push(file.clone());
+        let c_files = files.clone();
 
         // support vim editor. watch dir
         // https://docs.rs/notify/latest/notify/#editor-behaviour
         // https://github.com/notify-rs/notify/issues/113#issuecomment-281836995
 
-        let dir = file
-            .parent()
-            .ok_or_else(|| Error::Message("failed to get config dir".to_owned()))?;
+        let mut dirs = vec![];
+        for file in &files {
+            let dir = file
+                .parent()
+                .ok_or_else(|| Error::Message("failed to get config dir".to_owned()))?;
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
 
         let mut watcher = RecommendedWatcher::new(
             move |result: Result<Event, notify::Error>| match result {
@@ -257,7 +444,7 @@ impl SettingWrapper {
                     let is_modify = matches!(event.kind, EventKind::Modify(ModifyKind::Any));
                     #[cfg(not(target_os = "windows"))]
                     let is_modify = matches!(event.kind, EventKind::Modify(ModifyKind::Data(_)));
-                    if is_modify && event.paths.contains(&c_file) {
+                    if is_modify && event.paths.iter().any(|p| c_files.contains(p)) {
                         match c_setting.reload(&c_file, env_prefix.clone()) {
                             Ok(_) => {
                                 info!("Reload config success {:?}", c_file);
@@ -280,7 +467,9 @@ impl SettingWrapper {
             notify::Config::default(),
         )?;
 
-        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        for dir in dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
         // save watcher
         setting.watcher = Some(Arc::new(watcher));
 
@@ -346,6 +535,16 @@ impl Setting {
             "supported_nips": info.supported_nips,
             "limitation": &self.limitation,
         });
+        // NIP-22 accepted created_at window, 0 is no limit
+        let limitation = &self.limitation;
+        if limitation.max_event_time_older_than_now > 0 {
+            val["limitation"]["created_at_lower_limit"] =
+                json!(limitation.max_event_time_older_than_now);
+        }
+        if limitation.max_event_time_newer_than_now > 0 {
+            val["limitation"]["created_at_upper_limit"] =
+                json!(limitation.max_event_time_newer_than_now);
+        }
         self.ext_limitation.iter().for_each(|(k, v)| {
             val["limitation"][k] = v.clone();
         });
@@ -355,6 +554,20 @@ impl Setting {
         Ok(serde_json::to_string_pretty(&val)?)
     }
 
+    /// the included files of the config file
+    pub fn include_files<P: AsRef<Path>>(file: P) -> Result<Vec<PathBuf>> {
+        let config = Config::builder()
+            .add_source(File::with_name(file.as_ref().to_str().unwrap()))
+            .build()?;
+        let include = match config.get::<Vec<PathBuf>>("include") {
+            Ok(include) => include,
+            Err(ConfigError::NotFound(_)) => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        let dir = file.as_ref().parent().unwrap_or(Path::new(""));
+        Ok(include.iter().map(|path| dir.join(path)).collect())
+    }
+
     /// read config from file and env
     pub fn read<P: AsRef<Path>>(file: P, env_prefix: Option<String>) -> Result<Self> {
         let builder = Config::builder();
@@ -364,6 +577,9 @@ impl Setting {
             // .add_source(Config::try_from(&Self::default())?)
             // override with file contents
             .add_source(File::with_name(file.as_ref().to_str().unwrap()));
+        for path in Self::include_files(&file)? {
+            config = config.add_source(File::from(path));
+        }
         if let Some(prefix) = env_prefix {
             config = config.add_source(Self::env_source(&prefix));
         }
@@ -402,6 +618,29 @@ impl Setting {
         Ok(setting)
     }
 
+    /// check the setting values which can't be corrected
+    pub fn validate(&self) -> Result<()> {
+        if let Some(pubkey) = &self.information.pubkey {
+            let mut bytes = [0u8; 32];
+            hex::decode_to_slice(pubkey, &mut bytes).map_err(|_| {
+                Error::Invalid("information.pubkey must be a 32 bytes hex string".to_owned())
+            })?;
+        }
+        if self.network.host.is_empty() {
+            return Err(Error::Invalid("network.host is required".to_owned()));
+        }
+        if self.limitation.max_subscriptions == 0
+            || self.limitation.max_filters == 0
+            || self.limitation.max_limit == 0
+        {
+            return Err(Error::Invalid(
+                "limitation max_subscriptions, max_filters and max_limit must be greater than 0"
+                    .to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
     fn correct(&mut self) {
         if self.network.heartbeat_timeout <= self.network.heartbeat_interval {
             error!("network heartbeat_timeout must bigger than heartbeat_interval, use defaults");
@@ -443,6 +682,18 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn encryption_key() -> Result<()> {
+        let mut data = Data::default();
+        assert_eq!(data.encryption_key()?, None);
+        data.encryption_key = Some(hex::encode([1u8; 32]));
+        assert_eq!(data.encryption_key()?, Some([1u8; 32]));
+        assert!(!format!("{:?}", data).contains(&hex::encode([1u8; 32])));
+        data.encryption_key = Some("xx".to_owned());
+        assert!(data.encryption_key().is_err());
+        Ok(())
+    }
+
     #[test]
     fn render() -> Result<()> {
         let mut def = Setting::default();
@@ -458,6 +709,14 @@ mod tests {
             .contains(&Value::Number(serde_json::Number::from(1234567))));
         assert_eq!(val["payments_url"], json!("https://payments"));
         assert_eq!(val["limitation"]["payment_required"], json!(true));
+        // NIP-22
+        assert_eq!(val["limitation"]["created_at_lower_limit"], json!(94608000));
+        assert_eq!(val["limitation"]["created_at_upper_limit"], json!(900));
+        def.limitation.max_event_time_newer_than_now = 0;
+        let val: Value = serde_json::from_str(&def.render_information()?)?;
+        assert!(val["limitation"].get("created_at_upper_limit").is_none());
+        // NIP-40
+        assert_eq!(val["limitation"]["max_event_expiration_from_now"], json!(0));
         Ok(())
     }
 
@@ -466,6 +725,8 @@ mod tests {
         let setting = Setting::default();
         assert_eq!(setting.information.name, "");
         assert!(setting.information.supported_nips.contains(&1));
+        // added by the search extension
+        assert!(!setting.information.supported_nips.contains(&50));
 
         let file = Builder::new()
             .prefix("nostr-relay-config-test-read")
@@ -505,6 +766,86 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn include() -> Result<()> {
+        let dir = Builder::new()
+            .prefix("nostr-relay-config-test-include")
+            .tempdir()?;
+        let file = dir.path().join("rnostr.toml");
+        fs::write(
+            &file,
+            r#"
+        include = ["auth.toml", "ext/rate_limiter.toml"]
+        [information]
+        name = "nostr"
+        "#,
+        )?;
+        fs::write(
+            dir.path().join("auth.toml"),
+            r#"
+        [auth]
+        enabled = true
+        "#,
+        )?;
+        fs::create_dir(dir.path().join("ext"))?;
+        fs::write(
+            dir.path().join("ext/rate_limiter.toml"),
+            r#"
+        [rate_limiter]
+        enabled = true
+        [limitation]
+        max_limit = 10
+        "#,
+        )?;
+
+        let setting = Setting::read(&file, None)?;
+        assert_eq!(setting.information.name, "nostr");
+        assert_eq!(setting.limitation.max_limit, 10);
+        assert_eq!(setting.extra["auth"]["enabled"], json!(true));
+        assert_eq!(setting.extra["rate_limiter"]["enabled"], json!(true));
+        assert!(!setting.extra.contains_key("include"));
+
+        #[derive(Deserialize, Default)]
+        struct Auth {
+            enabled: bool,
+        }
+        assert!(setting.parse_extension::<Auth>("auth").enabled);
+
+        // missing included file
+        fs::write(&file, r#"include = ["missing.toml"]"#)?;
+        assert!(Setting::read(&file, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_env() -> Result<()> {
+        temp_env::with_vars(
+            [
+                ("RNOSTR_TEST_NETWORK__PORT", Some("8080")),
+                ("RNOSTR_TEST_NETWORK__HOST", Some("0.0.0.0")),
+                ("RNOSTR_TEST_INFORMATION__NAME", Some("env")),
+                ("RNOSTR_TEST_DATA__PATH", Some("/data")),
+                ("RNOSTR_TEST_LIMITATION__MAX_LIMIT", Some("10")),
+                ("RNOSTR_TEST_AUTH__ENABLED", Some("true")),
+            ],
+            || {
+                let setting = Setting::from_env("RNOSTR_TEST".to_owned()).unwrap();
+                assert_eq!(setting.network.port, 8080);
+                assert_eq!(setting.network.host, "0.0.0.0");
+                assert_eq!(setting.information.name, "env");
+                assert_eq!(setting.data.path, PathBuf::from("/data"));
+                assert_eq!(setting.limitation.max_limit, 10);
+                assert_eq!(setting.extra["auth"]["enabled"], json!(true));
+                // defaults
+                let def = Setting::default();
+                assert_eq!(setting.limitation.max_filters, def.limitation.max_filters);
+                assert_eq!(setting.thread, def.thread);
+                assert!(setting.information.supported_nips.contains(&1));
+            },
+        );
+        Ok(())
+    }
+
     #[test]
     fn watch() -> Result<()> {
         let file = Builder::new()
@@ -532,6 +873,20 @@ mod tests {
             assert_eq!(r.information.name, "nostr");
             assert!(r.information.supported_nips.contains(&1));
         }
+
+        // watch the included file
+        let dir = Builder::new()
+            .prefix("nostr-relay-config-test-watch-include")
+            .tempdir()?;
+        let file = dir.path().join("rnostr.toml");
+        let ext = dir.path().join("auth.toml");
+        fs::write(&file, r#"include = ["auth.toml"]"#)?;
+        fs::write(&ext, "[auth]\nenabled = false\n")?;
+        let setting = SettingWrapper::watch(&file, None, |_s| {})?;
+        assert_eq!(setting.read().extra["auth"]["enabled"], json!(false));
+        fs::write(&ext, "[auth]\nenabled = true\n")?;
+        sleep(Duration::from_secs(1));
+        assert_eq!(setting.read().extra["auth"]["enabled"], json!(true));
         Ok(())
     }
 }
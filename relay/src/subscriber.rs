@@ -1,11 +1,12 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     rc::{Rc, Weak},
+    sync::Arc,
 };
 
 use crate::{message::*, setting::SettingWrapper};
 use actix::prelude::*;
-use nostr_db::{EventIndex, Filter};
+use nostr_db::{Db, EventIndex, Filter, HexPrefix};
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 struct Key {
@@ -24,44 +25,61 @@ impl Key {
     }
 }
 
+/// the keys have variable length, separate them from the value,
+/// otherwise ["alt", "x"] collides with ["al", "tx"]
 fn concat_tag<K, I>(key: K, val: I) -> Vec<u8>
 where
     K: AsRef<[u8]>,
     I: AsRef<[u8]>,
 {
-    [key.as_ref(), val.as_ref()].concat()
+    [key.as_ref(), &[0], val.as_ref()].concat()
 }
 
+/// the prefix tags can't be looked up by the exact value, they go to the kinds or the linear scan
+fn indexed_by_tags(filter: &Filter) -> bool {
+    !filter.tags.is_empty() && filter.prefix_tags.is_empty()
+}
+
+/// the hex prefixes of ids and authors can't be looked up by the exact value, they go to the next index
+fn indexed_by_full(list: &[HexPrefix]) -> bool {
+    !list.is_empty() && list.iter().all(|p| p.full().is_some())
+}
+
+/// map subscription_id -> (order, filters, options) of a session
+type Subscriptions = HashMap<String, (u64, Vec<Rc<Filter>>, SubscriptionOptions)>;
+
 // index for fast filter
 #[derive(Debug, Default)]
 pub struct SubscriberIndex {
-    /// map session_id -> subscription_id -> filters
-    subscriptions: HashMap<usize, HashMap<String, Vec<Rc<Filter>>>>,
+    /// map session_id -> subscriptions
+    subscriptions: HashMap<usize, Subscriptions>,
+    /// the order of the next subscription
+    seq: u64,
     ids: HashMap<[u8; 32], HashMap<Key, Weak<Filter>>>,
     authors: HashMap<[u8; 32], HashMap<Key, Weak<Filter>>>,
     tags: HashMap<Vec<u8>, HashMap<Key, Weak<Filter>>>,
-    kinds: HashMap<u16, HashMap<Key, Weak<Filter>>>,
+    kinds: HashMap<u32, HashMap<Key, Weak<Filter>>>,
     others: HashMap<Key, Weak<Filter>>,
 }
 
 impl SubscriberIndex {
     fn install_index(&mut self, session_id: usize, sub_id: String, filters: &[Rc<Filter>]) {
         for (index, filter) in filters.iter().enumerate() {
-            if !filter.ids.is_empty() {
-                for key in filter.ids.iter() {
+            if indexed_by_full(&filter.ids) {
+                for key in filter.ids.iter().filter_map(HexPrefix::full) {
                     self.ids.entry(*key).or_default().insert(
                         Key::new(session_id, sub_id.clone(), index),
                         Rc::downgrade(filter),
                     );
                 }
-            } else if !filter.authors.is_empty() {
-                for key in filter.authors.iter() {
+            } else if indexed_by_full(&filter.authors) {
+                for key in filter.authors.iter().filter_map(HexPrefix::full) {
                     self.authors.entry(*key).or_default().insert(
                         Key::new(session_id, sub_id.clone(), index),
                         Rc::downgrade(filter),
                     );
                 }
-            } else if !filter.tags.is_empty() {
+            } else if indexed_by_tags(filter) {
                 for (tag, values) in filter.tags.iter() {
                     for val in values.iter() {
                         self.tags.entry(concat_tag(tag, val)).or_default().insert(
@@ -88,15 +106,15 @@ impl SubscriberIndex {
 
     fn uninstall_index(&mut self, session_id: usize, limit_sub_id: Option<&String>) {
         if let Some(subs) = self.subscriptions.get(&session_id) {
-            for (sub_id, filters) in subs {
+            for (sub_id, (_, filters, _)) in subs {
                 if let Some(limit_sub_id) = limit_sub_id {
                     if limit_sub_id != sub_id {
                         continue;
                     }
                 }
                 for (index, filter) in filters.iter().enumerate() {
-                    if !filter.ids.is_empty() {
-                        for key in filter.ids.iter() {
+                    if indexed_by_full(&filter.ids) {
+                        for key in filter.ids.iter().filter_map(HexPrefix::full) {
                             if let Some(map) = self.ids.get_mut(key) {
                                 map.remove(&Key::new(session_id, sub_id.clone(), index));
                                 if map.is_empty() {
@@ -104,8 +122,8 @@ impl SubscriberIndex {
                                 }
                             }
                         }
-                    } else if !filter.authors.is_empty() {
-                        for key in filter.authors.iter() {
+                    } else if indexed_by_full(&filter.authors) {
+                        for key in filter.authors.iter().filter_map(HexPrefix::full) {
                             if let Some(map) = self.authors.get_mut(key) {
                                 map.remove(&Key::new(session_id, sub_id.clone(), index));
                                 if map.is_empty() {
@@ -113,7 +131,7 @@ impl SubscriberIndex {
                                 }
                             }
                         }
-                    } else if !filter.tags.is_empty() {
+                    } else if indexed_by_tags(filter) {
                         for (tag, values) in filter.tags.iter() {
                             for val in values.iter() {
                                 let key = concat_tag(tag, val);
@@ -148,6 +166,7 @@ impl SubscriberIndex {
         session_id: usize,
         sub_id: String,
         filters: Vec<Filter>,
+        options: SubscriptionOptions,
         limit: usize,
     ) -> Subscribed {
         // according to NIP-01, <subscription_id> is an arbitrary, non-empty string of max length 64 chars
@@ -170,10 +189,51 @@ impl SubscriberIndex {
         let map = self.subscriptions.entry(session_id).or_default();
 
         // NIP01: overwrite the previous subscription
-        map.insert(sub_id, filters);
+        map.insert(sub_id, (self.seq, filters, options));
+        self.seq += 1;
         Subscribed::Ok
     }
 
+    /// Remove the latest subscriptions of the sessions over the limit,
+    /// return the removed (session_id, sub_id).
+    pub fn prune(&mut self, limit: usize) -> Vec<(usize, String)> {
+        let mut pruned = vec![];
+        for (session_id, subs) in &self.subscriptions {
+            if subs.len() > limit {
+                let mut list = subs
+                    .iter()
+                    .map(|(id, (seq, ..))| (*seq, id))
+                    .collect::<Vec<_>>();
+                list.sort();
+                for (_, sub_id) in &list[limit..] {
+                    pruned.push((*session_id, (*sub_id).clone()));
+                }
+            }
+        }
+        for (session_id, sub_id) in &pruned {
+            self.remove(*session_id, Some(sub_id));
+        }
+        pruned
+    }
+
+    /// the subscription exists with the same filters and options
+    pub fn contains(
+        &self,
+        session_id: usize,
+        sub_id: &str,
+        filters: &[Filter],
+        options: &SubscriptionOptions,
+    ) -> bool {
+        self.subscriptions
+            .get(&session_id)
+            .and_then(|subs| subs.get(sub_id))
+            .is_some_and(|(_, old, old_options)| {
+                old_options == options
+                    && old.len() == filters.len()
+                    && old.iter().zip(filters).all(|(a, b)| **a == *b)
+            })
+    }
+
     pub fn remove(&mut self, session_id: usize, sub_id: Option<&String>) {
         self.uninstall_index(session_id, sub_id);
         if let Some(sub_id) = sub_id {
@@ -238,7 +298,7 @@ impl SubscriberIndex {
 
     pub fn lookup1(&self, event: &EventIndex, mut f: impl FnMut(&usize, &String)) {
         for (session_id, subs) in &self.subscriptions {
-            for (sub_id, filters) in subs {
+            for (sub_id, (_, filters, _)) in subs {
                 for filter in filters {
                     if filter.r#match(event) {
                         f(session_id, sub_id);
@@ -256,6 +316,12 @@ pub struct Subscriber {
     pub subscriptions: HashMap<usize, HashMap<String, Vec<Filter>>>,
     pub index: SubscriberIndex,
     pub setting: SettingWrapper,
+    /// check the shadowbanned authors of the dispatched events
+    pub db: Option<Arc<Db>>,
+    /// map session_id -> authenticated pubkey
+    pub viewers: HashMap<usize, [u8; 32]>,
+    /// the sessions receive the NIP-70 protected events of their own only
+    pub protect_reads: HashSet<usize>,
 }
 
 impl Subscriber {
@@ -265,8 +331,23 @@ impl Subscriber {
             subscriptions: HashMap::new(),
             setting,
             index: SubscriberIndex::default(),
+            db: None,
+            viewers: HashMap::new(),
+            protect_reads: HashSet::new(),
         }
     }
+
+    /// Dispatch the events of the shadowbanned authors to their own sessions only
+    pub fn with_db(mut self, db: Option<Arc<Db>>) -> Self {
+        self.db = db;
+        self
+    }
+
+    fn is_shadowbanned(&self, pubkey: &[u8; 32]) -> bool {
+        self.db
+            .as_ref()
+            .is_some_and(|db| db.is_shadowbanned_cached(pubkey))
+    }
 }
 
 impl Actor for Subscriber {
@@ -279,11 +360,27 @@ impl Actor for Subscriber {
 impl Handler<Subscribe> for Subscriber {
     type Result = Subscribed;
     fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Subscribed {
+        let r = self.setting.read();
+        let sub = msg.subscription;
+        if let Some(viewer) = sub.viewer {
+            self.viewers.insert(msg.id, viewer);
+        }
+        if sub.protect_reads {
+            self.protect_reads.insert(msg.id);
+        }
+        if !r.limitation.resend_on_resubscribe
+            && self
+                .index
+                .contains(msg.id, &sub.id, &sub.filters, &sub.options)
+        {
+            return Subscribed::Unchanged;
+        }
         self.index.add(
             msg.id,
-            msg.subscription.id,
-            msg.subscription.filters,
-            self.setting.read().limitation.max_subscriptions,
+            sub.id,
+            sub.filters,
+            sub.options,
+            r.limitation.max_subscriptions,
         )
     }
 }
@@ -291,17 +388,52 @@ impl Handler<Subscribe> for Subscriber {
 impl Handler<Unsubscribe> for Subscriber {
     type Result = ();
     fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) {
+        if msg.sub_id.is_none() {
+            self.viewers.remove(&msg.id);
+            self.protect_reads.remove(&msg.id);
+        }
         self.index.remove(msg.id, msg.sub_id.as_ref());
     }
 }
 
+impl Handler<SettingChanged> for Subscriber {
+    type Result = ();
+    fn handle(&mut self, _: SettingChanged, _: &mut Self::Context) {
+        let limit = self.setting.read().limitation.max_subscriptions;
+        // the server sends the CLOSED to a slow session even when the mailbox is full
+        for (session_id, sub_id) in self.index.prune(limit) {
+            self.addr.do_send(SubscribeResult {
+                id: session_id,
+                msg: OutgoingMessage::closed(&sub_id, "error: subscription limit reduced"),
+                sub_id,
+            });
+        }
+    }
+}
+
 impl Handler<Dispatch> for Subscriber {
     type Result = ();
     fn handle(&mut self, msg: Dispatch, _: &mut Self::Context) {
         let event = &msg.event;
         let index = event.index();
-        let event_str = event.to_string();
+        let event_str = event
+            .raw()
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| event.to_string());
+        let author = self
+            .is_shadowbanned(event.pubkey())
+            .then_some(event.pubkey());
+        let protected = event.is_protected();
         self.index.lookup(index, |session_id, sub_id| {
+            if author.is_some() && self.viewers.get(session_id) != author {
+                return;
+            }
+            if protected
+                && self.protect_reads.contains(session_id)
+                && self.viewers.get(session_id) != Some(event.pubkey())
+            {
+                return;
+            }
             self.addr.do_send(SubscribeResult {
                 id: *session_id,
                 msg: OutgoingMessage::event(sub_id, &event_str),
@@ -380,6 +512,7 @@ mod tests {
                     filters: vec![Filter {
                         ..Default::default()
                     }],
+                    ..Default::default()
                 },
             })
             .await?;
@@ -394,6 +527,7 @@ mod tests {
                     filters: vec![Filter {
                         ..Default::default()
                     }],
+                    ..Default::default()
                 },
             })
             .await?;
@@ -408,6 +542,7 @@ mod tests {
                         kinds: vec![1000].into(),
                         ..Default::default()
                     }],
+                    ..Default::default()
                 },
             })
             .await?;
@@ -422,6 +557,7 @@ mod tests {
                         kinds: vec![1000].into(),
                         ..Default::default()
                     }],
+                    ..Default::default()
                 },
             })
             .await?;
@@ -437,6 +573,7 @@ mod tests {
                         kinds: vec![1000].into(),
                         ..Default::default()
                     }],
+                    ..Default::default()
                 },
             })
             .await?;
@@ -489,6 +626,7 @@ mod tests {
             1,
             "all".to_owned(),
             vec![Filter::from_str("{}")?, Filter::from_str("{}")?],
+            Default::default(),
             5,
         );
 
@@ -512,6 +650,7 @@ mod tests {
         "###,
                 )?,
             ],
+            Default::default(),
             5,
         );
         index.add(
@@ -534,6 +673,7 @@ mod tests {
         "###,
                 )?,
             ],
+            Default::default(),
             5,
         );
         index.add(
@@ -555,6 +695,7 @@ mod tests {
         "###,
                 )?,
             ],
+            Default::default(),
             5,
         );
         index.add(
@@ -577,6 +718,7 @@ mod tests {
         "###,
                 )?,
             ],
+            Default::default(),
             5,
         );
         index.add(
@@ -591,6 +733,7 @@ mod tests {
           }
         "###,
             )?],
+            Default::default(),
             5,
         );
         // override
@@ -606,6 +749,7 @@ mod tests {
           }
         "###,
             )?],
+            Default::default(),
             5,
         );
         assert_eq!(ok, Subscribed::Ok);
@@ -693,4 +837,220 @@ mod tests {
         assert_eq!(index.tags.len(), 0);
         Ok(())
     }
+
+    #[test]
+    fn prefix_tags() -> Result<()> {
+        let mut index = SubscriberIndex::default();
+        let mut filter = Filter::from_str(r###"{"#d": ["app/settings/"]}"###)?;
+        filter.prefix_tags = vec![b"d".to_vec()].into();
+        index.add(
+            1,
+            "prefix".to_owned(),
+            vec![filter.clone()],
+            Default::default(),
+            5,
+        );
+        // route to the linear scan
+        assert_eq!(index.others.len(), 1);
+        assert!(index.tags.is_empty());
+
+        let event = |d: &str| {
+            format!(
+                r###"
+        {{
+           "id": "0000000000000000000000000000000000000000000000000000000000000000",
+           "pubkey": "0000000000000000000000000000000000000000000000000000000000000001",
+           "kind": 30078,
+           "tags": [["d", "{}"]],
+           "content": "",
+           "created_at": 0,
+           "sig": "633db60e2e7082c13a47a6b19d663d45b2a2ebdeaf0b4c35ef83be2738030c54fc7fd56d139652937cdca875ee61b51904a1d0d0588a6acd6168d7be2909d693"
+         }}
+       "###,
+                d
+            )
+        };
+        assert_eq!(
+            lookup(&index, &event("app/settings/theme"))?,
+            vec![(1, "prefix".to_owned())]
+        );
+        assert!(lookup(&index, &event("app/other"))?.is_empty());
+
+        // with kinds
+        filter.kinds = vec![30078].into();
+        index.add(1, "prefix".to_owned(), vec![filter], Default::default(), 5);
+        assert!(index.others.is_empty());
+        assert_eq!(index.kinds.len(), 1);
+        assert_eq!(lookup(&index, &event("app/settings/"))?.len(), 1);
+        assert!(lookup(&index, &event("app/setting"))?.is_empty());
+
+        index.remove(1, None);
+        assert!(index.kinds.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn contains() -> Result<()> {
+        let mut index = SubscriberIndex::default();
+        let filters = vec![Filter::from_str(r###"{"kinds": [1], "limit": 10}"###)?];
+        let options = SubscriptionOptions::default();
+        index.add(1, "a".to_owned(), filters.clone(), options.clone(), 5);
+        assert!(index.contains(1, "a", &filters, &options));
+        assert!(!index.contains(2, "a", &filters, &options));
+        assert!(!index.contains(1, "b", &filters, &options));
+        let other = vec![Filter::from_str(r###"{"kinds": [1], "limit": 11}"###)?];
+        assert!(!index.contains(1, "a", &other, &options));
+        assert!(!index.contains(1, "a", &[filters.clone(), other].concat(), &options));
+        let options = SubscriptionOptions {
+            total_limit: Some(5),
+            ..Default::default()
+        };
+        assert!(!index.contains(1, "a", &filters, &options));
+        Ok(())
+    }
+
+    #[test]
+    fn hex_prefix() -> Result<()> {
+        let mut index = SubscriberIndex::default();
+        let filter = Filter::from_str(r###"{"authors": ["00000000"]}"###)?;
+        index.add(1, "prefix".to_owned(), vec![filter], Default::default(), 5);
+        // route to the linear scan
+        assert_eq!(index.others.len(), 1);
+        assert!(index.authors.is_empty());
+
+        let event = |pubkey: &str| {
+            format!(
+                r###"
+        {{
+           "id": "0000000000000000000000000000000000000000000000000000000000000000",
+           "pubkey": "{}",
+           "kind": 1,
+           "tags": [],
+           "content": "",
+           "created_at": 0,
+           "sig": "633db60e2e7082c13a47a6b19d663d45b2a2ebdeaf0b4c35ef83be2738030c54fc7fd56d139652937cdca875ee61b51904a1d0d0588a6acd6168d7be2909d693"
+         }}
+       "###,
+                pubkey
+            )
+        };
+        assert_eq!(
+            lookup(&index, &event(&hex::encode([0; 32])))?,
+            vec![(1, "prefix".to_owned())]
+        );
+        assert!(lookup(&index, &event(&hex::encode([1; 32])))?.is_empty());
+
+        index.remove(1, None);
+        assert!(index.others.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn shadowban() -> Result<()> {
+        let db = Arc::new(Db::open(crate::temp_data_path("subscriber_shadowban")?)?);
+        let author = [2u8; 32];
+        db.shadowban(&author)?;
+        let banned = Event::new([1; 32], author, 1, 1, vec![], "".to_owned(), [0; 64])?;
+        let other = Event::new([2; 32], [3; 32], 1, 1, vec![], "".to_owned(), [0; 64])?;
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let subscriber = Subscriber::new(addr, Setting::default().into())
+            .with_db(Some(db))
+            .start();
+        // session 1 is anonymous, 2 is the other user, 3 is the author
+        for (id, viewer) in [(1, None), (2, Some([3u8; 32])), (3, Some(author))] {
+            let res = subscriber
+                .send(Subscribe {
+                    id,
+                    subscription: Subscription {
+                        id: "sub".to_owned(),
+                        filters: vec![Filter::default()],
+                        viewer,
+                        ..Default::default()
+                    },
+                })
+                .await?;
+            assert_eq!(res, Subscribed::Ok);
+        }
+        let sessions = |event: Event| {
+            let subscriber = subscriber.clone();
+            let messages = messages.clone();
+            async move {
+                messages.write().clear();
+                subscriber.send(Dispatch { id: 0, event }).await?;
+                sleep(Duration::from_millis(100)).await;
+                let mut ids = messages.read().iter().map(|m| m.id).collect::<Vec<_>>();
+                ids.sort();
+                Ok::<_, anyhow::Error>(ids)
+            }
+        };
+        assert_eq!(sessions(banned).await?, vec![3]);
+        assert_eq!(sessions(other).await?, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn prune_on_setting_changed() -> Result<()> {
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let setting: SettingWrapper = Setting::default().into();
+        let subscriber = Subscriber::new(addr, setting.clone()).start();
+        // session 1 has 3 subscriptions, session 2 has 1
+        for (id, sub_id) in [(1, "a"), (1, "b"), (2, "a"), (1, "c")] {
+            let res = subscriber
+                .send(Subscribe {
+                    id,
+                    subscription: Subscription {
+                        id: sub_id.to_owned(),
+                        filters: vec![Filter::default()],
+                        ..Default::default()
+                    },
+                })
+                .await?;
+            assert_eq!(res, Subscribed::Ok);
+        }
+
+        setting.write().limitation.max_subscriptions = 1;
+        subscriber.send(SettingChanged).await?;
+        sleep(Duration::from_millis(100)).await;
+        let mut closed = messages
+            .read()
+            .iter()
+            .map(|m| (m.id, m.sub_id.clone(), m.msg.0.clone()))
+            .collect::<Vec<_>>();
+        closed.sort();
+        // the latest subscriptions are closed
+        assert_eq!(
+            closed,
+            vec![
+                (
+                    1,
+                    "b".to_owned(),
+                    r#"["CLOSED","b","error: subscription limit reduced"]"#.to_owned()
+                ),
+                (
+                    1,
+                    "c".to_owned(),
+                    r#"["CLOSED","c","error: subscription limit reduced"]"#.to_owned()
+                ),
+            ]
+        );
+
+        // the closed subscriptions don't receive the events
+        messages.write().clear();
+        let event = Event::new([1; 32], [1; 32], 1, 1, vec![], "".to_owned(), [0; 64])?;
+        subscriber.send(Dispatch { id: 0, event }).await?;
+        sleep(Duration::from_millis(100)).await;
+        let mut subs = messages
+            .read()
+            .iter()
+            .map(|m| (m.id, m.sub_id.clone()))
+            .collect::<Vec<_>>();
+        subs.sort();
+        assert_eq!(subs, vec![(1, "a".to_owned()), (2, "a".to_owned())]);
+        Ok(())
+    }
 }
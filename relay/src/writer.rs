@@ -1,7 +1,7 @@
-use crate::{message::*, Result};
+use crate::{message::*, QueryCache, Result};
 use actix::prelude::*;
 use metrics::{counter, histogram};
-use nostr_db::{now, CheckEventResult, Db};
+use nostr_db::{now, CheckEventResult, Db, EPHEMERAL_EXPIRED_SECONDS};
 use std::{
     sync::Arc,
     time::{Duration, Instant},
@@ -13,7 +13,7 @@ use tracing::{debug, error, info};
 
 const WRITE_INTERVAL_MS: u64 = 100;
 const DEL_INTERVAL_SECONDS: u64 = 60;
-const EPHEMERAL_EXPIRED_SECONDS: u64 = 60 * 5;
+const EXPIRATION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Writer {
     pub db: Arc<Db>,
@@ -21,6 +21,11 @@ pub struct Writer {
     pub events: Vec<WriteEvent>,
     pub write_interval_ms: u64,
     pub del_interval_seconds: u64,
+    /// 0 disables the sweep of the expired events
+    pub expiration_sweep_interval: Duration,
+    /// Flush the database to disk periodically, when it is opened without sync on commit
+    pub flush_interval: Option<Duration>,
+    pub cache: Option<Arc<QueryCache>>,
 }
 
 impl Writer {
@@ -31,26 +36,72 @@ impl Writer {
             events: Vec::new(),
             write_interval_ms: WRITE_INTERVAL_MS,
             del_interval_seconds: DEL_INTERVAL_SECONDS,
+            expiration_sweep_interval: EXPIRATION_SWEEP_INTERVAL,
+            flush_interval: None,
+            cache: None,
         }
     }
 
+    /// Invalidate the cached query results by the written events
+    pub fn with_cache(mut self, cache: Option<Arc<QueryCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_expiration_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiration_sweep_interval = interval;
+        self
+    }
+
+    pub fn with_flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Write the pending events in one transaction. When the map is full, grow the map size
+    /// up to `data.max_map_size` and write them again, the results are sent after the commit.
     pub fn write(&mut self) -> Result<()> {
         if !self.events.is_empty() {
             let start = Instant::now();
-            let mut writer = self.db.writer()?;
-            while let Some(event) = self.events.pop() {
-                let res = self.db.put(&mut writer, &event.event);
-                debug!(
-                    "write event: {} {} {:?}",
-                    event.id,
-                    event.event.id_str(),
-                    res,
-                );
-
+            let mut events = std::mem::take(&mut self.events);
+            // the latest received first
+            events.reverse();
+            let results = loop {
+                match self.write_events(&events) {
+                    Err(nostr_db::Error::Kv(nostr_db::kv::Error::MapFull))
+                        if self.db.grow_map_size()? =>
+                    {
+                        info!("grow the map size");
+                    }
+                    Err(err) => {
+                        for event in events {
+                            event_rejected("write_error");
+                            let eid = event.event.id_str();
+                            self.addr.do_send(WriteEventResult::Message {
+                                id: event.id,
+                                event: event.event,
+                                msg: OutgoingMessage::ok(&eid, false, "write event error"),
+                            });
+                        }
+                        return Err(err.into());
+                    }
+                    Ok(results) => break results,
+                }
+            };
+            for (event, res) in events.into_iter().zip(results) {
                 match res {
                     Ok(result) => {
-                        if let CheckEventResult::Ok(_num) = result {
-                            counter!("nostr_relay_new_event").increment(1);
+                        match &result {
+                            CheckEventResult::Ok(_num) => {
+                                counter!("nostr_relay_new_event").increment(1);
+                                if let Some(cache) = &self.cache {
+                                    cache.invalidate(&event.event);
+                                }
+                            }
+                            CheckEventResult::Invald(_) => event_rejected("invalid"),
+                            CheckEventResult::Deleted => event_rejected("deleted"),
+                            CheckEventResult::ReplaceIgnored => event_rejected("replaced"),
+                            CheckEventResult::Duplicate => {}
                         }
                         self.addr.do_send(WriteEventResult::Write {
                             id: event.id,
@@ -60,6 +111,7 @@ impl Writer {
                     }
                     Err(err) => {
                         error!(error = err.to_string(), "write event error");
+                        event_rejected("write_error");
                         let eid = event.event.id_str();
                         self.addr.do_send(WriteEventResult::Message {
                             id: event.id,
@@ -69,50 +121,70 @@ impl Writer {
                     }
                 }
             }
-            self.db.commit(writer)?;
             histogram!("nostr_relay_db_write").record(start.elapsed());
         }
         Ok(())
     }
 
+    /// Put the events and commit, return the result of each event.
+    /// The full map fails the whole transaction.
+    fn write_events(
+        &self,
+        events: &[WriteEvent],
+    ) -> Result<Vec<Result<CheckEventResult, nostr_db::Error>>, nostr_db::Error> {
+        let mut writer = self.db.writer()?;
+        let mut results = Vec::with_capacity(events.len());
+        for event in events {
+            let res = self.db.put(&mut writer, &event.event);
+            debug!(
+                "write event: {} {} {:?}",
+                event.id,
+                event.event.id_str(),
+                res,
+            );
+            if let Err(nostr_db::Error::Kv(nostr_db::kv::Error::MapFull)) = res {
+                return Err(nostr_db::Error::Kv(nostr_db::kv::Error::MapFull));
+            }
+            results.push(res);
+        }
+        self.db.commit(writer)?;
+        Ok(results)
+    }
+
     pub fn do_write(&mut self) {
         if let Err(err) = self.write() {
             error!(error = err.to_string(), "write events error");
         }
     }
 
-    pub fn del_expired(&self) -> Result<()> {
-        let reader = self.db.reader()?;
-        let iter = self
-            .db
-            .iter_expiration::<Vec<u8>, _>(&reader, Some(now()))?;
-        let mut ids = vec![];
-        for id in iter {
-            let id = id?;
-            ids.push(id);
+    /// Delete the expired events, return the number of deleted events
+    pub fn del_expired(&self) -> Result<usize> {
+        let num = self.db.del_expired(now())?;
+        if num > 0 {
+            info!("deleted {} expired events", num);
         }
-        self.db.batch_del(ids)?;
-        Ok(())
+        Ok(num)
     }
 
-    pub fn del_ephemeral(&self) -> Result<()> {
-        let reader = self.db.reader()?;
-        let iter = self
-            .db
-            .iter_ephemeral::<Vec<u8>, _>(&reader, Some(now() - EPHEMERAL_EXPIRED_SECONDS))?;
-        let mut ids = vec![];
-        for id in iter {
-            let id = id?;
-            ids.push(id);
+    pub fn do_del_expired(&self) {
+        if let Err(err) = self.del_expired() {
+            error!(error = err.to_string(), "delete expired events error");
         }
-        self.db.batch_del(ids)?;
+    }
+
+    pub fn do_flush(&self) {
+        if let Err(err) = self.db.flush() {
+            error!(error = err.to_string(), "flush database error");
+        }
+    }
+
+    pub fn del_ephemeral(&self) -> Result<()> {
+        self.db
+            .del_ephemeral(now().saturating_sub(EPHEMERAL_EXPIRED_SECONDS))?;
         Ok(())
     }
 
     pub fn do_del(&self) {
-        if let Err(err) = self.del_expired() {
-            error!(error = err.to_string(), "delete expired events error");
-        }
         if let Err(err) = self.del_ephemeral() {
             error!(error = err.to_string(), "delete ephemeral events error");
         }
@@ -130,19 +202,34 @@ impl Actor for Writer {
                 act.do_write();
             },
         );
-        // delete expired and ephemeral events
+        // delete ephemeral events
         ctx.run_interval(
             Duration::from_secs(self.del_interval_seconds),
             |act, _ctx| {
                 act.do_del();
             },
         );
+        // delete expired events
+        if !self.expiration_sweep_interval.is_zero() {
+            ctx.run_interval(self.expiration_sweep_interval, |act, _ctx| {
+                act.do_del_expired();
+            });
+        }
+
+        if let Some(interval) = self.flush_interval {
+            ctx.run_interval(interval, |act, _ctx| {
+                act.do_flush();
+            });
+        }
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         info!("Actor writer stopped");
         // save event when stopped
         self.do_write();
+        if self.flush_interval.is_some() {
+            self.do_flush();
+        }
     }
 }
 
@@ -153,15 +240,30 @@ impl Handler<WriteEvent> for Writer {
     }
 }
 
+impl Handler<WriteEvents> for Writer {
+    type Result = ();
+    fn handle(&mut self, msg: WriteEvents, _: &mut Self::Context) {
+        let id = msg.id;
+        self.events
+            .extend(msg.events.into_iter().map(|event| WriteEvent { id, event }));
+        // write the batch now
+        self.do_write();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{str::FromStr, time::Duration};
 
     use super::*;
-    use crate::temp_data_path;
+    use crate::{counter_value, temp_data_path};
     use actix_rt::time::sleep;
     use anyhow::Result;
-    use nostr_db::{Event, Filter};
+    use metrics_util::debugging::DebuggingRecorder;
+    use nostr_db::{
+        secp256k1::{rand::thread_rng, Keypair},
+        Event, Filter,
+    };
     use parking_lot::RwLock;
 
     #[derive(Default)]
@@ -200,6 +302,7 @@ mod tests {
 
         let mut writer = Writer::new(Arc::clone(&db), addr.clone());
         writer.del_interval_seconds = 1;
+        writer.expiration_sweep_interval = Duration::from_secs(1);
         writer.write_interval_ms = 100;
         let writer = writer.start();
 
@@ -293,4 +396,103 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn del_expired() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_del_expired")?)?);
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let time = now();
+        let expired = Event::create(
+            &key_pair,
+            time - 10,
+            1,
+            vec![vec!["expiration".to_owned(), (time - 1).to_string()]],
+            "".to_owned(),
+        )?;
+        let alive = Event::create(
+            &key_pair,
+            time - 10,
+            1,
+            vec![vec!["expiration".to_owned(), (time + 100).to_string()]],
+            "".to_owned(),
+        )?;
+        db.batch_put([&expired, &alive])?;
+
+        let receiver = Receiver::default().start();
+        let writer = Writer::new(Arc::clone(&db), receiver.recipient());
+        assert_eq!(writer.del_expired()?, 1);
+        let txn = db.reader()?;
+        assert!(db.get::<Event, _, _>(&txn, expired.id())?.is_none());
+        assert!(db.get::<Event, _, _>(&txn, alive.id())?.is_some());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn grow_map_size() -> Result<()> {
+        let db = Arc::new(Db::open_with(
+            temp_data_path("writer_grow_map_size")?,
+            nostr_db::DbOptions {
+                map_size: Some(1024 * 1024),
+                max_map_size: Some(64 * 1024 * 1024),
+                ..Default::default()
+            },
+        )?);
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.start().recipient());
+        // 2MB past the 1MB map in one transaction
+        for i in 0..256u32 {
+            let mut id = [0; 32];
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            writer.events.push(WriteEvent {
+                id: i as usize,
+                event: Event::new(id, [1; 32], 10, 1, vec![], "a".repeat(8 * 1024), [0; 64])?,
+            });
+        }
+        writer.write()?;
+        sleep(Duration::from_millis(100)).await;
+        let r = messages.read();
+        assert_eq!(r.len(), 256);
+        assert!(r.iter().all(|msg| matches!(
+            msg,
+            WriteEventResult::Write {
+                result: CheckEventResult::Ok(_),
+                ..
+            }
+        )));
+        assert!(db.env_info()?.map_size > 1024 * 1024);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn rejected_metrics() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_rejected")?)?);
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient());
+
+        let time = now();
+        let newer = Event::create(&key_pair, time, 0, vec![], "".to_owned())?;
+        let older = Event::create(&key_pair, time - 1, 0, vec![], "".to_owned())?;
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // the events are written from the end
+        metrics::with_local_recorder(&recorder, || -> Result<()> {
+            for event in [older, newer] {
+                writer.events.push(WriteEvent { id: 1, event });
+            }
+            Ok(writer.write()?)
+        })?;
+
+        assert_eq!(
+            counter_value(
+                &snapshotter,
+                "nostr_relay_event_rejected_total",
+                ("reason", "replaced")
+            ),
+            1
+        );
+        Ok(())
+    }
 }
@@ -21,29 +21,184 @@ pub struct BenchOpts {
     /// only bench the count method
     #[arg(long, value_name = "BOOL")]
     pub count: bool,
+
+    /// read ahead the number of events before reading, compare the first cold scan time
+    #[arg(long, value_name = "NUM", default_value = "0")]
+    pub prefetch: usize,
+
+    /// overwrite since in the filters by the duration before now, such as 7d, 12h, 30m
+    #[arg(long, value_name = "DURATION", value_parser = nostr_relay::duration::parse_str)]
+    pub since: Option<Duration>,
+
+    /// overwrite until in the filters by the duration before now, such as 7d, 12h, 30m
+    #[arg(long, value_name = "DURATION", value_parser = nostr_relay::duration::parse_str)]
+    pub until: Option<Duration>,
+
+    /// Compare with the second filter side by side
+    #[arg(long, value_name = "FILTER")]
+    pub filter2: Option<Filter>,
 }
 
 pub fn bench_opts(mut opts: BenchOpts) -> anyhow::Result<u64> {
     opts.filter.build_words();
-    let count = bench(&opts.path, &opts.filter, opts.count)?;
+    crate::relative_time(&mut opts.filter, opts.since, opts.until);
+    if let Some(mut filter2) = opts.filter2 {
+        filter2.build_words();
+        crate::relative_time(&mut filter2, opts.since, opts.until);
+        let res = bench_compare(
+            &opts.path,
+            &opts.filter,
+            &filter2,
+            opts.count,
+            opts.prefetch,
+            Duration::from_secs(5),
+        )?;
+        print_compare(&opts.filter, &filter2, &res);
+        return Ok(res.0.size);
+    }
+    let count = bench(&opts.path, &opts.filter, opts.count, opts.prefetch)?;
     Ok(count)
 }
 
-pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
-    fn once(db: &Db, filter: &Filter, count: bool) -> Result<(u64, Stats)> {
-        let reader = db.reader()?;
-        let mut iter = db.iter::<String, _>(&reader, filter)?;
-        if count {
-            Ok(iter.size()?)
-        } else {
-            let mut c = 0;
-            for event in iter.by_ref() {
-                let _json: String = event?;
-                c += 1;
-            }
-            Ok((c, iter.stats()))
+fn query_once(db: &Db, filter: &Filter, count: bool, prefetch: usize) -> Result<(u64, Stats)> {
+    let reader = db.reader()?;
+    let mut iter = db.iter::<String, _>(&reader, filter)?;
+    iter.prefetch(prefetch);
+    if count {
+        Ok(iter.size()?)
+    } else {
+        let mut c = 0;
+        for event in iter.by_ref() {
+            let _json: String = event?;
+            c += 1;
         }
+        Ok((c, iter.stats()))
+    }
+}
+
+/// The bench result of a filter
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub size: u64,
+    pub stats: Stats,
+    /// the first query time
+    pub cold: Duration,
+    /// average query time single threaded
+    pub single: Duration,
+    /// average query time multi-threaded
+    pub multi: Duration,
+    /// number of queries of each bench
+    pub times: u64,
+}
+
+fn measure(
+    db: &Db,
+    filter: &Filter,
+    count: bool,
+    prefetch: usize,
+    duration: Duration,
+) -> Result<BenchResult> {
+    let now = Instant::now();
+    let (size, stats) = query_once(db, filter, count, prefetch)?;
+    let cold = now.elapsed();
+    let mut times = (duration.as_nanos() / cold.as_nanos().max(1)) as u64;
+    if times == 0 {
+        times = 10;
+    }
+
+    let now = Instant::now();
+    for _i in 0..times {
+        query_once(db, filter, count, prefetch)?;
+    }
+    let single = now.elapsed() / times as u32;
+
+    let now = Instant::now();
+    (0..times).into_par_iter().try_for_each(|_| {
+        query_once(db, filter, count, prefetch)?;
+        Ok::<_, crate::Error>(())
+    })?;
+    let multi = now.elapsed() / times as u32;
+    Ok(BenchResult {
+        size,
+        stats,
+        cold,
+        single,
+        multi,
+        times,
+    })
+}
+
+/// Bench two filters in turn, each bench runs about the duration
+pub fn bench_compare(
+    path: &PathBuf,
+    filter: &Filter,
+    filter2: &Filter,
+    count: bool,
+    prefetch: usize,
+    duration: Duration,
+) -> Result<(BenchResult, BenchResult)> {
+    let db = Db::open(path)?;
+    let res1 = measure(&db, filter, count, prefetch, duration)?;
+    let res2 = measure(&db, filter2, count, prefetch, duration)?;
+    Ok((res1, res2))
+}
+
+/// Print the compare result as a table
+pub fn print_compare(filter: &Filter, filter2: &Filter, res: &(BenchResult, BenchResult)) {
+    let (a, b) = res;
+    println!("filter: {:?}", filter);
+    println!("filter2: {:?}", filter2);
+    println!("{:<16} {:>20} {:>20}", "", "filter", "filter2");
+    println!("{:<16} {:>20} {:>20}", "Size", a.size, b.size);
+    println!(
+        "{:<16} {:>20} {:>20}",
+        "Scan index", a.stats.scan_index, b.stats.scan_index
+    );
+    println!(
+        "{:<16} {:>20} {:>20}",
+        "Get index", a.stats.get_index, b.stats.get_index
+    );
+    println!(
+        "{:<16} {:>20} {:>20}",
+        "Get data", a.stats.get_data, b.stats.get_data
+    );
+    println!(
+        "{:<16} {:>20} {:>20}",
+        "Cold scan",
+        format!("{:?}", a.cold),
+        format!("{:?}", b.cold)
+    );
+    for (name, x, y) in [
+        ("Single threaded", a.single, b.single),
+        ("Multi-threaded", a.multi, b.multi),
+    ] {
+        println!(
+            "{:<16} {:>20} {:>20}",
+            name,
+            format!("{:?}", x),
+            format!("{:?}", y)
+        );
+        println!(
+            "{:<16} {:>20} {:>20}",
+            "",
+            fmt_per_sec(1, &x),
+            fmt_per_sec(1, &y)
+        );
     }
+    println!(
+        "Speedup (filter2 / filter): single threaded {:.2}x, multi-threaded {:.2}x",
+        speedup(a.single, b.single),
+        speedup(a.multi, b.multi)
+    );
+}
+
+/// how many times the second is faster than the first
+fn speedup(first: Duration, second: Duration) -> f64 {
+    first.as_nanos() as f64 / (second.as_nanos() as f64).max(1.0)
+}
+
+pub fn bench(path: &PathBuf, filter: &Filter, count: bool, prefetch: usize) -> Result<u64> {
+    let once = |db: &Db, filter: &Filter, count: bool| query_once(db, filter, count, prefetch);
 
     let db = Db::open(path)?;
     let now = Instant::now();
@@ -53,7 +208,12 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
     println!("{:?}", filter);
     println!("Size: {:?}", res.0);
     println!("{:?}", res.1);
-    println!("Time: {:?}, {}", elapsed, fmt_per_sec(1, &elapsed));
+    println!(
+        "Cold scan time: {:?}, {}, prefetch: {}",
+        elapsed,
+        fmt_per_sec(1, &elapsed),
+        prefetch
+    );
     let mut times = (Duration::from_secs(2).as_nanos() / elapsed.as_nanos()) as u64;
     if times == 0 {
         times = 10;
@@ -122,3 +282,53 @@ pub fn fmt_per_sec(count: u64, dur: &Duration) -> String {
     let count = (count as f64) / (dur.as_nanos() as f64) * 1_000_000_000.0;
     format!("{}/s", fmt_num(count))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_db::Event;
+
+    #[test]
+    fn compare() -> anyhow::Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("rnostr-bench-compare")
+            .tempdir()?;
+        let path = dir.path().to_path_buf();
+        {
+            let db = Db::open(&path)?;
+            let events = (0..20u8)
+                .map(|i| {
+                    Event::new(
+                        [i; 32],
+                        [1; 32],
+                        i as u64,
+                        (i % 2 + 1) as u32,
+                        vec![],
+                        "".to_owned(),
+                        [0; 64],
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            db.batch_put(events)?;
+        }
+        let filter = Filter::default();
+        let filter2: Filter = r#"{"kinds":[1]}"#.parse()?;
+        for count in [false, true] {
+            let (a, b) = bench_compare(
+                &path,
+                &filter,
+                &filter2,
+                count,
+                0,
+                Duration::from_millis(10),
+            )?;
+            assert_eq!(a.size, 20);
+            assert_eq!(b.size, 10);
+            assert!(a.times > 0 && b.times > 0);
+            assert!(a.stats.scan_index > b.stats.scan_index);
+            print_compare(&filter, &filter2, &(a, b));
+        }
+        assert!(speedup(Duration::from_secs(2), Duration::from_secs(1)) > 1.9);
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{Error, Result};
+use clap::Parser;
+use nostr_db::secp256k1::{rand::thread_rng, Keypair};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const HEADER: &str =
+    "# Generated by `rnostr init`, start the relay by `rnostr relay -c rnostr.toml`
+#
+";
+
+/// The documented config with the defaults
+const TEMPLATE: &str = include_str!("../rnostr.example.toml");
+
+/// init options
+#[derive(Debug, Clone, Parser)]
+pub struct InitOpts {
+    /// The directory to write the "rnostr.toml" config file and create the data directory
+    #[arg(value_name = "PATH", default_value = ".")]
+    pub path: PathBuf,
+
+    /// Overwrite the config file if exists
+    #[arg(long, value_name = "BOOL")]
+    pub force: bool,
+}
+
+/// The result of init
+#[derive(Debug, Clone)]
+pub struct Init {
+    /// the config file path
+    pub config: PathBuf,
+    /// the data directory path
+    pub data: PathBuf,
+    /// hex encoded secret key of the generated keypair
+    pub secret_key: String,
+    /// hex encoded x-only public key of the generated keypair, set as the relay information pubkey
+    pub pubkey: String,
+}
+
+/// Generate a keypair, write a config skeleton and create the data directory
+pub fn init<P: AsRef<Path>>(path: P, force: bool) -> Result<Init> {
+    let path = path.as_ref();
+    let config = path.join("rnostr.toml");
+    if config.exists() && !force {
+        return Err(Error::Message(format!(
+            "{} already exists, use --force to overwrite",
+            config.display()
+        )));
+    }
+
+    let keypair = Keypair::new_global(&mut thread_rng());
+    let secret_key = keypair.secret_key().display_secret().to_string();
+    let pubkey = keypair.x_only_public_key().0.to_string();
+
+    let data = path.join("data");
+    let content = TEMPLATE
+        .replacen("# pubkey = \"\"", &format!("pubkey = \"{}\"", pubkey), 1)
+        .replacen(
+            "path = \"./data\"",
+            &format!("path = {}", toml::Value::String(data.display().to_string())),
+            1,
+        );
+
+    fs::create_dir_all(&data)?;
+    fs::write(&config, format!("{}{}", HEADER, content))?;
+
+    Ok(Init {
+        config,
+        data,
+        secret_key,
+        pubkey,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::Setting;
+
+    #[test]
+    fn init_dir() -> anyhow::Result<()> {
+        let dir = tempfile::Builder::new().prefix("rnostr-init").tempdir()?;
+        let res = init(dir.path(), false)?;
+        assert!(res.data.is_dir());
+        assert_eq!(res.secret_key.len(), 64);
+        assert_eq!(res.pubkey.len(), 64);
+
+        let content = fs::read_to_string(&res.config)?;
+        assert!(content.starts_with("# Generated by `rnostr init`"));
+        // the documented sections
+        for section in [
+            "[information]",
+            "[data]",
+            "[network]",
+            "[limitation]",
+            "[auth]",
+        ] {
+            assert!(content.contains(section), "{}", section);
+        }
+        assert!(content.contains("# the data path (restart required)"));
+        let setting = Setting::read(&res.config, None)?;
+        setting.validate()?;
+        assert_eq!(setting.information.pubkey, Some(res.pubkey.clone()));
+        assert_eq!(setting.data.path, res.data);
+        assert_eq!(setting.network, Setting::default().network);
+        assert_eq!(setting.limitation, Setting::default().limitation);
+
+        // exists
+        assert!(init(dir.path(), false).is_err());
+        let res1 = init(dir.path(), true)?;
+        assert_ne!(res1.pubkey, res.pubkey);
+        Ok(())
+    }
+}
@@ -1,18 +1,21 @@
 use clap::Parser;
 use clio::{Input, Output};
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use nostr_db::{Db, Event, Filter, FromEventData};
+use nostr_db::{BatchOpts, CheckpointInfo, Db, Event, Filter, FromEventData};
 use rayon::prelude::*;
 use std::{
     fs::File,
     io::{BufRead, BufReader, Write},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 mod bench;
+mod init;
 mod relay;
 
 pub use bench::*;
+pub use init::*;
 pub use relay::*;
 
 #[derive(thiserror::Error, Debug)]
@@ -44,6 +47,10 @@ pub struct ImportOpts {
     #[arg(long, value_name = "BOOL")]
     pub search: bool,
 
+    /// Verify the event id and signature, drop the invalid events
+    #[arg(long)]
+    pub verify: bool,
+
     /// input jsonl data file, use '-' for stdin
     #[clap(value_parser, default_value = "-")]
     pub input: Input,
@@ -56,11 +63,20 @@ pub struct ExportOpts {
     #[arg(value_name = "PATH")]
     pub path: PathBuf,
 
-    /// [NIP-01](https://nips.be/1) Filter
+    /// [NIP-01](https://nips.be/1) Filter, the empty filter dumps all the events in the insertion order
     #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
     pub filter: Filter,
 
-    /// overwrite order in the filter, By default, if the filter provides a limit, it will order by time descending, otherwise ascending
+    /// overwrite since in the filter by the duration before now, such as 7d, 12h, 30m
+    #[arg(long, value_name = "DURATION", value_parser = nostr_relay::duration::parse_str)]
+    pub since: Option<Duration>,
+
+    /// overwrite until in the filter by the duration before now, such as 7d, 12h, 30m
+    #[arg(long, value_name = "DURATION", value_parser = nostr_relay::duration::parse_str)]
+    pub until: Option<Duration>,
+
+    /// overwrite order in the filter, By default, if the filter provides a limit, it will order by time descending, otherwise ascending.
+    /// `--desc true` dumps all the events by time descending
     #[arg(long, value_name = "BOOL")]
     pub desc: Option<bool>,
 
@@ -85,10 +101,26 @@ pub struct DeleteOpts {
     pub dry_run: bool,
 }
 
+/// backup options
+#[derive(Debug, Clone, Parser)]
+pub struct BackupOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// Backup directory, created if missing, must not contain a database
+    #[arg(value_name = "DEST")]
+    pub dest: PathBuf,
+
+    /// Omit the free pages, smaller but slower
+    #[arg(long)]
+    pub compact: bool,
+}
+
 /// import
 pub fn import_opts(opts: ImportOpts) -> anyhow::Result<usize> {
     fn run_import_opts<F: Fn(usize)>(opts: ImportOpts, f: F) -> anyhow::Result<usize> {
-        let count = import(&opts.path, opts.input, 10000, opts.search, f)?;
+        let count = import(&opts.path, opts.input, 10000, opts.search, opts.verify, f)?;
         Ok(count)
     }
 
@@ -120,6 +152,7 @@ pub fn import<F: Fn(usize)>(
     input: Input,
     batch: usize,
     search: bool,
+    verify: bool,
     f: F,
 ) -> Result<usize> {
     let db = Db::open(path)?;
@@ -128,6 +161,7 @@ pub fn import<F: Fn(usize)>(
     let lines = reader.lines();
     let mut batches = vec![];
     let mut count = 0;
+    let mut invalid = 0;
 
     fn parse_events(batches: &Vec<String>, search: bool) -> Vec<Event> {
         batches
@@ -149,6 +183,26 @@ pub fn import<F: Fn(usize)>(
             })
             .collect()
     }
+
+    // drop the events with invalid id or signature
+    let verify_events = |events: Vec<Event>, invalid: &mut usize| -> Vec<Event> {
+        if !verify {
+            return events;
+        }
+        let results = Event::verify_batch(&events);
+        events
+            .into_iter()
+            .zip(results)
+            .filter_map(|(event, res)| match res {
+                Ok(()) => Some(event),
+                Err(e) => {
+                    println!("error: {} {}", event.id_str(), e);
+                    *invalid += 1;
+                    None
+                }
+            })
+            .collect()
+    };
     let parse_batch = 30;
     let mut writer = db.writer()?;
     for item in lines.enumerate() {
@@ -157,9 +211,10 @@ pub fn import<F: Fn(usize)>(
         if index > 0 && index % parse_batch == 0 {
             // batch write
             // count += db.batch_put()?;
-            let events = parse_events(&batches, search);
+            let events = verify_events(parse_events(&batches, search), &mut invalid);
             for event in events {
-                db.put(&mut writer, event)?;
+                // the uid is ascending, append it directly
+                db.put_append(&mut writer, event)?;
                 count += 1;
             }
             batches.clear();
@@ -174,9 +229,19 @@ pub fn import<F: Fn(usize)>(
 
     db.commit(writer)?;
 
-    db.batch_put(parse_events(&batches, search))?;
-    count += batches.len();
+    let events = verify_events(parse_events(&batches, search), &mut invalid);
+    count += events.len();
+    db.batch_put_opts(
+        events,
+        BatchOpts {
+            append: true,
+            ..Default::default()
+        },
+    )?;
     db.flush()?;
+    if invalid > 0 {
+        println!("dropped {} invalid events", invalid);
+    }
     Ok(count)
 }
 
@@ -199,7 +264,19 @@ fn create_pb(total: u64) -> ProgressBar {
     pb
 }
 
-pub fn export_opts(opts: ExportOpts) -> anyhow::Result<usize> {
+/// Set the since and until of the filter by the durations before now
+pub fn relative_time(filter: &mut Filter, since: Option<Duration>, until: Option<Duration>) {
+    let now = nostr_db::now();
+    if let Some(since) = since {
+        filter.since = Some(now.saturating_sub(since.as_secs()));
+    }
+    if let Some(until) = until {
+        filter.until = Some(now.saturating_sub(until.as_secs()));
+    }
+}
+
+pub fn export_opts(mut opts: ExportOpts) -> anyhow::Result<usize> {
+    relative_time(&mut opts.filter, opts.since, opts.until);
     fn run_export_opts<F: Fn(usize)>(mut opts: ExportOpts, f: F) -> anyhow::Result<usize> {
         opts.filter.build_words();
         if let Some(desc) = opts.desc {
@@ -225,7 +302,7 @@ pub fn export_opts(opts: ExportOpts) -> anyhow::Result<usize> {
 }
 
 pub fn count(path: &PathBuf, filter: &Filter) -> Result<u64> {
-    let db = Db::open(path)?;
+    let db = Db::open_read_only(path)?;
     let reader = db.reader()?;
     let iter = db.iter::<String, _>(&reader, filter)?;
     Ok(iter.size()?.0)
@@ -237,8 +314,21 @@ pub fn export<F: Fn(usize)>(
     filter: &Filter,
     f: F,
 ) -> Result<usize> {
-    let db = Db::open(path)?;
+    let db = Db::open_read_only(path)?;
     let reader = db.reader()?;
+    if *filter == Filter::default() {
+        // dump everything in the insertion order
+        let mut count = 0;
+        db.export_all(&reader, |json| {
+            output.write_all(json)?;
+            output.write_all(b"\n")?;
+            count += 1;
+            f(count);
+            Ok(())
+        })?;
+        output.finish()?;
+        return Ok(count);
+    }
     let iter = db.iter::<String, _>(&reader, filter)?;
     let mut count = 0;
     for event in iter {
@@ -268,3 +358,32 @@ pub fn delete(path: &PathBuf, filter: &Filter, dry_run: bool) -> Result<usize> {
     db.commit(writer)?;
     Ok(ids.len())
 }
+
+/// Online backup, the relay can keep running
+pub fn backup(path: &PathBuf, dest: &PathBuf, compact: bool) -> Result<CheckpointInfo> {
+    let db = Db::open(path)?;
+    Ok(db.backup(dest, compact)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_since() -> anyhow::Result<()> {
+        let mut opts = ExportOpts::try_parse_from(["export", "data", "--since", "1d"])?;
+        assert_eq!(opts.since, Some(Duration::from_secs(86400)));
+        relative_time(&mut opts.filter, opts.since, opts.until);
+        let since = opts.filter.since.unwrap();
+        assert!(since.abs_diff(nostr_db::now() - 86400) <= 1);
+        assert!(opts.filter.until.is_none());
+
+        let mut opts = BenchOpts::try_parse_from(["bench", "data", "--until", "12h"])?;
+        relative_time(&mut opts.filter, opts.since, opts.until);
+        let until = opts.filter.until.unwrap();
+        assert!(until.abs_diff(nostr_db::now() - 43200) <= 1);
+
+        assert!(ExportOpts::try_parse_from(["export", "data", "--since", "1x"]).is_err());
+        Ok(())
+    }
+}
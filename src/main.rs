@@ -29,6 +29,11 @@ enum Commands {
     Relay(RelayOpts),
     /// Delete data by filter
     Delete(DeleteOpts),
+    /// Generate a keypair and a config skeleton, create the data directory
+    Init(InitOpts),
+    /// Backup the database while the relay is running
+    #[command(arg_required_else_help = true)]
+    Backup(BackupOpts),
 }
 
 fn main() -> anyhow::Result<()> {
@@ -45,7 +50,7 @@ fn main() -> anyhow::Result<()> {
             bench_opts(opts)?;
         }
         Commands::Relay(opts) => {
-            relay(&opts.config, opts.watch)?;
+            relay(opts.config.as_ref(), opts.watch, opts.dry_run)?;
         }
         Commands::Delete(opts) => {
             let count = delete(&opts.path, &opts.filter, opts.dry_run)?;
@@ -55,6 +60,18 @@ fn main() -> anyhow::Result<()> {
                 println!("Deleted {} events", count);
             }
         }
+        Commands::Init(opts) => {
+            let res = init(&opts.path, opts.force)?;
+            println!("Config: {}", res.config.display());
+            println!("Data: {}", res.data.display());
+            println!("Public key: {}", res.pubkey);
+            println!("Secret key: {}", res.secret_key);
+            println!("Keep the secret key safe, it is not saved in the config");
+        }
+        Commands::Backup(opts) => {
+            let info = backup(&opts.path, &opts.dest, opts.compact)?;
+            println!("Backup to {}, seq {}", info.path.display(), info.seq);
+        }
     }
     Ok(())
 }
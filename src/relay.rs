@@ -7,37 +7,43 @@ use tracing::info;
 /// Start relay options
 #[derive(Debug, Clone, Parser)]
 pub struct RelayOpts {
-    /// Nostr relay config path
-    #[arg(
-        short = 'c',
-        value_name = "PATH",
-        default_value = "./config/rnostr.toml"
-    )]
-    pub config: PathBuf,
+    /// Nostr relay config path, configure by environment variables only when absent
+    #[arg(short = 'c', long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
 
     /// Auto reload when config changed
     #[arg(long, value_name = "BOOL")]
     pub watch: bool,
+
+    /// Validate the config, database and bind address, then exit without serving
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 #[actix_rt::main]
-pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
+pub async fn relay(config: Option<&PathBuf>, watch: bool, dry_run: bool) -> Result<()> {
     tracing_subscriber::fmt::init();
     info!("Start relay server");
 
     // actix_rt::System::new().block_on(async {
     // });
 
-    let app_data = App::create(Some(config), watch, Some("RNOSTR".to_owned()), None)?;
+    if dry_run {
+        App::dry_run(config, Some("RNOSTR".to_owned()), None)?;
+        info!("Dry run ok");
+        return Ok(());
+    }
+
+    let app_data = App::create(config, watch, Some("RNOSTR".to_owned()), None)?;
     let db = app_data.db.clone();
-    app_data
+    let app_data = app_data
         .add_extension(nostr_extensions::Metrics::new())
         .add_extension(nostr_extensions::Auth::new())
+        .add_extension(nostr_extensions::KindPolicy::new())
         .add_extension(nostr_extensions::Ratelimiter::new())
         .add_extension(nostr_extensions::Count::new(db))
-        .add_extension(nostr_extensions::Search::new())
-        .web_server()?
-        .await?;
+        .add_extension(nostr_extensions::Search::new());
+    app_data.web_server()?.await?;
     info!("Relay server shutdown");
 
     Ok(())